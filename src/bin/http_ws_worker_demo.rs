@@ -1,6 +1,6 @@
-use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::broadcast;
 use uuid::Uuid;
-use client_side::domain::ConversationId;
+use client_side::domain::{ChatBody, ConversationId};
 use client_side::protocol::network::*;
 
 #[tokio::main]
@@ -23,31 +23,36 @@ async fn main() -> anyhow::Result<()> {
     //     Err(e) => println!("{}", e),
     // }
 
-    let (tx0, mut rx0) = unbounded_channel();
-    let worker0 = RealWsWorker::try_new(0u64, "fake-access-token:testuser0".to_string(), tx0.clone()).await?;
+    let config = NetworkConfig { cert_path: Some("certs/dev_cert.pem".into()), ..NetworkConfig::default() };
+    let tls_config = load_tls_config(config.cert_path.as_deref())?;
+
+    let (tx0, mut rx0) = broadcast::channel(config.channel_capacity);
+    let worker0 = RealWsWorker::try_new(config.ws_url.clone(), tls_config.clone(), 0u64, "fake-access-token:testuser0".to_string(), tx0.clone(), std::sync::Arc::default()).await?;
     let message0 = ClientToServer::Send(SendMessage {
         message_seq: 0,
-        content: ChatContent { conversation_id: ConversationId(Uuid::nil()), content: "Hello".to_string() },
+        client_id: Uuid::new_v4(),
+        content: ChatContent { conversation_id: ConversationId(Uuid::nil()), content: ChatBody::Text("Hello".to_string()), attachment: None },
     });
 
-    let (tx1, mut rx1) = unbounded_channel();
-    let worker1 = RealWsWorker::try_new(0u64, "fake-access-token:testuser1".to_string(), tx1.clone()).await?;
+    let (tx1, mut rx1) = broadcast::channel(config.channel_capacity);
+    let worker1 = RealWsWorker::try_new(config.ws_url.clone(), tls_config.clone(), 0u64, "fake-access-token:testuser1".to_string(), tx1.clone(), std::sync::Arc::default()).await?;
     let message1 = ClientToServer::Send(SendMessage {
         message_seq: 0,
-        content: ChatContent { conversation_id: ConversationId(Uuid::nil()), content: "Hi".to_string() },
+        client_id: Uuid::new_v4(),
+        content: ChatContent { conversation_id: ConversationId(Uuid::nil()), content: ChatBody::Text("Hi".to_string()), attachment: None },
     });
 
     let _ = worker0.to_sender.send(message0)?;
     let _ = worker1.to_sender.send(message1)?;
 
     let recv0 = tokio::spawn(async move {
-        if let Some(r) = rx0.recv().await {
+        if let Ok(r) = rx0.recv().await {
             println!("{:?}", r);
         }
     });
 
     let recv1 = tokio::spawn(async move {
-        if let Some(r) = rx1.recv().await {
+        if let Ok(r) = rx1.recv().await {
             println!("{:?}", r);
         }
     });
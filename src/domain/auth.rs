@@ -7,3 +7,39 @@ pub struct AuthTokens {
     pub refresh_token: String,
     pub refresh_expires_in: u64,  // seconds
 }
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// A rough client-side guess; the server decides what it accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Strength {
+    TooShort,
+    Weak,
+    Ok,
+    Strong,
+}
+
+/// Rates `password` by its length and how many of lowercase, uppercase, digits and
+/// symbols it mixes.
+pub fn password_strength(password: &str) -> Strength {
+    let length = password.chars().count();
+    if length < MIN_PASSWORD_LENGTH {
+        return Strength::TooShort;
+    }
+
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_ascii_digit()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ]
+    .into_iter()
+    .filter(|&present| present)
+    .count();
+
+    match classes {
+        0 | 1 => Strength::Weak,
+        _ if length >= 12 && classes >= 3 => Strength::Strong,
+        _ => Strength::Ok,
+    }
+}
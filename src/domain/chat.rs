@@ -2,3 +2,74 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ConversationId(pub uuid::Uuid);
+
+/// A file uploaded ahead of the message that references it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub blob_id: uuid::Uuid,
+    pub mime: String,
+    pub filename: String,
+}
+
+impl Attachment {
+    pub fn is_image(&self) -> bool {
+        self.mime.starts_with("image/")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConversationKind {
+    Direct,
+    Group,
+}
+
+/// What a chat message says; edits and deletions point at an earlier message by its `seq`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "ChatBodyRepr", into = "ChatBodyRepr")]
+pub enum ChatBody {
+    Text(String),
+    Edit { target_seq: u64, text: String },
+    Delete { target_seq: u64 },
+    /// Written by the server rather than a member, e.g. someone joining.
+    System(String),
+}
+
+/// Plain text stays a bare string on the wire, which is all older peers send and understand.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ChatBodyRepr {
+    Plain(String),
+    Tagged(TaggedChatBody),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum TaggedChatBody {
+    Text { text: String },
+    Edit { target_seq: u64, text: String },
+    Delete { target_seq: u64 },
+    System { text: String },
+}
+
+impl From<ChatBodyRepr> for ChatBody {
+    fn from(repr: ChatBodyRepr) -> Self {
+        match repr {
+            ChatBodyRepr::Plain(text) | ChatBodyRepr::Tagged(TaggedChatBody::Text { text }) => ChatBody::Text(text),
+            ChatBodyRepr::Tagged(TaggedChatBody::Edit { target_seq, text }) => ChatBody::Edit { target_seq, text },
+            ChatBodyRepr::Tagged(TaggedChatBody::Delete { target_seq }) => ChatBody::Delete { target_seq },
+            ChatBodyRepr::Tagged(TaggedChatBody::System { text }) => ChatBody::System(text),
+        }
+    }
+}
+
+impl From<ChatBody> for ChatBodyRepr {
+    fn from(body: ChatBody) -> Self {
+        match body {
+            ChatBody::Text(text) => ChatBodyRepr::Plain(text),
+            ChatBody::Edit { target_seq, text } => ChatBodyRepr::Tagged(TaggedChatBody::Edit { target_seq, text }),
+            ChatBody::Delete { target_seq } => ChatBodyRepr::Tagged(TaggedChatBody::Delete { target_seq }),
+            ChatBody::System(text) => ChatBodyRepr::Tagged(TaggedChatBody::System { text }),
+        }
+    }
+}
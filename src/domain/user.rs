@@ -2,3 +2,41 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct UserId(pub uuid::Uuid);
+
+pub const MIN_USERNAME_LENGTH: usize = 3;
+pub const MAX_USERNAME_LENGTH: usize = 32;
+
+/// Why `validate_username` turned a username down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsernameError {
+    TooShort,
+    TooLong,
+    InvalidCharacter(char),
+}
+
+impl std::fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsernameError::TooShort => write!(f, "Username needs at least {} characters", MIN_USERNAME_LENGTH),
+            UsernameError::TooLong => write!(f, "Username can have at most {} characters", MAX_USERNAME_LENGTH),
+            UsernameError::InvalidCharacter(c) => write!(f, "Username cannot contain '{}'", c),
+        }
+    }
+}
+
+impl std::error::Error for UsernameError {}
+
+/// A front-line check so an obviously bad username does not spend a captcha; the server
+/// still has the last word, e.g. on duplicates.
+///
+/// Allows ASCII letters, digits, `_`, `-` and `.`.
+pub fn validate_username(username: &str) -> Result<(), UsernameError> {
+    if let Some(c) = username.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))) {
+        return Err(UsernameError::InvalidCharacter(c));
+    }
+    match username.len() {
+        length if length < MIN_USERNAME_LENGTH => Err(UsernameError::TooShort),
+        length if length > MAX_USERNAME_LENGTH => Err(UsernameError::TooLong),
+        _ => Ok(()),
+    }
+}
@@ -2,3 +2,4 @@ pub mod shell;
 pub mod page;
 pub mod protocol;
 pub mod domain;
+pub mod util;
@@ -1,6 +1,7 @@
 use clap::{Parser};
 use tracing_subscriber::EnvFilter;
 use client_side::*;
+use client_side::protocol::network::NetworkConfig;
 
 fn main() {
     let args = shell::Args::parse();
@@ -11,11 +12,23 @@ fn main() {
         .with_env_filter(EnvFilter::new(log_config))
         .init();
 
+    // Bad arguments stay bad, but a network that failed to start can be retried.
+    let app = match NetworkConfig::try_new(&args.api_base_url, &args.ws_url, args.cert_path) {
+        Ok(config) => shell::App::try_new(config.clone()).unwrap_or_else(|e| {
+            tracing::error!("{:#}", e);
+            shell::App::new_fatal(format!("{:#}", e), Some(config))
+        }),
+        Err(e) => {
+            tracing::error!("{:#}", e);
+            shell::App::new_fatal(format!("{:#}", e), None)
+        }
+    };
+
     if let Err(e) = eframe::run_native(
-        "ClientSide",
+        shell::APP_NAME,
         eframe::NativeOptions::default(),
-        Box::new(|_cc| Ok(Box::new(shell::App::new()))),
+        Box::new(|_cc| Ok(Box::new(app))),
     ) {
         tracing::error!("{}", e);
     }
-}
\ No newline at end of file
+}
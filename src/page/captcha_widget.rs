@@ -0,0 +1,297 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use crossbeam_channel::Sender;
+use eframe::egui;
+use eframe::egui::{TextBuffer, TextureHandle};
+use tracing::{trace, warn};
+use uuid::Uuid;
+use crate::page::{captcha_failed_message, load_base64_texture, Update};
+use crate::protocol::network::{CaptchaEvent, NetworkError, NetworkInterface, WithGeneration};
+use crate::shell::AppMessage;
+use crate::util::Debouncer;
+
+/// The shortest time between two captcha fetches; clicks in between are ignored.
+pub const CAPTCHA_RELOAD_COOLDOWN: Duration = Duration::from_millis(750);
+
+/// Fetch results for a `CaptchaWidget`, wrapped by the embedding page's own message type.
+#[derive(Debug)]
+pub enum CaptchaMessage {
+    Fetched(u64, Uuid, String, DateTime<Utc>),
+    Failed(u64),
+}
+
+/// What the user did to the captcha in a frame.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CaptchaInput {
+    AnswerChanged,
+    /// Enter in the answer field.
+    Submitted,
+    /// The image or retry button was clicked; the widget already started the new fetch.
+    ReloadRequested,
+}
+
+pub struct CaptchaResponse {
+    /// The answer field, e.g. to move focus onto or off it.
+    pub field: egui::Response,
+    pub input: Option<CaptchaInput>,
+}
+
+/// The captcha part of a form: the answer field and the image, which is fetched on creation and
+/// again when clicked or expired. A spinner or a retry button stands in while there is no image.
+pub struct CaptchaWidget {
+    message_tx: Sender<AppMessage>,
+    map_function: Arc<Box<dyn Fn(CaptchaMessage) -> AppMessage + Send + Sync>>,
+    real_network: Rc<RefCell<dyn NetworkInterface>>,
+    timeout_ms: u64,
+    texture_name: &'static str,
+
+    answer: String,
+    generation: Option<u64>,
+    /// Started by each fetch, for `CAPTCHA_RELOAD_COOLDOWN`.
+    reload_cooldown: Debouncer,
+    id: Option<Uuid>,
+    expire_at: Option<DateTime<Utc>>,
+    base64: String,
+    texture: Option<TextureHandle>,
+}
+
+impl CaptchaWidget {
+    pub fn new(
+        message_tx: Sender<AppMessage>,
+        map_function: Arc<Box<dyn Fn(CaptchaMessage) -> AppMessage + Send + Sync>>,
+        real_network: Rc<RefCell<dyn NetworkInterface>>,
+        timeout_ms: u64,
+        texture_name: &'static str,
+    ) -> Self {
+        let mut widget = Self {
+            message_tx,
+            map_function,
+            real_network,
+            timeout_ms,
+            texture_name,
+            answer: String::new(),
+            generation: None,
+            reload_cooldown: Debouncer::new(CAPTCHA_RELOAD_COOLDOWN),
+            id: None,
+            expire_at: None,
+            base64: String::new(),
+            texture: None,
+        };
+        widget.reload();
+        widget
+    }
+
+    /// `None` until an image is up, so nothing can be submitted against a captcha the user has not seen.
+    pub fn id(&self) -> Option<Uuid> {
+        self.id
+    }
+
+    pub fn answer(&self) -> &str {
+        &self.answer
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.id.is_none() && self.generation.is_some()
+    }
+
+    /// Swaps in a fresh captcha after an attempt, since any attempt spends the current one.
+    pub fn renew(&mut self) {
+        self.answer.clear();
+        self.reload();
+    }
+
+    fn reload(&mut self) {
+        self.id = None;
+        self.expire_at = None;
+        self.texture = None;
+        self.reload_cooldown.record();
+        fetch_captcha(self.message_tx.clone(), self.map_function.clone(), &mut self.generation, self.real_network.clone(), self.timeout_ms);
+    }
+
+    /// Gives up on a stuck fetch, leaving the retry button; a late reply fails the generation check.
+    fn cancel(&mut self) {
+        if let Some(generation) = self.generation.take() {
+            if let Err(e) = self.real_network.borrow_mut().cancel(generation) {
+                trace!("Captcha fetch already finished: {:#}", e);
+            }
+        }
+    }
+
+    fn cooling_down(&self) -> bool {
+        !self.reload_cooldown.ready()
+    }
+
+    /// An expired captcha can only be rejected, so swap it before the user tries. Like a click, this
+    /// waits out the cooldown, in case the server hands out captchas that are expired on arrival.
+    fn renew_if_expired(&mut self) {
+        let expired = self.id.is_some() && self.expire_at.is_some_and(|expire_at| expire_at <= Utc::now());
+        if expired && !self.cooling_down() {
+            trace!("Captcha expired, fetching a new one");
+            self.renew();
+        }
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) -> CaptchaResponse {
+        let mut input = None;
+        ui.label("Captcha:");
+        let field = ui.text_edit_singleline(&mut self.answer);
+        if field.changed() {
+            input = Some(CaptchaInput::AnswerChanged);
+        }
+        if field.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+            input = Some(CaptchaInput::Submitted);
+        }
+
+        if !self.base64.is_empty() {
+            let base64_string = self.base64.take();
+            match load_base64_texture(ui.ctx(), &base64_string, self.texture_name) {
+                Ok(texture) => self.texture = Some(texture),
+                Err(e) => {
+                    // Same as a failed fetch, so the retry button shows instead of nothing.
+                    warn!("Failed to decode captcha of {} bytes: {:#}", base64_string.len(), e);
+                    self.generation = None;
+                    self.id = None;
+                    self.expire_at = None;
+                }
+            }
+        }
+
+        self.renew_if_expired();
+        let expires_in = self.expire_at.map(|expire_at| expire_at - Utc::now());
+
+        // Clicks during the cooldown are ignored by greying out the reload controls.
+        let can_reload = !self.cooling_down();
+        if let Some(texture) = self.texture.as_ref() {
+            if ui.add_enabled(can_reload, egui::ImageButton::new(texture)).clicked() {
+                self.reload();
+                input = Some(CaptchaInput::ReloadRequested);
+            }
+            if let Some(left) = expires_in.filter(|_| self.id.is_some()) {
+                ui.label(egui::RichText::new(format!("Expires in {}s", left.num_seconds().max(0))).weak().small());
+                ui.ctx().request_repaint_after(Duration::from_secs(1));
+            }
+        } else if self.generation.is_some() {
+            let cancel = ui.horizontal(|ui| {
+                ui.add(egui::Spinner::new());
+                ui.label("Loading captcha...");
+                ui.small_button("Cancel").clicked()
+            }).inner;
+            if cancel {
+                self.cancel();
+            }
+        } else if ui.add_enabled(can_reload, egui::Button::new(captcha_failed_message())).clicked() {
+            self.reload();
+            input = Some(CaptchaInput::ReloadRequested);
+        }
+
+        CaptchaResponse { field, input }
+    }
+}
+
+impl Update<CaptchaMessage> for CaptchaWidget {
+    fn update_one(&mut self, message: CaptchaMessage) {
+        match message {
+            CaptchaMessage::Fetched(generation, id, base64_string, expire_at) if self.generation == Some(generation) => {
+                self.id = Some(id);
+                self.expire_at = Some(expire_at);
+                self.base64 = base64_string;
+            }
+            CaptchaMessage::Failed(generation) if self.generation == Some(generation) => {
+                self.generation = None;
+                self.id = None;
+                self.expire_at = None;
+                self.texture = None;
+            }
+            CaptchaMessage::Fetched(..) | CaptchaMessage::Failed(_) => {
+                warn!("Drop one captcha message due to generation mismatch");
+            }
+        }
+    }
+}
+
+impl Drop for CaptchaWidget {
+    fn drop(&mut self) {
+        // A finished fetch is already gone, so a failed cancel is expected here.
+        if let Some(generation) = self.generation {
+            if self.real_network.borrow_mut().cancel(generation).is_ok() {
+                trace!("Cancelled captcha fetch on leaving the page: {}", generation);
+            }
+        }
+    }
+}
+
+fn fetch_captcha(
+    message_tx: Sender<AppMessage>,
+    map_function: Arc<Box<dyn Fn(CaptchaMessage) -> AppMessage + Send + Sync>>,
+    generation: &mut Option<u64>,
+    network: Rc<RefCell<dyn NetworkInterface>>,
+    timeout_ms: u64,
+) {
+    let message_tx_clone = message_tx.clone();
+    let map_function_clone = map_function.clone();
+    let map = move |event: WithGeneration<CaptchaEvent>| {
+        let generation = event.generation;
+        let message = match event.result.result {
+            Ok(data) => CaptchaMessage::Fetched(generation, data.id, data.image_base64, data.expire_at),
+            Err(_) => CaptchaMessage::Failed(generation),
+        };
+        let _ = message_tx_clone.send(map_function_clone(message));
+    };
+
+    let map_err = move |error: WithGeneration<NetworkError>| {
+        let message = CaptchaMessage::Failed(error.generation);
+        let _ = message_tx.send(map_function(message));
+    };
+
+    *generation = network.borrow_mut().fetch_captcha(
+        timeout_ms,
+        Box::new(map),
+        Box::new(map_err),
+    ).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::Receiver;
+    use crate::page::LoginMessage;
+    use crate::protocol::network::{CaptchaData, FakeNetworkInterface, FakeReply};
+
+    fn widget(network: Rc<RefCell<FakeNetworkInterface>>) -> (CaptchaWidget, Receiver<AppMessage>) {
+        let (message_tx, message_rx) = crossbeam_channel::unbounded();
+        let map_function: Arc<Box<dyn Fn(CaptchaMessage) -> AppMessage + Send + Sync>> =
+            Arc::new(Box::new(|message| AppMessage::Login(0, LoginMessage::Captcha(message))));
+        (CaptchaWidget::new(message_tx, map_function, network, 1000, "captcha"), message_rx)
+    }
+
+    fn pump(widget: &mut CaptchaWidget, message_rx: &Receiver<AppMessage>) {
+        while let Ok(message) = message_rx.try_recv() {
+            if let AppMessage::Login(_, LoginMessage::Captcha(message)) = message {
+                widget.update_one(message);
+            }
+        }
+    }
+
+    fn expired_captcha() -> FakeReply<CaptchaEvent> {
+        let data = CaptchaData { id: Uuid::new_v4(), image_base64: String::new(), expire_at: Utc::now() - chrono::Duration::seconds(1) };
+        FakeReply::Event(CaptchaEvent { result: Ok(data) })
+    }
+
+    #[test]
+    fn expired_captcha_is_refetched_only_after_the_cooldown() {
+        let fake = Rc::new(RefCell::new(FakeNetworkInterface::new()));
+        fake.borrow_mut().captcha_replies.extend([expired_captcha(), expired_captcha()]);
+        let (mut widget, messages) = widget(fake.clone());
+        pump(&mut widget, &messages);
+
+        widget.renew_if_expired();
+        assert_eq!(fake.borrow().captcha_replies.len(), 1);
+
+        widget.reload_cooldown.reset();
+        widget.renew_if_expired();
+        assert!(fake.borrow().captcha_replies.is_empty());
+    }
+}
@@ -1,14 +1,19 @@
+use crossbeam_channel::Sender;
 use eframe::egui;
 use eframe::egui::Context;
 use crate::page::View;
+use crate::shell::AppMessage;
 
 pub struct FatalPage {
+    message_tx: Sender<AppMessage>,
     error_message: String,
+    /// Whether restarting the network may help; otherwise quitting is the only way out.
+    recoverable: bool,
 }
 
 impl FatalPage {
-    pub fn new(error_message: String) -> Self {
-        Self { error_message }
+    pub fn new(message_tx: Sender<AppMessage>, error_message: String, recoverable: bool) -> Self {
+        Self { message_tx, error_message, recoverable }
     }
 }
 
@@ -20,7 +25,22 @@ impl View for FatalPage {
             .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
             .show(ctx, |ui| {
                 ui.label(format!("Cause: {}", self.error_message));
-                ui.label("Please restart the application.");
+                if self.recoverable {
+                    ui.label("Return to login to try again, or quit.");
+                } else {
+                    ui.label("Please restart the application.");
+                }
+
+                ui.separator();
+
+                ui.horizontal(|ui| {
+                    if self.recoverable && ui.button("Return to login").clicked() {
+                        let _ = self.message_tx.send(AppMessage::Restart);
+                    }
+                    if ui.button("Quit").clicked() {
+                        let _ = self.message_tx.send(AppMessage::Exiting);
+                    }
+                });
             });
     }
-}
\ No newline at end of file
+}
@@ -1,59 +1,1608 @@
 use std::cell::RefCell;
-use std::rc::{Rc, Weak};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ops::Range;
+use std::rc::Rc;
 use std::string::ToString;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::Context as _;
+use chrono::{DateTime, Local, TimeDelta, Utc};
 use crossbeam_channel::Sender;
-use crate::page::{LoginMessage, Network, NetworkEvent, Route, Update, View};
+use crate::page::{attachment_offline_message, change_failed_message, conversations_failed_message, direct_failed_message, disconnect_message, dropped_after_handshake_message, load_image_texture, messages_dropped_message, open_conversation_failed_message, reconnect_failed_message, reconnected_message, rejection_message, send_failed_message, send_queue_full_message, session_expired_message, theme_toggle, user_message, LoginMessage, Route, Update, View};
 use eframe::egui;
-use eframe::egui::Context;
-use once_cell::sync::Lazy;
+use eframe::egui::{Context, TextureHandle};
+use tracing::{debug, trace, warn};
 use uuid::Uuid;
-use crate::domain::{ConversationId, UserId};
-use crate::protocol::network::{MessageError, MessageEvent, MessageSent, NetworkInterface, StreamMessage, WithGeneration};
-use crate::shell::AppMessage;
+use crate::domain::{validate_username, Attachment, ChatBody, ConversationId, ConversationKind, UserId};
+use crate::protocol::network::{AttachmentEvent, AttachmentRequest, ChatMessage, ConnectionState, ConversationInfo, ConversationsEvent, DirectEvent, DisconnectReason, HistoryEvent, ConversationMember, LogoutEvent, MembershipNotification, MessageBatchEvent, MessageError, MessageEvent, NetworkError, NetworkInterface, NetworkTimeouts, PresenceEvent, PresenceNotification, SessionEvent, StreamMessage, TokenInfo, WithGeneration};
+use crate::shell::{AppMessage, StreamBuffer, ToastLevel};
+use crate::util::Debouncer;
 
 pub enum LobbyMessage {
     Placeholder,
-    ChatSent(u64, String),
-    ChatReceived(u64, String),
     Stream(StreamMessage),
-    MessageSent(String),
-    MessageFailed(String),
+    MessageSent(u64, Option<u64>),
+    /// With the reason to show.
+    MessageFailed(u64, String),
+    BatchSent(u64, Vec<MessageEvent>),
+    BatchFailed(u64, String),
+    HistoryLoaded(ConversationId, Vec<ChatMessage>),
+    HistoryFailed(ConversationId),
+    /// Messages fetched to fill a `seq` gap that `REORDER_WAIT` did not close.
+    GapLoaded(ConversationId, Vec<ChatMessage>),
+    GapFailed(ConversationId),
+    AttachmentLoaded(Uuid, Vec<u8>),
+    AttachmentFailed(Uuid),
+    ConversationsLoaded(u64, Vec<ConversationInfo>),
+    ConversationsFailed(u64),
+    PresenceLoaded(u64, Vec<PresenceNotification>),
+    PresenceFailed(u64),
+    DirectOpened(u64, ConversationInfo),
+    /// With the reason to show under the input.
+    DirectFailed(u64, String),
+    Reconnected(u64),
+    ReconnectFailed(u64),
+    LoggedOut,
+}
+
+/// Where the user was in the lobby, kept by the app so a rebuilt lobby, e.g. after logging in
+/// again, opens there instead of at the first conversation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LobbyPosition {
+    pub send_to: Option<ConversationId>,
+    /// Whether the conversation was scrolled to its newest entries.
+    pub at_bottom: bool,
+}
+
+impl Default for LobbyPosition {
+    fn default() -> Self {
+        Self { send_to: None, at_bottom: true }
+    }
+}
+
+/// What a lobby opens with, besides what every page is wired up with.
+pub struct LobbyConfig {
+    pub token_info: TokenInfo,
+    /// Entries kept per conversation, see `DEFAULT_SCROLLBACK_LIMIT`.
+    pub scrollback_limit: usize,
+    pub close_to_background: bool,
+    pub position: LobbyPosition,
 }
 
 pub struct LobbyPage {
     message_tx: Sender<AppMessage>,
     map_function: Box<dyn Fn(LobbyMessage) -> AppMessage>,
     new_map_function: Arc<Box<dyn Fn(LobbyMessage) -> AppMessage + Send + Sync>>,
-    network: Weak<RefCell<dyn Network>>,
     real_network: Rc<RefCell<dyn NetworkInterface>>,
+    timeouts: NetworkTimeouts,
+
+    user_id: UserId,
+    /// Where `connect_chat` went at login, reused by the "Reconnect" button.
+    chat_address: String,
+    jwt: String,
+    disconnect_reason: Option<DisconnectReason>,
+    reconnect_generation: Option<u64>,
+    logout_generation: Option<u64>,
+    conversations: Vec<ConversationInfo>,
+    conversations_generation: Option<u64>,
+    /// Who is online, for every user heard about rather than only those on screen; users missing
+    /// from it fall back to `ConversationMember::online`.
+    presence: HashMap<UserId, bool>,
+    presence_generation: Option<u64>,
+    /// The `open_direct` request of an `@username` input still looking for its conversation.
+    direct_generation: Option<u64>,
+    /// What to send once `direct_generation` finds the conversation; may be empty.
+    direct_text: String,
+    /// Why the last `@username` could not be written to, shown under the input until it changes.
+    direct_error: Option<String>,
+    chat_history: HashMap<ConversationId, Vec<ChatEntry>>,
+    unread_counts: HashMap<ConversationId, usize>,
+    history_loading: HashSet<ConversationId>,
+    history_exhausted: HashSet<ConversationId>,
+    /// When loading history last failed, per conversation; scrolled to the top it would be asked again every frame.
+    history_failed_at: HashMap<ConversationId, Instant>,
+    /// Conversations that dropped old entries to stay within `scrollback_limit`; they load older ones on request only.
+    history_evicted: HashSet<ConversationId>,
+    /// Conversations showing older entries loaded on request; nothing is evicted from them until the
+    /// user scrolls back down, or the next push would take the loaded page away again.
+    history_kept: HashSet<ConversationId>,
+    /// Received entries that arrived ahead of a missing `seq`, per conversation.
+    held: HashMap<ConversationId, HeldEntries>,
+    /// Edits and deletions shown ahead of their ACK, by send generation, to undo on a rejection.
+    pending_changes: HashMap<u64, PendingChange>,
+    scrollback_limit: usize,
+    /// Mirrors the app setting for the checkbox; changes go back as `AppMessage::SetCloseToBackground`.
+    close_to_background: bool,
+    /// Whether the connection metrics window is open.
+    show_metrics: bool,
+    /// Blob ids of attachments fetched or being fetched, so each is requested once.
+    attachment_requested: HashSet<Uuid>,
+    /// Fetched attachments waiting for the next frame to become textures.
+    attachment_bytes: HashMap<Uuid, Vec<u8>>,
+    attachment_textures: HashMap<Uuid, TextureHandle>,
+    /// Own images by `client_id`, waiting for the next frame to become `own_attachment_textures`.
+    own_attachment_bytes: HashMap<Uuid, Vec<u8>>,
+    /// Shown from the sent bytes, so own images need not come back from the server first.
+    own_attachment_textures: HashMap<Uuid, TextureHandle>,
+    typing: HashMap<ConversationId, HashMap<UserId, Instant>>,
+    /// Highest `seq` another member has read, per conversation.
+    read_by_others: HashMap<ConversationId, u64>,
+    /// Highest `seq` we have reported as read, per conversation.
+    read_reported: HashMap<ConversationId, u64>,
+    typing_debouncer: Debouncer,
+    usernames: HashMap<UserId, String>,
+    input: String,
+    /// `seq` of the own message the input is replacing, while editing one.
+    editing: Option<u64>,
+    /// An image pasted from the clipboard, sent with the input as its caption on the next Send.
+    pasted: Option<OutgoingFile>,
+    pasted_texture: Option<TextureHandle>,
+    send_limiter: SendLimiter,
+    /// Client ids of text messages held back by `send_limiter` or a lost connection, oldest first.
+    queued_sends: VecDeque<(ConversationId, Uuid)>,
+    /// Narrows the open conversation to entries containing it, ignoring case; empty shows all of them.
+    search: String,
+    /// Index of the current hit among the search hits, moved by Prev and Next.
+    search_hit: usize,
+    /// Set when the current hit changed and has to be scrolled into view.
+    scroll_to_hit: bool,
+    /// Set when the search is cleared, so the view goes back to the newest entries.
+    scroll_to_bottom: bool,
+    /// Whether the open conversation was scrolled to its newest entries last frame.
+    at_bottom: bool,
+    /// Last sent as `AppMessage::SetLobbyPosition`, so it only goes out on a change.
+    reported_position: LobbyPosition,
+
+    send_to: Option<ConversationId>,
+}
+
+impl LobbyPage {
+    pub fn new(
+        message_tx: Sender<AppMessage>,
+        map_function: Box<dyn Fn(LobbyMessage) -> AppMessage>,
+        new_map_function: Arc<Box<dyn Fn(LobbyMessage) -> AppMessage + Send + Sync>>,
+        real_network: Rc<RefCell<dyn NetworkInterface>>,
+        timeouts: NetworkTimeouts,
+        config: LobbyConfig,
+    ) -> Self {
+        let LobbyConfig { token_info, scrollback_limit, close_to_background, position } = config;
+        let mut page = Self {
+            message_tx: message_tx.clone(),
+            map_function,
+            new_map_function,
+            real_network,
+            timeouts,
+            user_id: token_info.user_id,
+            chat_address: token_info.chat_address.unwrap_or_default(),
+            jwt: token_info.access_token,
+            disconnect_reason: None,
+            reconnect_generation: None,
+            logout_generation: None,
+            conversations: Vec::new(),
+            conversations_generation: None,
+            presence: HashMap::new(),
+            presence_generation: None,
+            direct_generation: None,
+            direct_text: String::new(),
+            direct_error: None,
+            chat_history: HashMap::new(),
+            unread_counts: HashMap::new(),
+            history_loading: HashSet::new(),
+            history_failed_at: HashMap::new(),
+            history_exhausted: HashSet::new(),
+            history_evicted: HashSet::new(),
+            history_kept: HashSet::new(),
+            held: HashMap::new(),
+            pending_changes: HashMap::new(),
+            // A single page of history must fit, or loading older messages would evict them straight away.
+            scrollback_limit: scrollback_limit.max(HISTORY_PAGE_SIZE as usize),
+            close_to_background,
+            show_metrics: false,
+            attachment_requested: HashSet::new(),
+            attachment_bytes: HashMap::new(),
+            attachment_textures: HashMap::new(),
+            own_attachment_bytes: HashMap::new(),
+            own_attachment_textures: HashMap::new(),
+            typing: HashMap::new(),
+            read_by_others: HashMap::new(),
+            read_reported: HashMap::new(),
+            typing_debouncer: Debouncer::new(TYPING_SEND_INTERVAL),
+            usernames: HashMap::new(),
+            input: String::new(),
+            editing: None,
+            pasted: None,
+            pasted_texture: None,
+            send_limiter: SendLimiter::new(),
+            queued_sends: VecDeque::new(),
+            search: String::new(),
+            search_hit: 0,
+            scroll_to_hit: false,
+            scroll_to_bottom: position.at_bottom,
+            at_bottom: position.at_bottom,
+            reported_position: position.clone(),
+            send_to: position.send_to.clone(),
+        };
+        if let Some(send_to) = position.send_to {
+            page.load_history(send_to);
+        }
+        #[cfg(feature = "manual-test")]
+        page.set_conversations(test_conversations());
+        page.refresh_conversations();
+        page.refresh_presence();
+        page
+    }
+
+    /// Known once a conversation lists the user among its members.
+    pub fn username(&self) -> Option<&str> {
+        self.usernames.get(&self.user_id).map(String::as_str)
+    }
+
+    /// Messages held back by the send rate limit or a lost connection, which the network has not seen yet.
+    pub fn queued_count(&self) -> usize {
+        self.queued_sends.len()
+    }
+
+    pub fn unread_total(&self) -> usize {
+        self.unread_counts.values().sum()
+    }
+
+    /// Whether `conversation_id` is the conversation on screen.
+    pub fn is_showing(&self, conversation_id: &ConversationId) -> bool {
+        self.send_to.as_ref() == Some(conversation_id)
+    }
+
+    /// What the conversation shows, oldest first.
+    #[cfg(test)]
+    pub(crate) fn texts(&self, conversation_id: &ConversationId) -> Vec<String> {
+        self.chat_history
+            .get(conversation_id)
+            .map(|entries| entries.iter().map(|entry| entry.content.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Takes the stream messages held while the lobby was being built, before any live ones.
+    pub fn take_held_stream(&mut self, buffer: StreamBuffer) {
+        for message in buffer.into_messages() {
+            self.receive_stream(message);
+        }
+    }
+
+    /// Catches a session that went down between `connect_chat` succeeding and the lobby being built
+    /// without anything held saying so, which would otherwise leave the lobby silently disconnected.
+    /// It is shown as lost and reconnected; one still retrying on its own is left to it.
+    pub fn check_connection(&mut self) {
+        let state = self.real_network.borrow().session_state();
+        if state != ConnectionState::Disconnected || self.disconnect_reason.is_some() {
+            return;
+        }
+        warn!("Chat session dropped before the lobby was up");
+        self.disconnect_reason = Some(DisconnectReason::ConnectionError);
+        self.notify(ToastLevel::Warning, dropped_after_handshake_message());
+        self.reconnect();
+    }
+
+    fn receive_stream(&mut self, message: StreamMessage) {
+        match message {
+            StreamMessage::Distribute(message) => {
+                if let Some(sender_name) = message.sender_name {
+                    self.usernames.insert(message.sender.clone(), sender_name);
+                }
+                if let Some(typing) = self.typing.get_mut(&message.conversation_id) {
+                    typing.remove(&message.sender);
+                }
+                match message.content {
+                    ChatBody::Text(text) => {
+                        let entry = ChatEntry::received(Some(message.sender), message.sent_at, message.seq, text, message.attachment);
+                        let unmatched = match message.client_id {
+                            Some(client_id) => self.replace_pending(&message.conversation_id, client_id, entry),
+                            None => Some(entry),
+                        };
+                        if let Some(entry) = unmatched {
+                            self.push_received(message.conversation_id, entry);
+                        }
+                    }
+                    ChatBody::System(text) => {
+                        let entry = ChatEntry::received(None, message.sent_at, message.seq, text, None);
+                        self.push_received(message.conversation_id, entry);
+                    }
+                    change @ (ChatBody::Edit { .. } | ChatBody::Delete { .. }) => {
+                        self.apply_change(&message.conversation_id, change);
+                    }
+                }
+            }
+            StreamMessage::Typing(notification) => {
+                if notification.sender != self.user_id {
+                    self.typing
+                        .entry(notification.conversation_id)
+                        .or_default()
+                        .insert(notification.sender, Instant::now());
+                }
+            }
+            StreamMessage::Read(notification) => {
+                if notification.reader != self.user_id {
+                    let read_up_to = self.read_by_others.entry(notification.conversation_id).or_default();
+                    *read_up_to = (*read_up_to).max(notification.up_to_seq);
+                }
+            }
+            StreamMessage::Presence(notification) => {
+                self.presence.insert(notification.user_id, notification.online);
+            }
+            StreamMessage::Membership(notification) => self.change_membership(notification),
+            StreamMessage::ConnectionState(state) => {
+                if state == ConnectionState::Connected && self.disconnect_reason.take().is_some() {
+                    self.notify(ToastLevel::Info, reconnected_message());
+                }
+                // Presence changes while disconnected were missed.
+                if state == ConnectionState::Connected {
+                    self.refresh_presence();
+                }
+            }
+            // The network renews the token and reconnects on its own; if it cannot, a `SessionError` follows.
+            StreamMessage::Disconnected { reason } if !reason.is_transient() => {
+                debug!("Chat connection needs a renewed token: {}", reason);
+            }
+            StreamMessage::Disconnected { reason: DisconnectReason::GaveUp } => {
+                warn!("Stopped reconnecting to chat");
+                self.disconnect_reason = Some(DisconnectReason::GaveUp);
+                self.notify(ToastLevel::Error, disconnect_message(DisconnectReason::GaveUp));
+            }
+            StreamMessage::Disconnected { reason } => {
+                warn!("Chat connection lost: {}", reason);
+                self.disconnect_reason = Some(reason);
+                self.notify(ToastLevel::Warning, disconnect_message(reason));
+            }
+            StreamMessage::MessagesDropped { count } => {
+                warn!("Missed {} chat messages, the conversation may be incomplete", count);
+                self.notify(ToastLevel::Warning, messages_dropped_message(count));
+            }
+            StreamMessage::SessionError(error) => {
+                warn!("Session error, returning to login: {:?}", error);
+                self.notify(ToastLevel::Error, session_expired_message());
+                let _ = self.message_tx.send(AppMessage::ReqNavigate(Route::LoginPage(None)));
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum DeliveryState {
+    /// Held back by the send rate limit or until the connection is back.
+    Queued,
+    Sending,
+    Sent,
+    Failed,
+}
+
+#[derive(Clone)]
+struct OutgoingFile {
+    pub filename: String,
+    pub mime: String,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Clone)]
+enum EntryAttachment {
+    /// Not confirmed yet; the bytes are kept so a failed send can be retried.
+    Outgoing(OutgoingFile),
+    Uploaded(String),
+    Remote(Attachment),
+}
+
+impl EntryAttachment {
+    fn filename(&self) -> &str {
+        match self {
+            EntryAttachment::Outgoing(file) => &file.filename,
+            EntryAttachment::Uploaded(filename) => filename,
+            EntryAttachment::Remote(attachment) => &attachment.filename,
+        }
+    }
+}
+
+struct ChatEntry {
+    pub sender: Option<UserId>,
+    pub send_generation: Option<u64>,
+    /// Id of an own message, kept across retries so the server can tell them apart from new sends.
+    pub client_id: Option<Uuid>,
+    pub sent_at: DateTime<Utc>,
+    pub seq: Option<u64>,
+    pub content: String,
+    pub attachment: Option<EntryAttachment>,
+    pub delivery: DeliveryState,
+    pub edited: bool,
+    pub deleted: bool,
+}
+
+impl ChatEntry {
+    fn received(sender: Option<UserId>, sent_at: DateTime<Utc>, seq: Option<u64>, content: String, attachment: Option<Attachment>) -> Self {
+        let attachment = attachment.map(EntryAttachment::Remote);
+        Self { sender, send_generation: None, client_id: None, sent_at, seq, content, attachment, delivery: DeliveryState::Sent, edited: false, deleted: false }
+    }
+
+    fn is_own(&self, user_id: &UserId) -> bool {
+        self.sender.as_ref() == Some(user_id)
+    }
+
+    /// Whether the server knows this message by a `seq` that edits and deletions can point at.
+    fn is_editable(&self, user_id: &UserId) -> bool {
+        self.is_own(user_id) && self.seq.is_some() && !self.deleted
+    }
+
+    /// `sent_at` is on the server clock, so `clock_offset` turns it back into local time.
+    fn label(&self, usernames: &HashMap<UserId, String>, user_id: &UserId, clock_offset: TimeDelta) -> String {
+        let time = (self.sent_at - clock_offset).with_timezone(&Local).format("%H:%M");
+        let body = match &self.attachment {
+            _ if self.deleted => "(deleted)".to_string(),
+            Some(attachment) if self.content.is_empty() => format!("[{}]", attachment.filename()),
+            Some(attachment) => format!("{} [{}]", self.content, attachment.filename()),
+            None => self.content.clone(),
+        };
+        let body = if self.edited && !self.deleted { format!("{} (edited)", body) } else { body };
+        let text = match &self.sender {
+            Some(_) if self.is_own(user_id) => format!("you: {}", body),
+            Some(sender) => {
+                let username = usernames.get(sender).cloned().unwrap_or_else(|| sender.0.to_string());
+                format!("{}: {}", username, body)
+            }
+            None => body,
+        };
+        match self.delivery {
+            DeliveryState::Failed => format!("[{}] {} (failed)", time, text),
+            DeliveryState::Queued | DeliveryState::Sending | DeliveryState::Sent => format!("[{}] {}", time, text),
+        }
+    }
+}
+
+/// Entries of one conversation waiting for the `seq` before them, so a conversation reads in the
+/// server's order even when the stream delivers out of it.
+struct HeldEntries {
+    since: Instant,
+    entries: BTreeMap<u64, ChatEntry>,
+}
+
+/// What an entry looked like before an own edit or deletion that the server may still reject.
+struct PendingChange {
+    conversation_id: ConversationId,
+    target_seq: u64,
+    content: String,
+    attachment: Option<EntryAttachment>,
+    edited: bool,
+    deleted: bool,
+}
+
+/// Token bucket for text sends, so holding Enter or pasting a script cannot flood the server.
+struct SendLimiter {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl SendLimiter {
+    fn new() -> Self {
+        Self { tokens: SEND_BURST, refilled_at: Instant::now() }
+    }
+
+    /// Whole sends allowed right now.
+    fn available(&mut self) -> usize {
+        let now = Instant::now();
+        let refilled = now.duration_since(self.refilled_at).as_secs_f64() * SEND_RATE_PER_SEC;
+        self.tokens = (self.tokens + refilled).min(SEND_BURST);
+        self.refilled_at = now;
+        self.tokens as usize
+    }
+
+    fn take(&mut self, count: usize) {
+        self.tokens -= count as f64;
+    }
+
+    fn next_token_in(&self) -> Duration {
+        Duration::from_secs_f64((1.0 - self.tokens).max(0.0) / SEND_RATE_PER_SEC)
+    }
+}
+
+impl LobbyPage {
+    /// Returns `false` when the message was refused because `MAX_QUEUED_SENDS` are already waiting.
+    fn send_message(&mut self, conversation_id: ConversationId, content: String) -> bool {
+        // Nothing may overtake a queued message, so once one is queued the rest queue behind it.
+        let connected = self.real_network.borrow().is_connected();
+        if connected && self.queued_sends.is_empty() && self.send_limiter.available() > 0 {
+            self.send_limiter.take(1);
+            self.send_entry(conversation_id, content, None);
+            return true;
+        }
+        if self.queued_sends.len() >= MAX_QUEUED_SENDS {
+            self.notify(ToastLevel::Warning, send_queue_full_message(MAX_QUEUED_SENDS));
+            return false;
+        }
+
+        let client_id = Uuid::new_v4();
+        let sent_at = self.server_now();
+        self.chat_history.entry(conversation_id.clone()).or_default().push(ChatEntry {
+            sender: Some(self.user_id.clone()),
+            send_generation: None,
+            client_id: Some(client_id),
+            sent_at,
+            seq: None,
+            content,
+            attachment: None,
+            delivery: DeliveryState::Queued,
+            edited: false,
+            deleted: false,
+        });
+        self.evict_old_entries(&conversation_id);
+        self.queued_sends.push_back((conversation_id, client_id));
+        true
+    }
+
+    /// Sends as many queued messages as the limiter allows, batching runs in the same conversation.
+    fn flush_queued_sends(&mut self) {
+        let mut available = self.send_limiter.available();
+        while available > 0 {
+            let Some((conversation_id, _)) = self.queued_sends.front().cloned() else {
+                break;
+            };
+            let mut client_ids = Vec::new();
+            while client_ids.len() < available {
+                match self.queued_sends.front() {
+                    Some((next, client_id)) if *next == conversation_id => {
+                        client_ids.push(*client_id);
+                        self.queued_sends.pop_front();
+                    }
+                    _ => break,
+                }
+            }
+            available -= client_ids.len();
+            self.send_limiter.take(client_ids.len());
+            self.dispatch_batch(conversation_id, client_ids);
+        }
+    }
+
+    fn dispatch_batch(&mut self, conversation_id: ConversationId, client_ids: Vec<Uuid>) {
+        let Some(entries) = self.chat_history.get_mut(&conversation_id) else {
+            return;
+        };
+        let queued = |entry: &ChatEntry| {
+            entry.delivery == DeliveryState::Queued && entry.client_id.is_some_and(|id| client_ids.contains(&id))
+        };
+        let messages = entries
+            .iter()
+            .filter(|entry| queued(entry))
+            .filter_map(|entry| Some((entry.client_id?, ChatBody::Text(entry.content.clone()))))
+            .collect();
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map = move |event: WithGeneration<MessageBatchEvent>| {
+            let _ = message_tx.send(map_function(LobbyMessage::BatchSent(event.generation, event.result.results)));
+        };
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map_err = move |error: WithGeneration<NetworkError>| {
+            let _ = message_tx.send(map_function(LobbyMessage::BatchFailed(error.generation, user_message(&error.result))));
+        };
+
+        let result = self.real_network.borrow_mut().send_chat_messages(
+            conversation_id,
+            messages,
+            self.timeouts.send_ms,
+            Box::new(map),
+            Box::new(map_err),
+        );
+        for entry in entries.iter_mut().filter(|entry| queued(entry)) {
+            entry.send_generation = result.as_ref().ok().copied();
+            entry.delivery = if result.is_ok() { DeliveryState::Sending } else { DeliveryState::Failed };
+        }
+    }
+
+    fn send_attachment(&mut self, conversation_id: ConversationId, file: OutgoingFile) {
+        self.send_entry(conversation_id, String::new(), Some(file));
+    }
+
+    /// Holds the clipboard image for a caption, or says why there is none to paste.
+    fn paste_image(&mut self, ctx: &Context) {
+        let file = match read_clipboard_image() {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Failed to paste image: {:#}", e);
+                self.notify(ToastLevel::Warning, format!("{:#}", e));
+                return;
+            }
+        };
+        match load_image_texture(ctx, &file.bytes, "pasted_image") {
+            Ok(texture) => {
+                self.pasted_texture = Some(texture);
+                self.pasted = Some(file);
+            }
+            Err(e) => warn!("Failed to preview pasted image: {:#}", e),
+        }
+    }
+
+    fn clear_pasted(&mut self) {
+        self.pasted = None;
+        self.pasted_texture = None;
+    }
+
+    fn send_entry(&mut self, conversation_id: ConversationId, content: String, file: Option<OutgoingFile>) {
+        let client_id = Uuid::new_v4();
+        if let Some(file) = file.as_ref().filter(|file| file.mime.starts_with("image/")) {
+            self.own_attachment_bytes.insert(client_id, file.bytes.clone());
+        }
+        let result = self.dispatch_message(conversation_id.clone(), client_id, ChatBody::Text(content.clone()), file.clone());
+
+        // Shown right away with the corrected client clock; the ACK confirms it later.
+        let sent_at = self.server_now();
+        self.chat_history.entry(conversation_id.clone()).or_default().push(ChatEntry {
+            sender: Some(self.user_id.clone()),
+            send_generation: result.as_ref().ok().copied(),
+            client_id: Some(client_id),
+            sent_at,
+            seq: None,
+            content,
+            attachment: file.map(EntryAttachment::Outgoing),
+            delivery: if result.is_ok() { DeliveryState::Sending } else { DeliveryState::Failed },
+            edited: false,
+            deleted: false,
+        });
+        self.evict_old_entries(&conversation_id);
+    }
+
+    fn resend_message(&mut self, conversation_id: ConversationId, index: usize) {
+        let Some((client_id, content, file)) = self.chat_history
+            .get(&conversation_id)
+            .and_then(|entries| entries.get(index))
+            .map(|entry| {
+                let file = match &entry.attachment {
+                    Some(EntryAttachment::Outgoing(file)) => Some(file.clone()),
+                    _ => None,
+                };
+                (entry.client_id.unwrap_or_else(Uuid::new_v4), entry.content.clone(), file)
+            })
+        else {
+            return;
+        };
+
+        let result = self.dispatch_message(conversation_id.clone(), client_id, ChatBody::Text(content), file);
+
+        let sent_at = self.server_now();
+        if let Some(entry) = self.chat_history.get_mut(&conversation_id).and_then(|entries| entries.get_mut(index)) {
+            entry.send_generation = result.as_ref().ok().copied();
+            entry.sent_at = sent_at;
+            entry.delivery = if result.is_ok() { DeliveryState::Sending } else { DeliveryState::Failed };
+        }
+    }
+
+    fn dispatch_message(&mut self, conversation_id: ConversationId, client_id: Uuid, content: ChatBody, file: Option<OutgoingFile>) -> anyhow::Result<u64> {
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map = move |event: WithGeneration<MessageEvent>| {
+            let message = match event.result.result {
+                Ok(sent) => LobbyMessage::MessageSent(event.generation, sent.seq),
+                Err(error) => LobbyMessage::MessageFailed(event.generation, rejection_message(&error, event.result.detail)),
+            };
+            let _ = message_tx.send(map_function(message));
+        };
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map_err = move |error: WithGeneration<NetworkError>| {
+            let message = LobbyMessage::MessageFailed(error.generation, user_message(&error.result));
+            let _ = message_tx.send(map_function(message));
+        };
+
+        match file {
+            Some(file) => self.real_network.borrow_mut().send_chat_attachment(
+                AttachmentRequest {
+                    conversation_id,
+                    client_id,
+                    // Text is all that can go along with a file, as its caption.
+                    caption: match content {
+                        ChatBody::Text(caption) => caption,
+                        _ => String::new(),
+                    },
+                    bytes: file.bytes,
+                    mime: file.mime,
+                    filename: file.filename,
+                },
+                self.timeouts.upload_ms,
+                Box::new(map),
+                Box::new(map_err),
+            ),
+            None => self.real_network.borrow_mut().send_chat_message(
+                conversation_id,
+                client_id,
+                content,
+                self.timeouts.send_ms,
+                Box::new(map),
+                Box::new(map_err),
+            ),
+        }
+    }
+
+    fn fetch_attachment(&mut self, blob_id: Uuid) {
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map = move |event: WithGeneration<AttachmentEvent>| {
+            let blob_id = event.result.blob_id;
+            let message = match event.result.result {
+                Ok(bytes) => LobbyMessage::AttachmentLoaded(blob_id, bytes),
+                Err(error) => {
+                    warn!("Failed to fetch attachment {}: {:?}", blob_id, error);
+                    LobbyMessage::AttachmentFailed(blob_id)
+                }
+            };
+            let _ = message_tx.send(map_function(message));
+        };
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map_err = move |error: WithGeneration<NetworkError>| {
+            warn!("Attachment request failed: {:?}", error.result);
+            let _ = message_tx.send(map_function(LobbyMessage::AttachmentFailed(blob_id)));
+        };
+
+        let result = self.real_network.borrow_mut().fetch_attachment(
+            blob_id,
+            self.timeouts.attachment_ms,
+            Box::new(map),
+            Box::new(map_err),
+        );
+        match result {
+            Ok(_) => {
+                self.attachment_requested.insert(blob_id);
+            }
+            Err(error) => warn!("Failed to request attachment: {:?}", error),
+        }
+    }
+
+    /// Requests the page of messages preceding the oldest one already loaded.
+    fn load_history(&mut self, conversation_id: ConversationId) {
+        if self.history_loading.contains(&conversation_id) || self.history_exhausted.contains(&conversation_id) {
+            return;
+        }
+        if self.history_failed_at.get(&conversation_id).is_some_and(|failed_at| failed_at.elapsed() < HISTORY_RETRY_COOLDOWN) {
+            return;
+        }
+
+        // Unresolved own messages may outlive older evicted ones, so they do not mark where loaded history ends.
+        let before = self.chat_history
+            .get(&conversation_id)
+            .and_then(|entries| entries
+                .iter()
+                .filter(|entry| !matches!(entry.delivery, DeliveryState::Queued | DeliveryState::Sending))
+                .map(|entry| entry.sent_at)
+                .min());
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map = move |event: WithGeneration<HistoryEvent>| {
+            let HistoryEvent { conversation_id, result } = event.result;
+            let message = match result {
+                Ok(messages) => LobbyMessage::HistoryLoaded(conversation_id, messages),
+                Err(error) => {
+                    warn!("Failed to load history: {:?}", error);
+                    LobbyMessage::HistoryFailed(conversation_id)
+                }
+            };
+            let _ = message_tx.send(map_function(message));
+        };
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let failed_conversation = conversation_id.clone();
+        let map_err = move |_: WithGeneration<NetworkError>| {
+            let _ = message_tx.send(map_function(LobbyMessage::HistoryFailed(failed_conversation)));
+        };
+
+        let result = self.real_network.borrow_mut().fetch_history(
+            conversation_id.clone(),
+            before,
+            HISTORY_PAGE_SIZE,
+            self.timeouts.history_ms,
+            Box::new(map),
+            Box::new(map_err),
+        );
+        match result {
+            Ok(_) => {
+                self.history_loading.insert(conversation_id);
+            }
+            Err(error) => {
+                warn!("Failed to request history: {:?}", error);
+                self.history_failed_at.insert(conversation_id, Instant::now());
+            }
+        }
+    }
+
+    fn prepend_history(&mut self, conversation_id: ConversationId, messages: Vec<ChatMessage>) {
+        self.history_loading.remove(&conversation_id);
+        self.history_failed_at.remove(&conversation_id);
+        // Past eviction only "Load older messages" asks for more.
+        if self.history_evicted.contains(&conversation_id) {
+            self.history_kept.insert(conversation_id.clone());
+        }
+        if messages.len() < HISTORY_PAGE_SIZE as usize {
+            self.history_exhausted.insert(conversation_id.clone());
+            self.history_evicted.remove(&conversation_id);
+        }
+
+        let mut older = Vec::with_capacity(messages.len());
+        let mut changes = Vec::new();
+        for message in messages {
+            if let Some(sender_name) = message.sender_name {
+                self.usernames.insert(message.sender.clone(), sender_name);
+            }
+            match message.content {
+                ChatBody::Text(text) => {
+                    older.push(ChatEntry::received(Some(message.sender), message.sent_at, message.seq, text, message.attachment));
+                }
+                ChatBody::System(text) => {
+                    older.push(ChatEntry::received(None, message.sent_at, message.seq, text, None));
+                }
+                change @ (ChatBody::Edit { .. } | ChatBody::Delete { .. }) => changes.push(change),
+            }
+        }
+        self.chat_history.entry(conversation_id.clone()).or_default().splice(0..0, older);
+        // Only messages loaded by now can be changed; older targets are already gone from this page.
+        for change in changes {
+            self.apply_change(&conversation_id, change);
+        }
+    }
+
+    /// Requests the page of messages preceding `before`, which ends at a `seq` gap.
+    fn fill_gap(&mut self, conversation_id: ConversationId, before: DateTime<Utc>) {
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map = move |event: WithGeneration<HistoryEvent>| {
+            let HistoryEvent { conversation_id, result } = event.result;
+            let message = match result {
+                Ok(messages) => LobbyMessage::GapLoaded(conversation_id, messages),
+                Err(error) => {
+                    warn!("Failed to load missing messages: {:?}", error);
+                    LobbyMessage::GapFailed(conversation_id)
+                }
+            };
+            let _ = message_tx.send(map_function(message));
+        };
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let failed_conversation = conversation_id.clone();
+        let map_err = move |_: WithGeneration<NetworkError>| {
+            let _ = message_tx.send(map_function(LobbyMessage::GapFailed(failed_conversation)));
+        };
+
+        if let Err(error) = self.real_network.borrow_mut().fetch_history(
+            conversation_id,
+            Some(before),
+            HISTORY_PAGE_SIZE,
+            self.timeouts.history_ms,
+            Box::new(map),
+            Box::new(map_err),
+        ) {
+            warn!("Failed to request missing messages: {:?}", error);
+        }
+    }
+
+    /// Puts the fetched messages not shown yet in their place; a gap wider than a page keeps the rest of it.
+    fn merge_gap(&mut self, conversation_id: ConversationId, messages: Vec<ChatMessage>) {
+        let mut changes = Vec::new();
+        for message in messages {
+            let Some(seq) = message.seq.filter(|&seq| !self.has_seq(&conversation_id, seq)) else {
+                continue;
+            };
+            if let Some(sender_name) = message.sender_name {
+                self.usernames.insert(message.sender.clone(), sender_name);
+            }
+            match message.content {
+                ChatBody::Text(text) => {
+                    let entry = ChatEntry::received(Some(message.sender), message.sent_at, Some(seq), text, message.attachment);
+                    self.show_entry(conversation_id.clone(), entry);
+                }
+                ChatBody::System(text) => {
+                    let entry = ChatEntry::received(None, message.sent_at, Some(seq), text, None);
+                    self.show_entry(conversation_id.clone(), entry);
+                }
+                change @ (ChatBody::Edit { .. } | ChatBody::Delete { .. }) => changes.push(change),
+            }
+        }
+        for change in changes {
+            self.apply_change(&conversation_id, change);
+        }
+    }
+
+    /// Edits or deletes the entry with the `seq` an `Edit` or `Delete` points at, in place.
+    fn apply_change(&mut self, conversation_id: &ConversationId, change: ChatBody) {
+        let target_seq = match &change {
+            ChatBody::Edit { target_seq, .. } | ChatBody::Delete { target_seq } => *target_seq,
+            ChatBody::Text(_) | ChatBody::System(_) => return,
+        };
+        let Some(entry) = self.chat_history
+            .get_mut(conversation_id)
+            .and_then(|entries| entries.iter_mut().rev().find(|entry| entry.seq == Some(target_seq)))
+        else {
+            trace!("No loaded message to change: {}", target_seq);
+            return;
+        };
+        match change {
+            ChatBody::Edit { text, .. } => {
+                entry.content = text;
+                entry.edited = true;
+            }
+            ChatBody::Delete { .. } => {
+                entry.content.clear();
+                entry.attachment = None;
+                entry.deleted = true;
+            }
+            ChatBody::Text(_) | ChatBody::System(_) => {}
+        }
+    }
+
+    /// Sends an `Edit` or `Delete` and applies it right away; the server distributes it to the others.
+    /// A rejection puts the entry back as it was.
+    fn send_change(&mut self, conversation_id: ConversationId, change: ChatBody) {
+        let target_seq = match &change {
+            ChatBody::Edit { target_seq, .. } | ChatBody::Delete { target_seq } => *target_seq,
+            ChatBody::Text(_) | ChatBody::System(_) => return,
+        };
+        let previous = self.chat_history
+            .get(&conversation_id)
+            .and_then(|entries| entries.iter().rev().find(|entry| entry.seq == Some(target_seq)))
+            .map(|entry| PendingChange {
+                conversation_id: conversation_id.clone(),
+                target_seq,
+                content: entry.content.clone(),
+                attachment: entry.attachment.clone(),
+                edited: entry.edited,
+                deleted: entry.deleted,
+            });
+        match self.dispatch_message(conversation_id.clone(), Uuid::new_v4(), change.clone(), None) {
+            Ok(generation) => {
+                if let Some(previous) = previous {
+                    self.pending_changes.insert(generation, previous);
+                }
+                self.apply_change(&conversation_id, change);
+            }
+            Err(e) => warn!("Failed to send change: {:#}", e),
+        }
+    }
+
+    fn revert_change(&mut self, previous: PendingChange) {
+        let Some(entry) = self.chat_history
+            .get_mut(&previous.conversation_id)
+            .and_then(|entries| entries.iter_mut().rev().find(|entry| entry.seq == Some(previous.target_seq)))
+        else {
+            trace!("Changed message no longer loaded: {}", previous.target_seq);
+            return;
+        };
+        entry.content = previous.content;
+        entry.attachment = previous.attachment;
+        entry.edited = previous.edited;
+        entry.deleted = previous.deleted;
+    }
+
+    fn refresh_conversations(&mut self) {
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map = move |event: WithGeneration<ConversationsEvent>| {
+            let message = match event.result.result {
+                Ok(conversations) => LobbyMessage::ConversationsLoaded(event.generation, conversations),
+                Err(error) => {
+                    warn!("Failed to list conversations: {:?}", error);
+                    LobbyMessage::ConversationsFailed(event.generation)
+                }
+            };
+            let _ = message_tx.send(map_function(message));
+        };
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map_err = move |error: WithGeneration<NetworkError>| {
+            warn!("Conversation list request failed: {:?}", error.result);
+            let _ = message_tx.send(map_function(LobbyMessage::ConversationsFailed(error.generation)));
+        };
+
+        self.conversations_generation = self.real_network.borrow_mut().list_conversations(
+            self.timeouts.conversations_ms,
+            Box::new(map),
+            Box::new(map_err),
+        ).inspect_err(|e| warn!("Failed to request conversations: {:#}", e)).ok();
+    }
+
+    fn refresh_presence(&mut self) {
+        if self.presence_generation.is_some() {
+            return;
+        }
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map = move |event: WithGeneration<PresenceEvent>| {
+            let message = match event.result.result {
+                Ok(presence) => LobbyMessage::PresenceLoaded(event.generation, presence),
+                Err(error) => {
+                    warn!("Failed to fetch presence: {:?}", error);
+                    LobbyMessage::PresenceFailed(event.generation)
+                }
+            };
+            let _ = message_tx.send(map_function(message));
+        };
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map_err = move |error: WithGeneration<NetworkError>| {
+            let _ = message_tx.send(map_function(LobbyMessage::PresenceFailed(error.generation)));
+        };
+
+        self.presence_generation = self.real_network.borrow_mut().fetch_presence(
+            self.timeouts.presence_ms,
+            Box::new(map),
+            Box::new(map_err),
+        ).inspect_err(|e| warn!("Failed to request presence: {:#}", e)).ok();
+    }
+
+    fn is_online(&self, member: &ConversationMember) -> bool {
+        self.presence.get(&member.user_id).copied().unwrap_or(member.online)
+    }
+
+    fn set_conversations(&mut self, conversations: Vec<ConversationInfo>) {
+        for member in conversations.iter().flat_map(|conversation| &conversation.members) {
+            self.usernames.insert(member.user_id.clone(), member.username.clone());
+        }
+        self.conversations = conversations;
+        #[cfg(feature = "manual-test")]
+        for conversation in test_conversations() {
+            if !self.conversations.iter().any(|known| known.conversation_id == conversation.conversation_id) {
+                self.conversations.push(conversation);
+            }
+        }
+
+        if self.send_to.is_none() {
+            if let Some(first) = self.conversations.first() {
+                let conversation_id = first.conversation_id.clone();
+                self.send_to = Some(conversation_id.clone());
+                self.load_history(conversation_id);
+            }
+        }
+    }
+
+    /// Adds or removes a member of a listed conversation; being removed ourselves drops the conversation.
+    fn change_membership(&mut self, notification: MembershipNotification) {
+        let MembershipNotification { conversation_id, member, joined } = notification;
+        let Some(index) = self.conversations.iter().position(|known| known.conversation_id == conversation_id) else {
+            trace!("Membership change for an unlisted conversation: {}", conversation_id.0);
+            return;
+        };
+        if !joined && member.user_id == self.user_id {
+            let conversation = self.conversations.remove(index);
+            self.notify(ToastLevel::Info, format!("You are no longer in {}", conversation.display_name));
+            self.unread_counts.remove(&conversation_id);
+            self.held.remove(&conversation_id);
+            if self.send_to.as_ref() == Some(&conversation_id) {
+                self.clear_search();
+                self.send_to = None;
+            }
+            return;
+        }
+
+        let members = &mut self.conversations[index].members;
+        members.retain(|known| known.user_id != member.user_id);
+        if joined {
+            self.usernames.insert(member.user_id.clone(), member.username.clone());
+            self.presence.insert(member.user_id.clone(), member.online);
+            members.push(member);
+        }
+    }
+
+    fn report_position(&mut self) {
+        let position = LobbyPosition { send_to: self.send_to.clone(), at_bottom: self.at_bottom };
+        if position != self.reported_position {
+            self.reported_position = position.clone();
+            let _ = self.message_tx.send(AppMessage::SetLobbyPosition(position));
+        }
+    }
+
+    /// Brings `conversation_id` on screen, as picking it from the list does.
+    fn show_conversation(&mut self, conversation_id: ConversationId) {
+        if self.send_to.as_ref() != Some(&conversation_id) {
+            self.clear_search();
+        }
+        self.unread_counts.remove(&conversation_id);
+        self.send_to = Some(conversation_id);
+    }
+
+    /// Sends `text` to the direct conversation with `username`, asking the server for it
+    /// unless it is already listed; an empty `text` only switches over.
+    fn write_to(&mut self, username: String, text: String) {
+        self.direct_error = None;
+        if let Err(error) = validate_username(&username) {
+            self.direct_error = Some(direct_failed_message(&username, &error));
+            return;
+        }
+        let known = self.conversations
+            .iter()
+            .filter(|conversation| conversation.kind == ConversationKind::Direct)
+            .find(|conversation| conversation.members
+                .iter()
+                .any(|member| member.user_id != self.user_id && member.username == username))
+            .map(|conversation| conversation.conversation_id.clone());
+        match known {
+            Some(conversation_id) => self.deliver_to(conversation_id, text),
+            None => self.open_direct(username, text),
+        }
+    }
+
+    fn deliver_to(&mut self, conversation_id: ConversationId, text: String) {
+        self.show_conversation(conversation_id.clone());
+        if text.is_empty() || self.send_message(conversation_id, text) {
+            self.input.clear();
+        }
+    }
+
+    fn open_direct(&mut self, username: String, text: String) {
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map = move |event: WithGeneration<DirectEvent>| {
+            let DirectEvent { username, result } = event.result;
+            let message = match result {
+                Ok(conversation) => LobbyMessage::DirectOpened(event.generation, conversation),
+                Err(error) => {
+                    warn!("Failed to open direct conversation: {:?}", error);
+                    LobbyMessage::DirectFailed(event.generation, direct_failed_message(&username, &error))
+                }
+            };
+            let _ = message_tx.send(map_function(message));
+        };
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map_err = move |error: WithGeneration<NetworkError>| {
+            warn!("Direct conversation request failed: {:?}", error.result);
+            let reason = user_message(&error.result);
+            let _ = message_tx.send(map_function(LobbyMessage::DirectFailed(error.generation, reason)));
+        };
+
+        let result = self.real_network.borrow_mut().open_direct(
+            username,
+            self.timeouts.conversations_ms,
+            Box::new(map),
+            Box::new(map_err),
+        );
+        match result {
+            Ok(generation) => {
+                self.direct_generation = Some(generation);
+                self.direct_text = text;
+            }
+            Err(e) => {
+                warn!("Failed to request direct conversation: {:#}", e);
+                self.direct_error = Some(open_conversation_failed_message());
+            }
+        }
+    }
+
+    /// Known usernames starting with the `@` prefix being typed, for completing it.
+    fn mention_suggestions(&self) -> Vec<String> {
+        let Some(prefix) = self.input.trim_start().strip_prefix('@') else {
+            return Vec::new();
+        };
+        if prefix.contains(char::is_whitespace) {
+            return Vec::new();
+        }
+        let prefix = prefix.to_lowercase();
+        let mut names = self.usernames
+            .iter()
+            .filter(|(user_id, name)| **user_id != self.user_id && name.to_lowercase().starts_with(&prefix))
+            .map(|(_, name)| name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        names.truncate(MAX_MENTION_SUGGESTIONS);
+        names
+    }
+
+    /// The completions, lookup spinner and error that go with an `@username` input.
+    fn mention_row(&mut self, ui: &mut egui::Ui, input: &egui::Response) {
+        let suggestions = self.mention_suggestions();
+        // Not tied to the input's focus, which a click on a suggestion takes away before it lands.
+        if !suggestions.is_empty() {
+            egui::Frame::popup(ui.style()).show(ui, |ui| {
+                for name in suggestions {
+                    if ui.selectable_label(false, format!("@{}", name)).clicked() {
+                        self.input = format!("@{} ", name);
+                        input.request_focus();
+                    }
+                }
+            });
+        }
+        if self.direct_generation.is_some() {
+            ui.horizontal(|ui| {
+                ui.add(egui::Spinner::new());
+                ui.label("Opening conversation…");
+            });
+        }
+        if let Some(error) = &self.direct_error {
+            ui.colored_label(ui.visuals().error_fg_color, error);
+        }
+    }
+
+    fn notify_typing(&mut self, conversation_id: ConversationId) {
+        if !self.typing_debouncer.try_record() {
+            return;
+        }
+        if let Err(error) = self.real_network.borrow_mut().send_typing(conversation_id) {
+            warn!("Failed to send typing notice: {:?}", error);
+        }
+    }
+
+    /// Names of the users seen typing in `conversation_id` recently.
+    fn typing_names(&self, conversation_id: &ConversationId) -> Vec<String> {
+        self.typing
+            .get(conversation_id)
+            .into_iter()
+            .flatten()
+            .filter(|(_, seen_at)| seen_at.elapsed() < TYPING_DISPLAY_DURATION)
+            .map(|(user_id, _)| self.usernames.get(user_id).cloned().unwrap_or_else(|| user_id.0.to_string()))
+            .collect()
+    }
+
+    fn report_read(&mut self, conversation_id: ConversationId) {
+        let Some(newest) = self.chat_history
+            .get(&conversation_id)
+            .and_then(|entries| entries.iter().filter_map(|entry| entry.seq).max())
+        else {
+            return;
+        };
+        if self.read_reported.get(&conversation_id).is_some_and(|&reported| reported >= newest) {
+            return;
+        }
+
+        match self.real_network.borrow_mut().send_read_receipt(conversation_id.clone(), newest) {
+            Ok(()) => {
+                self.read_reported.insert(conversation_id, newest);
+            }
+            Err(error) => warn!("Failed to send read receipt: {:?}", error),
+        }
+    }
+
+    /// Index of the last own message in `conversation_id` that another member has read.
+    fn seen_index(&self, conversation_id: &ConversationId) -> Option<usize> {
+        let read_up_to = *self.read_by_others.get(conversation_id)?;
+        self.chat_history
+            .get(conversation_id)?
+            .iter()
+            .rposition(|entry| entry.is_own(&self.user_id) && entry.seq.is_some_and(|seq| seq <= read_up_to))
+    }
+
+    /// The search field with the hit count and Prev/Next, which wrap around.
+    fn search_bar(&mut self, ui: &mut egui::Ui, hit_count: usize) {
+        ui.horizontal(|ui| {
+            let field = ui.add(egui::TextEdit::singleline(&mut self.search).hint_text("Search this conversation"));
+            if field.changed() {
+                if self.search.is_empty() {
+                    self.clear_search();
+                } else {
+                    self.search_hit = 0;
+                    self.scroll_to_hit = true;
+                }
+            }
+            if self.search.is_empty() {
+                return;
+            }
+            let mut step = None;
+            // Enter in the field jumps to the next hit and keeps the field focused for another.
+            if field.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                step = Some(1);
+                field.request_focus();
+            }
+            if hit_count == 0 {
+                ui.label("No matches");
+            } else {
+                ui.label(format!("{}/{}", self.search_hit + 1, hit_count));
+                if ui.small_button("Prev").clicked() {
+                    step = Some(hit_count - 1);
+                }
+                if ui.small_button("Next").clicked() {
+                    step = Some(1);
+                }
+            }
+            if ui.small_button("Clear").clicked() {
+                self.clear_search();
+            }
+            if let (Some(step), true) = (step, hit_count > 0) {
+                self.search_hit = (self.search_hit + step) % hit_count;
+                self.scroll_to_hit = true;
+            }
+        });
+    }
+
+    fn clear_search(&mut self) {
+        self.search.clear();
+        self.search_hit = 0;
+        self.scroll_to_hit = false;
+        self.scroll_to_bottom = true;
+    }
+
+    fn set_delivery(&mut self, generation: u64, delivery: DeliveryState, seq: Option<u64>) {
+        let entry = self.chat_history
+            .values_mut()
+            .flat_map(|entries| entries.iter_mut())
+            .find(|entry| entry.send_generation == Some(generation));
+        match entry {
+            Some(entry) => {
+                entry.delivery = delivery;
+                if seq.is_some() {
+                    entry.seq = seq;
+                }
+                // The bytes are only needed for a retry.
+                if let (DeliveryState::Sent, Some(EntryAttachment::Outgoing(file))) = (delivery, &entry.attachment) {
+                    entry.attachment = Some(EntryAttachment::Uploaded(file.filename.clone()));
+                }
+            }
+            None => warn!("Drop delivery update for unknown message: {}", generation),
+        }
+    }
+
+    /// Resolves the entries sent together under `generation`, in the order they were queued,
+    /// and returns how many failed.
+    fn set_batch_delivery(&mut self, generation: u64, results: Vec<MessageEvent>) -> usize {
+        let mut failed = 0;
+        let mut results = results.into_iter();
+        let entries = self.chat_history
+            .values_mut()
+            .flat_map(|entries| entries.iter_mut())
+            .filter(|entry| entry.send_generation == Some(generation));
+        for entry in entries {
+            match results.next().map(|event| event.result) {
+                Some(Ok(sent)) => {
+                    entry.delivery = DeliveryState::Sent;
+                    entry.seq = sent.seq.or(entry.seq);
+                }
+                Some(Err(_)) | None => {
+                    entry.delivery = DeliveryState::Failed;
+                    failed += 1;
+                }
+            }
+        }
+        failed
+    }
+
+    /// The local clock corrected to the server's, so own messages sort among received ones.
+    fn server_now(&self) -> DateTime<Utc> {
+        Utc::now() + self.real_network.borrow().clock_offset()
+    }
+
+    fn notify(&self, level: ToastLevel, text: impl Into<String>) {
+        let _ = self.message_tx.send(AppMessage::Notify { level, text: text.into() });
+    }
+
+    /// Shows `entry` in `seq` order. One arriving ahead of a missing `seq` is held for up to
+    /// `REORDER_WAIT`, and one already shown, e.g. replayed after a reconnect, is dropped.
+    fn push_received(&mut self, conversation_id: ConversationId, entry: ChatEntry) {
+        let Some(seq) = entry.seq else {
+            // Older servers leave `seq` out; arrival order is all there is.
+            self.show_entry(conversation_id, entry);
+            return;
+        };
+        if self.has_seq(&conversation_id, seq) {
+            debug!(seq, "Skip message already shown");
+            return;
+        }
+        // Without a shown `seq` there is nothing to be ahead of, e.g. the first message of an unopened conversation.
+        match self.newest_seq(&conversation_id) {
+            Some(newest) if seq > newest + 1 => {
+                trace!(seq, newest, "Hold message until the ones before it arrive");
+                self.held
+                    .entry(conversation_id)
+                    .or_insert_with(|| HeldEntries { since: Instant::now(), entries: BTreeMap::new() })
+                    .entries
+                    .insert(seq, entry);
+            }
+            _ => {
+                self.show_entry(conversation_id.clone(), entry);
+                self.release_held(&conversation_id, false);
+            }
+        }
+    }
+
+    /// Swaps the own entry sent under `client_id` for its distributed copy, which can arrive before the ACK.
+    /// Hands `entry` back if no entry is still waiting for one.
+    fn replace_pending(&mut self, conversation_id: &ConversationId, client_id: Uuid, mut entry: ChatEntry) -> Option<ChatEntry> {
+        let Some(entries) = self.chat_history.get_mut(conversation_id) else {
+            return Some(entry);
+        };
+        let Some(index) = entries.iter().position(|shown| {
+            shown.client_id == Some(client_id) && matches!(shown.delivery, DeliveryState::Sending | DeliveryState::Failed)
+        }) else {
+            return Some(entry);
+        };
+        let pending = entries.remove(index);
+        // Kept so the ACK still finds the entry, and has nothing left to change.
+        entry.send_generation = pending.send_generation;
+        entry.client_id = pending.client_id;
+        let position = match entry.seq {
+            Some(seq) => entries.iter().position(|shown| shown.seq.is_some_and(|shown| shown > seq)).unwrap_or(entries.len()),
+            None => index,
+        };
+        entries.insert(position, entry);
+        None
+    }
+
+    /// Inserts before the first entry with a later `seq`; own entries still waiting for theirs stay put.
+    fn show_entry(&mut self, conversation_id: ConversationId, entry: ChatEntry) {
+        if self.send_to.as_ref() != Some(&conversation_id) {
+            *self.unread_counts.entry(conversation_id.clone()).or_default() += 1;
+        }
+        let entries = self.chat_history.entry(conversation_id.clone()).or_default();
+        let position = entry.seq
+            .and_then(|seq| entries.iter().position(|shown| shown.seq.is_some_and(|shown| shown > seq)))
+            .unwrap_or(entries.len());
+        entries.insert(position, entry);
+        self.evict_old_entries(&conversation_id);
+    }
+
+    fn newest_seq(&self, conversation_id: &ConversationId) -> Option<u64> {
+        self.chat_history
+            .get(conversation_id)
+            .and_then(|entries| entries.iter().filter_map(|entry| entry.seq).max())
+    }
+
+    fn has_seq(&self, conversation_id: &ConversationId, seq: u64) -> bool {
+        self.chat_history
+            .get(conversation_id)
+            .is_some_and(|entries| entries.iter().any(|entry| entry.seq == Some(seq)))
+            || self.held.get(conversation_id).is_some_and(|held| held.entries.contains_key(&seq))
+    }
+
+    /// Shows the held entries of `conversation_id` the gap no longer stands in front of; with `expired`,
+    /// all of them, and the gap is fetched from history instead.
+    fn release_held(&mut self, conversation_id: &ConversationId, expired: bool) {
+        let Some(mut held) = self.held.remove(conversation_id) else {
+            return;
+        };
+        let gap_before = held.entries.values().next().map(|entry| entry.sent_at);
+        while let Some(entry) = held.entries.first_entry() {
+            let next = self.newest_seq(conversation_id).map(|newest| newest + 1);
+            if !expired && next.is_some_and(|next| *entry.key() > next) {
+                break;
+            }
+            let entry = entry.remove();
+            self.show_entry(conversation_id.clone(), entry);
+        }
+        if !held.entries.is_empty() {
+            self.held.insert(conversation_id.clone(), held);
+        } else if let (true, Some(before)) = (expired, gap_before) {
+            warn!("Messages missing in {}, fetching them", conversation_id.0);
+            self.fill_gap(conversation_id.clone(), before);
+        }
+    }
+
+    /// Gives up waiting on gaps older than `REORDER_WAIT`, e.g. a message an ACK for an own send
+    /// was expected to fill, and returns how long until the next one would be.
+    fn release_expired(&mut self) -> Option<Duration> {
+        let expired: Vec<ConversationId> = self.held
+            .iter()
+            .filter(|(_, held)| held.since.elapsed() >= REORDER_WAIT)
+            .map(|(conversation_id, _)| conversation_id.clone())
+            .collect();
+        for conversation_id in expired {
+            self.release_held(&conversation_id, true);
+        }
+        // An own message confirmed in the meantime may have closed a gap too.
+        let waiting: Vec<ConversationId> = self.held.keys().cloned().collect();
+        for conversation_id in waiting {
+            self.release_held(&conversation_id, false);
+        }
+        self.held.values().map(|held| REORDER_WAIT.saturating_sub(held.since.elapsed())).min()
+    }
+
+    /// Drops the oldest entries past `scrollback_limit`, along with their attachment textures, unless
+    /// the conversation is in `history_kept`.
+    /// Queued and sending entries stay until they resolve, whatever their age.
+    fn evict_old_entries(&mut self, conversation_id: &ConversationId) {
+        if self.history_kept.contains(conversation_id) {
+            return;
+        }
+        let Some(entries) = self.chat_history.get_mut(conversation_id) else {
+            return;
+        };
+        let mut excess = entries.len().saturating_sub(self.scrollback_limit);
+        if excess == 0 {
+            return;
+        }
 
-    chat_generation: Option<u64>,
-    chat_history: Vec<String>,
-    input: String,
+        let mut evicted_blobs = Vec::new();
+        let mut evicted_own = Vec::new();
+        entries.retain(|entry| {
+            let evict = excess > 0 && !matches!(entry.delivery, DeliveryState::Queued | DeliveryState::Sending);
+            if evict {
+                excess -= 1;
+                if let Some(EntryAttachment::Remote(attachment)) = &entry.attachment {
+                    evicted_blobs.push(attachment.blob_id);
+                }
+                evicted_own.extend(entry.client_id);
+            }
+            !evict
+        });
+        for blob_id in evicted_blobs {
+            self.attachment_textures.remove(&blob_id);
+            self.attachment_requested.remove(&blob_id);
+        }
+        for client_id in evicted_own {
+            self.own_attachment_bytes.remove(&client_id);
+            self.own_attachment_textures.remove(&client_id);
+        }
+        self.history_exhausted.remove(conversation_id);
+        self.history_evicted.insert(conversation_id.clone());
+    }
 
-    send_to: ConversationKind,
-}
+    /// Lets the history loaded on request go again, once the user is back at the newest messages.
+    fn release_kept_history(&mut self, conversation_id: &ConversationId) {
+        if self.history_kept.remove(conversation_id) {
+            self.evict_old_entries(conversation_id);
+        }
+    }
 
-impl LobbyPage {
-    pub fn new(
-        message_tx: Sender<AppMessage>,
-        map_function: Box<dyn Fn(LobbyMessage) -> AppMessage>,
-        new_map_function: Arc<Box<dyn Fn(LobbyMessage) -> AppMessage + Send + Sync>>,
-        network: Weak<RefCell<dyn Network>>,
-        real_network: Rc<RefCell<dyn NetworkInterface>>,
-        chat_generation: u64,
-    ) -> Self {
-        Self {
-            message_tx: message_tx.clone(),
-            map_function,
-            new_map_function,
-            network,
-            real_network,
-            chat_generation: Some(chat_generation),
-            chat_history: vec![],
-            input: String::new(),
-            send_to: TEST_CONVERSATIONS.get(0).unwrap().kind
+    fn reconnect(&mut self) {
+        // Start from a clean slate; the old session may still be retrying on its own.
+        if self.real_network.borrow_mut().disconnect_chat().is_ok() {
+            trace!("Dropped the old chat session before reconnecting");
+        }
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map = move |event: WithGeneration<SessionEvent>| {
+            let message = match event.result.result {
+                Ok(_) => LobbyMessage::Reconnected(event.generation),
+                Err(_) => LobbyMessage::ReconnectFailed(event.generation),
+            };
+            let _ = message_tx.send(map_function(message));
+        };
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map_err = move |error: WithGeneration<NetworkError>| {
+            warn!("Reconnect request failed: {:?}", error.result);
+            let _ = message_tx.send(map_function(LobbyMessage::ReconnectFailed(error.generation)));
+        };
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let result = self.real_network.borrow_mut().connect_chat(
+            self.chat_address.clone(),
+            self.jwt.clone(),
+            Box::new(move |message| {
+                let _ = message_tx.send(map_function(LobbyMessage::Stream(message)));
+            }),
+            self.timeouts.connect_ms,
+            Box::new(map),
+            Box::new(map_err),
+        );
+        self.reconnect_generation = result
+            .inspect_err(|e| warn!("Failed to start reconnecting: {:#}", e))
+            .ok();
+    }
+
+    fn logout(&mut self) {
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map = move |event: WithGeneration<LogoutEvent>| {
+            if let Err(error) = event.result.result {
+                warn!("Logout was not confirmed by the server: {:?}", error);
+            }
+            let _ = message_tx.send(map_function(LobbyMessage::LoggedOut));
+        };
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map_err = move |error: WithGeneration<NetworkError>| {
+            warn!("Logout request failed: {:?}", error.result);
+            let _ = message_tx.send(map_function(LobbyMessage::LoggedOut));
+        };
+
+        self.logout_generation = self.real_network.borrow_mut().logout(
+            self.timeouts.logout_ms,
+            Box::new(map),
+            Box::new(map_err),
+        ).ok();
+
+        if self.logout_generation.is_none() {
+            let _ = self.message_tx.send(AppMessage::ReqNavigate(Route::LoginPage(None)));
         }
     }
 }
@@ -61,26 +1610,104 @@ impl LobbyPage {
 impl Update<LobbyMessage> for LobbyPage {
     fn update_one(&mut self, message: LobbyMessage) {
         match message {
-            LobbyMessage::ChatSent(generation, message) => {
-                if Some(generation) == self.chat_generation {
-                    self.chat_history.push(message);
-                }
+            LobbyMessage::Reconnected(generation) if self.reconnect_generation == Some(generation) => {
+                self.reconnect_generation = None;
+                self.disconnect_reason = None;
+                self.notify(ToastLevel::Info, reconnected_message());
+            }
+            LobbyMessage::ReconnectFailed(generation) if self.reconnect_generation == Some(generation) => {
+                self.reconnect_generation = None;
+                self.notify(ToastLevel::Error, reconnect_failed_message());
+            }
+            LobbyMessage::MessageSent(generation, _) if self.pending_changes.remove(&generation).is_some() => {
+                trace!("Change confirmed: {}", generation);
+            }
+            LobbyMessage::MessageSent(generation, seq) => {
+                self.set_delivery(generation, DeliveryState::Sent, seq);
             }
-            LobbyMessage::ChatReceived(generation, message) => {
-                if Some(generation) == self.chat_generation {
-                    self.chat_history.push(message);
+            LobbyMessage::MessageFailed(generation, reason) => match self.pending_changes.remove(&generation) {
+                Some(previous) => {
+                    self.revert_change(previous);
+                    self.notify(ToastLevel::Warning, change_failed_message(&reason));
                 }
+                None => {
+                    self.set_delivery(generation, DeliveryState::Failed, None);
+                    self.notify(ToastLevel::Warning, send_failed_message(1, Some(&reason)));
+                }
+            },
+            LobbyMessage::BatchSent(generation, results) => {
+                // One reason stands for the batch; they usually share it, e.g. a rate limit.
+                let reason = results
+                    .iter()
+                    .find_map(|event| event.result.as_ref().err().map(|error| rejection_message(error, event.detail.clone())));
+                let failed = self.set_batch_delivery(generation, results);
+                if failed > 0 {
+                    self.notify(ToastLevel::Warning, send_failed_message(failed, reason.as_deref()));
+                }
+            }
+            LobbyMessage::BatchFailed(generation, reason) => {
+                let failed = self.set_batch_delivery(generation, Vec::new());
+                self.notify(ToastLevel::Warning, send_failed_message(failed, Some(&reason)));
+            }
+            LobbyMessage::HistoryLoaded(conversation_id, messages) => {
+                self.prepend_history(conversation_id, messages);
+            }
+            LobbyMessage::HistoryFailed(conversation_id) => {
+                self.history_loading.remove(&conversation_id);
+                self.history_failed_at.insert(conversation_id, Instant::now());
+            }
+            LobbyMessage::GapLoaded(conversation_id, messages) => {
+                self.merge_gap(conversation_id, messages);
+            }
+            LobbyMessage::GapFailed(conversation_id) => {
+                trace!("Leave the gap in {} as it is", conversation_id.0);
+            }
+            LobbyMessage::AttachmentLoaded(blob_id, bytes) => {
+                self.attachment_bytes.insert(blob_id, bytes);
+            }
+            LobbyMessage::AttachmentFailed(blob_id) => {
+                trace!("Leave attachment as a placeholder: {}", blob_id);
+            }
+            LobbyMessage::ConversationsLoaded(generation, conversations) if self.conversations_generation == Some(generation) => {
+                self.conversations_generation = None;
+                self.set_conversations(conversations);
+            }
+            LobbyMessage::ConversationsFailed(generation) if self.conversations_generation == Some(generation) => {
+                self.conversations_generation = None;
+                self.notify(ToastLevel::Warning, conversations_failed_message());
+            }
+            LobbyMessage::PresenceLoaded(generation, presence) if self.presence_generation == Some(generation) => {
+                self.presence_generation = None;
+                self.presence = presence
+                    .into_iter()
+                    .map(|notification| (notification.user_id, notification.online))
+                    .collect();
             }
-            LobbyMessage::MessageSent(message) => {
-                self.chat_history.push(message);
+            LobbyMessage::PresenceFailed(generation) if self.presence_generation == Some(generation) => {
+                self.presence_generation = None;
             }
-            LobbyMessage::MessageFailed(message) => {
-                self.chat_history.push(message);
+            LobbyMessage::DirectOpened(generation, conversation) if self.direct_generation == Some(generation) => {
+                self.direct_generation = None;
+                for member in &conversation.members {
+                    self.usernames.insert(member.user_id.clone(), member.username.clone());
+                }
+                let conversation_id = conversation.conversation_id.clone();
+                if !self.conversations.iter().any(|known| known.conversation_id == conversation_id) {
+                    self.conversations.push(conversation);
+                }
+                let text = std::mem::take(&mut self.direct_text);
+                self.deliver_to(conversation_id, text);
             }
-            LobbyMessage::Stream(message) => {
-                let message = match message { StreamMessage::Distribute(message) => message };
-                self.chat_history.push(message.content);
+            LobbyMessage::DirectFailed(generation, reason) if self.direct_generation == Some(generation) => {
+                self.direct_generation = None;
+                self.direct_text.clear();
+                self.direct_error = Some(reason);
             }
+            LobbyMessage::LoggedOut => {
+                self.logout_generation = None;
+                let _ = self.message_tx.send(AppMessage::ReqNavigate(Route::LoginPage(None)));
+            }
+            LobbyMessage::Stream(message) => self.receive_stream(message),
             _ => {}
         }
     }
@@ -88,139 +1715,932 @@ impl Update<LobbyMessage> for LobbyPage {
 
 impl View for LobbyPage {
     fn view(&mut self, ctx: &Context) {
+        // Polled rather than tracked from the stream, so Send never outlives the connection.
+        let connection_state = self.real_network.borrow().session_state();
+        let connected = self.real_network.borrow().is_connected();
+        let clock_offset = self.real_network.borrow().clock_offset();
+        // Queued messages wait out a disconnect rather than failing.
+        if connected {
+            self.flush_queued_sends();
+        }
+        if !self.queued_sends.is_empty() {
+            ctx.request_repaint_after(self.send_limiter.next_token_in());
+        }
+        if let Some(next) = self.release_expired() {
+            ctx.request_repaint_after(next);
+        }
         egui::Window::new("Lobby")
             .collapsible(false)
             .resizable(false)
             .anchor(egui::Align2::CENTER_BOTTOM, [0.0, 0.0])
             .show(ctx, |ui| {
-                if ui.button("Logout").clicked() {
-                    self.message_tx.send(AppMessage::ReqNavigate(Route::LoginPage)).unwrap();
-                }
+                ui.horizontal(|ui| {
+                    let enabled = self.logout_generation.is_none();
+                    if ui.add_enabled(enabled, egui::Button::new("Logout")).clicked() {
+                        self.logout();
+                    }
+                    if !enabled {
+                        ui.add(egui::Spinner::new());
+                        ui.label("Logging out...");
+                    }
+                    theme_toggle(ui, &self.message_tx);
+                    let keep_running = ui.checkbox(&mut self.close_to_background, "Keep running when closed")
+                        .on_hover_text("Closing the window minimizes it and stays connected");
+                    if keep_running.changed() {
+                        let _ = self.message_tx.send(AppMessage::SetCloseToBackground(self.close_to_background));
+                    }
+                    ui.toggle_value(&mut self.show_metrics, "Connection")
+                        .on_hover_text("Round trip and message counts of the chat connection");
+                    if ui.button("Quit").clicked() {
+                        let _ = self.message_tx.send(AppMessage::Exiting);
+                    }
+                });
 
                 ui.separator();
 
-                egui::ScrollArea::vertical()
+                let Some(send_to) = self.send_to.clone() else {
+                    ui.label("Pick a conversation, or write to someone with @username.");
+                    let input = ui.text_edit_singleline(&mut self.input);
+                    if input.changed() {
+                        self.direct_error = None;
+                    }
+                    if input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) && self.direct_generation.is_none() {
+                        if let Some((username, text)) = parse_mention(&self.input) {
+                            self.write_to(username.to_string(), text.to_string());
+                        }
+                        input.request_focus();
+                    }
+                    self.mention_row(ui, &input);
+                    return;
+                };
+
+                let peer = self.conversations
+                    .iter()
+                    .find(|conversation| conversation.conversation_id == send_to && conversation.kind == ConversationKind::Direct)
+                    .and_then(|conversation| conversation.members.iter().find(|member| member.user_id != self.user_id));
+                if let Some(peer) = peer {
+                    ui.horizontal(|ui| {
+                        presence_dot(ui, self.is_online(peer));
+                        ui.strong(&peer.username);
+                    });
+                }
+
+                for (blob_id, bytes) in std::mem::take(&mut self.attachment_bytes) {
+                    match load_image_texture(ctx, &bytes, &blob_id.to_string()) {
+                        Ok(texture) => {
+                            self.attachment_textures.insert(blob_id, texture);
+                        }
+                        Err(e) => warn!("Failed to decode attachment {}: {:#}", blob_id, e),
+                    }
+                }
+                for (client_id, bytes) in std::mem::take(&mut self.own_attachment_bytes) {
+                    match load_image_texture(ctx, &bytes, &client_id.to_string()) {
+                        Ok(texture) => {
+                            self.own_attachment_textures.insert(client_id, texture);
+                        }
+                        Err(e) => warn!("Failed to decode own attachment {}: {:#}", client_id, e),
+                    }
+                }
+
+                // Labels are what the user reads, so they are also what the search looks at.
+                let labels = self.chat_history
+                    .get(&send_to)
+                    .into_iter()
+                    .flatten()
+                    .map(|entry| entry.label(&self.usernames, &self.user_id, clock_offset))
+                    .collect::<Vec<_>>();
+                let hit_ranges = labels.iter().map(|label| find_matches(label, &self.search)).collect::<Vec<_>>();
+                let hits = (0..labels.len()).filter(|index| !hit_ranges[*index].is_empty()).collect::<Vec<_>>();
+                let searching = !self.search.is_empty();
+                // Hits come and go with the history, e.g. when old entries are evicted.
+                self.search_hit = self.search_hit.min(hits.len().saturating_sub(1));
+                self.search_bar(ui, hits.len());
+                let current_hit = hits.get(self.search_hit).copied();
+
+                let mut to_fetch = Vec::new();
+                let mut load_older = false;
+                let scroll_output = egui::ScrollArea::vertical()
                     .auto_shrink([false, false])
-                    .stick_to_bottom(true)
+                    .stick_to_bottom(!searching)
                     .max_height(50.0)
                     .show(ui, |ui| {
                         ui.set_width(ui.available_width());
-                        for message in &self.chat_history {
-                            ui.label(message);
+                        if self.history_loading.contains(&send_to) {
+                            ui.add(egui::Spinner::new());
+                        } else if self.history_evicted.contains(&send_to) && ui.button("Load older messages").clicked() {
+                            load_older = true;
+                        }
+                        let mut resend = None;
+                        let mut edit = None;
+                        let mut delete = None;
+                        let seen_index = self.seen_index(&send_to);
+                        for (index, (entry, label)) in self.chat_history.get(&send_to).into_iter().flatten().zip(labels).enumerate() {
+                            if searching && hit_ranges[index].is_empty() {
+                                continue;
+                            }
+                            let pending = matches!(entry.delivery, DeliveryState::Queued | DeliveryState::Sending);
+                            let text: egui::WidgetText = if searching {
+                                highlighted(ui, &label, &hit_ranges[index], pending, current_hit == Some(index)).into()
+                            } else if pending {
+                                egui::RichText::new(label).weak().italics().into()
+                            } else {
+                                label.into()
+                            };
+                            let failed = entry.delivery == DeliveryState::Failed;
+                            let response = if entry.is_own(&self.user_id) {
+                                let label = ui.with_layout(egui::Layout::right_to_left(egui::Align::TOP), |ui| {
+                                    if seen_index == Some(index) {
+                                        ui.label(egui::RichText::new("seen").weak().small());
+                                    }
+                                    if failed && ui.add_enabled(connected, egui::Button::new("Retry").small()).clicked() {
+                                        resend = Some(index);
+                                    }
+                                    ui.add(egui::Label::new(text).sense(egui::Sense::click()))
+                                }).inner;
+                                if let (true, Some(seq)) = (entry.is_editable(&self.user_id), entry.seq) {
+                                    label.context_menu(|ui| {
+                                        if ui.add_enabled(connected, egui::Button::new("Edit")).clicked() {
+                                            edit = Some((seq, entry.content.clone()));
+                                            ui.close_menu();
+                                        }
+                                        if ui.add_enabled(connected, egui::Button::new("Delete")).clicked() {
+                                            delete = Some(seq);
+                                            ui.close_menu();
+                                        }
+                                    });
+                                }
+                                label
+                            } else {
+                                ui.label(text)
+                            };
+                            if self.scroll_to_hit && current_hit == Some(index) {
+                                response.scroll_to_me(Some(egui::Align::Center));
+                                self.scroll_to_hit = false;
+                            }
+                            let own_texture = entry.client_id.and_then(|client_id| self.own_attachment_textures.get(&client_id));
+                            if let Some(texture) = own_texture {
+                                ui.add(egui::Image::from_texture(texture).max_height(ATTACHMENT_PREVIEW_HEIGHT));
+                            } else if let Some(EntryAttachment::Remote(attachment)) = &entry.attachment {
+                                if let Some(texture) = self.attachment_textures.get(&attachment.blob_id) {
+                                    ui.add(egui::Image::from_texture(texture).max_height(ATTACHMENT_PREVIEW_HEIGHT));
+                                } else if attachment.is_image() && !self.attachment_requested.contains(&attachment.blob_id) {
+                                    to_fetch.push(attachment.blob_id);
+                                }
+                            }
+                        }
+                        if let Some(index) = resend {
+                            self.resend_message(send_to.clone(), index);
+                        }
+                        if let Some((seq, content)) = edit {
+                            self.editing = Some(seq);
+                            self.input = content;
+                        }
+                        if let Some(target_seq) = delete {
+                            self.send_change(send_to.clone(), ChatBody::Delete { target_seq });
+                        }
+                        if std::mem::take(&mut self.scroll_to_bottom) {
+                            ui.scroll_to_cursor(Some(egui::Align::BOTTOM));
                         }
                     });
 
+                for blob_id in to_fetch {
+                    self.fetch_attachment(blob_id);
+                }
+
+                // Once entries were evicted, scrolling up alone would pull them back only to evict them again.
+                // A search shows a few entries that may well fit without scrolling, so it does not count either.
+                let at_top = scroll_output.state.offset.y <= 0.0 && !self.history_evicted.contains(&send_to) && !searching;
+                if load_older || at_top {
+                    self.load_history(send_to.clone());
+                }
+
+                let at_bottom = scroll_output.state.offset.y + scroll_output.inner_rect.height()
+                    >= scroll_output.content_size.y - 1.0;
+                // A search scrolls on its own, so where it leaves the view says nothing about the user.
+                if !searching {
+                    self.at_bottom = at_bottom;
+                    if at_bottom {
+                        self.release_kept_history(&send_to);
+                    }
+                }
+                if at_bottom && !searching && ctx.input(|i| i.focused) {
+                    self.report_read(send_to.clone());
+                }
+
                 ui.separator();
 
-                ui.horizontal(|ui| {
-                    let input = ui.text_edit_singleline(&mut self.input);
-                    if ui.button("Send").clicked()
-                        || (input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)))
-                    {
-                        if !self.input.is_empty() {
-                            let conversation_id = &TEST_CONVERSATIONS.iter().find(|e| {
-                                e.kind == self.send_to
-                            }).unwrap().conversation_id;
-
-                            let input_message = self.input.clone();
-                            let message_tx = self.message_tx.clone();
-                            let map_function = self.new_map_function.clone();
-                            let map = move |event: WithGeneration<MessageEvent>| {
-                                let message = match event.result.result {
-                                    Ok(_) => LobbyMessage::MessageSent(input_message),
-                                    Err(_) => LobbyMessage::MessageFailed(input_message),
-                                };
-                                let _ = message_tx.send(map_function(message));
-                            };
+                let typing_names = self.typing_names(&send_to);
+                if !typing_names.is_empty() {
+                    let verb = if typing_names.len() == 1 { "is" } else { "are" };
+                    ui.label(egui::RichText::new(format!("{} {} typing…", typing_names.join(", "), verb)).weak());
+                    ctx.request_repaint_after(Duration::from_millis(500));
+                }
 
-                            let input_message = self.input.clone();
-                            let message_tx = self.message_tx.clone();
-                            let map_function = self.new_map_function.clone();
-                            let map_err = move |_error| {
-                                let message = LobbyMessage::MessageFailed(input_message);
-                                let _ = message_tx.send(map_function(message));
-                            };
+                if let Some(reason) = self.disconnect_reason {
+                    ui.horizontal(|ui| {
+                        ui.colored_label(ui.visuals().warn_fg_color, format!("Connection lost: {}", reason));
+                        let reconnecting = self.reconnect_generation.is_some();
+                        if ui.add_enabled(!reconnecting, egui::Button::new("Reconnect")).clicked() {
+                            self.reconnect();
+                        }
+                    });
+                }
+                if matches!(connection_state, ConnectionState::Connecting | ConnectionState::Reconnecting) {
+                    ui.horizontal(|ui| {
+                        ui.add(egui::Spinner::new());
+                        ui.label("Reconnecting…");
+                    });
+                } else if connection_state == ConnectionState::Reauthenticating {
+                    ui.horizontal(|ui| {
+                        ui.add(egui::Spinner::new());
+                        ui.label("Re-authenticating…");
+                    });
+                } else if connection_state == ConnectionState::Disconnected && self.disconnect_reason.is_none() {
+                    // E.g. the connection never came up; a lost one already says why above.
+                    ui.colored_label(ui.visuals().warn_fg_color, "Disconnected, new messages wait until reconnected");
+                }
+
+                if self.editing.is_some() {
+                    ui.horizontal(|ui| {
+                        ui.label(egui::RichText::new("Editing a message").weak());
+                        if ui.small_button("Cancel").clicked() {
+                            self.editing = None;
+                            self.input.clear();
+                        }
+                    });
+                }
+
+                if let Some(file) = &self.pasted {
+                    let mut remove = false;
+                    ui.horizontal(|ui| {
+                        if let Some(texture) = &self.pasted_texture {
+                            ui.add(egui::Image::from_texture(texture).max_height(PASTE_PREVIEW_HEIGHT));
+                        }
+                        let text = format!("{} ({} KiB), type a caption and Send", file.filename, file.bytes.len().div_ceil(1024));
+                        ui.label(egui::RichText::new(text).weak());
+                        remove = ui.small_button("Remove").clicked();
+                    });
+                    if remove {
+                        self.clear_pasted();
+                    }
+                }
 
-                            let _ = self.real_network.borrow_mut().send_chat_message(
-                                conversation_id.clone(),
-                                self.input.trim().to_string(),
-                                1000,
-                                Box::new(map),
-                                Box::new(map_err),
-                            );
-
-                            // self.network.upgrade().unwrap().borrow_mut().send_chat_message(self.chat_generation.unwrap(), self.input.clone(), 1000, Box::new(|e| {
-                            //     match e {
-                            //         NetworkEvent::ChatSent(generation, message) => {
-                            //             AppMessage::Lobby(LobbyMessage::ChatSent(generation, message))
-                            //         }
-                            //         NetworkEvent::ChatReceived(generation, message) => {
-                            //             AppMessage::Lobby(LobbyMessage::ChatReceived(generation, message))
-                            //         }
-                            //         _ => { AppMessage::PlaceHolder }
-                            //     }
-                            // })).unwrap();
-
-                            // self.chat_history.push(self.input.trim().to_owned());
+                ui.horizontal(|ui| {
+                    // Enter sends; Shift+Enter is the only shortcut that inserts a newline.
+                    let enter_pressed = ui.input(|i| i.key_pressed(egui::Key::Enter) && !i.modifiers.shift);
+                    let input = ui.add(
+                        egui::TextEdit::multiline(&mut self.input)
+                            .desired_rows(INPUT_ROWS)
+                            .return_key(egui::KeyboardShortcut::new(egui::Modifiers::SHIFT, egui::Key::Enter)),
+                    );
+                    if connected && input.changed() && !self.input.trim().is_empty() {
+                        self.notify_typing(send_to.clone());
+                    }
+                    // New messages queue while disconnected; an edit or a pasted image has to go out now.
+                    let can_send = connected || (self.editing.is_none() && self.pasted.is_none());
+                    if ui.add_enabled(can_send, egui::Button::new("Send")).clicked()
+                        || (can_send && input.has_focus() && enter_pressed)
+                    {
+                        // A leading `@username` is just text here; only the input without a conversation switches to someone.
+                        let text = self.input.trim().to_string();
+                        if let Some(file) = self.pasted.take() {
+                            self.clear_pasted();
+                            self.send_entry(send_to.clone(), text, Some(file));
                             self.input.clear();
+                        } else if !text.is_empty() {
+                            let accepted = match self.editing.take() {
+                                Some(target_seq) => {
+                                    self.send_change(send_to.clone(), ChatBody::Edit { target_seq, text });
+                                    true
+                                }
+                                None => self.send_message(send_to.clone(), text),
+                            };
+                            if accepted {
+                                self.input.clear();
+                            }
                         }
                         input.request_focus();
                     }
+                    // egui only passes text pastes on, so an image is pasted with a button.
+                    let can_paste = self.editing.is_none();
+                    if ui.add_enabled(can_paste, egui::Button::new("Paste image")).on_hover_text("Attach the image on the clipboard").clicked() {
+                        self.paste_image(ctx);
+                    }
                 });
+
+                if !self.queued_sends.is_empty() {
+                    let text = if connected {
+                        format!("Slow down, {} message(s) waiting to send", self.queued_sends.len())
+                    } else {
+                        format!("{} message(s) will be sent once reconnected", self.queued_sends.len())
+                    };
+                    ui.colored_label(ui.visuals().warn_fg_color, text);
+                }
+
+                ui.label(egui::RichText::new("Drop a file here to send it.").weak().small());
+                for dropped in ctx.input(|i| i.raw.dropped_files.clone()) {
+                    match read_dropped_file(dropped) {
+                        Ok(file) if connected => self.send_attachment(send_to.clone(), file),
+                        Ok(file) => {
+                            warn!("Not connected, dropping attachment: {}", file.filename);
+                            self.notify(ToastLevel::Warning, attachment_offline_message(&file.filename));
+                        }
+                        Err(e) => {
+                            warn!("Failed to read dropped file: {:#}", e);
+                            self.notify(ToastLevel::Warning, format!("{:#}", e));
+                        }
+                    }
+                }
             });
 
-        egui::Window::new("Debug conversations")
+        egui::Window::new("Conversations")
             .collapsible(false)
             .resizable(false)
             .anchor(egui::Align2::RIGHT_CENTER, [0.0, 0.0])
             .show(ctx, |ui| {
-                for conversation_info in TEST_CONVERSATIONS.iter() {
-                    ui.radio_value(&mut self.send_to, conversation_info.kind, conversation_info.display_name);
+                ui.horizontal(|ui| {
+                    let loading = self.conversations_generation.is_some();
+                    if ui.add_enabled(!loading, egui::Button::new("Refresh")).clicked() {
+                        self.refresh_conversations();
+                    }
+                    if loading {
+                        ui.add(egui::Spinner::new());
+                    }
+                });
+                let mut selected = None;
+                for conversation_info in &self.conversations {
+                    let unread = self.unread_counts.get(&conversation_info.conversation_id).copied().unwrap_or(0);
+                    let text = match unread {
+                        0 => conversation_info.display_name.clone(),
+                        unread => format!("{} ({})", conversation_info.display_name, unread),
+                    };
+                    let checked = self.send_to.as_ref() == Some(&conversation_info.conversation_id);
+                    if ui.radio(checked, text).clicked() {
+                        selected = Some(conversation_info.conversation_id.clone());
+                    }
+                }
+                if let Some(conversation_id) = selected {
+                    self.show_conversation(conversation_id);
                 }
             });
+
+        let group = self.send_to.as_ref().and_then(|send_to| self.conversations
+            .iter()
+            .find(|conversation| &conversation.conversation_id == send_to && conversation.kind == ConversationKind::Group));
+        if let Some(group) = group {
+            egui::Window::new("Members")
+                .collapsible(false)
+                .resizable(false)
+                .anchor(egui::Align2::LEFT_CENTER, [0.0, 0.0])
+                .show(ctx, |ui| {
+                    let mut members = group.members.iter().collect::<Vec<_>>();
+                    members.sort_by(|a, b| self.is_online(b).cmp(&self.is_online(a)).then_with(|| a.username.cmp(&b.username)));
+                    let online = members.iter().filter(|member| self.is_online(member)).count();
+                    ui.label(egui::RichText::new(format!("{} members, {} online", members.len(), online)).weak());
+                    for member in members {
+                        ui.horizontal(|ui| {
+                            presence_dot(ui, self.is_online(member));
+                            let name = if member.user_id == self.user_id {
+                                format!("{} (you)", member.username)
+                            } else {
+                                member.username.clone()
+                            };
+                            ui.label(name);
+                        });
+                    }
+                });
+        }
+
+        self.report_position();
+
+        if self.show_metrics {
+            let metrics = self.real_network.borrow().metrics();
+            egui::Window::new("Connection")
+                .open(&mut self.show_metrics)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    egui::Grid::new("connection_metrics").num_columns(2).show(ui, |ui| {
+                        ui.label("State");
+                        ui.label(format!("{:?}", connection_state));
+                        ui.end_row();
+                        ui.label("Last ping");
+                        ui.label(metrics.last_rtt.map_or("-".to_string(), |rtt| format!("{:.1} ms", rtt.as_secs_f64() * 1000.0)));
+                        ui.end_row();
+                        ui.label("Messages sent");
+                        ui.label(metrics.messages_sent.to_string());
+                        ui.end_row();
+                        ui.label("Messages received");
+                        ui.label(metrics.messages_received.to_string());
+                        ui.end_row();
+                        ui.label("Reconnects");
+                        ui.label(metrics.reconnects.to_string());
+                        ui.end_row();
+                        ui.label("Connected for");
+                        ui.label(metrics.connected_for.map_or("-".to_string(), |up| format!("{}s", up.as_secs())));
+                        ui.end_row();
+                        ui.label("Requests in flight");
+                        ui.label(metrics.in_flight.to_string());
+                        ui.end_row();
+                    });
+                });
+            // The counters move without any message arriving here, e.g. a pong.
+            ctx.request_repaint_after(Duration::from_secs(1));
+        }
     }
 }
 
-#[derive(Debug)]
-struct UserInfo {
-    pub username: String,
-    pub user_id: UserId,
+impl Drop for LobbyPage {
+    fn drop(&mut self) {
+        let mut network = self.real_network.borrow_mut();
+        let pending = self.chat_history
+            .values()
+            .flatten()
+            .filter(|entry| entry.delivery == DeliveryState::Sending)
+            .filter_map(|entry| entry.send_generation);
+        for generation in pending {
+            if network.cancel(generation).is_ok() {
+                trace!("Cancelled pending send on leaving lobby: {}", generation);
+            }
+        }
+        if let Some(generation) = self.reconnect_generation {
+            if network.cancel(generation).is_ok() {
+                trace!("Cancelled reconnect on leaving lobby: {}", generation);
+            }
+        }
+        if let Some(generation) = self.direct_generation {
+            if network.cancel(generation).is_ok() {
+                trace!("Cancelled direct conversation lookup on leaving lobby: {}", generation);
+            }
+        }
+    }
 }
 
-static TEST_USERS: Lazy<Vec<UserInfo>> = Lazy::new(|| {
-    (0..2)
-        .map(|i| {
-            let username = format!("testuser{}", i);
-            let user_id = UserId(Uuid::new_v5(&Uuid::NAMESPACE_OID, username.as_bytes()));
-            UserInfo { username, user_id }
-        })
-        .collect()
-});
+const HISTORY_PAGE_SIZE: u32 = 50;
+const HISTORY_RETRY_COOLDOWN: Duration = Duration::from_secs(5);
+/// How long a message arriving ahead of a missing `seq` waits for it before showing anyway.
+const REORDER_WAIT: Duration = Duration::from_millis(1500);
+pub const DEFAULT_SCROLLBACK_LIMIT: usize = 3000;
+const ATTACHMENT_PREVIEW_HEIGHT: f32 = 120.0;
+const INPUT_ROWS: usize = 2;
+const TYPING_SEND_INTERVAL: Duration = Duration::from_secs(2);
+const TYPING_DISPLAY_DURATION: Duration = Duration::from_secs(3);
+const MAX_MENTION_SUGGESTIONS: usize = 5;
+/// Pasted images are scaled down to fit this on either side before upload.
+const MAX_PASTE_DIMENSION: u32 = 2048;
+/// Refused beyond this even after scaling, e.g. a noisy photo that compresses badly.
+const MAX_PASTE_BYTES: usize = 5 * 1024 * 1024;
+const MAX_ATTACHMENT_BYTES: u64 = 10 * 1024 * 1024;
+const PASTE_PREVIEW_HEIGHT: f32 = 64.0;
+const SEND_RATE_PER_SEC: f64 = 5.0;
+const SEND_BURST: f64 = 5.0;
+/// Messages queued beyond this are refused, so a long outage cannot pile up an unbounded backlog.
+const MAX_QUEUED_SENDS: usize = 100;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-enum ConversationKind {
-    Direct,
-    Group,
+/// `@username text` at the start of an input: who to write to and what to send them, which may be empty.
+fn parse_mention(input: &str) -> Option<(&str, &str)> {
+    let mention = input.trim_start().strip_prefix('@')?;
+    let (username, text) = mention.split_once(char::is_whitespace).unwrap_or((mention, ""));
+    (!username.is_empty()).then_some((username, text.trim()))
+}
+
+/// Byte ranges of `needle` in `haystack`, comparing chars by their lower case; none for an empty `needle`.
+fn find_matches(haystack: &str, needle: &str) -> Vec<Range<usize>> {
+    let fold = |c: char| c.to_lowercase().next().unwrap_or(c);
+    let needle = needle.chars().map(fold).collect::<Vec<_>>();
+    let chars = haystack.char_indices().collect::<Vec<_>>();
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while !needle.is_empty() && start + needle.len() <= chars.len() {
+        let window = &chars[start..start + needle.len()];
+        if window.iter().zip(&needle).all(|((_, c), n)| fold(*c) == *n) {
+            let end = chars.get(start + needle.len()).map_or(haystack.len(), |(at, _)| *at);
+            ranges.push(chars[start].0..end);
+            start += needle.len();
+        } else {
+            start += 1;
+        }
+    }
+    ranges
+}
+
+/// `text` with `ranges` on the selection color; the current hit is also underlined.
+fn highlighted(ui: &egui::Ui, text: &str, ranges: &[Range<usize>], pending: bool, current: bool) -> egui::text::LayoutJob {
+    let visuals = ui.visuals();
+    let plain = egui::TextFormat {
+        font_id: egui::TextStyle::Body.resolve(ui.style()),
+        color: if pending { visuals.weak_text_color() } else { visuals.text_color() },
+        italics: pending,
+        ..Default::default()
+    };
+    let mut hit = plain.clone();
+    hit.background = visuals.selection.bg_fill;
+    if current {
+        hit.underline = egui::Stroke::new(1.0, visuals.strong_text_color());
+    }
+
+    let mut job = egui::text::LayoutJob::default();
+    let mut at = 0;
+    for range in ranges {
+        job.append(&text[at..range.start], 0.0, plain.clone());
+        job.append(&text[range.clone()], 0.0, hit.clone());
+        at = range.end;
+    }
+    job.append(&text[at..], 0.0, plain);
+    job
+}
+
+fn presence_dot(ui: &mut egui::Ui, online: bool) {
+    let (dot, hover) = if online {
+        (egui::RichText::new("●").color(egui::Color32::GREEN), "Online")
+    } else {
+        (egui::RichText::new("○").weak(), "Offline")
+    };
+    ui.label(dot).on_hover_text(hover);
+}
+
+/// The dropped file within `MAX_ATTACHMENT_BYTES`; a file on disk is measured before it is read.
+fn read_dropped_file(dropped: egui::DroppedFile) -> anyhow::Result<OutgoingFile> {
+    let filename = dropped.path
+        .as_ref()
+        .and_then(|path| path.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or(dropped.name);
+    let too_large = |size: u64| {
+        anyhow::anyhow!("{} is {} KiB, the limit is {} KiB", filename, size.div_ceil(1024), MAX_ATTACHMENT_BYTES / 1024)
+    };
+    let bytes = match (&dropped.bytes, &dropped.path) {
+        (Some(bytes), _) => bytes.to_vec(),
+        (None, Some(path)) => {
+            let size = std::fs::metadata(path).with_context(|| format!("Could not read {}", filename))?.len();
+            if size > MAX_ATTACHMENT_BYTES {
+                return Err(too_large(size));
+            }
+            std::fs::read(path).with_context(|| format!("Could not read {}", filename))?
+        }
+        (None, None) => anyhow::bail!("Could not read {}", filename),
+    };
+    // Also catches bytes handed over in memory, and a file that grew since it was measured.
+    if bytes.len() as u64 > MAX_ATTACHMENT_BYTES {
+        return Err(too_large(bytes.len() as u64));
+    }
+    let mime = match dropped.mime.as_str() {
+        "" => guess_mime(&filename).to_string(),
+        mime => mime.to_string(),
+    };
+    Ok(OutgoingFile { filename, mime, bytes })
+}
+
+/// The image on the clipboard as a PNG within `MAX_PASTE_DIMENSION` and `MAX_PASTE_BYTES`.
+fn read_clipboard_image() -> anyhow::Result<OutgoingFile> {
+    let image = arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.get_image())
+        .map_err(|e| match e {
+            arboard::Error::ContentNotAvailable => anyhow::anyhow!("There is no image on the clipboard"),
+            e => anyhow::Error::new(e).context("Could not read the clipboard"),
+        })?;
+    let bytes = encode_pasted_image(image.width as u32, image.height as u32, image.bytes.into_owned())?;
+    let filename = format!("pasted-{}.png", Local::now().format("%Y%m%d-%H%M%S"));
+    Ok(OutgoingFile { filename, mime: "image/png".to_string(), bytes })
+}
+
+fn encode_pasted_image(width: u32, height: u32, rgba: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let image = image::RgbaImage::from_raw(width, height, rgba).context("The clipboard image is malformed")?;
+    let mut image = image::DynamicImage::ImageRgba8(image);
+    if width.max(height) > MAX_PASTE_DIMENSION {
+        image = image.resize(MAX_PASTE_DIMENSION, MAX_PASTE_DIMENSION, image::imageops::FilterType::Triangle);
+    }
+    let mut bytes = Vec::new();
+    image
+        .write_to(&mut std::io::Cursor::new(&mut bytes), image::ImageFormat::Png)
+        .context("Could not encode the pasted image")?;
+    if bytes.len() > MAX_PASTE_BYTES {
+        anyhow::bail!("The pasted image is {} KiB, the limit is {} KiB", bytes.len().div_ceil(1024), MAX_PASTE_BYTES / 1024);
+    }
+    Ok(bytes)
 }
 
-#[derive(Debug)]
-struct ConversationInfo {
-    pub kind: ConversationKind,
-    pub display_name: &'static str,
-    pub conversation_id: ConversationId,
+fn guess_mime(filename: &str) -> &'static str {
+    let extension = filename.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
 }
 
-static TEST_CONVERSATIONS: Lazy<Vec<ConversationInfo>> = Lazy::new(|| {
+#[cfg(feature = "manual-test")]
+fn test_conversations() -> Vec<ConversationInfo> {
+    use crate::domain::ConversationKind;
+
+    let members = (0..2)
+        .map(|i| {
+            let username = format!("testuser{}", i);
+            let user_id = UserId(Uuid::new_v5(&Uuid::NAMESPACE_OID, username.as_bytes()));
+            ConversationMember { user_id, username, online: i == 0 }
+        })
+        .collect::<Vec<_>>();
     vec![
         ConversationInfo {
-            kind: ConversationKind::Direct,
-            display_name: "Direct: 0 ↔ 1",
             conversation_id: ConversationId(Uuid::new_v5(&Uuid::NAMESPACE_OID, b"test_direct0")),
+            kind: ConversationKind::Direct,
+            display_name: "Direct: 0 ↔ 1".to_string(),
+            members: members.clone(),
         },
         ConversationInfo {
-            kind: ConversationKind::Group,
-            display_name: "Group: 0, 1, 2",
             conversation_id: ConversationId(Uuid::new_v5(&Uuid::NAMESPACE_OID, b"test_group0")),
+            kind: ConversationKind::Group,
+            display_name: "Group: 0, 1, 2".to_string(),
+            members,
         },
     ]
-});
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::Receiver;
+    use crate::util::wait_until;
+    use crate::protocol::network::{FakeNetworkInterface, FakeReply, FakeWsConnector, NetworkConfig, NetworkImpl, SessionError};
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// A lobby on a `FakeNetworkInterface`, which the test keeps a handle to.
+    fn fake_lobby() -> (LobbyPage, Rc<RefCell<FakeNetworkInterface>>, Receiver<AppMessage>) {
+        let fake = Rc::new(RefCell::new(FakeNetworkInterface::new()));
+        let (page, message_rx) = lobby(fake.clone());
+        (page, fake, message_rx)
+    }
+
+    /// Hands the lobby what its requests sent back; the fake completes them on the spot.
+    fn pump(lobby: &mut LobbyPage, message_rx: &Receiver<AppMessage>) {
+        while let Ok(message) = message_rx.try_recv() {
+            if let AppMessage::Lobby(_, message) = message {
+                lobby.update_one(message);
+            }
+        }
+    }
+
+    fn message(conversation_id: &ConversationId, seq: u64, text: &str) -> ChatMessage {
+        ChatMessage {
+            sender: UserId(Uuid::new_v5(&Uuid::NAMESPACE_OID, b"peer")),
+            sender_name: None,
+            conversation_id: conversation_id.clone(),
+            content: ChatBody::Text(text.to_string()),
+            sent_at: Utc::now(),
+            seq: Some(seq),
+            attachment: None,
+            client_id: None,
+        }
+    }
+
+    fn receive(lobby: &mut LobbyPage, message: ChatMessage) {
+        lobby.update_one(LobbyMessage::Stream(StreamMessage::Distribute(message)));
+    }
+
+    fn lobby(network: Rc<RefCell<dyn NetworkInterface>>) -> (LobbyPage, Receiver<AppMessage>) {
+        let (message_tx, message_rx) = crossbeam_channel::unbounded();
+        let config = LobbyConfig {
+            token_info: TokenInfo::for_test(),
+            scrollback_limit: 100,
+            close_to_background: false,
+            position: LobbyPosition::default(),
+        };
+        let page = LobbyPage::new(
+            message_tx,
+            Box::new(|message| AppMessage::Lobby(0, message)),
+            Arc::new(Box::new(|message| AppMessage::Lobby(0, message))),
+            network,
+            NetworkTimeouts::default(),
+            config,
+        );
+        (page, message_rx)
+    }
+
+    #[test]
+    fn session_closed_right_after_the_handshake_is_reconnected() {
+        let mut config = NetworkConfig::try_new("http://127.0.0.1:1/api/", "ws://127.0.0.1:1/", None).unwrap();
+        config.reconnect_attempts = 0;
+        let connector = FakeWsConnector::default();
+        let network = Rc::new(RefCell::new(NetworkImpl::with_ws_connector(config, Arc::new(connector.clone())).unwrap()));
+        let (connected_tx, connected_rx) = std::sync::mpsc::channel();
+        network.borrow_mut().connect_chat(
+            String::new(),
+            "access-token".to_string(),
+            Box::new(|_| {}),
+            5000,
+            Box::new(move |event| connected_tx.send(event.result.result.is_ok()).unwrap()),
+            Box::new(|_| {}),
+        ).unwrap();
+        assert!(connected_rx.recv_timeout(WAIT).unwrap());
+
+        // The server hangs up before the lobby for the session is built.
+        connector.latest().unwrap().drop_connection(DisconnectReason::ClosedByServer);
+        wait_until(|| network.borrow().session_state() == ConnectionState::Disconnected);
+        let (mut lobby, _messages) = lobby(network.clone());
+        lobby.check_connection();
+
+        assert_eq!(lobby.disconnect_reason, Some(DisconnectReason::ConnectionError));
+        assert!(lobby.reconnect_generation.is_some());
+        wait_until(|| connector.workers().len() == 2);
+    }
+
+    #[test]
+    fn messages_received_out_of_order_show_in_seq_order() {
+        let (mut lobby, _fake, _messages) = fake_lobby();
+        let conversation_id = ConversationId(Uuid::new_v4());
+
+        receive(&mut lobby, message(&conversation_id, 1, "one"));
+        receive(&mut lobby, message(&conversation_id, 3, "three"));
+        assert_eq!(lobby.texts(&conversation_id), ["one"]);
+        receive(&mut lobby, message(&conversation_id, 2, "two"));
+
+        assert_eq!(lobby.texts(&conversation_id), ["one", "two", "three"]);
+        assert!(lobby.held.is_empty());
+    }
+
+    #[test]
+    fn gap_not_closed_in_time_is_filled_from_history() {
+        let (mut lobby, fake, messages) = fake_lobby();
+        let conversation_id = ConversationId(Uuid::new_v4());
+        receive(&mut lobby, message(&conversation_id, 1, "one"));
+        receive(&mut lobby, message(&conversation_id, 4, "four"));
+        lobby.held.get_mut(&conversation_id).unwrap().since -= REORDER_WAIT;
+        // History comes newest first and overlaps what is already shown.
+        let page = vec![message(&conversation_id, 3, "three"), message(&conversation_id, 2, "two"), message(&conversation_id, 1, "one")];
+        fake.borrow_mut().history_replies.push_back(FakeReply::Event(HistoryEvent { conversation_id: conversation_id.clone(), result: Ok(page) }));
+
+        lobby.release_expired();
+        pump(&mut lobby, &messages);
+
+        assert_eq!(lobby.texts(&conversation_id), ["one", "two", "three", "four"]);
+        assert!(lobby.held.is_empty());
+    }
+
+    #[test]
+    fn own_message_distributed_before_its_ack_shows_once() {
+        let (mut lobby, _fake, messages) = fake_lobby();
+        let conversation_id = ConversationId(Uuid::new_v4());
+        lobby.send_entry(conversation_id.clone(), "hello".to_string(), None);
+        let client_id = lobby.chat_history[&conversation_id][0].client_id;
+        // The fake acknowledges with `seq` 0, which the server handed out for the distribute too.
+        let mut distributed = message(&conversation_id, 0, "hello");
+        distributed.sender = lobby.user_id.clone();
+        distributed.client_id = client_id;
+
+        receive(&mut lobby, distributed);
+        pump(&mut lobby, &messages);
+
+        assert_eq!(lobby.texts(&conversation_id), ["hello"]);
+        let entry = &lobby.chat_history[&conversation_id][0];
+        assert_eq!(entry.seq, Some(0));
+        assert!(entry.delivery == DeliveryState::Sent);
+    }
+
+    #[test]
+    fn failed_history_is_not_asked_again_until_the_cooldown_passes() {
+        let (mut lobby, fake, messages) = fake_lobby();
+        let conversation_id = ConversationId(Uuid::new_v4());
+        fake.borrow_mut().history_replies.push_back(FakeReply::Error(NetworkError::Timeout));
+        let page = vec![message(&conversation_id, 1, "one")];
+        fake.borrow_mut().history_replies.push_back(FakeReply::Event(HistoryEvent { conversation_id: conversation_id.clone(), result: Ok(page) }));
+
+        lobby.load_history(conversation_id.clone());
+        pump(&mut lobby, &messages);
+        lobby.load_history(conversation_id.clone());
+        assert_eq!(fake.borrow().history_replies.len(), 1);
+
+        *lobby.history_failed_at.get_mut(&conversation_id).unwrap() -= HISTORY_RETRY_COOLDOWN;
+        lobby.load_history(conversation_id.clone());
+        pump(&mut lobby, &messages);
+
+        assert_eq!(lobby.texts(&conversation_id), ["one"]);
+        assert!(!lobby.history_failed_at.contains_key(&conversation_id));
+    }
+
+    #[test]
+    fn dropped_file_over_the_limit_is_refused_before_reading() {
+        let path = std::env::temp_dir().join(format!("client_side-{}.bin", Uuid::new_v4()));
+        // Sparse, so the test does not write the megabytes it claims.
+        std::fs::File::create(&path).unwrap().set_len(MAX_ATTACHMENT_BYTES + 1).unwrap();
+        let dropped = egui::DroppedFile { path: Some(path.clone()), ..Default::default() };
+
+        let result = read_dropped_file(dropped);
+        let _ = std::fs::remove_file(&path);
+
+        assert!(result.err().unwrap().to_string().contains("the limit is"));
+    }
+
+    #[test]
+    fn own_image_attachment_is_kept_to_show_inline() {
+        let (mut lobby, _fake, _messages) = fake_lobby();
+        let conversation_id = ConversationId(Uuid::new_v4());
+        let image = OutgoingFile { filename: "cat.png".to_string(), mime: "image/png".to_string(), bytes: vec![1, 2, 3] };
+        let document = OutgoingFile { filename: "notes.txt".to_string(), mime: "text/plain".to_string(), bytes: vec![4, 5, 6] };
+
+        lobby.send_entry(conversation_id.clone(), String::new(), Some(image));
+        lobby.send_entry(conversation_id.clone(), String::new(), Some(document));
+
+        let image_id = lobby.chat_history[&conversation_id][0].client_id.unwrap();
+        assert_eq!(lobby.own_attachment_bytes.keys().collect::<Vec<_>>(), [&image_id]);
+    }
+
+    #[test]
+    fn rejected_edit_and_delete_are_undone() {
+        let (mut lobby, fake, messages) = fake_lobby();
+        let conversation_id = ConversationId(Uuid::new_v4());
+        let mut own = message(&conversation_id, 1, "hello");
+        own.sender = lobby.user_id.clone();
+        receive(&mut lobby, own);
+        for _ in 0..2 {
+            let rejected = MessageEvent { result: Err(MessageError::NotAMember), detail: None };
+            fake.borrow_mut().send_replies.push_back(FakeReply::Event(rejected));
+        }
+
+        lobby.send_change(conversation_id.clone(), ChatBody::Edit { target_seq: 1, text: "bye".to_string() });
+        assert_eq!(lobby.texts(&conversation_id), ["bye"]);
+        pump(&mut lobby, &messages);
+        lobby.send_change(conversation_id.clone(), ChatBody::Delete { target_seq: 1 });
+        pump(&mut lobby, &messages);
+
+        let entry = &lobby.chat_history[&conversation_id][0];
+        assert_eq!(entry.content, "hello");
+        assert!(!entry.edited && !entry.deleted);
+        assert!(lobby.pending_changes.is_empty());
+    }
+
+    #[test]
+    fn accepted_edit_stays() {
+        let (mut lobby, _fake, messages) = fake_lobby();
+        let conversation_id = ConversationId(Uuid::new_v4());
+        let mut own = message(&conversation_id, 1, "hello");
+        own.sender = lobby.user_id.clone();
+        receive(&mut lobby, own);
+
+        lobby.send_change(conversation_id.clone(), ChatBody::Edit { target_seq: 1, text: "bye".to_string() });
+        pump(&mut lobby, &messages);
+
+        assert_eq!(lobby.texts(&conversation_id), ["bye"]);
+        assert!(lobby.pending_changes.is_empty());
+    }
+
+    #[test]
+    fn older_messages_loaded_on_request_stay_until_scrolled_back_down() {
+        let (mut lobby, fake, messages) = fake_lobby();
+        lobby.scrollback_limit = 2;
+        let conversation_id = ConversationId(Uuid::new_v4());
+        for (seq, text) in [(1, "one"), (2, "two"), (3, "three")] {
+            receive(&mut lobby, message(&conversation_id, seq, text));
+        }
+        assert_eq!(lobby.texts(&conversation_id), ["two", "three"]);
+        let page = vec![message(&conversation_id, 1, "one")];
+        fake.borrow_mut().history_replies.push_back(FakeReply::Event(HistoryEvent { conversation_id: conversation_id.clone(), result: Ok(page) }));
+
+        lobby.load_history(conversation_id.clone());
+        pump(&mut lobby, &messages);
+        receive(&mut lobby, message(&conversation_id, 4, "four"));
+        assert_eq!(lobby.texts(&conversation_id), ["one", "two", "three", "four"]);
+
+        lobby.release_kept_history(&conversation_id);
+        assert_eq!(lobby.texts(&conversation_id), ["three", "four"]);
+    }
+
+    #[test]
+    fn leaving_after_the_app_stopped_listening_does_not_panic() {
+        let (mut lobby, _fake, message_rx) = fake_lobby();
+        drop(message_rx);
+
+        lobby.update_one(LobbyMessage::LoggedOut);
+        lobby.update_one(LobbyMessage::Stream(StreamMessage::SessionError(SessionError::RefreshFailed)));
+    }
+
+    #[test]
+    fn sends_queued_while_disconnected_go_out_in_order_on_reconnect() {
+        let (mut lobby, fake, message_rx) = fake_lobby();
+        let conversation_id = ConversationId(Uuid::new_v4());
+        let ctx = egui::Context::default();
+        let frame = |lobby: &mut LobbyPage| {
+            let _ = ctx.run(egui::RawInput::default(), |ctx| lobby.view(ctx));
+        };
+
+        assert!(lobby.send_message(conversation_id.clone(), "first".to_string()));
+        assert!(lobby.send_message(conversation_id.clone(), "second".to_string()));
+        frame(&mut lobby);
+        assert!(fake.borrow().sent.is_empty());
+        assert_eq!(lobby.queued_count(), 2);
+
+        fake.borrow_mut()
+            .connect_chat(String::new(), String::new(), Box::new(|_| {}), 5000, Box::new(|_| {}), Box::new(|_| {}))
+            .unwrap();
+        frame(&mut lobby);
+        pump(&mut lobby, &message_rx);
+
+        assert!(matches!(&fake.borrow().sent[..], [
+            (first_id, ChatBody::Text(first)),
+            (second_id, ChatBody::Text(second)),
+        ] if *first_id == conversation_id && first == "first" && *second_id == conversation_id && second == "second"));
+        assert_eq!(lobby.queued_count(), 0);
+        assert!(lobby.chat_history[&conversation_id].iter().all(|entry| entry.delivery == DeliveryState::Sent));
+    }
+}
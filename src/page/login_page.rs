@@ -38,29 +38,30 @@
 //! }
 //! ```
 
-use crate::page::{FakeNetwork, Network, NetworkEvent, Route, Update, View};
+use crate::domain::validate_username;
+use crate::page::{chat_connect_message, login_failed_message, password_field, rejection_message, theme_toggle, user_message, CaptchaInput, CaptchaMessage, CaptchaWidget, Route, Update, View};
 use crate::shell::AppMessage;
-use base64::Engine;
 use crossbeam_channel::Sender;
 use eframe::egui;
-use eframe::egui::{TextBuffer, TextureHandle, TextureOptions};
 use std::cell::RefCell;
-use std::rc::{Rc, Weak};
+use std::rc::Rc;
 use std::sync::Arc;
 use tracing::{event, trace, warn};
 use uuid::Uuid;
-use crate::protocol::network::{CaptchaData, CaptchaError, CaptchaEvent, LoginError, LoginEvent, NetworkError, NetworkInterface, TokenInfo, WithGeneration};
+use crate::protocol::network::{CaptchaData, CaptchaError, ChatConnError, LoginError, LoginEvent, NetworkError, NetworkInterface, NetworkTimeouts, TokenInfo, WithGeneration};
 
 pub enum LoginMessage {
     PlaceHolder,
     UsernameChanged(String),
     PasswordChanged(String),
     CaptchaChanged(String),
-    CaptchaFetched(u64, Uuid, String),
-    CaptchaFailed(u64),
-    LoginSuccess(u64, String, String),
-    LoginFailed(u64),
-    ChatFailed,
+    Captcha(CaptchaMessage),
+    LoginSuccess(u64, TokenInfo),
+    LoginFailed(u64, String),
+    /// The server rejected the captcha answer, saying so in the text; the rest of the form is still good.
+    CaptchaRejected(u64, String),
+    /// With what the chat server said about it, if anything.
+    ChatFailed(ChatConnError, Option<String>),
     NavigateTo(String),
 }
 
@@ -68,23 +69,19 @@ pub enum LoginState {
     RequestSent,
     Success(String, String),
     Failure(String),
-    ChatFailed,
+    ChatFailed(ChatConnError, Option<String>),
 }
 
 pub struct LoginPage {
     message_tx: Sender<AppMessage>,
     map_function: Box<dyn Fn(LoginMessage) -> AppMessage>,
     new_map_function: Arc<Box<dyn Fn(LoginMessage) -> AppMessage + Send + Sync>>,
-    network: Weak<RefCell<dyn Network>>,
     real_network: Rc<RefCell<dyn NetworkInterface>>,
+    timeouts: NetworkTimeouts,
     username: String,
     password: String,
-
-    captcha: String,
-    captcha_generation: Option<u64>,
-    captcha_id: Option<Uuid>,
-    captcha_base64: String,
-    captcha_texture: Option<TextureHandle>,
+    password_revealed: bool,
+    captcha: CaptchaWidget,
 
     login_generation: Option<u64>,
     login_state: Option<LoginState>,
@@ -95,30 +92,106 @@ impl LoginPage {
         message_tx: Sender<AppMessage>,
         map_function: Box<dyn Fn(LoginMessage) -> AppMessage>,
         new_map_function: Arc<Box<dyn Fn(LoginMessage) -> AppMessage + Send + Sync>>,
-        network: Weak<RefCell<dyn Network>>,
         real_network: Rc<RefCell<dyn NetworkInterface>>,
+        timeouts: NetworkTimeouts,
     ) -> Self {
-        let mut captcha_generation = None;
-        // fetch_captcha(&mut captcha_generation, network.clone());
-        fetch_real_captcha(message_tx.clone(), new_map_function.clone(), &mut captcha_generation, real_network.clone());
+        let captcha_map = new_map_function.clone();
+        let captcha = CaptchaWidget::new(
+            message_tx.clone(),
+            Arc::new(Box::new(move |m| captcha_map(LoginMessage::Captcha(m)))),
+            real_network.clone(),
+            timeouts.captcha_ms,
+            "captcha",
+        );
 
         Self {
             message_tx: message_tx.clone(),
             map_function,
             new_map_function,
-            network,
             real_network,
+            timeouts,
             username: "".to_string(),
             password: "".to_string(),
-            captcha: "".to_string(),
-            captcha_generation,
-            captcha_id: None,
-            captcha_base64: "".to_string(),
-            captcha_texture: None,
+            password_revealed: false,
+            captcha,
             login_generation: None,
             login_state: None,
         }
     }
+
+    /// Takes over a username typed elsewhere; an empty one keeps what is already here.
+    pub fn prefill_username(&mut self, username: String) {
+        if !username.is_empty() {
+            self.username = username;
+        }
+    }
+}
+
+impl LoginPage {
+    /// Gives up on a stuck login so the form can be submitted again.
+    fn cancel_login(&mut self) {
+        if let Some(generation) = self.login_generation.take() {
+            if let Err(e) = self.real_network.borrow_mut().cancel(generation) {
+                trace!("Login already finished: {:#}", e);
+            }
+        }
+        self.login_state = None;
+        // The request may have reached the server and spent the captcha anyway.
+        self.captcha.renew();
+    }
+}
+
+impl LoginPage {
+    /// Whether no login is underway and a captcha is up to be answered.
+    fn can_submit(&self) -> bool {
+        self.captcha.id().is_some() && matches!(
+            self.login_state,
+            None | Some(LoginState::Failure(_)) | Some(LoginState::ChatFailed(..)),
+        )
+    }
+
+    fn submit(&mut self) {
+        let Some(captcha_id) = self.captcha.id().filter(|_| self.can_submit()) else {
+            return;
+        };
+        self.login_state = Some(LoginState::RequestSent);
+        self.login_generation = self.login(captcha_id).ok();
+    }
+
+    /// Sends the form with the answer to the captcha shown as `captcha_id`.
+    fn login(&self, captcha_id: Uuid) -> anyhow::Result<u64> {
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map = move |event: WithGeneration<LoginEvent>| {
+            let generation = event.generation;
+            // The server's own explanation beats the generic text of the error it came with.
+            let LoginEvent { result, detail } = event.result;
+            let message = match result {
+                Ok(token) => LoginMessage::LoginSuccess(generation, token),
+                Err(error @ LoginError::WrongCaptcha) => LoginMessage::CaptchaRejected(generation, rejection_message(&error, detail)),
+                Err(error) => LoginMessage::LoginFailed(generation, rejection_message(&error, detail)),
+            };
+            let _ = message_tx.send(map_function(message));
+        };
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map_err = move |error: WithGeneration<NetworkError>| {
+            let generation = error.generation;
+            let message = LoginMessage::LoginFailed(generation, user_message(&error.result));
+            let _ = message_tx.send(map_function(message));
+        };
+
+        self.real_network.borrow_mut().login(
+            self.username.clone(),
+            self.password.clone(),
+            captcha_id,
+            self.captcha.answer().to_string(),
+            self.timeouts.login_ms,
+            Box::new(map),
+            Box::new(map_err),
+        )
+    }
 }
 
 impl Update<LoginMessage> for LoginPage {
@@ -126,37 +199,31 @@ impl Update<LoginMessage> for LoginPage {
         match message {
             LoginMessage::UsernameChanged(username) => self.username = username,
             LoginMessage::PasswordChanged(password) => self.password = password,
-            LoginMessage::CaptchaFetched(generation, id, base64_string) => {
-                if self.captcha_generation == Some(generation) {
-                    self.captcha_id = Some(id);
-                    self.captcha_base64 = base64_string;
-                } else {
-                    warn!("Drop one fetched message due to generation mismatch");
+            LoginMessage::Captcha(message) => self.captcha.update_one(message),
+            LoginMessage::LoginSuccess(generation, token_info) => {
+                if self.login_generation == Some(generation) {
+                    let address = token_info.chat_address.clone().unwrap_or_default();
+                    self.login_state = Some(LoginState::Success(address, token_info.access_token.clone()));
+                    let _ = self.message_tx.send(AppMessage::ReqNavigate(Route::LobbyPage(token_info)));
                 }
             }
-            LoginMessage::CaptchaFailed(generation) => {
-                if self.captcha_generation == Some(generation) {
-                    self.captcha_generation = None;
-                    self.captcha_texture = None;
+            LoginMessage::LoginFailed(generation, reason) => {
+                if self.login_generation == Some(generation) {
+                    self.login_state = Some(LoginState::Failure(reason));
                 } else {
                     warn!("Drop one failed message due to generation mismatch");
                 }
             }
-            LoginMessage::LoginSuccess(generation, address, jwt) => {
-                if self.login_generation == Some(generation) {
-                    self.login_state = Some(LoginState::Success(address.clone(), jwt.clone()));
-                    self.message_tx.send(AppMessage::ReqNavigate(Route::LobbyPage(address, jwt))).unwrap();
-                }
-            }
-            LoginMessage::LoginFailed(generation) => {
+            LoginMessage::CaptchaRejected(generation, reason) => {
                 if self.login_generation == Some(generation) {
-                    self.login_state = Some(LoginState::Failure("Login failed".to_string()));
+                    self.login_state = Some(LoginState::Failure(reason));
+                    self.captcha.renew();
                 } else {
                     warn!("Drop one failed message due to generation mismatch");
                 }
             }
-            LoginMessage::ChatFailed => {
-                self.login_state = Some(LoginState::ChatFailed);
+            LoginMessage::ChatFailed(error, detail) => {
+                self.login_state = Some(LoginState::ChatFailed(error, detail));
             }
             _ => {}
         }
@@ -179,9 +246,14 @@ impl View for LoginPage {
                         )))
                         .unwrap_or_default();
                 }
+                // Only a hint: accounts made under older rules may not pass it, and the server decides anyway.
+                if let (Err(error), false) = (validate_username(&self.username), self.username.is_empty()) {
+                    ui.label(error.to_string());
+                }
 
                 ui.label("Password:");
-                if ui.text_edit_singleline(&mut self.password).changed() {
+                let password_field = password_field(ui, &mut self.password, &mut self.password_revealed);
+                if password_field.changed() {
                     let map_function = self.map_function.as_ref();
                     self.message_tx
                         .send(map_function(LoginMessage::PasswordChanged(
@@ -190,8 +262,15 @@ impl View for LoginPage {
                         .unwrap_or_default();
                 }
 
-                ui.label("Captcha:");
-                if ui.text_edit_singleline(&mut self.captcha).changed() {
+                // Tab skips the eye toggle, captcha image and row buttons, so the fields lead straight to Submit.
+                let password_tabbed = tabbed_out(ui, &password_field);
+
+                let captcha = self.captcha.show(ui);
+                if password_tabbed {
+                    captcha.field.request_focus();
+                }
+                let captcha_tabbed = tabbed_out(ui, &captcha.field);
+                if captcha.input == Some(CaptchaInput::AnswerChanged) {
                     let map_function = self.map_function.as_ref();
                     self.message_tx
                         .send(map_function(LoginMessage::CaptchaChanged(
@@ -199,35 +278,12 @@ impl View for LoginPage {
                         )))
                         .unwrap_or_default();
                 }
-                if !self.captcha_base64.is_empty() {
-                    let base64_string = self.captcha_base64.take();
-                    self.captcha_texture = load_base64_texture(ctx, &*base64_string, "captcha");
-                }
-
-                if let Some(texture) = self.captcha_texture.as_ref() {
-                    let image_button = egui::ImageButton::new(texture);
-                    if ui.add(image_button).clicked() {
-                        self.captcha_texture = None;
-                        // fetch_captcha(&mut self.captcha_generation, self.network.clone());
-                        fetch_real_captcha(self.message_tx.clone(), self.new_map_function.clone(), &mut self.captcha_generation, self.real_network.clone());
-                    }
-                } else if let Some(_) = self.captcha_generation {
-                    ui.horizontal(|ui| {
-                        ui.add(egui::Spinner::new());
-                        ui.label("Loading captcha...");
-                    });
-                } else {
-                    if ui.button("Reload captcha").clicked() {
-                        // fetch_captcha(&mut self.captcha_generation, self.network.clone());
-                        fetch_real_captcha(self.message_tx.clone(), self.new_map_function.clone(), &mut self.captcha_generation, self.real_network.clone());
-                    }
-                }
 
                 ui.separator();
 
                 ui.horizontal(|ui| {
                     if ui.button("Sign up").clicked() {
-                        self.message_tx.send(AppMessage::ReqNavigate(Route::SignupPage)).unwrap();
+                        let _ = self.message_tx.send(AppMessage::ReqNavigate(Route::SignupPage(Some(self.username.clone()))));
                         // let map_function = self.map_function.as_ref();
                         // self.message_tx
                         //     .send(map_function(LoginMessage::NavigateTo(
@@ -235,154 +291,114 @@ impl View for LoginPage {
                         //     )))
                         //     .unwrap_or_default();
                     }
+                    theme_toggle(ui, &self.message_tx);
+
+                    let enabled = self.can_submit();
+                    let submit = ui.add_enabled(enabled, egui::Button::new("Submit"));
+                    if captcha_tabbed && enabled {
+                        submit.request_focus();
+                    }
+                    // Enter in the captcha field goes through the same guard as clicking Submit.
+                    if submit.clicked() || captcha.input == Some(CaptchaInput::Submitted) {
+                        self.submit();
+                    }
 
-                    let enabled = matches!(
-                        self.login_state,
-                        None | Some(LoginState::Failure(_)) | Some(LoginState::ChatFailed),
-                    );
-                    if ui.add_enabled(enabled, egui::Button::new("Submit")).clicked() {
-                        self.login_state = Some(LoginState::RequestSent);
-                        login(self.message_tx.clone(), self.new_map_function.clone(),
-                              self.username.clone(), self.password.clone(), self.captcha_id.unwrap().clone(), self.captcha.clone(),
-                              &mut self.login_generation, self.real_network.clone());
-
-                        // let map_function = |e| match e {
-                        //     NetworkEvent::LoginSucceeded(generation, address, jwt) => {
-                        //         AppMessage::Login(LoginMessage::LoginSuccess(
-                        //             generation, address, jwt,
-                        //         ))
-                        //     }
-                        //     NetworkEvent::LoginFailed(generation) => {
-                        //         AppMessage::Login(LoginMessage::LoginFailed(generation))
-                        //     }
-                        //     _ => AppMessage::PlaceHolder,
-                        // };
-                        // self.login_generation = self
-                        //     .network
-                        //     .upgrade()
-                        //     .unwrap()
-                        //     .borrow_mut()
-                        //     .login(self.username.clone(), self.password.clone(), self.captcha.clone(), 1000, Box::new(map_function))
-                        //     .ok();
+                    if self.captcha.is_loading() && self.login_state.is_none() {
+                        ui.label("Please wait for the captcha.");
                     }
 
+                    let mut cancel = false;
                     if let Some(ref state) = self.login_state {
                         ui.horizontal(|ui| match state {
                             LoginState::RequestSent => {
                                 ui.add(egui::Spinner::new());
                                 ui.label("Waiting for authentication...");
+                                cancel = ui.small_button("Cancel").clicked();
                             }
                             LoginState::Success(_, _) => {
                                 ui.add(egui::Spinner::new());
                                 ui.label("Establishing connection...");
                             }
                             LoginState::Failure(reason) => {
-                                ui.label(format!("Login failed: {}", reason));
+                                ui.label(login_failed_message(reason));
                             }
-                            LoginState::ChatFailed => {
-                                ui.label("Failed to connect to chat server. Please retry.");
+                            LoginState::ChatFailed(error, detail) => {
+                                ui.label(chat_connect_message(error, detail.clone()));
                             }
                         });
                     }
+                    if cancel {
+                        self.cancel_login();
+                    }
                 });
             });
     }
 }
 
-fn fetch_captcha(captcha_generation: &mut Option<u64>, network: Weak<RefCell<dyn Network>>) {
-    let map_function = |e: NetworkEvent| match e {
-        NetworkEvent::CaptchaFetched(generation, captcha) => {
-            AppMessage::Login(LoginMessage::CaptchaFetched(generation, Uuid::nil(), captcha))
-        }
-        NetworkEvent::CaptchaFailed(generation) => {
-            AppMessage::Login(LoginMessage::CaptchaFailed(generation))
+impl Drop for LoginPage {
+    fn drop(&mut self) {
+        // A finished login is already gone, so a failed cancel is expected here; `captcha` cancels its own fetch.
+        if let Some(generation) = self.login_generation {
+            if self.real_network.borrow_mut().cancel(generation).is_ok() {
+                trace!("Cancelled pending request on leaving login: {}", generation);
+            }
         }
-        _ => AppMessage::Login(LoginMessage::PlaceHolder),
-    };
-    *captcha_generation = network
-        .upgrade()
-        .unwrap()
-        .borrow_mut()
-        .fetch_captcha(1000, Box::new(map_function))
-        .ok();
+    }
 }
 
-fn fetch_real_captcha(
-    message_tx: Sender<AppMessage>,
-    map_function: Arc<Box<dyn Fn(LoginMessage) -> AppMessage + Send + Sync>>,
-    captcha_generation: &mut Option<u64>,
-    network: Rc<RefCell<dyn NetworkInterface>>,
-) {
-    let message_tx_clone = message_tx.clone();
-    let map_function_clone = map_function.clone();
-    let map = move |event: WithGeneration<CaptchaEvent>| {
-        let generation = event.generation;
-        let message = match event.result.result {
-            Ok(data) => LoginMessage::CaptchaFetched(generation, data.id, data.image_base64),
-            Err(_) => LoginMessage::CaptchaFailed(generation),
-        };
-        let _ = message_tx_clone.send(map_function_clone(message));
-    };
-
-    let map_err = move |error: WithGeneration<NetworkError>| {
-        let generation = error.generation;
-        let message = LoginMessage::CaptchaFailed(generation);
-        let _ = message_tx.send(map_function(message));
-    };
-
-    *captcha_generation = network.borrow_mut().fetch_captcha(
-        1000,
-        Box::new(map),
-        Box::new(map_err),
-    ).ok();
+/// Whether `response` gave up focus this frame because of a plain Tab, as opposed to Shift+Tab or a click.
+fn tabbed_out(ui: &egui::Ui, response: &egui::Response) -> bool {
+    response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Tab) && !i.modifiers.shift)
 }
 
-fn load_base64_texture(ctx: &egui::Context, encoded: &str, name: &str) -> Option<TextureHandle> {
-    let decoded = base64::engine::general_purpose::STANDARD
-        .decode(encoded)
-        .ok()?;
-    let image_data = image::load_from_memory(&decoded).ok()?;
-    let size = [image_data.width() as _, image_data.height() as _];
-    let rgba = image_data.to_rgba8();
-    let pixels = rgba.as_flat_samples();
-    let color_image = egui::ColorImage::from_rgba_unmultiplied(size, pixels.as_slice());
-    Some(ctx.load_texture(name, color_image, TextureOptions::default()))
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::network::FakeNetworkInterface;
+    use crossbeam_channel::Receiver;
 
-fn login(
-    message_tx: Sender<AppMessage>,
-    map_function: Arc<Box<dyn Fn(LoginMessage) -> AppMessage + Send + Sync>>,
-    username: String,
-    password: String,
-    captcha_id: Uuid,
-    captcha_answer: String,
-    login_generation: &mut Option<u64>,
-    network: Rc<RefCell<dyn NetworkInterface>>,
-) {
-    let message_tx_clone = message_tx.clone();
-    let map_function_clone = map_function.clone();
-    let map = move |event: WithGeneration<LoginEvent>| {
-        let generation = event.generation;
-        let message = match event.result.result {
-            Ok(token) => LoginMessage::LoginSuccess(generation, "".to_string(), token.access_token),
-            Err(_) => LoginMessage::LoginFailed(generation),
-        };
-        let _ = message_tx_clone.send(map_function_clone(message));
-    };
-
-    let map_err = move |error: WithGeneration<NetworkError>| {
-        let generation = error.generation;
-        let message = LoginMessage::LoginFailed(generation);
-        let _ = message_tx.send(map_function(message));
-    };
-
-    *login_generation = network.borrow_mut().login(
-        username,
-        password,
-        captcha_id,
-        captcha_answer,
-        1000,
-        Box::new(map),
-        Box::new(map_err),
-    ).ok()
+    /// A login page on a `FakeNetworkInterface`; its captcha has been fetched but not handed to it yet.
+    fn fake_login() -> (LoginPage, Receiver<AppMessage>) {
+        let (message_tx, message_rx) = crossbeam_channel::unbounded();
+        let page = LoginPage::new(
+            message_tx,
+            Box::new(|message| AppMessage::Login(0, message)),
+            Arc::new(Box::new(|message| AppMessage::Login(0, message))),
+            Rc::new(RefCell::new(FakeNetworkInterface::new())),
+            NetworkTimeouts::default(),
+        );
+        (page, message_rx)
+    }
+
+    fn pump(page: &mut LoginPage, message_rx: &Receiver<AppMessage>) {
+        while let Ok(message) = message_rx.try_recv() {
+            if let AppMessage::Login(_, message) = message {
+                page.update_one(message);
+            }
+        }
+    }
+
+    #[test]
+    fn submit_before_the_captcha_arrives_is_ignored() {
+        let (mut page, message_rx) = fake_login();
+
+        page.submit();
+        assert!(page.login_state.is_none());
+        assert!(page.login_generation.is_none());
+
+        pump(&mut page, &message_rx);
+        page.submit();
+        assert!(page.login_generation.is_some());
+    }
+
+    #[test]
+    fn login_after_the_app_stopped_listening_does_not_panic() {
+        let (mut page, message_rx) = fake_login();
+        drop(message_rx);
+        page.login_generation = Some(7);
+
+        page.update_one(LoginMessage::LoginSuccess(7, TokenInfo::for_test()));
+
+        assert!(matches!(page.login_state, Some(LoginState::Success(..))));
+    }
 }
@@ -6,6 +6,7 @@ mod fatal_page;
 mod lobby_page;
 mod login_page;
 mod signup_page;
+mod splash_page;
 
 pub use update::*;
 pub use view::*;
@@ -15,9 +16,18 @@ pub use fatal_page::*;
 pub use lobby_page::*;
 pub use login_page::*;
 pub use signup_page::*;
+pub use splash_page::*;
 
-mod network;
 mod route;
+mod captcha_widget;
+mod password_field;
+mod texture;
+mod theme_toggle;
+mod user_message;
 
-pub use network::*;
-pub use route::*;
\ No newline at end of file
+pub use route::*;
+pub use captcha_widget::*;
+pub use password_field::*;
+pub use texture::*;
+pub use theme_toggle::*;
+pub use user_message::*;
\ No newline at end of file
@@ -0,0 +1,14 @@
+use eframe::egui;
+
+/// A masked single-line field with an eye button that shows the text while `revealed` is set.
+///
+/// Each field keeps its own `revealed`, so showing one password does not show another.
+pub fn password_field(ui: &mut egui::Ui, password: &mut String, revealed: &mut bool) -> egui::Response {
+    ui.horizontal(|ui| {
+        let response = ui.add(egui::TextEdit::singleline(password).password(!*revealed));
+        let hint = if *revealed { "Hide password" } else { "Show password" };
+        ui.toggle_value(revealed, "👁").on_hover_text(hint);
+        response
+    })
+    .inner
+}
@@ -1,10 +1,15 @@
+use crate::protocol::network::{ChatConnError, TokenInfo};
+
 #[derive(Debug)]
 pub enum Route {
-    FatalPage,
-    LobbyPage(String, String),
+    /// Gives up on the current page with the reason shown; `Restart` is offered when the network can be rebuilt.
+    FatalPage(String),
+    LobbyPage(TokenInfo),
     ChatConnSuccess,
-    ChatConnFailure,
-    LoginPage,
+    /// With what the chat server said about it, if anything.
+    ChatConnFailure(ChatConnError, Option<String>),
+    /// With the username typed on the page left behind, if any; passwords never travel between pages.
+    LoginPage(Option<String>),
     ShutdownPage,
-    SignupPage,
+    SignupPage(Option<String>),
 }
\ No newline at end of file
@@ -1,15 +1,23 @@
 use std::time::Instant;
+use crossbeam_channel::Sender;
 use eframe::egui;
 use eframe::egui::Context;
 use crate::page::View;
+use crate::shell::AppMessage;
 
 pub struct ShutdownPage {
+    message_tx: Sender<AppMessage>,
+    /// Until then the network is still running and exiting can be cancelled.
+    cancel_until: Instant,
     deadline: Instant
 }
 
 impl ShutdownPage {
-    pub fn new(deadline: Instant) -> ShutdownPage {
-        ShutdownPage { deadline }
+    pub fn new(message_tx: Sender<AppMessage>, cancel_until: Instant, deadline: Instant) -> ShutdownPage {
+        ShutdownPage { message_tx, cancel_until, deadline }
+    }
+    pub fn get_cancel_until(&self) -> Instant {
+        self.cancel_until
     }
     pub fn get_deadline(&self) -> Instant {
         self.deadline
@@ -24,10 +32,20 @@ impl View for ShutdownPage {
             .resizable(false)
             .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
             .show(ctx, |ui| {
-                ui.label(format!(
-                    "Cleaning up... The application will close in {} seconds.",
-                    (self.deadline - now).as_secs_f32().ceil()
-                ));
+                if now < self.cancel_until {
+                    ui.label(format!(
+                        "The application will start closing in {} seconds.",
+                        (self.cancel_until - now).as_secs_f32().ceil()
+                    ));
+                    if ui.button("Cancel").clicked() {
+                        let _ = self.message_tx.send(AppMessage::CancelExit);
+                    }
+                } else {
+                    ui.label(format!(
+                        "Cleaning up... The application will close in {} seconds.",
+                        self.deadline.saturating_duration_since(now).as_secs_f32().ceil()
+                    ));
+                }
             });
     }
-}
\ No newline at end of file
+}
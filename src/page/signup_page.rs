@@ -1,31 +1,143 @@
 use std::cell::RefCell;
-use std::rc::Weak;
+use std::rc::Rc;
+use std::sync::Arc;
 use crossbeam_channel::Sender;
 use eframe::egui;
 use eframe::egui::Context;
-use tracing::trace;
-use crate::page::{Network, Route, View};
+use tracing::{trace, warn};
+use uuid::Uuid;
+use crate::domain::{password_strength, validate_username, Strength};
+use crate::page::{password_field, rejection_message, signup_failed_message, unsent_message, user_message, CaptchaInput, CaptchaMessage, CaptchaWidget, Route, Update, View};
+use crate::protocol::network::{NetworkError, NetworkInterface, NetworkTimeouts, SignupEvent, WithGeneration};
 use crate::shell::AppMessage;
 
 #[derive(Debug)]
 pub enum SignupMessage {
     Placeholder,
+    Captcha(CaptchaMessage),
+    SignupSuccess(u64),
+    SignupFailed(u64, String),
+}
+
+pub enum SignupState {
+    RequestSent,
+    Success,
+    Failure(String),
 }
 
 pub struct SignupPage {
     message_tx: Sender<AppMessage>,
     map_function: Box<dyn Fn(SignupMessage) -> AppMessage>,
+    new_map_function: Arc<Box<dyn Fn(SignupMessage) -> AppMessage + Send + Sync>>,
+    real_network: Rc<RefCell<dyn NetworkInterface>>,
+    timeouts: NetworkTimeouts,
+    username: String,
+    password: String,
+    password_revealed: bool,
+    confirm_password: String,
+    confirm_revealed: bool,
+    captcha: CaptchaWidget,
+
+    signup_generation: Option<u64>,
+    signup_state: Option<SignupState>,
 }
 
 impl SignupPage {
     pub fn new(
         message_tx: Sender<AppMessage>,
         map_function: Box<dyn Fn(SignupMessage) -> AppMessage>,
-        _network: Weak<RefCell<dyn Network>>,
+        new_map_function: Arc<Box<dyn Fn(SignupMessage) -> AppMessage + Send + Sync>>,
+        real_network: Rc<RefCell<dyn NetworkInterface>>,
+        timeouts: NetworkTimeouts,
     ) -> Self {
+        let captcha_map = new_map_function.clone();
+        let captcha = CaptchaWidget::new(
+            message_tx.clone(),
+            Arc::new(Box::new(move |m| captcha_map(SignupMessage::Captcha(m)))),
+            real_network.clone(),
+            timeouts.captcha_ms,
+            "signup_captcha",
+        );
+
         Self {
             message_tx,
             map_function,
+            new_map_function,
+            real_network,
+            timeouts,
+            username: String::new(),
+            password: String::new(),
+            password_revealed: false,
+            confirm_password: String::new(),
+            confirm_revealed: false,
+            captcha,
+            signup_generation: None,
+            signup_state: None,
+        }
+    }
+
+    /// Takes over a username typed elsewhere; an empty one keeps what is already here.
+    pub fn prefill_username(&mut self, username: String) {
+        if !username.is_empty() {
+            self.username = username;
+        }
+    }
+
+    /// Sends the form with the answer to the captcha shown as `captcha_id`.
+    fn signup(&self, captcha_id: Uuid) -> anyhow::Result<u64> {
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map = move |event: WithGeneration<SignupEvent>| {
+            let generation = event.generation;
+            // The server has the last word, e.g. `SignupError::WeakPassword` for a password passing the local check,
+            // and its own explanation beats the generic text of the error.
+            let SignupEvent { result, detail } = event.result;
+            let message = match result {
+                Ok(()) => SignupMessage::SignupSuccess(generation),
+                Err(error) => SignupMessage::SignupFailed(generation, rejection_message(&error, detail)),
+            };
+            let _ = message_tx.send(map_function(message));
+        };
+
+        let message_tx = self.message_tx.clone();
+        let map_function = self.new_map_function.clone();
+        let map_err = move |error: WithGeneration<NetworkError>| {
+            let generation = error.generation;
+            let message = SignupMessage::SignupFailed(generation, user_message(&error.result));
+            let _ = message_tx.send(map_function(message));
+        };
+
+        self.real_network.borrow_mut().signup(
+            self.username.clone(),
+            self.password.clone(),
+            captcha_id,
+            self.captcha.answer().to_string(),
+            self.timeouts.signup_ms,
+            Box::new(map),
+            Box::new(map_err),
+        )
+    }
+}
+
+impl Update<SignupMessage> for SignupPage {
+    fn update_one(&mut self, message: SignupMessage) {
+        match message {
+            SignupMessage::Captcha(message) => self.captcha.update_one(message),
+            SignupMessage::SignupSuccess(generation) if self.signup_generation == Some(generation) => {
+                self.signup_generation = None;
+                self.signup_state = Some(SignupState::Success);
+            }
+            SignupMessage::SignupFailed(generation, reason) if self.signup_generation == Some(generation) => {
+                self.signup_generation = None;
+                self.signup_state = Some(SignupState::Failure(reason));
+                // A captcha is spent by any attempt, so the next one needs a fresh image.
+                self.captcha.renew();
+            }
+            SignupMessage::SignupSuccess(_)
+            | SignupMessage::SignupFailed(..) => {
+                warn!("Drop one signup message due to generation mismatch");
+            }
+            SignupMessage::Placeholder => {}
         }
     }
 }
@@ -37,15 +149,98 @@ impl View for SignupPage {
             .resizable(false)
             .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
             .show(ctx, |ui| {
+                ui.label("Username:");
+                ui.text_edit_singleline(&mut self.username);
+                let username_valid = validate_username(&self.username);
+                if let (Err(error), false) = (username_valid, self.username.is_empty()) {
+                    ui.label(error.to_string());
+                }
+
+                ui.label("Password:");
+                password_field(ui, &mut self.password, &mut self.password_revealed);
+                let strength = password_strength(&self.password);
+                if !self.password.is_empty() {
+                    strength_meter(ui, strength);
+                }
+
+                ui.label("Confirm password:");
+                password_field(ui, &mut self.confirm_password, &mut self.confirm_revealed);
+                let passwords_match = self.password == self.confirm_password;
+                if !passwords_match && !self.confirm_password.is_empty() {
+                    ui.label("Passwords do not match.");
+                }
+
+                let captcha = self.captcha.show(ui);
+
+                ui.separator();
+
                 ui.horizontal(|ui| {
+                    if ui.button("Back").clicked() {
+                        trace!("Back on Signup");
+                        let _ = self.message_tx.send(AppMessage::NavigateBack);
+                    }
                     if ui.button("Go Login").clicked() {
                         trace!("Go Login on Signup");
-                        self.message_tx.send(AppMessage::ReqNavigate(Route::LoginPage)).unwrap();
+                        let _ = self.message_tx.send(AppMessage::ReqNavigate(Route::LoginPage(Some(self.username.clone()))));
                     }
-                    if ui.button("Submit").clicked() {
+
+                    let enabled = self.captcha.id().is_some()
+                        && username_valid.is_ok()
+                        && strength != Strength::TooShort
+                        && passwords_match
+                        && !matches!(self.signup_state, Some(SignupState::RequestSent));
+                    let submit = ui.add_enabled(enabled, egui::Button::new("Submit"));
+                    let submitted = submit.clicked() || (captcha.input == Some(CaptchaInput::Submitted) && enabled);
+                    if let (true, Some(captcha_id)) = (submitted, self.captcha.id()) {
                         trace!("Submit on Signup");
+                        let sent = self.signup(captcha_id);
+                        self.signup_generation = sent.as_ref().ok().copied();
+                        // Without a request under way nothing would ever take the spinner down.
+                        self.signup_state = Some(match sent {
+                            Ok(_) => SignupState::RequestSent,
+                            Err(error) => {
+                                warn!("Failed to send signup request: {:?}", error);
+                                SignupState::Failure(unsent_message(&error))
+                            }
+                        });
                     }
                 });
+
+                if let Some(ref state) = self.signup_state {
+                    ui.horizontal(|ui| match state {
+                        SignupState::RequestSent => {
+                            ui.add(egui::Spinner::new());
+                            ui.label("Creating account...");
+                        }
+                        SignupState::Success => {
+                            ui.label("Account created. You can log in now.");
+                        }
+                        SignupState::Failure(reason) => {
+                            ui.label(signup_failed_message(reason));
+                        }
+                    });
+                }
             });
     }
 }
+
+impl Drop for SignupPage {
+    fn drop(&mut self) {
+        // A finished signup is already gone, so a failed cancel is expected here; `captcha` cancels its own fetch.
+        if let Some(generation) = self.signup_generation {
+            if self.real_network.borrow_mut().cancel(generation).is_ok() {
+                trace!("Cancelled pending request on leaving signup: {}", generation);
+            }
+        }
+    }
+}
+
+fn strength_meter(ui: &mut egui::Ui, strength: Strength) {
+    let (fraction, text, color) = match strength {
+        Strength::TooShort => (0.1, "Too short", egui::Color32::RED),
+        Strength::Weak => (0.35, "Weak", egui::Color32::ORANGE),
+        Strength::Ok => (0.7, "Ok", egui::Color32::YELLOW),
+        Strength::Strong => (1.0, "Strong", egui::Color32::GREEN),
+    };
+    ui.add(egui::ProgressBar::new(fraction).fill(color).text(text).desired_width(160.0));
+}
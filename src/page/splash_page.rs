@@ -0,0 +1,57 @@
+use crossbeam_channel::Sender;
+use eframe::egui;
+use eframe::egui::Context;
+use crate::page::{server_unreachable_message, View};
+use crate::shell::AppMessage;
+
+/// Up while the app checks that the server is reachable and compatible, before anything asks for credentials.
+pub struct SplashPage {
+    message_tx: Sender<AppMessage>,
+    /// Why the last check failed; `None` while one is running.
+    failure: Option<String>,
+}
+
+impl SplashPage {
+    pub fn new(message_tx: Sender<AppMessage>) -> Self {
+        Self { message_tx, failure: None }
+    }
+
+    pub fn set_checking(&mut self) {
+        self.failure = None;
+    }
+
+    pub fn set_failure(&mut self, reason: String) {
+        self.failure = Some(reason);
+    }
+}
+
+impl View for SplashPage {
+    fn view(&mut self, ctx: &Context) {
+        egui::Window::new("Connecting")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| match &self.failure {
+                None => {
+                    ui.horizontal(|ui| {
+                        ui.add(egui::Spinner::new());
+                        ui.label("Connecting to server...");
+                    });
+                }
+                Some(reason) => {
+                    ui.label(server_unreachable_message(reason));
+
+                    ui.separator();
+
+                    ui.horizontal(|ui| {
+                        if ui.button("Retry").clicked() {
+                            let _ = self.message_tx.send(AppMessage::CheckServer);
+                        }
+                        if ui.button("Quit").clicked() {
+                            let _ = self.message_tx.send(AppMessage::Exiting);
+                        }
+                    });
+                }
+            });
+    }
+}
@@ -0,0 +1,21 @@
+use anyhow::Context;
+use base64::Engine;
+use eframe::egui;
+use eframe::egui::{TextureHandle, TextureOptions};
+
+pub fn load_base64_texture(ctx: &egui::Context, encoded: &str, name: &str) -> anyhow::Result<TextureHandle> {
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .context("Invalid base64")?;
+    load_image_texture(ctx, &decoded, name)
+}
+
+/// Decodes any format the `image` crate knows, e.g. PNG or JPEG.
+pub fn load_image_texture(ctx: &egui::Context, bytes: &[u8], name: &str) -> anyhow::Result<TextureHandle> {
+    let image_data = image::load_from_memory(bytes).context("Unsupported or corrupt image")?;
+    let size = [image_data.width() as _, image_data.height() as _];
+    let rgba = image_data.to_rgba8();
+    let pixels = rgba.as_flat_samples();
+    let color_image = egui::ColorImage::from_rgba_unmultiplied(size, pixels.as_slice());
+    Ok(ctx.load_texture(name, color_image, TextureOptions::default()))
+}
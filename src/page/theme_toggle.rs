@@ -0,0 +1,15 @@
+use crossbeam_channel::Sender;
+use eframe::egui;
+use crate::shell::{AppMessage, Theme};
+
+/// A button that switches to the other theme; the shell applies and saves it.
+pub fn theme_toggle(ui: &mut egui::Ui, message_tx: &Sender<AppMessage>) {
+    let theme = Theme::of(ui.visuals());
+    let label = match theme {
+        Theme::Dark => "Light theme",
+        Theme::Light => "Dark theme",
+    };
+    if ui.button(label).clicked() {
+        let _ = message_tx.send(AppMessage::SetTheme(theme.toggled()));
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt::Display;
+use crate::protocol::network::{ChatConnError, DisconnectReason, NetworkError};
+
+/// What to tell the user about a request that ended without an answer.
+pub fn user_message(error: &NetworkError) -> String {
+    let text = match error {
+        NetworkError::Timeout => "The server did not answer in time",
+        NetworkError::UsrCancelled => "Cancelled",
+        NetworkError::SysCancelled => "The app is shutting down",
+        NetworkError::Aborted => "Something went wrong, please try again",
+    };
+    text.to_string()
+}
+
+/// What to tell the user about a request that could not be started at all, e.g. while the network is shutting down.
+pub fn unsent_message(error: &anyhow::Error) -> String {
+    format!("Could not send the request: {}", error)
+}
+
+/// What to tell the user about a request the server answered with `error`, e.g. a `LoginError`:
+/// the server's own words on it if it sent any, otherwise the error's text.
+pub fn rejection_message(error: &impl Display, detail: Option<String>) -> String {
+    detail.unwrap_or_else(|| error.to_string())
+}
+
+/// What the login page shows once the chat connection after logging in failed with `error`.
+pub fn chat_connect_message(error: &ChatConnError, detail: Option<String>) -> String {
+    match (error, detail) {
+        (_, Some(detail)) => detail,
+        // Retrying with the same credentials would only fail the same way.
+        (ChatConnError::Unauthorized, None) => format!("{}.", error),
+        (error, None) => format!("{}. Please retry.", error),
+    }
+}
+
+pub fn login_failed_message(reason: &str) -> String {
+    format!("Login failed: {}", reason)
+}
+
+pub fn signup_failed_message(reason: &str) -> String {
+    format!("Signup failed: {}", reason)
+}
+
+pub fn captcha_failed_message() -> String {
+    "Failed to load captcha, click to retry".to_string()
+}
+
+pub fn server_unreachable_message(reason: &str) -> String {
+    format!("Could not reach the server: {}", reason)
+}
+
+pub fn saved_session_expired_message() -> String {
+    "Your saved session has expired, please log in again".to_string()
+}
+
+pub fn saved_session_unreachable_message() -> String {
+    "Could not reach the server to resume your session".to_string()
+}
+
+pub fn chat_unstarted_message() -> String {
+    "Could not start the chat connection".to_string()
+}
+
+/// What to tell the user about the chat connection dropping for `reason`.
+pub fn disconnect_message(reason: DisconnectReason) -> String {
+    match reason {
+        DisconnectReason::GaveUp => "Could not reconnect, use Reconnect to try again".to_string(),
+        reason => format!("Connection lost: {}, reconnecting…", reason),
+    }
+}
+
+pub fn dropped_after_handshake_message() -> String {
+    "Connection lost right after connecting, reconnecting…".to_string()
+}
+
+pub fn reconnect_failed_message() -> String {
+    "Reconnecting failed".to_string()
+}
+
+pub fn reconnected_message() -> String {
+    "Reconnected".to_string()
+}
+
+/// Why the direct conversation with `username` could not be opened, e.g. a `DirectError` or an invalid name.
+pub fn direct_failed_message(username: &str, reason: &dyn std::fmt::Display) -> String {
+    format!("@{}: {}", username, reason)
+}
+
+pub fn session_expired_message() -> String {
+    "Your session has expired, please log in again".to_string()
+}
+
+pub fn messages_dropped_message(count: u64) -> String {
+    format!("Missed {} message(s), the conversation may be incomplete", count)
+}
+
+pub fn conversations_failed_message() -> String {
+    "Failed to load conversations".to_string()
+}
+
+pub fn open_conversation_failed_message() -> String {
+    "Could not open the conversation".to_string()
+}
+
+/// Unlike text, a dropped file is not queued for the reconnect.
+pub fn attachment_offline_message(filename: &str) -> String {
+    format!("{} was not sent, attachments need a connection", filename)
+}
+
+pub fn send_queue_full_message(queued: usize) -> String {
+    format!("{} messages are already waiting to send", queued)
+}
+
+/// What to tell the user about `failed` messages the server did not take, with the `reason` they share if known.
+pub fn send_failed_message(failed: usize, reason: Option<&str>) -> String {
+    match (failed, reason) {
+        (1, Some(reason)) => format!("A message could not be sent: {}", reason),
+        (1, None) => "A message could not be sent".to_string(),
+        (failed, Some(reason)) => format!("{} message(s) could not be sent: {}", failed, reason),
+        (failed, None) => format!("{} message(s) could not be sent", failed),
+    }
+}
+
+/// What to tell the user about an own edit or deletion the server refused, which is undone again.
+pub fn change_failed_message(reason: &str) -> String {
+    format!("The change could not be made: {}", reason)
+}
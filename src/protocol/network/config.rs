@@ -0,0 +1,117 @@
+use anyhow::{anyhow, Context};
+use std::path::PathBuf;
+use std::time::Duration;
+use url::Url;
+
+pub const DEFAULT_API_BASE_URL: &str = "https://127.0.0.1:8443/api/v1";
+pub const DEFAULT_WS_URL: &str = "wss://127.0.0.1:8443/api/v1/chat";
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 1024;
+pub const DEFAULT_CAPTCHA_ATTEMPTS: u32 = 3;
+pub const DEFAULT_RECONNECT_ATTEMPTS: u32 = 8;
+pub const DEFAULT_RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+pub const DEFAULT_RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    pub api_base_url: Url,
+    pub ws_url: Url,
+    /// PEM bundle of trusted roots; the platform's native root store is used when `None`.
+    pub cert_path: Option<PathBuf>,
+    pub timeouts: NetworkTimeouts,
+    /// Bound of the task result channel and of each session's incoming message channel.
+    ///
+    /// A full result channel makes finished tasks wait; a result that cannot wait, like the
+    /// `NetworkError::Aborted` of a dropped task, is logged and counted instead. A full message
+    /// channel drops its oldest messages, which surfaces as `StreamMessage::MessagesDropped`.
+    pub channel_capacity: usize,
+    /// How often `fetch_captcha` is tried before it fails; retries stop early at the request's timeout.
+    pub captcha_attempts: u32,
+    /// How often a lost chat connection is tried again before `DisconnectReason::GaveUp`; zero gives up straight away.
+    pub reconnect_attempts: u32,
+    /// The wait after the first failed reconnect, doubled after each one up to `reconnect_max_backoff`.
+    pub reconnect_initial_backoff: Duration,
+    pub reconnect_max_backoff: Duration,
+    /// How many requests may run at once; more wait for a free slot, which counts against their timeout.
+    pub max_in_flight: usize,
+}
+
+/// Per-request timeouts in milliseconds, handed to the pages by `App`.
+#[derive(Debug, Clone, Copy)]
+pub struct NetworkTimeouts {
+    pub captcha_ms: u64,
+    pub login_ms: u64,
+    pub signup_ms: u64,
+    pub connect_ms: u64,
+    pub send_ms: u64,
+    pub history_ms: u64,
+    pub conversations_ms: u64,
+    pub presence_ms: u64,
+    /// Covers uploading an attachment and sending the message that references it.
+    pub upload_ms: u64,
+    pub attachment_ms: u64,
+    pub logout_ms: u64,
+    /// How long a send waits for the server's ACK once the frame is out; keep it below `send_ms`.
+    pub ack_ms: u64,
+    pub server_info_ms: u64,
+}
+
+impl Default for NetworkTimeouts {
+    fn default() -> Self {
+        Self {
+            captcha_ms: 5000,
+            login_ms: 10000,
+            signup_ms: 10000,
+            connect_ms: 10000,
+            send_ms: 5000,
+            history_ms: 10000,
+            conversations_ms: 10000,
+            presence_ms: 5000,
+            upload_ms: 30000,
+            attachment_ms: 30000,
+            logout_ms: 5000,
+            ack_ms: 4000,
+            server_info_ms: 5000,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn try_new(api_base_url: &str, ws_url: &str, cert_path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let api_base_url = Url::parse(api_base_url)
+            .with_context(|| format!("Invalid API base URL: {}", api_base_url))?;
+        if !matches!(api_base_url.scheme(), "http" | "https") {
+            return Err(anyhow!("API base URL must use http or https: {}", api_base_url));
+        }
+
+        let ws_url = parse_ws_url(ws_url)?;
+
+        Ok(Self {
+            api_base_url,
+            ws_url,
+            cert_path,
+            timeouts: NetworkTimeouts::default(),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            captcha_attempts: DEFAULT_CAPTCHA_ATTEMPTS,
+            reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            reconnect_initial_backoff: DEFAULT_RECONNECT_INITIAL_BACKOFF,
+            reconnect_max_backoff: DEFAULT_RECONNECT_MAX_BACKOFF,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+        })
+    }
+}
+
+pub fn parse_ws_url(ws_url: &str) -> anyhow::Result<Url> {
+    let parsed = Url::parse(ws_url)
+        .with_context(|| format!("Invalid WebSocket URL: {}", ws_url))?;
+    if !matches!(parsed.scheme(), "ws" | "wss") {
+        return Err(anyhow!("WebSocket URL must use ws or wss: {}", parsed));
+    }
+    Ok(parsed)
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self::try_new(DEFAULT_API_BASE_URL, DEFAULT_WS_URL, None).expect("Default URLs are valid")
+    }
+}
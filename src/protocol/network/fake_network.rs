@@ -0,0 +1,489 @@
+use crate::domain::{ChatBody, ConversationId, ConversationKind, UserId};
+use crate::protocol::network::*;
+use chrono::{DateTime, TimeDelta, Utc};
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+
+const CAPTCHA_PNG_0: &str = "iVBORw0KGgoAAAANSUhEUgAAAGQAAAAyCAMAAACd646MAAAAP1BMVEUAAAAAOnJjndUIQnpRi8OBu/N8tu4qZJwMRn6Lxf0KRHxSjMRalMwoYppmoNhrpd01b6cgWpI8dq4tZ58WUIhMzA4eAAAAAXRSTlMAQObYZgAAAapJREFUeJzsmM1yhCAMgJN1RmU86Pj+D9vpAiHEwAaxTA+bQ9WK+fKLZuEfysuyaO1kvAyUde2lWBZxBvbxTII4hDKA8YfiqncRnwiic2UKBomXbZp53TmAs8QAKgdPmxo4ooPOU6VEDwIEAKZpskNkB+mepDBhPJ8IDI/kSoXEI0tWZ2VoEIqdT1fC3QWR8QpDGHLfFyquC0QylMfsECRCyoU/R10hxa0hgpiUstAjQsp6vh4yRkuuqIgAgTWmVIOpQLg/NaX6Jd8DGAxSJMOdgq4SJSslBLkTgPCQ940/Fl+diELxWw5Rryz+6ZD5+OEjwK9w3KnjODQIUBEiwJwZ+v5X7SPg98H4GggWlzxJV/M8i2iw3C8FjAPuff642FL8X8lgUVkWlZLMELVISZAk0BhQ84SZq9JZ0V4oBqZOCvVJHY+8waFCsFLCyitW06D3+QMvA4u2MmZvJldMLmxm+/6ZcghFrWZZPIk7QUBUGFs7PlGM67ath2KVEYyvfOWG1Ie1hxiVYY2ke86wMIbM72Mow38l4N8ULWNoE4NB2obdJspPAAAA//9aeATJZ1KZSAAAAABJRU5ErkJggg==";
+const CAPTCHA_PNG_1: &str = "iVBORw0KGgoAAAANSUhEUgAAAGQAAAAyCAMAAACd646MAAAAP1BMVEUAAAARfGBu2b0ahWkok3d+6c1NuJxBrJBl0LRPup4Qe19s17seiW0CbVFTvqIynYEch2t+6c0qlXk0n4NFsJTJ6I4rAAAAAXRSTlMAQObYZgAAAbtJREFUeJzsl93usyAMxtstmQkjWYj3f69vhiJtaeVDZt6Df0/GJuuvD31Ahbvicwfjc4HybKacXHtXGE+d4lvZkfGuUXSGr1Bwi0TpKYlQqgyglPnBU0fafAYgI+IPJIl83/xutphvM4CU/mW4nYIAU0ShaHn8dBt7v3ANgqTHVEm6lqqIn10bTeoQAwJhP5gb7dHAkEJALNEx8np/Ho8aJTYXRd081TF2wh2tSsjyI5WmCXHOYf5Djx+Q9/aotewUoOPT81SNyBY/nVqibCkn5S0qQ4UhiHkCE+DlUSmz00qMxcmOR9HK7Zv3XjKQVngGyV40HH84zUvBUCS3laSw9i7qxpEK6Bw500LybGUj0w/UgXyMuzfqhCzGBB1DQL5hiEHbKOUOyZy8O8KJZ9V465iSnhsdQlAadMYoHn+0v4hGh/2eVhzZjUpU/aXBYv8Wu6qWMJaAH3LLslyCgNFQZq6rSkjOvE8Q2YFoOnEcl89vcb788rETuu9eWjS8g1xjPO13kGkRAc2MMErpmBuCTXGDfIViM9w8ih13MP7iP4xXx9x1lPFqpqzrOky5gdERNyDG418AAAD//3/dBjfl+kg/AAAAAElFTkSuQmCC";
+/// Default captcha images, handed out in turn.
+const CAPTCHA_PNGS: [&str; 2] = [CAPTCHA_PNG_0, CAPTCHA_PNG_1];
+const CAPTCHA_LIFETIME_SECS: i64 = 300;
+
+type ErrFunction = Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>;
+type StreamFunction = Box<dyn Fn(StreamMessage) + Send + Sync>;
+
+/// How a scripted call of `FakeNetworkInterface` completes.
+pub enum FakeReply<T> {
+    /// Handed to `map_function`.
+    Event(T),
+    /// Handed to `err_function`, e.g. `NetworkError::Timeout`.
+    Error(NetworkError),
+    /// Does not complete until cancelled, like a request still in flight.
+    Pending,
+}
+
+/// `NetworkInterface` without a server, for driving pages headlessly.
+///
+/// Every call pops its reply from the matching queue and completes synchronously; an empty
+/// queue means success. Stream messages reach the connected session through `emit`.
+pub struct FakeNetworkInterface {
+    generation: u64,
+    next_seq: u64,
+    pub user_id: UserId,
+    pub captcha_replies: VecDeque<FakeReply<CaptchaEvent>>,
+    pub signup_replies: VecDeque<FakeReply<SignupEvent>>,
+    pub login_replies: VecDeque<FakeReply<LoginEvent>>,
+    pub refresh_replies: VecDeque<FakeReply<RefreshEvent>>,
+    pub logout_replies: VecDeque<FakeReply<LogoutEvent>>,
+    pub history_replies: VecDeque<FakeReply<HistoryEvent>>,
+    pub conversations_replies: VecDeque<FakeReply<ConversationsEvent>>,
+    pub presence_replies: VecDeque<FakeReply<PresenceEvent>>,
+    /// An empty queue opens a direct conversation with a made-up user of the asked name.
+    pub direct_replies: VecDeque<FakeReply<DirectEvent>>,
+    pub attachment_replies: VecDeque<FakeReply<AttachmentEvent>>,
+    pub connect_replies: VecDeque<FakeReply<SessionEvent>>,
+    pub send_replies: VecDeque<FakeReply<MessageEvent>>,
+    pub batch_replies: VecDeque<FakeReply<MessageBatchEvent>>,
+    pub server_info_replies: VecDeque<FakeReply<ServerInfoEvent>>,
+    /// Messages passed to `send_chat_message` and `send_chat_messages`, in order.
+    pub sent: Vec<(ConversationId, ChatBody)>,
+    /// Filenames passed to `send_chat_attachment`, in order; these share `send_replies`.
+    pub sent_attachments: Vec<(ConversationId, String)>,
+    /// Generations passed to `cancel`, in order.
+    pub cancelled: Vec<u64>,
+    /// The last token passed to `set_auth_token`.
+    pub auth_token: Option<String>,
+    /// What `clock_offset` reports.
+    pub clock_offset: TimeDelta,
+    /// What `metrics` reports.
+    pub metrics: ConnectionMetrics,
+    /// What `pending_count` reports.
+    pub pending_count: usize,
+    /// `err_function`s of `FakeReply::Pending` calls, for `cancel` to complete.
+    pending: HashMap<u64, ErrFunction>,
+    msg_function: Option<Box<dyn Fn(StreamMessage) + Send + Sync>>,
+    /// `subscribe_stream` callbacks with their ids; `emit` reaches them after `msg_function`.
+    subscribers: Vec<(u64, StreamFunction)>,
+    next_subscription: u64,
+    /// The `connect_chat` generation of the connected session, which `cancel` also ends.
+    session_generation: Option<u64>,
+}
+
+impl Default for FakeNetworkInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FakeNetworkInterface {
+    pub fn new() -> Self {
+        Self {
+            generation: 0,
+            next_seq: 0,
+            user_id: UserId(Uuid::nil()),
+            captcha_replies: VecDeque::new(),
+            signup_replies: VecDeque::new(),
+            login_replies: VecDeque::new(),
+            refresh_replies: VecDeque::new(),
+            logout_replies: VecDeque::new(),
+            history_replies: VecDeque::new(),
+            conversations_replies: VecDeque::new(),
+            presence_replies: VecDeque::new(),
+            direct_replies: VecDeque::new(),
+            attachment_replies: VecDeque::new(),
+            connect_replies: VecDeque::new(),
+            send_replies: VecDeque::new(),
+            batch_replies: VecDeque::new(),
+            server_info_replies: VecDeque::new(),
+            sent: Vec::new(),
+            sent_attachments: Vec::new(),
+            cancelled: Vec::new(),
+            auth_token: None,
+            clock_offset: TimeDelta::zero(),
+            metrics: ConnectionMetrics::default(),
+            pending_count: 0,
+            pending: HashMap::new(),
+            msg_function: None,
+            subscribers: Vec::new(),
+            next_subscription: 0,
+            session_generation: None,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.msg_function.is_some()
+    }
+
+    /// Delivers `message` to the connected session as if the server had sent it.
+    pub fn emit(&self, message: StreamMessage) -> anyhow::Result<()> {
+        let msg_function = self
+            .msg_function
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No chat session to emit to"))?;
+        msg_function(message.clone());
+        for (_, subscriber) in &self.subscribers {
+            subscriber(message.clone());
+        }
+        Ok(())
+    }
+
+    /// Shorthand for emitting a `Distribute` message.
+    pub fn emit_chat(&self, sender: UserId, conversation_id: ConversationId, content: &str, sent_at: DateTime<Utc>) -> anyhow::Result<()> {
+        self.emit(StreamMessage::Distribute(ChatMessage {
+            sender,
+            sender_name: None,
+            conversation_id,
+            content: ChatBody::Text(content.to_string()),
+            sent_at,
+            seq: None,
+            attachment: None,
+            client_id: None,
+        }))
+    }
+
+    fn token_info(&self) -> TokenInfo {
+        TokenInfo {
+            user_id: self.user_id.clone(),
+            access_token: "fake-access-token".to_string(),
+            access_expires_in: 3600,
+            refresh_token: "fake-refresh-token".to_string(),
+            refresh_expires_in: 86400,
+            chat_address: None,
+        }
+    }
+
+    fn complete<T>(
+        &mut self,
+        reply: Option<FakeReply<T>>,
+        default: impl FnOnce(&mut Self) -> T,
+        map_function: Box<dyn FnOnce(WithGeneration<T>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> u64 {
+        let generation = self.generation;
+        self.generation += 1;
+        match reply {
+            None => map_function(WithGeneration { generation, result: default(self) }),
+            Some(FakeReply::Event(result)) => map_function(WithGeneration { generation, result }),
+            Some(FakeReply::Error(result)) => err_function(WithGeneration { generation, result }),
+            Some(FakeReply::Pending) => {
+                self.pending.insert(generation, err_function);
+            }
+        }
+        generation
+    }
+    fn complete_send(
+        &mut self,
+        map_function: Box<dyn FnOnce(WithGeneration<MessageEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> u64 {
+        let reply = self.send_replies.pop_front();
+        let default = |fake: &mut Self| {
+            let seq = fake.next_seq;
+            fake.next_seq += 1;
+            MessageEvent { result: Ok(MessageSent { seq: Some(seq) }), detail: None }
+        };
+        self.complete(reply, default, map_function, err_function)
+    }
+}
+
+impl NetworkInterface for FakeNetworkInterface {
+    fn fetch_captcha(
+        &mut self,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<CaptchaEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let reply = self.captcha_replies.pop_front();
+        let image = CAPTCHA_PNGS[self.generation as usize % CAPTCHA_PNGS.len()];
+        let default = |_: &mut Self| CaptchaEvent {
+            result: Ok(CaptchaData {
+                id: Uuid::new_v4(),
+                image_base64: image.to_string(),
+                expire_at: Utc::now() + chrono::Duration::seconds(CAPTCHA_LIFETIME_SECS),
+            }),
+        };
+        Ok(self.complete(reply, default, map_function, err_function))
+    }
+
+    fn signup(
+        &mut self,
+        _username: String,
+        _password: String,
+        _captcha_id: Uuid,
+        _captcha_answer: String,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<SignupEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let reply = self.signup_replies.pop_front();
+        let default = |_: &mut Self| SignupEvent { result: Ok(()), detail: None };
+        Ok(self.complete(reply, default, map_function, err_function))
+    }
+
+    fn login(
+        &mut self,
+        _username: String,
+        _password: String,
+        _captcha_id: Uuid,
+        _captcha_answer: String,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<LoginEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let reply = self.login_replies.pop_front();
+        let default = |fake: &mut Self| LoginEvent { result: Ok(fake.token_info()), detail: None };
+        Ok(self.complete(reply, default, map_function, err_function))
+    }
+
+    fn restore_auth(&mut self, _token_info: TokenInfo) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn set_auth_token(&mut self, access_token: String, _expires_in: u64) -> anyhow::Result<()> {
+        self.auth_token = Some(access_token);
+        Ok(())
+    }
+
+    fn refresh(
+        &mut self,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<RefreshEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let reply = self.refresh_replies.pop_front();
+        let default = |fake: &mut Self| RefreshEvent { result: Ok(fake.token_info()) };
+        Ok(self.complete(reply, default, map_function, err_function))
+    }
+
+    fn logout(
+        &mut self,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<LogoutEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        self.msg_function = None;
+        let reply = self.logout_replies.pop_front();
+        let default = |_: &mut Self| LogoutEvent { result: Ok(()) };
+        Ok(self.complete(reply, default, map_function, err_function))
+    }
+
+    fn fetch_history(
+        &mut self,
+        conversation_id: ConversationId,
+        _before: Option<DateTime<Utc>>,
+        _limit: u32,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<HistoryEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let reply = self.history_replies.pop_front();
+        let default = move |_: &mut Self| HistoryEvent { conversation_id, result: Ok(Vec::new()) };
+        Ok(self.complete(reply, default, map_function, err_function))
+    }
+
+    fn list_conversations(
+        &mut self,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<ConversationsEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let reply = self.conversations_replies.pop_front();
+        let default = |_: &mut Self| ConversationsEvent { result: Ok(Vec::new()) };
+        Ok(self.complete(reply, default, map_function, err_function))
+    }
+
+    fn fetch_presence(
+        &mut self,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<PresenceEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let reply = self.presence_replies.pop_front();
+        let default = |_: &mut Self| PresenceEvent { result: Ok(Vec::new()) };
+        Ok(self.complete(reply, default, map_function, err_function))
+    }
+
+    fn open_direct(
+        &mut self,
+        username: String,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<DirectEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let reply = self.direct_replies.pop_front();
+        let default = move |fake: &mut Self| {
+            let members = vec![
+                ConversationMember { user_id: fake.user_id.clone(), username: "me".to_string(), online: true },
+                ConversationMember { user_id: UserId(Uuid::new_v4()), username: username.clone(), online: false },
+            ];
+            let conversation = ConversationInfo {
+                conversation_id: ConversationId(Uuid::new_v4()),
+                kind: ConversationKind::Direct,
+                display_name: username.clone(),
+                members,
+            };
+            DirectEvent { username, result: Ok(conversation) }
+        };
+        Ok(self.complete(reply, default, map_function, err_function))
+    }
+
+    fn server_info(
+        &mut self,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<ServerInfoEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let reply = self.server_info_replies.pop_front();
+        let default = |_: &mut Self| ServerInfoEvent {
+            result: Ok(ServerInfo { version: "fake".to_string(), protocol_version: PROTOCOL_VERSION }),
+        };
+        Ok(self.complete(reply, default, map_function, err_function))
+    }
+
+    fn cancel(&mut self, generation: u64) -> anyhow::Result<()> {
+        self.cancelled.push(generation);
+        if let Some(err_function) = self.pending.remove(&generation) {
+            err_function(WithGeneration { generation, result: NetworkError::UsrCancelled });
+        }
+        if self.session_generation == Some(generation) {
+            self.session_generation = None;
+            self.msg_function = None;
+        }
+        Ok(())
+    }
+
+    fn connect_chat(
+        &mut self,
+        _address: String,
+        _jwt: String,
+        msg_function: Box<dyn Fn(StreamMessage) + Send + Sync>,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<SessionEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let reply = self.connect_replies.pop_front();
+        let connects = matches!(reply, None | Some(FakeReply::Event(SessionEvent { result: Ok(_), .. })));
+        if connects {
+            self.msg_function = Some(msg_function);
+        }
+        let default = |_: &mut Self| SessionEvent { result: Ok(ChatMetaData), detail: None };
+        let generation = self.complete(reply, default, map_function, err_function);
+        if connects {
+            self.session_generation = Some(generation);
+        }
+        Ok(generation)
+    }
+
+    fn disconnect_chat(&mut self) -> anyhow::Result<()> {
+        match self.msg_function.take() {
+            Some(_) => Ok(()),
+            None => Err(anyhow::anyhow!("No chat session to disconnect")),
+        }
+    }
+
+    fn subscribe_stream(&mut self, callback: Box<dyn Fn(StreamMessage) + Send + Sync>) -> u64 {
+        let subscription = self.next_subscription;
+        self.next_subscription += 1;
+        self.subscribers.push((subscription, callback));
+        subscription
+    }
+
+    fn unsubscribe_stream(&mut self, subscription: u64) -> anyhow::Result<()> {
+        let count = self.subscribers.len();
+        self.subscribers.retain(|(id, _)| *id != subscription);
+        anyhow::ensure!(self.subscribers.len() < count, "No such stream subscription: {}", subscription);
+        Ok(())
+    }
+
+    fn session_state(&self) -> ConnectionState {
+        match self.msg_function {
+            Some(_) => ConnectionState::Connected,
+            None => ConnectionState::Disconnected,
+        }
+    }
+
+    fn pending_count(&self) -> usize {
+        self.pending_count
+    }
+
+    fn clock_offset(&self) -> TimeDelta {
+        self.clock_offset
+    }
+
+    fn metrics(&self) -> ConnectionMetrics {
+        self.metrics
+    }
+
+    fn send_chat_message(
+        &mut self,
+        conversation_id: ConversationId,
+        _client_id: Uuid,
+        message: ChatBody,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<MessageEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        self.sent.push((conversation_id, message));
+        Ok(self.complete_send(map_function, err_function))
+    }
+
+    fn send_chat_attachment(
+        &mut self,
+        request: AttachmentRequest,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<MessageEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        self.sent_attachments.push((request.conversation_id, request.filename));
+        Ok(self.complete_send(map_function, err_function))
+    }
+
+    fn send_chat_messages(
+        &mut self,
+        conversation_id: ConversationId,
+        messages: Vec<(Uuid, ChatBody)>,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<MessageBatchEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let count = messages.len() as u64;
+        self.sent.extend(messages.into_iter().map(|(_, message)| (conversation_id.clone(), message)));
+        let reply = self.batch_replies.pop_front();
+        let default = |fake: &mut Self| {
+            let first = fake.next_seq;
+            fake.next_seq += count;
+            let results = (first..fake.next_seq)
+                .map(|seq| MessageEvent { result: Ok(MessageSent { seq: Some(seq) }), detail: None })
+                .collect();
+            MessageBatchEvent { results }
+        };
+        Ok(self.complete(reply, default, map_function, err_function))
+    }
+
+    fn fetch_attachment(
+        &mut self,
+        blob_id: Uuid,
+        _timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<AttachmentEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let reply = self.attachment_replies.pop_front();
+        let default = move |_: &mut Self| AttachmentEvent { blob_id, result: Ok(Vec::new()) };
+        Ok(self.complete(reply, default, map_function, err_function))
+    }
+
+    fn send_typing(&mut self, _conversation_id: ConversationId) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn send_read_receipt(&mut self, _conversation_id: ConversationId, _up_to_seq: u64) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self, on_done: Box<dyn FnOnce() + Send + Sync>) -> anyhow::Result<()> {
+        self.msg_function = None;
+        on_done();
+        Ok(())
+    }
+}
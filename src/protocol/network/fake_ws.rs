@@ -0,0 +1,145 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use anyhow::anyhow;
+use tokio::sync::{broadcast, watch};
+use url::Url;
+use uuid::Uuid;
+use crate::domain::{Attachment, ChatBody, ConversationId};
+use crate::protocol::network::{ChatConnError, DisconnectReason, WithGeneration};
+use crate::protocol::network::worker::{ConnectionCounters, WsConnector, WsWorker};
+use crate::protocol::network::ws_message::{ChatContent, ClientToServer, ReadReceipt, SendMessage, ServerToClient, TypingNotice};
+
+/// A `WsWorker` without a socket: what it sends is recorded, and what the server would send
+/// is put in by hand with `inject`.
+#[derive(Clone)]
+pub struct FakeWsWorker {
+    pub generation: u64,
+    /// The token the session connected with.
+    pub access_token: String,
+    sent: Arc<Mutex<Vec<ClientToServer>>>,
+    counters: Arc<ConnectionCounters>,
+    from_receiver: broadcast::Sender<WithGeneration<ServerToClient>>,
+    shutdown: Arc<watch::Sender<Option<DisconnectReason>>>,
+}
+
+impl FakeWsWorker {
+    /// Frames sent so far, oldest first.
+    pub fn sent(&self) -> Vec<ClientToServer> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    /// Delivers `message` as if the server had sent it on this connection.
+    pub fn inject(&self, message: ServerToClient) -> anyhow::Result<()> {
+        self.counters.count_received();
+        self.from_receiver
+            .send(WithGeneration { generation: self.generation, result: message })
+            .map_err(|_| anyhow!("No chat session is listening"))?;
+        Ok(())
+    }
+
+    /// Whether the connection has ended, by `close` or `drop_connection`.
+    pub fn is_closed(&self) -> bool {
+        self.shutdown.borrow().is_some()
+    }
+
+    /// Ends the connection as if it dropped, which hands the session to its reconnect supervisor.
+    pub fn drop_connection(&self, reason: DisconnectReason) {
+        self.shutdown.send_replace(Some(reason));
+    }
+
+    fn record(&self, message: ClientToServer) -> anyhow::Result<()> {
+        anyhow::ensure!(self.shutdown.borrow().is_none(), "Connection is closed");
+        self.sent.lock().unwrap().push(message);
+        self.counters.count_sent();
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl WsWorker for FakeWsWorker {
+    async fn send_message(
+        &self,
+        message_seq: u64,
+        client_id: Uuid,
+        conversation_id: ConversationId,
+        content: ChatBody,
+        attachment: Option<Attachment>,
+    ) -> anyhow::Result<()> {
+        self.record(ClientToServer::Send(SendMessage {
+            message_seq,
+            client_id,
+            content: ChatContent { conversation_id, content, attachment },
+        }))
+    }
+
+    async fn send_typing(&self, conversation_id: ConversationId) -> anyhow::Result<()> {
+        self.record(ClientToServer::Typing(TypingNotice { conversation_id }))
+    }
+
+    async fn send_read(&self, conversation_id: ConversationId, up_to_seq: u64) -> anyhow::Result<()> {
+        self.record(ClientToServer::Read(ReadReceipt { conversation_id, up_to_seq }))
+    }
+
+    async fn closed(&self) -> DisconnectReason {
+        let mut shutdown = self.shutdown.subscribe();
+        let reason = match shutdown.wait_for(|reason| reason.is_some()).await {
+            Ok(reason) => *reason,
+            Err(_) => None,
+        };
+        reason.unwrap_or(DisconnectReason::ConnectionError)
+    }
+
+    async fn close(&self) {
+        self.shutdown.send_replace(Some(DisconnectReason::ClosedByClient));
+    }
+}
+
+/// Hands out `FakeWsWorker`s for `NetworkImpl::with_ws_connector` and keeps a handle to each.
+#[derive(Clone, Default)]
+pub struct FakeWsConnector {
+    workers: Arc<Mutex<Vec<FakeWsWorker>>>,
+    refusing: Arc<AtomicBool>,
+}
+
+impl FakeWsConnector {
+    /// Every worker handed out, oldest first; a reconnect adds one.
+    pub fn workers(&self) -> Vec<FakeWsWorker> {
+        self.workers.lock().unwrap().clone()
+    }
+
+    pub fn latest(&self) -> Option<FakeWsWorker> {
+        self.workers.lock().unwrap().last().cloned()
+    }
+
+    /// While set, connecting fails the way an unreachable server does.
+    pub fn set_refusing(&self, refusing: bool) {
+        self.refusing.store(refusing, Ordering::Relaxed);
+    }
+}
+
+#[async_trait::async_trait]
+impl WsConnector for FakeWsConnector {
+    async fn connect(
+        &self,
+        _ws_url: Url,
+        generation: u64,
+        access_token: String,
+        from_receiver: broadcast::Sender<WithGeneration<ServerToClient>>,
+        counters: Arc<ConnectionCounters>,
+    ) -> anyhow::Result<Box<dyn WsWorker>> {
+        if self.refusing.load(Ordering::Relaxed) {
+            return Err(anyhow!("Connection refused").context(ChatConnError::Refused));
+        }
+        let (shutdown, _) = watch::channel(None);
+        let worker = FakeWsWorker {
+            generation,
+            access_token,
+            sent: Arc::default(),
+            counters,
+            from_receiver,
+            shutdown: Arc::new(shutdown),
+        };
+        self.workers.lock().unwrap().push(worker.clone());
+        Ok(Box::new(worker))
+    }
+}
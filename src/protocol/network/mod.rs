@@ -1,10 +1,22 @@
+mod config;
+#[cfg(any(test, feature = "manual-test"))]
+mod fake_network;
+#[cfg(any(test, feature = "manual-test"))]
+mod fake_ws;
 mod network;
 mod network_impl;
+mod offline_network;
 mod worker;
 mod ws_message;
 
+pub use config::*;
+#[cfg(any(test, feature = "manual-test"))]
+pub use fake_network::*;
+#[cfg(any(test, feature = "manual-test"))]
+pub use fake_ws::*;
 pub use network::*;
 pub use network_impl::*;
+pub use offline_network::*;
 
 #[cfg(any(test, feature = "manual-test"))]
 pub use worker::*;
@@ -1,5 +1,7 @@
-use crate::domain::{ConversationId, UserId};
+use crate::domain::{Attachment, ChatBody, ConversationId, ConversationKind, UserId};
+use chrono::{DateTime, TimeDelta, Utc};
 use std::fmt::Debug;
+use std::time::Duration;
 use uuid::Uuid;
 
 pub trait NetworkInterface {
@@ -29,7 +31,73 @@ pub trait NetworkInterface {
         map_function: Box<dyn FnOnce(WithGeneration<LoginEvent>) + Send + Sync>,
         err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
     ) -> anyhow::Result<u64>;
+    /// Adopts tokens kept from an earlier run so `refresh` can renew them without a login.
+    fn restore_auth(&mut self, token_info: TokenInfo) -> anyhow::Result<()>;
+    /// Replaces the access token later REST calls and chat reconnects use, e.g. one renewed elsewhere,
+    /// good for `expires_in` seconds; an open chat connection keeps the token it was opened with.
+    /// Tokens renewed by `refresh`, or after the chat server turned one down, take the same way.
+    fn set_auth_token(&mut self, access_token: String, expires_in: u64) -> anyhow::Result<()>;
+    fn refresh(
+        &mut self,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<RefreshEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64>;
+    fn logout(
+        &mut self,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<LogoutEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64>;
+    fn fetch_history(
+        &mut self,
+        conversation_id: ConversationId,
+        before: Option<DateTime<Utc>>,
+        limit: u32,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<HistoryEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64>;
+    fn list_conversations(
+        &mut self,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<ConversationsEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64>;
+    /// Who of the users sharing a conversation with us is online right now; `StreamMessage::Presence`
+    /// reports changes from there on.
+    fn fetch_presence(
+        &mut self,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<PresenceEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64>;
+    /// Looks up the user named `username` and the direct conversation with them, which the server
+    /// starts if there is none yet.
+    fn open_direct(
+        &mut self,
+        username: String,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<DirectEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64>;
+    fn server_info(
+        &mut self,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<ServerInfoEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64>;
+    /// Aborts a request still in flight; its `err_function` runs with `NetworkError::UsrCancelled`.
+    /// For a `connect_chat` generation this also ends the session it set up, if still current.
     fn cancel(&mut self, generation: u64) -> anyhow::Result<()>;
+    /// The returned generation names both the connect request and the session it sets up:
+    /// - while connecting, `cancel` aborts the request like any other;
+    /// - once connected, `map_function` has run and `cancel` ends that session, unless another
+    ///   `connect_chat` has replaced it since;
+    /// - `disconnect_chat` ends the current session whichever generation it came from.
+    ///
+    /// Replaces any existing session once connected; sends pending on the old one fail with
+    /// `MessageError::ConnectionLost`.
     fn connect_chat(
         &mut self,
         address: String,
@@ -39,19 +107,73 @@ pub trait NetworkInterface {
         map_function: Box<dyn FnOnce(WithGeneration<SessionEvent>) + Send + Sync>,
         err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
     ) -> anyhow::Result<u64>;
+    fn disconnect_chat(&mut self) -> anyhow::Result<()>;
+    /// Adds a consumer that sees every `StreamMessage` after `connect_chat`'s `msg_function`,
+    /// for the current session and any later one; returns the id `unsubscribe_stream` takes.
+    fn subscribe_stream(&mut self, callback: Box<dyn Fn(StreamMessage) + Send + Sync>) -> u64;
+    fn unsubscribe_stream(&mut self, subscription: u64) -> anyhow::Result<()>;
+    /// Current state of the chat session; cheap enough to call every frame.
+    fn session_state(&self) -> ConnectionState;
+    /// Whether a send would go out now rather than fail or wait for a reconnect; as cheap as `session_state`.
+    fn is_connected(&self) -> bool {
+        self.session_state() == ConnectionState::Connected
+    }
+    /// Chat messages handed to the server and still waiting for its ACK; lost if the app quits now.
+    fn pending_count(&self) -> usize;
+    /// Server clock minus the local clock, measured after logging in or renewing a session;
+    /// zero until then.
+    fn clock_offset(&self) -> TimeDelta;
+    /// Counters of the current chat session, for telling a flaky connection from a quiet one;
+    /// read without locking, so cheap enough to call every frame.
+    fn metrics(&self) -> ConnectionMetrics;
+    /// A retry passes the same `client_id`, so a message that went out twice is shown once.
     fn send_chat_message(
         &mut self,
         conversation_id: ConversationId,
-        message: String,
+        client_id: Uuid,
+        message: ChatBody,
         timeout: u64,
         map_function: Box<dyn FnOnce(WithGeneration<MessageEvent>) + Send + Sync>,
         err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
     ) -> anyhow::Result<u64>;
+    /// Uploads the file, then sends a message referencing it with `caption` as its text, which may be
+    /// empty; completes like `send_chat_message`.
+    fn send_chat_attachment(
+        &mut self,
+        request: AttachmentRequest,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<MessageEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64>;
+    /// Sends several messages after a single session lookup; each is still resolved by its own ACK.
+    fn send_chat_messages(
+        &mut self,
+        conversation_id: ConversationId,
+        messages: Vec<(Uuid, ChatBody)>,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<MessageBatchEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64>;
+    fn fetch_attachment(
+        &mut self,
+        blob_id: Uuid,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<AttachmentEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64>;
+    /// Tells the other members of `conversation_id` that the user is typing; best effort.
+    fn send_typing(&mut self, conversation_id: ConversationId) -> anyhow::Result<()>;
+    /// Marks every message of `conversation_id` up to `up_to_seq` as read; best effort.
+    fn send_read_receipt(&mut self, conversation_id: ConversationId, up_to_seq: u64) -> anyhow::Result<()>;
+    /// Cancels every task and closes the chat session.
+    ///
+    /// `on_done` runs on a background thread once the runtime has stopped cleanly.
+    fn shutdown(&mut self, on_done: Box<dyn FnOnce() + Send + Sync>) -> anyhow::Result<()>;
 }
 
 pub type NetworkResult = Result<NetworkEvent, NetworkError>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct WithGeneration<T> {
     pub generation: u64,
     pub result: T,
@@ -59,8 +181,11 @@ pub struct WithGeneration<T> {
 
 #[derive(Debug)]
 pub enum NetworkError {
+    /// The task stopped without a result, e.g. it panicked.
     Aborted,
+    /// The network is shutting down.
     SysCancelled,
+    /// `cancel` was called for the request.
     UsrCancelled,
     Timeout,
 }
@@ -70,10 +195,54 @@ pub enum NetworkEvent {
     Captcha(CaptchaEvent),
     Signup(SignupEvent),
     Login(LoginEvent),
+    Refresh(RefreshEvent),
+    Logout(LogoutEvent),
+    History(HistoryEvent),
+    Conversations(ConversationsEvent),
+    Presence(PresenceEvent),
+    Direct(DirectEvent),
+    Attachment(AttachmentEvent),
     Session(SessionEvent),
     Chat(MessageEvent),
+    ChatBatch(MessageBatchEvent),
+    ServerInfo(ServerInfoEvent),
+}
+
+/// The chat protocol this client speaks; a server reporting another one cannot be talked to.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub struct ServerInfoEvent {
+    pub result: Result<ServerInfo, ServerInfoError>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+    pub version: String,
+    pub protocol_version: u32,
+}
+
+impl ServerInfo {
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+}
+
+#[derive(Debug)]
+pub enum ServerInfoError {
+    FallbackError,
+}
+
+impl std::fmt::Display for ServerInfoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerInfoError::FallbackError => write!(f, "The server did not answer like a chat server"),
+        }
+    }
 }
 
+impl std::error::Error for ServerInfoError {}
+
 #[derive(Debug)]
 pub struct CaptchaEvent {
     pub result: Result<CaptchaData, CaptchaError>,
@@ -82,6 +251,8 @@ pub struct CaptchaEvent {
 pub struct CaptchaData {
     pub id: Uuid,
     pub image_base64: String,
+    /// After this the server rejects any answer to the captcha.
+    pub expire_at: DateTime<Utc>,
 }
 
 impl Debug for CaptchaData {
@@ -92,6 +263,7 @@ impl Debug for CaptchaData {
                 "image_base64",
                 &self.image_base64.chars().take(64).collect::<String>(),
             )
+            .field("expire_at", &self.expire_at)
             .finish()
     }
 }
@@ -104,6 +276,8 @@ pub enum CaptchaError {
 #[derive(Debug)]
 pub struct SignupEvent {
     pub result: Result<(), SignupError>,
+    /// The server's own words on the error, if it gave any; shown in place of the error's text.
+    pub detail: Option<String>,
 }
 
 #[derive(Debug)]
@@ -114,15 +288,67 @@ pub enum SignupError {
     FallbackError,
 }
 
+impl std::fmt::Display for SignupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignupError::DuplicateName => write!(f, "Username is already taken"),
+            SignupError::WeakPassword => write!(f, "Password is too weak"),
+            SignupError::WrongCaptcha => write!(f, "Wrong captcha"),
+            SignupError::FallbackError => write!(f, "Signup failed"),
+        }
+    }
+}
+
+impl std::error::Error for SignupError {}
+
+/// A human-readable reason the server sent along with an error, attached as context to the error it
+/// came with, so the error can still be told apart by its type.
+#[derive(Debug, Clone)]
+pub struct ServerDetail(pub String);
+
+impl ServerDetail {
+    /// The detail somewhere in `error`'s chain, if the server gave one.
+    pub fn of(error: &anyhow::Error) -> Option<String> {
+        error.downcast_ref::<ServerDetail>().map(|detail| detail.0.clone())
+    }
+}
+
+impl std::fmt::Display for ServerDetail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 #[derive(Debug)]
 pub struct LoginEvent {
     pub result: Result<TokenInfo, LoginError>,
+    /// The server's own words on the error, if it gave any; shown in place of the error's text.
+    pub detail: Option<String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TokenInfo {
     pub user_id: UserId,
     pub access_token: String,
+    pub access_expires_in: u64,  // seconds
+    pub refresh_token: String,
+    pub refresh_expires_in: u64,  // seconds
+    pub chat_address: Option<String>,
+}
+
+#[cfg(test)]
+impl TokenInfo {
+    /// Tokens for a made-up user, valid for an hour.
+    pub(crate) fn for_test() -> Self {
+        Self {
+            user_id: UserId(Uuid::new_v4()),
+            access_token: "access-token".to_string(),
+            access_expires_in: 3600,
+            refresh_token: "refresh-token".to_string(),
+            refresh_expires_in: 86400,
+            chat_address: None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -132,41 +358,379 @@ pub enum LoginError {
     FallbackError,
 }
 
+impl std::fmt::Display for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginError::Unauthorized => write!(f, "Wrong username or password"),
+            LoginError::WrongCaptcha => write!(f, "Wrong captcha"),
+            LoginError::FallbackError => write!(f, "Login failed"),
+        }
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+#[derive(Debug)]
+pub struct RefreshEvent {
+    pub result: Result<TokenInfo, RefreshError>,
+}
+
+#[derive(Debug)]
+pub enum RefreshError {
+    MissingToken,
+    Expired,
+    FallbackError,
+}
+
+impl std::fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefreshError::MissingToken => write!(f, "Not logged in"),
+            RefreshError::Expired => write!(f, "The session has expired"),
+            RefreshError::FallbackError => write!(f, "Failed to renew the session"),
+        }
+    }
+}
+
+impl std::error::Error for RefreshError {}
+
+#[derive(Debug)]
+pub struct LogoutEvent {
+    pub result: Result<(), LogoutError>,
+}
+
+#[derive(Debug)]
+pub enum LogoutError {
+    MissingToken,
+    FallbackError,
+}
+
+#[derive(Debug)]
+pub struct HistoryEvent {
+    pub conversation_id: ConversationId,
+    pub result: Result<Vec<ChatMessage>, HistoryError>,
+}
+
+#[derive(Debug)]
+pub enum HistoryError {
+    MissingToken,
+    Unauthorized,
+    FallbackError,
+}
+
+#[derive(Debug)]
+pub struct ConversationsEvent {
+    pub result: Result<Vec<ConversationInfo>, ConversationsError>,
+}
+
+#[derive(Debug)]
+pub enum ConversationsError {
+    MissingToken,
+    Unauthorized,
+    FallbackError,
+}
+
+#[derive(Debug)]
+pub struct PresenceEvent {
+    pub result: Result<Vec<PresenceNotification>, PresenceError>,
+}
+
+#[derive(Debug)]
+pub enum PresenceError {
+    MissingToken,
+    Unauthorized,
+    FallbackError,
+}
+
+#[derive(Debug)]
+pub struct DirectEvent {
+    pub username: String,
+    pub result: Result<ConversationInfo, DirectError>,
+}
+
+#[derive(Debug)]
+pub enum DirectError {
+    MissingToken,
+    Unauthorized,
+    UserNotFound,
+    FallbackError,
+}
+
+impl std::fmt::Display for DirectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DirectError::MissingToken | DirectError::Unauthorized => write!(f, "Not logged in"),
+            DirectError::UserNotFound => write!(f, "No such user"),
+            DirectError::FallbackError => write!(f, "Could not open the conversation"),
+        }
+    }
+}
+
+impl std::error::Error for DirectError {}
+
+#[derive(Debug)]
+pub struct AttachmentEvent {
+    pub blob_id: Uuid,
+    pub result: Result<Vec<u8>, AttachmentError>,
+}
+
+#[derive(Debug)]
+pub enum AttachmentError {
+    MissingToken,
+    Unauthorized,
+    FallbackError,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConversationInfo {
+    pub conversation_id: ConversationId,
+    pub kind: ConversationKind,
+    pub display_name: String,
+    pub members: Vec<ConversationMember>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConversationMember {
+    pub user_id: UserId,
+    pub username: String,
+    /// As of when the member was listed; `StreamMessage::Presence` keeps it current.
+    pub online: bool,
+}
+
 #[derive(Debug)]
 pub struct SessionEvent {
     pub result: Result<ChatMetaData, ChatConnError>,
+    /// What the chat server answered the refused handshake with, if it said anything readable.
+    pub detail: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct ChatMetaData;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ChatConnError {
+    /// The server turned the access token down, e.g. it expired; only logging in again helps.
+    Unauthorized,
+    Tls,
+    Refused,
     FallbackError,
 }
 
+impl std::fmt::Display for ChatConnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChatConnError::Unauthorized => write!(f, "The chat server rejected the login, please log in again"),
+            ChatConnError::Tls => write!(f, "Secure connection to the chat server failed"),
+            ChatConnError::Refused => write!(f, "The chat server is not reachable"),
+            ChatConnError::FallbackError => write!(f, "Failed to connect to chat server"),
+        }
+    }
+}
+
+impl std::error::Error for ChatConnError {}
+
 #[derive(Debug)]
 pub struct MessageEvent {
     pub result: Result<MessageSent, MessageError>,
+    /// Why the server refused the message or its attachment, in its own words, if it said.
+    pub detail: Option<String>,
+}
+
+impl MessageEvent {
+    pub fn failed(error: MessageError) -> Self {
+        Self { result: Err(error), detail: None }
+    }
 }
 
+/// Results of `send_chat_messages`, one per message in the order they were passed.
 #[derive(Debug)]
-pub struct MessageSent;
+pub struct MessageBatchEvent {
+    pub results: Vec<MessageEvent>,
+}
 
 #[derive(Debug)]
+pub struct MessageSent {
+    pub seq: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum MessageError {
     MissingSession,
+    Unauthorized,
+    ConnectionLost,
+    UnknownConversation,
+    NotAMember,
+    RateLimited,
+    AckTimeout,
+    UploadFailed,
     FallbackError,
 }
 
-#[derive(Debug)]
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageError::MissingSession => write!(f, "Not connected to chat"),
+            MessageError::Unauthorized => write!(f, "Not logged in"),
+            MessageError::ConnectionLost => write!(f, "The connection was lost"),
+            MessageError::UnknownConversation => write!(f, "The conversation no longer exists"),
+            MessageError::NotAMember => write!(f, "You are not in this conversation"),
+            MessageError::RateLimited => write!(f, "Sending too fast, slow down"),
+            MessageError::AckTimeout => write!(f, "The server did not confirm it in time"),
+            MessageError::UploadFailed => write!(f, "The attachment could not be uploaded"),
+            MessageError::FallbackError => write!(f, "Sending failed"),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+#[derive(Debug, Clone)]
 pub enum StreamMessage {
     Distribute(ChatMessage),
+    ConnectionState(ConnectionState),
+    SessionError(SessionError),
+    Typing(TypingNotification),
+    Read(ReadNotification),
+    Presence(PresenceNotification),
+    Membership(MembershipNotification),
+    /// The WebSocket dropped; a `ConnectionState` follows once reconnecting starts.
+    Disconnected { reason: DisconnectReason },
+    /// Incoming messages arrived faster than they were handled and the oldest `count` were skipped.
+    MessagesDropped { count: u64 },
 }
 
-#[derive(Debug)]
+impl StreamMessage {
+    /// The conversation this is about; `None` for messages about the connection as a whole.
+    pub fn conversation_id(&self) -> Option<&ConversationId> {
+        match self {
+            StreamMessage::Distribute(message) => Some(&message.conversation_id),
+            StreamMessage::Typing(notification) => Some(&notification.conversation_id),
+            StreamMessage::Read(notification) => Some(&notification.conversation_id),
+            StreamMessage::Membership(notification) => Some(&notification.conversation_id),
+            StreamMessage::ConnectionState(_)
+            | StreamMessage::SessionError(_)
+            | StreamMessage::Presence(_)
+            | StreamMessage::Disconnected { .. }
+            | StreamMessage::MessagesDropped { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    ClosedByServer,
+    ClosedByClient,
+    ConnectionError,
+    /// Closed with 1008 (policy violation), which the server sends when the access token expired
+    /// or was revoked.
+    AuthRevoked,
+    /// Closed with 1011 (internal error).
+    ServerError,
+    /// Reconnecting failed `NetworkConfig::reconnect_attempts` times in a row and was stopped.
+    GaveUp,
+}
+
+impl DisconnectReason {
+    /// Whether reconnecting with the same access token may help; otherwise it has to be renewed first.
+    pub fn is_transient(&self) -> bool {
+        !matches!(self, DisconnectReason::AuthRevoked)
+    }
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::ClosedByServer => write!(f, "Closed by server"),
+            DisconnectReason::ClosedByClient => write!(f, "Closed by client"),
+            DisconnectReason::ConnectionError => write!(f, "Connection error"),
+            DisconnectReason::AuthRevoked => write!(f, "Session revoked by server"),
+            DisconnectReason::ServerError => write!(f, "Server error"),
+            DisconnectReason::GaveUp => write!(f, "Too many failed reconnect attempts"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// `connect_chat` is in flight.
+    Connecting,
+    Connected,
+    Reconnecting,
+    /// The server turned the access token down; a renewed one is being fetched before reconnecting.
+    Reauthenticating,
+    Disconnected,
+}
+
+/// A snapshot of `NetworkInterface::metrics`; the connection counts start over with each `connect_chat`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionMetrics {
+    /// Round trip of the last answered WebSocket ping; `None` before the first pong.
+    pub last_rtt: Option<Duration>,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    /// Connections re-established after a drop.
+    pub reconnects: u64,
+    /// How long the current connection has been up; `None` while there is none.
+    pub connected_for: Option<Duration>,
+    /// Requests running now, up to `NetworkConfig::max_in_flight`; those waiting for a slot are not counted.
+    pub in_flight: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SessionError {
+    RefreshFailed,
+}
+
+#[derive(Debug, Clone)]
+pub struct TypingNotification {
+    pub sender: UserId,
+    pub conversation_id: ConversationId,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadNotification {
+    pub conversation_id: ConversationId,
+    pub reader: UserId,
+    pub up_to_seq: u64,
+}
+
+/// About the user, whichever conversations they are in.
+#[derive(Debug, Clone)]
+pub struct PresenceNotification {
+    pub user_id: UserId,
+    pub online: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct MembershipNotification {
+    pub conversation_id: ConversationId,
+    pub member: ConversationMember,
+    /// Otherwise the member left.
+    pub joined: bool,
+}
+
+/// A file for `NetworkInterface::send_chat_attachment`.
+#[derive(Debug, Clone)]
+pub struct AttachmentRequest {
+    pub conversation_id: ConversationId,
+    /// See `NetworkInterface::send_chat_message`.
+    pub client_id: Uuid,
+    /// May be empty.
+    pub caption: String,
+    pub bytes: Vec<u8>,
+    pub mime: String,
+    pub filename: String,
+}
+
+#[derive(Debug, Clone)]
 pub struct ChatMessage {
     pub sender: UserId,
+    pub sender_name: Option<String>,
     pub conversation_id: ConversationId,
-    pub content: String,
+    pub content: ChatBody,
+    pub sent_at: DateTime<Utc>,
+    pub seq: Option<u64>,
+    pub attachment: Option<Attachment>,
+    /// The `client_id` the sender sent this under, if the server echoes it.
+    pub client_id: Option<Uuid>,
 }
@@ -1,137 +1,530 @@
-use crate::domain::ConversationId;
+use crate::domain::{Attachment, ChatBody, ConversationId};
 use crate::protocol::network::{worker::*, ws_message::*, *};
+use chrono::{DateTime, TimeDelta, Utc};
 use dashmap::DashMap;
+use std::collections::{HashSet, VecDeque};
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tokio::sync::{Mutex, Notify};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::{oneshot, Mutex, Notify, Semaphore};
 use tokio::task::{AbortHandle, JoinHandle};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, debug_span, error, info, info_span, trace, warn, Instrument, Span};
+use url::Url;
 use uuid::Uuid;
 
 static INSTANCE_COUNTER: AtomicU64 = AtomicU64::new(0);
+const REFRESH_MARGIN: Duration = Duration::from_secs(30);
+const RUNTIME_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+const RECENT_CLIENT_IDS: usize = 256;
+/// Reconnects a send may be replayed over before it fails with `MessageError::ConnectionLost`.
+const MAX_REPLAYS: u32 = 3;
+
+type AckSender = oneshot::Sender<MessageEvent>;
+
+/// A file waiting to be uploaded by a send task.
+struct Upload {
+    bytes: Vec<u8>,
+    mime: String,
+    filename: String,
+}
+
+/// A message for `dispatch_send`, which uploads `upload` first if there is one.
+struct SendRequest {
+    conversation_id: ConversationId,
+    client_id: Uuid,
+    content: ChatBody,
+    upload: Option<Upload>,
+}
+
+/// Everything needed to put a message on the wire, again if a reconnect calls for it.
+#[derive(Clone)]
+struct OutgoingMessage {
+    client_id: Uuid,
+    conversation_id: ConversationId,
+    content: ChatBody,
+    attachment: Option<Attachment>,
+}
+
+impl OutgoingMessage {
+    async fn send(self, worker: &dyn WsWorker, message_seq: u64) -> anyhow::Result<()> {
+        worker.send_message(message_seq, self.client_id, self.conversation_id, self.content, self.attachment).await
+    }
+}
+
+/// A send waiting for its ACK.
+struct PendingSend {
+    ack_tx: AckSender,
+    message: OutgoingMessage,
+    replays: u32,
+    /// Notified each time a replay gets the message out.
+    replayed: Arc<Notify>,
+}
+
+/// Keeps a send's `message_buffer` entry alive until the send task finishes or is aborted.
+struct PendingAck {
+    message_id: u64,
+    message_buffer: Arc<DashMap<u64, PendingSend>>,
+    replayed: Arc<Notify>,
+}
+
+impl PendingAck {
+    fn insert(message_id: u64, message: OutgoingMessage, message_buffer: Arc<DashMap<u64, PendingSend>>) -> (Self, oneshot::Receiver<MessageEvent>) {
+        let (ack_tx, ack_rx) = oneshot::channel();
+        let replayed = Arc::new(Notify::new());
+        message_buffer.insert(message_id, PendingSend { ack_tx, message, replays: 0, replayed: replayed.clone() });
+        trace!(message_seq = message_id, "Insert pending message");
+        (Self { message_id, message_buffer, replayed }, ack_rx)
+    }
+}
+
+impl Drop for PendingAck {
+    fn drop(&mut self) {
+        if self.message_buffer.remove(&self.message_id).is_some() {
+            trace!(message_seq = self.message_id, "Remove pending message");
+        }
+    }
+}
+
+/// The chat session's `ConnectionState`, kept outside `session_record` so reading it takes no lock.
+#[derive(Default)]
+struct SessionState(AtomicU8);
+
+impl SessionState {
+    fn get(&self) -> ConnectionState {
+        match self.0.load(Ordering::Acquire) {
+            1 => ConnectionState::Connecting,
+            2 => ConnectionState::Connected,
+            3 => ConnectionState::Reconnecting,
+            4 => ConnectionState::Reauthenticating,
+            _ => ConnectionState::Disconnected,
+        }
+    }
+
+    fn set(&self, state: ConnectionState) {
+        self.0.store(Self::encode(state), Ordering::Release);
+    }
+
+    /// Sets `to` only while the state is still `from`, so a newer transition is not overwritten.
+    fn replace(&self, from: ConnectionState, to: ConnectionState) {
+        let _ = self.0.compare_exchange(Self::encode(from), Self::encode(to), Ordering::AcqRel, Ordering::Acquire);
+    }
+
+    fn encode(state: ConnectionState) -> u8 {
+        match state {
+            ConnectionState::Disconnected => 0,
+            ConnectionState::Connecting => 1,
+            ConnectionState::Connected => 2,
+            ConnectionState::Reconnecting => 3,
+            ConnectionState::Reauthenticating => 4,
+        }
+    }
+}
+
+/// Falls back to `Disconnected` when a connect attempt ends without connecting, e.g. on timeout.
+struct ConnectAttempt(Arc<SessionState>);
+
+impl Drop for ConnectAttempt {
+    fn drop(&mut self) {
+        self.0.replace(ConnectionState::Connecting, ConnectionState::Disconnected);
+    }
+}
+
+/// Client ids of the latest distributed messages, so a resend the server distributed twice shows once.
+#[derive(Default)]
+struct RecentClientIds {
+    order: VecDeque<Uuid>,
+    seen: HashSet<Uuid>,
+}
+
+impl RecentClientIds {
+    /// Returns `false` if `client_id` was already seen.
+    fn insert(&mut self, client_id: Uuid) -> bool {
+        if !self.seen.insert(client_id) {
+            return false;
+        }
+        self.order.push_back(client_id);
+        if self.order.len() > RECENT_CLIENT_IDS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Reports a task's result once; a task dropped before reporting, e.g. aborted or panicked,
+/// reports `NetworkError::Aborted` instead.
+struct ResultReporter {
+    generation: u64,
+    result_tx: Option<mpsc::Sender<WithGeneration<NetworkResult>>>,
+    dropped_results: Arc<AtomicU64>,
+}
+
+impl ResultReporter {
+    /// Waits for room in the result channel, so a backed-up UI slows the tasks down.
+    async fn report(mut self, result: NetworkResult) {
+        if let Some(result_tx) = self.result_tx.take() {
+            let _ = result_tx.send(WithGeneration { generation: self.generation, result }).await;
+        }
+    }
+}
+
+impl Drop for ResultReporter {
+    fn drop(&mut self) {
+        if let Some(result_tx) = self.result_tx.take() {
+            debug!(generation = self.generation, "Task was aborted");
+            let result = WithGeneration { generation: self.generation, result: Err(NetworkError::Aborted) };
+            // Dropping cannot wait for room, so the result is lost if the channel is full.
+            if let Err(TrySendError::Full(_)) = result_tx.try_send(result) {
+                let dropped = self.dropped_results.fetch_add(1, Ordering::Relaxed) + 1;
+                error!(generation = self.generation, dropped, "Result channel full, dropping task result");
+            }
+        }
+    }
+}
 
 struct TaskRecord {
     pub abort_handle: AbortHandle,
     pub callback: Box<dyn FnOnce(WithGeneration<NetworkResult>) + Send + Sync>,
 }
 
+type StreamCallback = Arc<Box<dyn Fn(StreamMessage) + Send + Sync>>;
+
+/// Callbacks added through `subscribe_stream`; they outlive any one session.
+#[derive(Default)]
+struct StreamSubscribers {
+    next_id: u64,
+    callbacks: Vec<(u64, StreamCallback)>,
+}
+
 struct SessionRecord {
+    /// The `connect_chat` generation this session came from.
+    pub generation: u64,
     pub ws_worker: Arc<Box<dyn WsWorker>>,
     pub task_handle: JoinHandle<()>,
-    pub callback: Arc<Box<dyn Fn(StreamMessage) + Send + Sync>>,
+    pub supervisor_handle: JoinHandle<()>,
+    pub callback: StreamCallback,
+    pub subscribers: Arc<std::sync::Mutex<StreamSubscribers>>,
+}
+
+impl SessionRecord {
+    fn emit(&self, stream_message: StreamMessage) {
+        Self::run_callback(self.callback.clone(), stream_message.clone());
+        // Copied out, so a callback that subscribes or unsubscribes does not deadlock.
+        let subscribers = self.subscribers
+            .lock()
+            .unwrap()
+            .callbacks
+            .iter()
+            .map(|(_, callback)| callback.clone())
+            .collect::<Vec<_>>();
+        for subscriber in subscribers {
+            Self::run_callback(subscriber, stream_message.clone());
+        }
+    }
+
+    fn run_callback(callback: StreamCallback, stream_message: StreamMessage) {
+        let callback = std::panic::AssertUnwindSafe(move || callback(stream_message));
+        if let Err(e) = std::panic::catch_unwind(callback) {
+            error!("Map function for WebSocket stream panicked: {:?}", e);
+        }
+    }
+}
+
+/// Everything needed to (re-)establish the WebSocket connection of one chat session.
+struct SessionConnector {
+    pub generation: u64,
+    pub ws_url: Url,
+    pub ws_connector: Arc<dyn WsConnector>,
+    pub chat_token: Arc<std::sync::Mutex<String>>,
+    pub http_worker: Box<dyn HttpWorker>,
+    pub auth_record: Arc<Mutex<Option<AuthRecord>>>,
+    pub message_tx: broadcast::Sender<WithGeneration<ServerToClient>>,
+    pub counters: Arc<ConnectionCounters>,
+    pub reconnect_attempts: u32,
+    pub reconnect_initial_backoff: Duration,
+    pub reconnect_max_backoff: Duration,
+}
+
+impl SessionConnector {
+    async fn access_token(&self) -> Result<String, RefreshError> {
+        let token = NetworkImpl::fresh_access_token(self.http_worker.as_ref(), &self.auth_record, &self.chat_token).await?;
+        Ok(token.unwrap_or_default())
+    }
+
+    /// A renewed access token even if the current one looks valid, for when the server turned it down.
+    async fn renewed_access_token(&self) -> Result<String, RefreshError> {
+        let mut auth_record = self.auth_record.lock().await;
+        let token_info = NetworkImpl::refresh_auth(self.http_worker.as_ref(), &mut auth_record, &self.chat_token).await?;
+        Ok(token_info.access_token)
+    }
+
+    async fn connect(&self, access_token: String) -> anyhow::Result<Box<dyn WsWorker>> {
+        self.ws_connector.connect(
+            self.ws_url.clone(),
+            self.generation,
+            access_token,
+            self.message_tx.clone(),
+            self.counters.clone(),
+        ).await
+    }
+}
+
+struct AuthRecord {
+    pub token_info: TokenInfo,
+    pub access_expires_at: Instant,
+    pub refresh_expires_at: Instant,
+}
+
+impl AuthRecord {
+    fn new(token_info: TokenInfo) -> Self {
+        let now = Instant::now();
+        Self {
+            access_expires_at: now + Duration::from_secs(token_info.access_expires_in),
+            refresh_expires_at: now + Duration::from_secs(token_info.refresh_expires_in),
+            token_info,
+        }
+    }
+
+    /// Swaps in a new access token good for `expires_in` seconds; the refresh token stays as it is.
+    fn replace_access_token(&mut self, access_token: String, expires_in: u64) {
+        self.access_expires_at = Instant::now() + Duration::from_secs(expires_in);
+        self.token_info.access_token = access_token;
+        self.token_info.access_expires_in = expires_in;
+    }
 }
 
 pub struct NetworkImpl {
     span: Span,
+    config: NetworkConfig,
+    ws_connector: Arc<dyn WsConnector>,
 
     generation: AtomicU64,
     task_records: Arc<DashMap<u64, TaskRecord>>,
+    /// One permit per running task, see `NetworkConfig::max_in_flight`.
+    task_permits: Arc<Semaphore>,
     cancellation_token: CancellationToken,
     runtime_handle: tokio::runtime::Handle,
-    join_set: tokio::task::JoinSet<()>,
+    /// Whether the runtime's clock only moves when idle or told to, see `with_paused_clock`.
+    #[cfg(any(test, feature = "manual-test"))]
+    clock_paused: bool,
 
-    result_tx: UnboundedSender<WithGeneration<NetworkResult>>,
-    runtime_thread_handle: std::thread::JoinHandle<()>,
+    result_tx: mpsc::Sender<WithGeneration<NetworkResult>>,
+    /// Results lost to a full result channel, see `NetworkConfig::channel_capacity`.
+    dropped_results: Arc<AtomicU64>,
+    runtime_thread_handle: Option<std::thread::JoinHandle<()>>,
 
     http_worker: Box<dyn HttpWorker>,
+    auth_record: Arc<Mutex<Option<AuthRecord>>>,
+    /// The token `connect_chat` was given, or a newer one; reconnects fall back to it without an `auth_record`.
+    chat_token: Arc<std::sync::Mutex<String>>,
 
     session_record: Arc<Mutex<Option<SessionRecord>>>,
+    /// The generation of the session in `session_record`, kept outside it so the UI thread can
+    /// tell which session is current without waiting for the runtime.
+    session_generation: Arc<std::sync::Mutex<Option<u64>>>,
+    session_state: Arc<SessionState>,
     message_id: AtomicU64,
-    message_buffer: Arc<DashMap<u64, Arc<Notify>>>,
+    message_buffer: Arc<DashMap<u64, PendingSend>>,
+    recent_client_ids: Arc<std::sync::Mutex<RecentClientIds>>,
+    /// Server clock minus the local clock in milliseconds, see `sync_clock`.
+    clock_offset_ms: Arc<AtomicI64>,
+    counters: Arc<ConnectionCounters>,
+    stream_subscribers: Arc<std::sync::Mutex<StreamSubscribers>>,
 }
 
 impl NetworkImpl {
-    pub fn try_new() -> anyhow::Result<Self> {
+    pub fn try_new(config: NetworkConfig) -> anyhow::Result<Self> {
+        Self::build(config, None, Self::runtime_builder())
+    }
+
+    /// Like `try_new`, but chat sessions connect through `ws_connector`, e.g. a `FakeWsConnector`,
+    /// so they run without a chat server.
+    #[cfg(any(test, feature = "manual-test"))]
+    pub fn with_ws_connector(config: NetworkConfig, ws_connector: Arc<dyn WsConnector>) -> anyhow::Result<Self> {
+        Self::build(config, Some(ws_connector), Self::runtime_builder())
+    }
+
+    /// Like `with_ws_connector`, on a runtime whose clock stands still: whenever nothing is ready to run
+    /// it jumps straight to the next timer, so timeouts, backoffs and ACK deadlines pass at once and in
+    /// order. `advance_clock` moves it by hand. Only tokio's timers follow it; `Instant::now` does not.
+    #[cfg(any(test, feature = "manual-test"))]
+    pub fn with_paused_clock(config: NetworkConfig, ws_connector: Arc<dyn WsConnector>) -> anyhow::Result<Self> {
+        let mut builder = Self::runtime_builder();
+        builder.start_paused(true);
+        let mut network = Self::build(config, Some(ws_connector), builder)?;
+        network.clock_paused = true;
+        Ok(network)
+    }
+
+    /// Moves the paused clock of a `with_paused_clock` instance forward, firing the timers due by then.
+    #[cfg(any(test, feature = "manual-test"))]
+    pub fn advance_clock(&self, duration: Duration) -> anyhow::Result<()> {
+        anyhow::ensure!(self.clock_paused, "The clock is not paused");
+        self.runtime_handle.block_on(tokio::time::advance(duration));
+        Ok(())
+    }
+
+    fn runtime_builder() -> tokio::runtime::Builder {
+        let mut builder = tokio::runtime::Builder::new_current_thread();
+        builder.enable_all();
+        builder
+    }
+
+    fn build(
+        config: NetworkConfig,
+        ws_connector: Option<Arc<dyn WsConnector>>,
+        mut runtime_builder: tokio::runtime::Builder,
+    ) -> anyhow::Result<Self> {
         let id = INSTANCE_COUNTER.fetch_add(1, Ordering::Relaxed);
         let span = debug_span!("NetworkImpl", instance_id = id);
 
+        let tls_config = load_tls_config(config.cert_path.as_deref())?;
+        let ws_connector = ws_connector.unwrap_or_else(|| Arc::new(RealWsConnector { tls_config: tls_config.clone() }));
+        anyhow::ensure!(config.channel_capacity > 0, "Channel capacity must not be zero");
 
         let generation = AtomicU64::new(0);
         let task_records = Arc::new(DashMap::new());
+        // No permits at all would leave every task waiting until it times out.
+        let task_permits = Arc::new(Semaphore::new(config.max_in_flight.max(1)));
         let cancellation_token = CancellationToken::new();
 
-        let (result_tx, result_rx) = unbounded_channel::<WithGeneration<NetworkResult>>();
-        let tokio_runtime = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?;
+        let (result_tx, result_rx) = mpsc::channel::<WithGeneration<NetworkResult>>(config.channel_capacity);
+        let dropped_results = Arc::new(AtomicU64::new(0));
+        let tokio_runtime = runtime_builder.build()?;
         let runtime_handle = tokio_runtime.handle().clone();
 
         let span_clone = span.clone();
         let records_clone = task_records.clone();
         let cancellation_token_clone = cancellation_token.clone();
-        let runtime_thread_handle = std::thread::spawn(move || {
-            tokio_runtime.block_on(Self::send_result_back(
-                records_clone,
-                cancellation_token_clone,
-                result_rx,
-            ).instrument(span_clone))
-        });
+        // Named so the runtime can be told apart from other instances in debuggers and crash dumps.
+        let runtime_thread_handle = std::thread::Builder::new()
+            .name(format!("client-net-{}", id))
+            .spawn(move || {
+                tokio_runtime.block_on(Self::send_result_back(
+                    records_clone,
+                    cancellation_token_clone,
+                    result_rx,
+                ).instrument(span_clone))
+            })?;
 
-        let join_set = tokio::task::JoinSet::new();
-
-        let http_worker = Box::new(RealHttpWorker::new());
+        let http_worker = Box::new(RealHttpWorker::try_new(config.api_base_url.clone(), tls_config.clone())?);
+        let auth_record = Arc::new(Mutex::new(None));
+        let chat_token = Arc::new(std::sync::Mutex::new(String::new()));
         let session_record = Arc::new(Mutex::new(None));
+        let session_generation = Arc::new(std::sync::Mutex::new(None));
+        let session_state = Arc::new(SessionState::default());
         let message_id = AtomicU64::new(0);
         let message_buffer = Arc::new(DashMap::new());
+        let recent_client_ids = Arc::new(std::sync::Mutex::new(RecentClientIds::default()));
+        let clock_offset_ms = Arc::new(AtomicI64::new(0));
+        let counters = Arc::new(ConnectionCounters::default());
+        let stream_subscribers = Arc::new(std::sync::Mutex::new(StreamSubscribers::default()));
 
         Ok(Self {
             span,
+            config,
+            ws_connector,
             generation,
             task_records,
+            task_permits,
             cancellation_token,
             runtime_handle,
-            join_set,
+            #[cfg(any(test, feature = "manual-test"))]
+            clock_paused: false,
             result_tx,
-            runtime_thread_handle,
+            dropped_results,
+            runtime_thread_handle: Some(runtime_thread_handle),
             http_worker,
+            auth_record,
+            chat_token,
             session_record,
+            session_generation,
+            session_state,
             message_id,
             message_buffer,
+            recent_client_ids,
+            clock_offset_ms,
+            counters,
+            stream_subscribers,
         })
     }
 
+    /// Estimates the clock offset from one `/time` round trip, taking the server's reading to be
+    /// from halfway through it; a failure keeps the previous estimate.
+    async fn sync_clock(worker: Box<dyn HttpWorker>, clock_offset_ms: Arc<AtomicI64>) {
+        let sent_at = Utc::now();
+        let server_now = match worker.server_time().await {
+            Ok(server_now) => server_now,
+            Err(error) => {
+                warn!("Failed to read the server clock: {:?}", error);
+                return;
+            }
+        };
+        let received_at = Utc::now();
+        let offset = server_now - (sent_at + (received_at - sent_at) / 2);
+        debug!("Server clock is {} ms ahead", offset.num_milliseconds());
+        clock_offset_ms.store(offset.num_milliseconds(), Ordering::Relaxed);
+    }
+
+    /// Number of task results lost to a full result channel so far.
+    pub fn dropped_results(&self) -> u64 {
+        self.dropped_results.load(Ordering::Relaxed)
+    }
+
     async fn send_result_back(
         task_records: Arc<DashMap<u64, TaskRecord>>,
         cancellation_token: CancellationToken,
-        mut result_rx: UnboundedReceiver<WithGeneration<NetworkResult>>,
+        mut result_rx: mpsc::Receiver<WithGeneration<NetworkResult>>,
     ) {
         loop {
             tokio::select! {
                 biased;
                 _ = cancellation_token.cancelled() => {
-                    let undone = result_rx.len();
-                    warn!("Unhandled messages when shutting down: {}", undone);
+                    // Results already queued still reach their callbacks; later ones are dropped with the runtime.
+                    let mut drained = 0;
+                    while let Ok(with_generation) = result_rx.try_recv() {
+                        Self::run_task_callback(&task_records, with_generation);
+                        drained += 1;
+                    }
+                    if drained > 0 {
+                        debug!("Delivered results when shutting down: {}", drained);
+                    }
+                    break;
                 }
                 result = result_rx.recv() => match result {
                     None => break,
-                    Some(with_generation) => {
-                        let generation = with_generation.generation;
-                        trace!("Retrieving task callback: {}", generation);
-                        if let Some((_, TaskRecord {abort_handle, callback})) = task_records.remove(&generation) {
-                            trace!("Executing task callback: {}", generation);
-                            abort_handle.abort();
-                            let callback = std::panic::AssertUnwindSafe(move || callback(with_generation));
-                            if let Err(e) = std::panic::catch_unwind(callback) {
-                                error!("Map function for {} panicked: {:?}", generation, e);
-                            }
-                        }
-                    }
+                    Some(with_generation) => Self::run_task_callback(&task_records, with_generation),
                 }
             }
         }
     }
 
+    fn run_task_callback(task_records: &DashMap<u64, TaskRecord>, with_generation: WithGeneration<NetworkResult>) {
+        let generation = with_generation.generation;
+        trace!(generation, "Retrieving task callback");
+        if let Some((_, TaskRecord {abort_handle, callback})) = task_records.remove(&generation) {
+            trace!(generation, "Executing task callback");
+            abort_handle.abort();
+            let callback = std::panic::AssertUnwindSafe(move || callback(with_generation));
+            if let Err(e) = std::panic::catch_unwind(callback) {
+                error!(generation, "Map function panicked: {:?}", e);
+            }
+        }
+    }
+
     async fn send_message_back(
         notify: Arc<Notify>,
         session_record: Arc<Mutex<Option<SessionRecord>>>,
-        message_buffer: Arc<DashMap<u64, Arc<Notify>>>,
+        message_buffer: Arc<DashMap<u64, PendingSend>>,
+        recent_client_ids: Arc<std::sync::Mutex<RecentClientIds>>,
         cancellation_token: CancellationToken,
-        mut message_rx: UnboundedReceiver<WithGeneration<ServerToClient>>,
+        mut message_rx: broadcast::Receiver<WithGeneration<ServerToClient>>,
     ) {
         notify.notified().await; // Wait until session_record is initialized
 
@@ -140,42 +533,94 @@ impl NetworkImpl {
                 biased;
                 _ = cancellation_token.cancelled() => {
                     let undone = message_rx.len();
-                    warn!("Unhandled WebSocket messages when shutting down: {}", undone);
+                    warn!(undone, "Unhandled WebSocket messages when shutting down");
+                    break;
                 }
                 message = message_rx.recv() => match message {
-                    None => break,
-                    Some(with_generation) => {
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(count)) => {
+                        warn!(count, "Dropped WebSocket messages that were not handled in time");
+                        if let Some(record) = &*session_record.lock().await {
+                            record.emit(StreamMessage::MessagesDropped { count });
+                        }
+                    }
+                    Ok(with_generation) => {
                         let generation = with_generation.generation;
                         match with_generation.result {
                             ServerToClient::Distribute(message) => {
-                                trace!("Receiving message: {}", message.content.content);
+                                if let Some(client_id) = message.client_id {
+                                    if !recent_client_ids.lock().unwrap().insert(client_id) {
+                                        debug!(%client_id, "Skip duplicate message");
+                                        continue;
+                                    }
+                                }
+                                trace!(generation, conversation_id = %message.content.conversation_id.0, seq = message.seq, "Receiving message");
                                 let stream_message = StreamMessage::Distribute(ChatMessage {
                                     sender: message.sender,
+                                    sender_name: message.sender_name,
                                     conversation_id: message.content.conversation_id,
                                     content: message.content.content,
+                                    sent_at: message.sent_at,
+                                    seq: message.seq,
+                                    attachment: message.content.attachment,
+                                    client_id: message.client_id,
                                 });
 
-                                debug!("Before get the lock");
                                 if let Some(record) = &*session_record.lock().await {
-                                    debug!("After get the lock");
-                                    let callback = record.callback.clone();
-                                    let callback = std::panic::AssertUnwindSafe(move || callback(stream_message));
-                                    if let Err(e) = std::panic::catch_unwind(callback) {
-                                        error!("Map function for WebSocket stream {} panicked: {:?}", generation, e);
-                                    }
+                                    record.emit(stream_message);
                                 }
                             }
-                            ServerToClient::ACK(ACK {message_seq}) => {
-                                trace!("Receiving ACK: {:?}", message_seq);
-                                let (_, notify) = match message_buffer.remove(&message_seq) {
+                            ServerToClient::ACK(ACK { message_seq, seq, .. }) => {
+                                trace!(message_seq, "Receiving ACK");
+                                let (_, PendingSend { ack_tx, .. }) = match message_buffer.remove(&message_seq) {
                                     Some(inner) => inner,
                                     None => {
-                                        trace!("Got None when ACK is received: {:?}", message_seq);
-                                        break;
+                                        // Late or duplicate ACKs are harmless, keep the stream alive.
+                                        warn!(message_seq, "Skip ACK for unknown message");
+                                        continue;
                                     }
                                 };
-                                notify.notify_one();
-                                trace!("Notify one: {:?}", message_seq);
+                                let _ = ack_tx.send(MessageEvent { result: Ok(MessageSent { seq }), detail: None });
+                                trace!(message_seq, "Acknowledge one");
+                            }
+                            ServerToClient::NACK(NACK { message_seq, reason, detail }) => {
+                                debug!(message_seq, ?reason, "Receiving NACK");
+                                let Some((_, PendingSend { ack_tx, .. })) = message_buffer.remove(&message_seq) else {
+                                    warn!(message_seq, "Skip NACK for unknown message");
+                                    continue;
+                                };
+                                let error = match reason {
+                                    NackReason::UnknownConversation => MessageError::UnknownConversation,
+                                    NackReason::NotAMember => MessageError::NotAMember,
+                                    NackReason::RateLimited => MessageError::RateLimited,
+                                    NackReason::Other => MessageError::FallbackError,
+                                };
+                                let _ = ack_tx.send(MessageEvent { result: Err(error), detail });
+                            }
+                            ServerToClient::Typing(TypingMessage { sender, conversation_id }) => {
+                                if let Some(record) = &*session_record.lock().await {
+                                    record.emit(StreamMessage::Typing(TypingNotification { sender, conversation_id }));
+                                }
+                            }
+                            ServerToClient::Read(ReadMessage { conversation_id, reader, up_to_seq }) => {
+                                if let Some(record) = &*session_record.lock().await {
+                                    record.emit(StreamMessage::Read(ReadNotification { conversation_id, reader, up_to_seq }));
+                                }
+                            }
+                            ServerToClient::Presence(PresenceMessage { user_id, online }) => {
+                                if let Some(record) = &*session_record.lock().await {
+                                    record.emit(StreamMessage::Presence(PresenceNotification { user_id, online }));
+                                }
+                            }
+                            ServerToClient::Membership(MembershipMessage { conversation_id, user_id, username, change, online }) => {
+                                let member = ConversationMember { user_id, username, online };
+                                let joined = change == MembershipChange::Joined;
+                                if let Some(record) = &*session_record.lock().await {
+                                    record.emit(StreamMessage::Membership(MembershipNotification { conversation_id, member, joined }));
+                                }
+                            }
+                            ServerToClient::Unknown => {
+                                debug!(generation, "Skip unknown message type");
                             }
                         };
                     }
@@ -184,82 +629,233 @@ impl NetworkImpl {
         }
     }
 
-    pub fn create_task(
-        &mut self,
-        task: Pin<Box<dyn Future<Output = NetworkEvent> + Send>>,
-        timeout: Duration,
-        callback: Box<dyn FnOnce(WithGeneration<NetworkResult>) + Send + Sync>,
-    ) -> anyhow::Result<u64> {
-        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
-        let cancellation_token = self.cancellation_token.clone();
-        let result_tx = self.result_tx.clone();
+    /// Waits for the session's connection to drop and re-establishes it with exponential backoff,
+    /// unless the server closed it for good or `NetworkConfig::reconnect_attempts` ran out.
+    async fn supervise_session(
+        connector: SessionConnector,
+        mut ws_worker: Arc<Box<dyn WsWorker>>,
+        session_record: Arc<Mutex<Option<SessionRecord>>>,
+        session_state: Arc<SessionState>,
+        message_buffer: Arc<DashMap<u64, PendingSend>>,
+        cancellation_token: CancellationToken,
+    ) {
+        loop {
+            let reason = tokio::select! {
+                _ = cancellation_token.cancelled() => break,
+                reason = ws_worker.closed() => reason,
+            };
 
-        let notify = Arc::new(Notify::new());
-        let notify_clone = notify.clone();
-        let cancellation_wrapped = async move {
-            notify_clone.notified().await;
-            let timeout_wrapped = async {
-                match tokio::time::timeout(timeout, task).await {
-                    Ok(e) => {
-                        debug!("Task finished: {}", generation);
-                        let message = WithGeneration {
-                            generation,
-                            result: Ok(e),
-                        };
-                        let _ = result_tx.send(message);
+            warn!(?reason, "Chat connection lost");
+            // The server would turn the same token away again, so the first attempt renews it;
+            // only a failed renewal ends the session, as `SessionError::RefreshFailed`.
+            let mut renew_token = !reason.is_transient();
+            let state = if renew_token { ConnectionState::Reauthenticating } else { ConnectionState::Reconnecting };
+
+            // Sends still waiting for an ACK are replayed once reconnected instead of failing here.
+            session_state.set(state);
+            if let Some(record) = &*session_record.lock().await {
+                record.emit(StreamMessage::Disconnected { reason });
+                record.emit(StreamMessage::ConnectionState(state));
+            }
+
+            let mut backoff = connector.reconnect_initial_backoff;
+            let mut attempts = 0;
+            let worker = loop {
+                // Left to the user from here, e.g. the lobby's Reconnect button, rather than retrying forever.
+                if attempts == connector.reconnect_attempts {
+                    warn!(attempts, "Giving up on chat connection");
+                    session_state.set(ConnectionState::Disconnected);
+                    Self::fail_pending_messages(&message_buffer);
+                    if let Some(record) = &*session_record.lock().await {
+                        record.emit(StreamMessage::Disconnected { reason: DisconnectReason::GaveUp });
                     }
-                    Err(_) => {
-                        debug!("Task {} timed out", generation);
-                        let message = WithGeneration {
-                            generation,
-                            result: Err(NetworkError::Timeout),
-                        };
-                        let _ = result_tx.send(message);
+                    return;
+                }
+                let access_token = if std::mem::take(&mut renew_token) {
+                    connector.renewed_access_token().await
+                } else {
+                    connector.access_token().await
+                };
+                let access_token = match access_token {
+                    Ok(access_token) => access_token,
+                    Err(error) => {
+                        warn!("Failed to refresh access token before reconnecting: {:?}", error);
+                        session_state.set(ConnectionState::Disconnected);
+                        Self::fail_pending_messages(&message_buffer);
+                        if let Some(record) = &*session_record.lock().await {
+                            record.emit(StreamMessage::SessionError(SessionError::RefreshFailed));
+                        }
+                        return;
+                    }
+                };
+
+                match connector.connect(access_token).await {
+                    Ok(worker) => break worker,
+                    Err(error) => {
+                        attempts += 1;
+                        // The token is renewed by now, so what is left is a plain reconnect.
+                        if session_state.get() == ConnectionState::Reauthenticating {
+                            session_state.set(ConnectionState::Reconnecting);
+                            if let Some(record) = &*session_record.lock().await {
+                                record.emit(StreamMessage::ConnectionState(ConnectionState::Reconnecting));
+                            }
+                        }
+                        if attempts == connector.reconnect_attempts {
+                            warn!("Failed to reconnect: {:?}", error);
+                            continue;
+                        }
+                        warn!(?backoff, "Failed to reconnect, retrying: {:?}", error);
+                        tokio::select! {
+                            _ = cancellation_token.cancelled() => return,
+                            _ = tokio::time::sleep(backoff) => {}
+                        }
+                        backoff = (backoff * 2).min(connector.reconnect_max_backoff);
                     }
                 }
             };
 
-            tokio::select! {
-                _ = cancellation_token.cancelled() => {
-                    debug!("Task {} was cancelled by global shutdown", generation);
-                    let message = WithGeneration {
-                        generation,
-                        result: Err(NetworkError::SysCancelled),
-                    };
-                    let _ = result_tx.send(message);
+            debug!("Chat connection re-established");
+            ws_worker = Arc::new(worker);
+            connector.counters.count_reconnect();
+            connector.counters.mark_connected();
+            session_state.set(ConnectionState::Connected);
+            if let Some(record) = &mut *session_record.lock().await {
+                record.ws_worker = ws_worker.clone();
+                record.emit(StreamMessage::ConnectionState(ConnectionState::Connected));
+            }
+            Self::replay_pending_messages(&**ws_worker, &message_buffer).await;
+        }
+    }
+
+    /// Sends the messages still waiting for an ACK again, oldest first, under their original
+    /// `message_seq` and client id so the server can tell a replay from a new message.
+    /// One replayed `MAX_REPLAYS` times already fails instead.
+    async fn replay_pending_messages(worker: &dyn WsWorker, message_buffer: &DashMap<u64, PendingSend>) {
+        let mut pending = message_buffer.iter().map(|entry| *entry.key()).collect::<Vec<_>>();
+        pending.sort_unstable();
+        for message_seq in pending {
+            // The entry guard is released before anything is removed or awaited.
+            let replay = message_buffer.get_mut(&message_seq).map(|mut entry| {
+                entry.replays += 1;
+                (entry.replays <= MAX_REPLAYS).then(|| (entry.message.clone(), entry.replayed.clone()))
+            });
+            match replay {
+                None => {}
+                Some(None) => {
+                    if let Some((_, PendingSend { ack_tx, .. })) = message_buffer.remove(&message_seq) {
+                        warn!(message_seq, replays = MAX_REPLAYS, "Giving up on message");
+                        let _ = ack_tx.send(MessageEvent::failed(MessageError::ConnectionLost));
+                    }
+                }
+                Some(Some((message, replayed))) => {
+                    debug!(message_seq, "Replaying message");
+                    match message.send(worker, message_seq).await {
+                        Ok(()) => replayed.notify_one(),
+                        Err(error) => warn!(message_seq, "Failed to replay message: {:?}", error),
+                    }
                 }
-                _ = timeout_wrapped => {}
             }
-        }.instrument(self.span.clone());
+        }
+    }
 
-        let abort_handle = self
-            .runtime_handle
-            .block_on(async { self.join_set.spawn(cancellation_wrapped) });
+    /// Stops the session's background tasks and fails its pending sends; the returned worker
+    /// is left for the caller to `close`, which may wait. The `ConnectionState` is left to the
+    /// caller as well, since a newer session may already be on its way.
+    fn teardown_session(record: SessionRecord, message_buffer: &DashMap<u64, PendingSend>) -> Arc<Box<dyn WsWorker>> {
+        let SessionRecord { ws_worker, task_handle, supervisor_handle, .. } = record;
+        supervisor_handle.abort();
+        task_handle.abort();
+        Self::fail_pending_messages(message_buffer);
+        ws_worker
+    }
 
-        let record = TaskRecord {
-            abort_handle,
-            callback,
-        };
-        self.task_records.insert(generation, record);
-        notify.notify_one();
+    fn fail_pending_messages(message_buffer: &DashMap<u64, PendingSend>) {
+        let pending = message_buffer.iter().map(|entry| *entry.key()).collect::<Vec<_>>();
+        for message_seq in pending {
+            if let Some((_, PendingSend { ack_tx, .. })) = message_buffer.remove(&message_seq) {
+                trace!(message_seq, "Fail pending message");
+                let _ = ack_tx.send(MessageEvent::failed(MessageError::ConnectionLost));
+            }
+        }
+    }
 
-        Ok(generation)
+    /// Renews the tokens, for `refresh`, an access token about to expire and one the chat server
+    /// turned down alike; the new access token replaces `chat_token` as well.
+    async fn refresh_auth(
+        worker: &dyn HttpWorker,
+        auth_record: &mut Option<AuthRecord>,
+        chat_token: &std::sync::Mutex<String>,
+    ) -> Result<TokenInfo, RefreshError> {
+        let record = auth_record.as_ref().ok_or(RefreshError::MissingToken)?;
+        if record.refresh_expires_at <= Instant::now() {
+            *auth_record = None;
+            return Err(RefreshError::Expired);
+        }
+
+        match worker.refresh_token(record.token_info.refresh_token.clone()).await {
+            Ok(token_info) => {
+                debug!("Access token refreshed");
+                *chat_token.lock().unwrap() = token_info.access_token.clone();
+                *auth_record = Some(AuthRecord::new(token_info.clone()));
+                Ok(token_info)
+            }
+            Err(error) if matches!(error.downcast_ref(), Some(RefreshError::Expired)) => {
+                warn!("Refresh token turned down by the server");
+                *auth_record = None;
+                Err(RefreshError::Expired)
+            }
+            Err(error) => {
+                error!("Failed to refresh access token: {:?}", error);
+                Err(RefreshError::FallbackError)
+            }
+        }
     }
-}
 
-impl NetworkInterface for NetworkImpl {
-    fn fetch_captcha(
+    /// Returns the current access token, refreshing it first when it is about to expire.
+    ///
+    /// Without tokens from `login` or `restore_auth` it is `chat_token`, the one handed to
+    /// `connect_chat` or `set_auth_token`; `Ok(None)` means there is neither.
+    async fn fresh_access_token(
+        worker: &dyn HttpWorker,
+        auth_record: &Mutex<Option<AuthRecord>>,
+        chat_token: &std::sync::Mutex<String>,
+    ) -> Result<Option<String>, RefreshError> {
+        let mut auth_record = auth_record.lock().await;
+        match &*auth_record {
+            None => Ok(Some(chat_token.lock().unwrap().clone()).filter(|token| !token.is_empty())),
+            Some(record) if record.access_expires_at > Instant::now() + REFRESH_MARGIN => {
+                Ok(Some(record.token_info.access_token.clone()))
+            }
+            Some(_) => {
+                let token_info = Self::refresh_auth(worker, &mut auth_record, chat_token).await?;
+                Ok(Some(token_info.access_token))
+            }
+        }
+    }
+
+    /// Shared by text and attachment sends; `upload` is stored before the message goes out.
+    fn dispatch_send(
         &mut self,
+        request: SendRequest,
         timeout: u64,
-        map_function: Box<dyn FnOnce(WithGeneration<CaptchaEvent>) + Send + Sync>,
+        map_function: Box<dyn FnOnce(WithGeneration<MessageEvent>) + Send + Sync>,
         err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
     ) -> anyhow::Result<u64> {
-        let worker = self.http_worker.clone();
-        let callback = Box::new(|result: WithGeneration<NetworkResult>| {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        let SendRequest { conversation_id, client_id, content, upload } = request;
+
+        // A reconnecting session still takes sends and replays them, but without one the task could only fail.
+        if self.session_state.get() == ConnectionState::Disconnected {
+            return Err(anyhow::anyhow!("No chat session to send to"));
+        }
+        let message_id = self.message_id.fetch_add(1, Ordering::Relaxed);
+
+        let callback = Box::new(move |result: WithGeneration<NetworkResult>| {
             let generation = result.generation;
             match result.result {
                 Ok(event) => match event {
-                    NetworkEvent::Captcha(event) => map_function(WithGeneration {
+                    NetworkEvent::Chat(event) => map_function(WithGeneration {
                         generation,
                         result: event,
                     }),
@@ -272,28 +868,269 @@ impl NetworkInterface for NetworkImpl {
             }
         });
 
+        let http_worker = self.http_worker.clone();
+        let auth_record = self.auth_record.clone();
+        let chat_token = self.chat_token.clone();
+        let session_record = self.session_record.clone();
+        let message_buffer = self.message_buffer.clone();
+        let ack_timeout = Duration::from_millis(self.config.timeouts.ack_ms);
+        let send_span = debug_span!(parent: &self.span, "send", message_seq = message_id, conversation_id = %conversation_id.0);
         let task = Box::pin(async move {
-            let result = match worker.fetch_captcha().await {
-                Ok(inner) => Ok(inner),
+            let access_token = match Self::fresh_access_token(http_worker.as_ref(), &auth_record, &chat_token).await {
+                Ok(access_token) => access_token,
                 Err(error) => {
-                    error!("Failed to fetch captcha: {:?}", error);
-                    Err(CaptchaError::FallbackError)
+                    warn!("Failed to refresh access token before sending: {:?}", error);
+                    if let Some(record) = &*session_record.lock().await {
+                        record.emit(StreamMessage::SessionError(SessionError::RefreshFailed));
+                    }
+                    return NetworkEvent::Chat(MessageEvent::failed(MessageError::Unauthorized));
                 }
             };
 
-            NetworkEvent::Captcha(CaptchaEvent { result })
-        });
+            let worker = match &*session_record.lock().await {
+                None => {
+                    return NetworkEvent::Chat(MessageEvent::failed(MessageError::MissingSession))
+                }
+                Some(record) => record.ws_worker.clone(),
+            };
 
-        Ok(self.create_task(task, Duration::from_millis(timeout), Box::new(callback))?)
+            let attachment = match (upload, access_token) {
+                (None, _) => None,
+                (Some(_), None) => {
+                    return NetworkEvent::Chat(MessageEvent::failed(MessageError::Unauthorized))
+                }
+                (Some(Upload { bytes, mime, filename }), Some(access_token)) => {
+                    match http_worker.upload_attachment(access_token, bytes, mime, filename).await {
+                        Ok(attachment) => Some(attachment),
+                        Err(error) => {
+                            error!("Failed to upload attachment: {:?}", error);
+                            let detail = ServerDetail::of(&error);
+                            return NetworkEvent::Chat(MessageEvent { result: Err(MessageError::UploadFailed), detail })
+                        }
+                    }
+                }
+            };
+
+            // Dropping the guard clears the entry on every exit, including an outer abort.
+            let message = OutgoingMessage { client_id, conversation_id, content, attachment };
+            let (pending, mut ack_rx) = PendingAck::insert(message_id, message.clone(), message_buffer);
+
+            // A send lost with the connection goes out again once the supervisor reconnects, and the
+            // ACK is only due from then on; giving up on the session fails it through `ack_rx` instead.
+            if let Err(error) = message.send(&**worker, message_id).await {
+                warn!("Failed to send message, waiting for a replay: {:?}", error);
+                tokio::select! {
+                    _ = pending.replayed.notified() => trace!("Message replayed"),
+                    ack = &mut ack_rx => {
+                        return NetworkEvent::Chat(ack.unwrap_or(MessageEvent::failed(MessageError::ConnectionLost)));
+                    }
+                }
+            }
+
+            trace!("Waiting for ACK");
+            NetworkEvent::Chat(Self::wait_for_ack(message_id, ack_rx, tokio::time::Instant::now() + ack_timeout).await)
+        }.instrument(send_span));
+
+        Ok(self.create_task(task, Duration::from_millis(timeout), callback)?)
     }
 
-    fn signup(
+    /// Resolves one send from its `PendingAck` channel, giving up at `deadline`.
+    async fn wait_for_ack(
+        message_id: u64,
+        ack_rx: oneshot::Receiver<MessageEvent>,
+        deadline: tokio::time::Instant,
+    ) -> MessageEvent {
+        match tokio::time::timeout_at(deadline, ack_rx).await {
+            Ok(ack) => ack.unwrap_or(MessageEvent::failed(MessageError::ConnectionLost)),
+            Err(_) => {
+                warn!(message_seq = message_id, "No ACK by the deadline");
+                MessageEvent::failed(MessageError::AckTimeout)
+            }
+        }
+    }
+
+    pub fn create_task(
         &mut self,
-        username: String,
-        password: String,
-        captcha_id: Uuid,
-        captcha_answer: String,
-        timeout: u64,
+        task: Pin<Box<dyn Future<Output = NetworkEvent> + Send>>,
+        timeout: Duration,
+        callback: Box<dyn FnOnce(WithGeneration<NetworkResult>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+        self.spawn_task(generation, task, timeout, callback);
+        Ok(generation)
+    }
+
+    /// `create_task` for a generation the caller took already, e.g. to name the task's outcome too.
+    fn spawn_task(
+        &mut self,
+        generation: u64,
+        task: Pin<Box<dyn Future<Output = NetworkEvent> + Send>>,
+        timeout: Duration,
+        callback: Box<dyn FnOnce(WithGeneration<NetworkResult>) + Send + Sync>,
+    ) {
+        let cancellation_token = self.cancellation_token.clone();
+        let task_permits = self.task_permits.clone();
+        // Created outside the task so that aborting it before its first poll still reports.
+        let reporter = ResultReporter {
+            generation,
+            result_tx: Some(self.result_tx.clone()),
+            dropped_results: self.dropped_results.clone(),
+        };
+
+        let notify = Arc::new(Notify::new());
+        let notify_clone = notify.clone();
+        let cancellation_wrapped = async move {
+            notify_clone.notified().await;
+            let result = tokio::select! {
+                _ = cancellation_token.cancelled() => {
+                    debug!(generation, "Task was cancelled by global shutdown");
+                    Err(NetworkError::SysCancelled)
+                }
+                result = tokio::time::timeout(timeout, Self::with_permit(generation, &task_permits, task)) => match result {
+                    Ok(e) => {
+                        debug!(generation, "Task finished");
+                        Ok(e)
+                    }
+                    Err(_) => {
+                        debug!(generation, "Task timed out");
+                        Err(NetworkError::Timeout)
+                    }
+                }
+            };
+            reporter.report(result).await;
+        }.instrument(self.span.clone());
+
+        // Spawning through the handle never blocks, so this is safe to call from inside a runtime too.
+        let abort_handle = self.runtime_handle.spawn(cancellation_wrapped).abort_handle();
+
+        let record = TaskRecord {
+            abort_handle,
+            callback,
+        };
+        self.task_records.insert(generation, record);
+        notify.notify_one();
+    }
+
+    /// Runs `task` once one of `task_permits` is free, holding it until the task is done or dropped.
+    async fn with_permit(generation: u64, task_permits: &Semaphore, task: Pin<Box<dyn Future<Output = NetworkEvent> + Send>>) -> NetworkEvent {
+        let _permit = match task_permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                debug!(generation, "Too many tasks in flight, waiting for one to finish");
+                // The semaphore is never closed, so this only ends with a permit.
+                task_permits.acquire().await.expect("Task semaphore is never closed")
+            }
+        };
+        task.await
+    }
+
+    /// Ends the chat session, or only the one `connect_chat` returned `generation` for;
+    /// `false` when there was no such session.
+    fn end_session(&self, generation: Option<u64>) -> bool {
+        let ending = {
+            let mut current = self.session_generation.lock().unwrap();
+            match (*current, generation) {
+                (None, _) => return false,
+                (Some(current), Some(generation)) if current != generation => return false,
+                (Some(_), _) => current.take(),
+            }
+        };
+        self.session_state.set(ConnectionState::Disconnected);
+        // Closing waits for the server, so it is left to the runtime instead of holding up the caller.
+        let session_record = self.session_record.clone();
+        let message_buffer = self.message_buffer.clone();
+        self.runtime_handle.spawn(async move {
+            let record = {
+                let mut record = session_record.lock().await;
+                // A `connect_chat` finishing in the meantime has already replaced and closed it.
+                match record.as_ref() {
+                    Some(current) if Some(current.generation) == ending => record.take(),
+                    _ => None,
+                }
+            };
+            if let Some(record) = record {
+                Self::teardown_session(record, &message_buffer).close().await;
+            }
+        }.instrument(self.span.clone()));
+        true
+    }
+}
+
+impl Drop for NetworkImpl {
+    fn drop(&mut self) {
+        let _enter = self.span.enter();
+        self.cancellation_token.cancel();
+
+        let Some(runtime_thread_handle) = self.runtime_thread_handle.take() else {
+            return;
+        };
+        let deadline = Instant::now() + RUNTIME_SHUTDOWN_TIMEOUT;
+        while !runtime_thread_handle.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        if runtime_thread_handle.is_finished() {
+            if runtime_thread_handle.join().is_err() {
+                error!("Runtime thread panicked");
+            }
+            debug!("Runtime thread stopped");
+        } else {
+            warn!("Runtime thread did not stop within {:?}, detaching it", RUNTIME_SHUTDOWN_TIMEOUT);
+        }
+    }
+}
+
+impl NetworkInterface for NetworkImpl {
+    fn fetch_captcha(
+        &mut self,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<CaptchaEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let worker = self.http_worker.clone();
+        let callback = Box::new(|result: WithGeneration<NetworkResult>| {
+            let generation = result.generation;
+            match result.result {
+                Ok(event) => match event {
+                    NetworkEvent::Captcha(event) => map_function(WithGeneration {
+                        generation,
+                        result: event,
+                    }),
+                    _ => error!("Unexpected network event: {:?}", event),
+                },
+                Err(error) => err_function(WithGeneration {
+                    generation,
+                    result: error,
+                }),
+            }
+        });
+
+        let attempts = self.config.captcha_attempts;
+        let timeout = Duration::from_millis(timeout);
+        // Retries end at the task's own deadline instead of running into its timeout.
+        let deadline = tokio::time::Instant::now() + timeout;
+        let task = Box::pin(async move {
+            let result = match worker.fetch_captcha(attempts, deadline).await {
+                Ok(inner) => Ok(inner),
+                Err(error) => {
+                    error!("Failed to fetch captcha: {:?}", error);
+                    Err(CaptchaError::FallbackError)
+                }
+            };
+
+            NetworkEvent::Captcha(CaptchaEvent { result })
+        });
+
+        Ok(self.create_task(task, timeout, Box::new(callback))?)
+    }
+
+    fn signup(
+        &mut self,
+        username: String,
+        password: String,
+        captcha_id: Uuid,
+        captcha_answer: String,
+        timeout: u64,
         map_function: Box<dyn FnOnce(WithGeneration<SignupEvent>) + Send + Sync>,
         err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
     ) -> anyhow::Result<u64> {
@@ -316,18 +1153,24 @@ impl NetworkInterface for NetworkImpl {
         });
 
         let task = Box::pin(async move {
-            let result = match worker
+            let (result, detail) = match worker
                 .signup(username, password, captcha_id, captcha_answer)
                 .await
             {
-                Ok(inner) => Ok(inner),
+                Ok(inner) => (Ok(inner), None),
                 Err(error) => {
-                    error!("Failed to signup: {:?}", error);
-                    Err(SignupError::FallbackError)
+                    let detail = ServerDetail::of(&error);
+                    match error.downcast::<SignupError>() {
+                        Ok(error) => (Err(error), detail),
+                        Err(error) => {
+                            error!("Failed to signup: {:?}", error);
+                            (Err(SignupError::FallbackError), detail)
+                        }
+                    }
                 }
             };
 
-            NetworkEvent::Signup(SignupEvent { result })
+            NetworkEvent::Signup(SignupEvent { result, detail })
         });
 
         Ok(self.create_task(task, Duration::from_millis(timeout), callback)?)
@@ -361,48 +1204,70 @@ impl NetworkInterface for NetworkImpl {
             }
         });
 
+        let auth_record = self.auth_record.clone();
+        let clock_offset_ms = self.clock_offset_ms.clone();
         let task = Box::pin(async move {
-            let result = match worker
+            let (result, detail) = match worker
                 .login(username, password, captcha_id, captcha_answer)
                 .await
             {
-                Ok(inner) => Ok(inner),
+                Ok(inner) => {
+                    *auth_record.lock().await = Some(AuthRecord::new(inner.clone()));
+                    tokio::spawn(Self::sync_clock(worker, clock_offset_ms).in_current_span());
+                    (Ok(inner), None)
+                }
                 Err(error) => {
-                    error!("Failed to login: {:?}", error);
-                    Err(LoginError::FallbackError)
+                    let detail = ServerDetail::of(&error);
+                    match error.downcast::<LoginError>() {
+                        Ok(error) => (Err(error), detail),
+                        Err(error) => {
+                            error!("Failed to login: {:?}", error);
+                            (Err(LoginError::FallbackError), detail)
+                        }
+                    }
                 }
             };
 
-            NetworkEvent::Login(LoginEvent { result })
+            NetworkEvent::Login(LoginEvent { result, detail })
         });
 
         Ok(self.create_task(task, Duration::from_millis(timeout), callback)?)
     }
 
-    fn cancel(&mut self, generation: u64) -> anyhow::Result<()> {
-        if let Some((_, TaskRecord { abort_handle, .. })) = self.task_records.remove(&generation) {
-            abort_handle.abort();
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("No such task: {:?}", generation))
-        }
+    fn restore_auth(&mut self, token_info: TokenInfo) -> anyhow::Result<()> {
+        // Tasks started later queue behind this one for the lock, so they already see the tokens.
+        let auth_record = self.auth_record.clone();
+        self.runtime_handle.spawn(async move {
+            *auth_record.lock().await = Some(AuthRecord::new(token_info));
+        });
+        Ok(())
     }
 
-    fn connect_chat(
+    fn set_auth_token(&mut self, access_token: String, expires_in: u64) -> anyhow::Result<()> {
+        // Set here rather than on the runtime, so a `connect_chat` right after is not undone.
+        *self.chat_token.lock().unwrap() = access_token.clone();
+        let auth_record = self.auth_record.clone();
+        self.runtime_handle.spawn(async move {
+            if let Some(record) = &mut *auth_record.lock().await {
+                record.replace_access_token(access_token, expires_in);
+            }
+        });
+        debug!("Access token replaced");
+        Ok(())
+    }
+
+    fn refresh(
         &mut self,
-        address: String,
-        jwt: String,
-        msg_function: Box<dyn Fn(StreamMessage) + Send + Sync>,
         timeout: u64,
-        map_function: Box<dyn FnOnce(WithGeneration<SessionEvent>) + Send + Sync>,
+        map_function: Box<dyn FnOnce(WithGeneration<RefreshEvent>) + Send + Sync>,
         err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
     ) -> anyhow::Result<u64> {
-        let stream_generation = self.generation.fetch_add(1, Ordering::Relaxed);
+        let worker = self.http_worker.clone();
         let callback = Box::new(move |result: WithGeneration<NetworkResult>| {
             let generation = result.generation;
             match result.result {
                 Ok(event) => match event {
-                    NetworkEvent::Session(event) => map_function(WithGeneration {
+                    NetworkEvent::Refresh(event) => map_function(WithGeneration {
                         generation,
                         result: event,
                     }),
@@ -415,66 +1280,98 @@ impl NetworkInterface for NetworkImpl {
             }
         });
 
-        let span = self.span.clone();
-        let runtime_handle = self.runtime_handle.clone();
-        let cancellation_token = self.cancellation_token.clone();
+        let auth_record = self.auth_record.clone();
+        let chat_token = self.chat_token.clone();
+        let clock_offset_ms = self.clock_offset_ms.clone();
+        let task = Box::pin(async move {
+            let result = Self::refresh_auth(worker.as_ref(), &mut *auth_record.lock().await, &chat_token).await;
+            if result.is_ok() {
+                tokio::spawn(Self::sync_clock(worker, clock_offset_ms).in_current_span());
+            }
+
+            NetworkEvent::Refresh(RefreshEvent { result })
+        });
+
+        Ok(self.create_task(task, Duration::from_millis(timeout), callback)?)
+    }
+
+    fn logout(
+        &mut self,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<LogoutEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let worker = self.http_worker.clone();
+        let callback = Box::new(move |result: WithGeneration<NetworkResult>| {
+            let generation = result.generation;
+            match result.result {
+                Ok(event) => match event {
+                    NetworkEvent::Logout(event) => map_function(WithGeneration {
+                        generation,
+                        result: event,
+                    }),
+                    _ => error!("Unexpected network event: {:?}", event),
+                },
+                Err(error) => err_function(WithGeneration {
+                    generation,
+                    result: error,
+                }),
+            }
+        });
+
+        let auth_record = self.auth_record.clone();
+        let chat_token = self.chat_token.clone();
         let session_record = self.session_record.clone();
+        let session_generation = self.session_generation.clone();
+        let session_state = self.session_state.clone();
         let message_buffer = self.message_buffer.clone();
-        let (message_tx, message_rx) = unbounded_channel();
         let task = Box::pin(async move {
-            let result = match RealWsWorker::try_new(stream_generation, jwt, message_tx).await {
-                Ok(worker) => {
-                    let notify = Arc::new(Notify::new());
-                    let task_handle = runtime_handle.spawn(Self::send_message_back(
-                        notify.clone(),
-                        session_record.clone(),
-                        message_buffer,
-                        cancellation_token,
-                        message_rx,
-                    ).instrument(span));
+            let record = session_record.lock().await.take();
+            if let Some(record) = record {
+                debug!("Tearing down chat session on logout");
+                *session_generation.lock().unwrap() = None;
+                session_state.set(ConnectionState::Disconnected);
+                Self::teardown_session(record, &message_buffer).close().await;
+            }
 
-                    *session_record.lock().await = Some(SessionRecord {
-                        ws_worker: Arc::new(Box::new(worker)),
-                        task_handle,
-                        callback: Arc::new(msg_function),
-                    });
-                    notify.notify_one();
-                    Ok(ChatMetaData)
-                }
-                Err(error) => {
-                    warn!("Failed to connect to chat server: {:?}", error);
-                    Err(ChatConnError::FallbackError)
-                }
+            // Neither token is any good after this.
+            let chat_token = std::mem::take(&mut *chat_token.lock().unwrap());
+            let access_token = match auth_record.lock().await.take() {
+                Some(record) => record.token_info.access_token,
+                None => chat_token,
+            };
+            let result = match access_token.as_str() {
+                "" => Err(LogoutError::MissingToken),
+                _ => match worker.logout(access_token).await {
+                    Ok(()) => Ok(()),
+                    Err(error) => {
+                        error!("Failed to logout: {:?}", error);
+                        Err(LogoutError::FallbackError)
+                    }
+                },
             };
 
-            NetworkEvent::Session(SessionEvent { result })
+            NetworkEvent::Logout(LogoutEvent { result })
         });
 
-        Ok(self.create_task(task, Duration::from_millis(timeout), Box::new(callback))?)
+        Ok(self.create_task(task, Duration::from_millis(timeout), callback)?)
     }
 
-    fn send_chat_message(
+    fn fetch_history(
         &mut self,
         conversation_id: ConversationId,
-        content: String,
+        before: Option<DateTime<Utc>>,
+        limit: u32,
         timeout: u64,
-        map_function: Box<dyn FnOnce(WithGeneration<MessageEvent>) + Send + Sync>,
+        map_function: Box<dyn FnOnce(WithGeneration<HistoryEvent>) + Send + Sync>,
         err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
     ) -> anyhow::Result<u64> {
-        let span = self.span.clone();
-        let _enter = span.enter();
-
-        let message_id = self.message_id.fetch_add(1, Ordering::Relaxed);
-
-        let span = self.span.clone();
-        let message_buffer = self.message_buffer.clone();
-        let content_clone = content.clone();
+        let worker = self.http_worker.clone();
         let callback = Box::new(move |result: WithGeneration<NetworkResult>| {
-            let _enter = span.enter();
             let generation = result.generation;
             match result.result {
                 Ok(event) => match event {
-                    NetworkEvent::Chat(event) => map_function(WithGeneration {
+                    NetworkEvent::History(event) => map_function(WithGeneration {
                         generation,
                         result: event,
                     }),
@@ -485,43 +1382,1256 @@ impl NetworkInterface for NetworkImpl {
                     result: error,
                 }),
             }
-            message_buffer.remove(&message_id);
-            trace!("Remove message in callback wrapper: {:?} {}", message_id, content_clone);
         });
 
-        let session_record = self.session_record.clone();
-        let message_buffer = self.message_buffer.clone();
+        let auth_record = self.auth_record.clone();
+        let chat_token = self.chat_token.clone();
         let task = Box::pin(async move {
-            let worker = match &*session_record.lock().await {
-                None => {
-                    return NetworkEvent::Chat(MessageEvent {
-                        result: Err(MessageError::MissingSession),
-                    })
+            let result = match Self::fresh_access_token(worker.as_ref(), &auth_record, &chat_token).await {
+                Ok(None) => Err(HistoryError::MissingToken),
+                Err(error) => {
+                    warn!("Failed to refresh access token before fetching history: {:?}", error);
+                    Err(HistoryError::Unauthorized)
+                }
+                Ok(Some(access_token)) => {
+                    match worker.fetch_history(access_token, conversation_id.clone(), before, limit).await {
+                        Ok(messages) => Ok(messages),
+                        Err(error) => {
+                            error!("Failed to fetch history: {:?}", error);
+                            let status = error.downcast_ref::<reqwest::Error>().and_then(|e| e.status());
+                            if status == Some(reqwest::StatusCode::UNAUTHORIZED) {
+                                Err(HistoryError::Unauthorized)
+                            } else {
+                                Err(HistoryError::FallbackError)
+                            }
+                        }
+                    }
                 }
-                Some(record) => record.ws_worker.clone(),
             };
 
-            let notify = Arc::new(Notify::new());
-            message_buffer.insert(message_id, notify.clone());
-            trace!("Insert message in task: {:?} {}", message_id, content);
+            NetworkEvent::History(HistoryEvent { conversation_id, result })
+        });
+
+        self.create_task(task, Duration::from_millis(timeout), callback)
+    }
 
-            if let Err(error) = worker.send_message(message_id, conversation_id.clone(), content.clone()).await {
-                error!("Failed to send message: {:?}", error);
-                return NetworkEvent::Chat(MessageEvent {
-                    result: Err(MessageError::FallbackError),
-                })
+    fn server_info(
+        &mut self,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<ServerInfoEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let worker = self.http_worker.clone();
+        let callback = Box::new(move |result: WithGeneration<NetworkResult>| {
+            let generation = result.generation;
+            match result.result {
+                Ok(event) => match event {
+                    NetworkEvent::ServerInfo(event) => map_function(WithGeneration {
+                        generation,
+                        result: event,
+                    }),
+                    _ => error!("Unexpected network event: {:?}", event),
+                },
+                Err(error) => err_function(WithGeneration {
+                    generation,
+                    result: error,
+                }),
             }
+        });
 
-            trace!("Waiting for notify");
-            notify.notified().await;
+        let task = Box::pin(async move {
+            let result = match worker.server_info().await {
+                Ok(info) => Ok(info),
+                Err(error) => {
+                    error!("Failed to fetch server info: {:?}", error);
+                    Err(ServerInfoError::FallbackError)
+                }
+            };
 
-            message_buffer.remove(&message_id);
-            trace!("Remove message after notify: {:?} {}", message_id, content);
-            NetworkEvent::Chat(MessageEvent {
-                result: Ok(MessageSent),
-            })
-        }.instrument(self.span.clone()));
+            NetworkEvent::ServerInfo(ServerInfoEvent { result })
+        });
 
-        Ok(self.create_task(task, Duration::from_millis(timeout), callback)?)
+        Ok(self.create_task(task, Duration::from_millis(timeout), Box::new(callback))?)
+    }
+
+    fn list_conversations(
+        &mut self,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<ConversationsEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let worker = self.http_worker.clone();
+        let callback = Box::new(move |result: WithGeneration<NetworkResult>| {
+            let generation = result.generation;
+            match result.result {
+                Ok(event) => match event {
+                    NetworkEvent::Conversations(event) => map_function(WithGeneration {
+                        generation,
+                        result: event,
+                    }),
+                    _ => error!("Unexpected network event: {:?}", event),
+                },
+                Err(error) => err_function(WithGeneration {
+                    generation,
+                    result: error,
+                }),
+            }
+        });
+
+        let auth_record = self.auth_record.clone();
+        let chat_token = self.chat_token.clone();
+        let task = Box::pin(async move {
+            let result = match Self::fresh_access_token(worker.as_ref(), &auth_record, &chat_token).await {
+                Ok(None) => Err(ConversationsError::MissingToken),
+                Err(error) => {
+                    warn!("Failed to refresh access token before listing conversations: {:?}", error);
+                    Err(ConversationsError::Unauthorized)
+                }
+                Ok(Some(access_token)) => match worker.list_conversations(access_token).await {
+                    Ok(conversations) => Ok(conversations),
+                    Err(error) => {
+                        error!("Failed to list conversations: {:?}", error);
+                        let status = error.downcast_ref::<reqwest::Error>().and_then(|e| e.status());
+                        if status == Some(reqwest::StatusCode::UNAUTHORIZED) {
+                            Err(ConversationsError::Unauthorized)
+                        } else {
+                            Err(ConversationsError::FallbackError)
+                        }
+                    }
+                },
+            };
+
+            NetworkEvent::Conversations(ConversationsEvent { result })
+        });
+
+        self.create_task(task, Duration::from_millis(timeout), callback)
+    }
+
+    fn fetch_presence(
+        &mut self,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<PresenceEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let worker = self.http_worker.clone();
+        let callback = Box::new(move |result: WithGeneration<NetworkResult>| {
+            let generation = result.generation;
+            match result.result {
+                Ok(event) => match event {
+                    NetworkEvent::Presence(event) => map_function(WithGeneration {
+                        generation,
+                        result: event,
+                    }),
+                    _ => error!("Unexpected network event: {:?}", event),
+                },
+                Err(error) => err_function(WithGeneration {
+                    generation,
+                    result: error,
+                }),
+            }
+        });
+
+        let auth_record = self.auth_record.clone();
+        let chat_token = self.chat_token.clone();
+        let task = Box::pin(async move {
+            let result = match Self::fresh_access_token(worker.as_ref(), &auth_record, &chat_token).await {
+                Ok(None) => Err(PresenceError::MissingToken),
+                Err(error) => {
+                    warn!("Failed to refresh access token before fetching presence: {:?}", error);
+                    Err(PresenceError::Unauthorized)
+                }
+                Ok(Some(access_token)) => match worker.fetch_presence(access_token).await {
+                    Ok(presence) => Ok(presence),
+                    Err(error) => {
+                        error!("Failed to fetch presence: {:?}", error);
+                        let status = error.downcast_ref::<reqwest::Error>().and_then(|e| e.status());
+                        if status == Some(reqwest::StatusCode::UNAUTHORIZED) {
+                            Err(PresenceError::Unauthorized)
+                        } else {
+                            Err(PresenceError::FallbackError)
+                        }
+                    }
+                },
+            };
+
+            NetworkEvent::Presence(PresenceEvent { result })
+        });
+
+        self.create_task(task, Duration::from_millis(timeout), callback)
+    }
+
+    fn open_direct(
+        &mut self,
+        username: String,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<DirectEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let worker = self.http_worker.clone();
+        let callback = Box::new(move |result: WithGeneration<NetworkResult>| {
+            let generation = result.generation;
+            match result.result {
+                Ok(event) => match event {
+                    NetworkEvent::Direct(event) => map_function(WithGeneration {
+                        generation,
+                        result: event,
+                    }),
+                    _ => error!("Unexpected network event: {:?}", event),
+                },
+                Err(error) => err_function(WithGeneration {
+                    generation,
+                    result: error,
+                }),
+            }
+        });
+
+        let auth_record = self.auth_record.clone();
+        let chat_token = self.chat_token.clone();
+        let task = Box::pin(async move {
+            let result = match Self::fresh_access_token(worker.as_ref(), &auth_record, &chat_token).await {
+                Ok(None) => Err(DirectError::MissingToken),
+                Err(error) => {
+                    warn!("Failed to refresh access token before opening a direct conversation: {:?}", error);
+                    Err(DirectError::Unauthorized)
+                }
+                Ok(Some(access_token)) => match worker.open_direct(access_token, username.clone()).await {
+                    Ok(conversation) => Ok(conversation),
+                    Err(error) => match error.downcast::<DirectError>() {
+                        Ok(error) => Err(error),
+                        Err(error) => {
+                            error!(%username, "Failed to open direct conversation: {:?}", error);
+                            Err(DirectError::FallbackError)
+                        }
+                    },
+                },
+            };
+
+            NetworkEvent::Direct(DirectEvent { username, result })
+        });
+
+        self.create_task(task, Duration::from_millis(timeout), callback)
+    }
+
+    fn cancel(&mut self, generation: u64) -> anyhow::Result<()> {
+        let _enter = self.span.enter();
+        // Whoever removes the record first owns the callback, so a result racing in is dropped.
+        let task = self.task_records.remove(&generation);
+        let task_found = task.is_some();
+        if let Some((_, TaskRecord { abort_handle, callback })) = task {
+            abort_handle.abort();
+            debug!(generation, "Task was cancelled by user");
+            callback(WithGeneration { generation, result: Err(NetworkError::UsrCancelled) });
+        }
+        // A `connect_chat` may have set up its session just before being aborted, or long ago.
+        let ended_session = self.end_session(Some(generation));
+        if ended_session {
+            debug!(generation, "Chat session was cancelled by user");
+        }
+        anyhow::ensure!(task_found || ended_session, "No such task: {:?}", generation);
+        Ok(())
+    }
+
+    fn connect_chat(
+        &mut self,
+        address: String,
+        jwt: String,
+        msg_function: Box<dyn Fn(StreamMessage) + Send + Sync>,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<SessionEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+        *self.chat_token.lock().unwrap() = jwt;
+        let callback = Box::new(move |result: WithGeneration<NetworkResult>| {
+            let generation = result.generation;
+            match result.result {
+                Ok(event) => match event {
+                    NetworkEvent::Session(event) => map_function(WithGeneration {
+                        generation,
+                        result: event,
+                    }),
+                    _ => error!("Unexpected network event: {:?}", event),
+                },
+                Err(error) => err_function(WithGeneration {
+                    generation,
+                    result: error,
+                }),
+            }
+        });
+
+        // An empty address means the server did not announce one, so use the configured URL.
+        let ws_url = match address.as_str() {
+            "" => self.config.ws_url.clone(),
+            address => parse_ws_url(address)?,
+        };
+
+        // Everything the session does in the background is tagged with its generation.
+        let span = debug_span!(parent: &self.span, "chat_session", generation);
+        let runtime_handle = self.runtime_handle.clone();
+        let cancellation_token = self.cancellation_token.clone();
+        let session_record = self.session_record.clone();
+        let session_generation = self.session_generation.clone();
+        let message_buffer = self.message_buffer.clone();
+        let recent_client_ids = self.recent_client_ids.clone();
+        let session_state = self.session_state.clone();
+        let subscribers = self.stream_subscribers.clone();
+        let counters = self.counters.clone();
+        // Counted per session; until it is replaced, the previous one may still add a few.
+        counters.reset();
+        session_state.set(ConnectionState::Connecting);
+        let attempt = ConnectAttempt(session_state.clone());
+        let (message_tx, message_rx) = broadcast::channel(self.config.channel_capacity);
+        let connector = SessionConnector {
+            generation,
+            ws_url,
+            ws_connector: self.ws_connector.clone(),
+            chat_token: self.chat_token.clone(),
+            http_worker: self.http_worker.clone(),
+            auth_record: self.auth_record.clone(),
+            message_tx,
+            counters: counters.clone(),
+            reconnect_attempts: self.config.reconnect_attempts,
+            reconnect_initial_backoff: self.config.reconnect_initial_backoff,
+            reconnect_max_backoff: self.config.reconnect_max_backoff,
+        };
+        let task = Box::pin(async move {
+            let access_token = match connector.access_token().await {
+                Ok(access_token) => access_token,
+                Err(error) => {
+                    warn!("Failed to refresh access token before connecting: {:?}", error);
+                    let error = match error {
+                        RefreshError::MissingToken | RefreshError::Expired => ChatConnError::Unauthorized,
+                        RefreshError::FallbackError => ChatConnError::FallbackError,
+                    };
+                    return NetworkEvent::Session(SessionEvent { result: Err(error), detail: None });
+                }
+            };
+
+            let (result, detail) = match connector.connect(access_token).await {
+                Ok(worker) => {
+                    let ws_worker: Arc<Box<dyn WsWorker>> = Arc::new(worker);
+                    let notify = Arc::new(Notify::new());
+                    let task_handle = runtime_handle.spawn(Self::send_message_back(
+                        notify.clone(),
+                        session_record.clone(),
+                        message_buffer.clone(),
+                        recent_client_ids,
+                        cancellation_token.clone(),
+                        message_rx,
+                    ).instrument(span.clone()));
+                    let supervisor_handle = runtime_handle.spawn(Self::supervise_session(
+                        connector,
+                        ws_worker.clone(),
+                        session_record.clone(),
+                        session_state.clone(),
+                        message_buffer.clone(),
+                        cancellation_token,
+                    ).instrument(span));
+
+                    let mut record = session_record.lock().await;
+                    // Sends still waiting on the session being replaced fail instead of waiting for an ACK that never comes.
+                    let previous = record
+                        .take()
+                        .map(|previous| Self::teardown_session(previous, &message_buffer));
+                    *session_generation.lock().unwrap() = Some(generation);
+                    *record = Some(SessionRecord {
+                        generation,
+                        ws_worker,
+                        task_handle,
+                        supervisor_handle,
+                        callback: Arc::new(msg_function),
+                        subscribers,
+                    });
+                    drop(record);
+                    counters.mark_connected();
+                    session_state.set(ConnectionState::Connected);
+                    notify.notify_one();
+                    if let Some(previous) = previous {
+                        debug!("Replaced the previous chat session");
+                        previous.close().await;
+                    }
+                    (Ok(ChatMetaData), None)
+                }
+                Err(error) => {
+                    warn!("Failed to connect to chat server: {:?}", error);
+                    let kind = error.downcast_ref::<ChatConnError>().copied().unwrap_or(ChatConnError::FallbackError);
+                    (Err(kind), ServerDetail::of(&error))
+                }
+            };
+
+            drop(attempt);
+            NetworkEvent::Session(SessionEvent { result, detail })
+        });
+
+        self.spawn_task(generation, task, Duration::from_millis(timeout), Box::new(callback));
+        Ok(generation)
+    }
+
+    fn disconnect_chat(&mut self) -> anyhow::Result<()> {
+        let _enter = self.span.enter();
+        anyhow::ensure!(self.end_session(None), "No chat session to disconnect");
+        debug!("Disconnected chat session");
+        Ok(())
+    }
+
+    fn subscribe_stream(&mut self, callback: Box<dyn Fn(StreamMessage) + Send + Sync>) -> u64 {
+        let mut subscribers = self.stream_subscribers.lock().unwrap();
+        let subscription = subscribers.next_id;
+        subscribers.next_id += 1;
+        subscribers.callbacks.push((subscription, Arc::new(callback)));
+        subscription
+    }
+
+    fn unsubscribe_stream(&mut self, subscription: u64) -> anyhow::Result<()> {
+        let callbacks = &mut self.stream_subscribers.lock().unwrap().callbacks;
+        let count = callbacks.len();
+        callbacks.retain(|(id, _)| *id != subscription);
+        anyhow::ensure!(callbacks.len() < count, "No such stream subscription: {}", subscription);
+        Ok(())
+    }
+
+    fn session_state(&self) -> ConnectionState {
+        self.session_state.get()
+    }
+
+    fn pending_count(&self) -> usize {
+        self.message_buffer.len()
+    }
+
+    fn clock_offset(&self) -> TimeDelta {
+        TimeDelta::milliseconds(self.clock_offset_ms.load(Ordering::Relaxed))
+    }
+
+    fn metrics(&self) -> ConnectionMetrics {
+        let metrics = self.counters.snapshot();
+        ConnectionMetrics {
+            connected_for: metrics.connected_for.filter(|_| self.is_connected()),
+            in_flight: self.config.max_in_flight.max(1) - self.task_permits.available_permits(),
+            ..metrics
+        }
+    }
+
+    fn send_chat_message(
+        &mut self,
+        conversation_id: ConversationId,
+        client_id: Uuid,
+        content: ChatBody,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<MessageEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let request = SendRequest { conversation_id, client_id, content, upload: None };
+        self.dispatch_send(request, timeout, map_function, err_function)
+    }
+
+    fn send_chat_attachment(
+        &mut self,
+        request: AttachmentRequest,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<MessageEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let AttachmentRequest { conversation_id, client_id, caption, bytes, mime, filename } = request;
+        let upload = Upload { bytes, mime, filename };
+        let request = SendRequest { conversation_id, client_id, content: ChatBody::Text(caption), upload: Some(upload) };
+        self.dispatch_send(request, timeout, map_function, err_function)
+    }
+
+    fn send_chat_messages(
+        &mut self,
+        conversation_id: ConversationId,
+        messages: Vec<(Uuid, ChatBody)>,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<MessageBatchEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+
+        if self.session_state.get() == ConnectionState::Disconnected {
+            return Err(anyhow::anyhow!("No chat session to send to"));
+        }
+        let count = messages.len() as u64;
+        let first_id = self.message_id.fetch_add(count, Ordering::Relaxed);
+
+        let callback = Box::new(move |result: WithGeneration<NetworkResult>| {
+            let generation = result.generation;
+            match result.result {
+                Ok(event) => match event {
+                    NetworkEvent::ChatBatch(event) => map_function(WithGeneration {
+                        generation,
+                        result: event,
+                    }),
+                    _ => error!("Unexpected network event: {:?}", event),
+                },
+                Err(error) => err_function(WithGeneration {
+                    generation,
+                    result: error,
+                }),
+            }
+        });
+
+        let http_worker = self.http_worker.clone();
+        let auth_record = self.auth_record.clone();
+        let chat_token = self.chat_token.clone();
+        let session_record = self.session_record.clone();
+        let message_buffer = self.message_buffer.clone();
+        let ack_timeout = Duration::from_millis(self.config.timeouts.ack_ms);
+        let send_span = debug_span!(parent: &self.span, "send_batch", first_message_seq = first_id, count, conversation_id = %conversation_id.0);
+        let task = Box::pin(async move {
+            let fail_all = |error: MessageError| {
+                let results = (0..count).map(|_| MessageEvent::failed(error)).collect();
+                NetworkEvent::ChatBatch(MessageBatchEvent { results })
+            };
+
+            if let Err(error) = Self::fresh_access_token(http_worker.as_ref(), &auth_record, &chat_token).await {
+                warn!("Failed to refresh access token before sending: {:?}", error);
+                if let Some(record) = &*session_record.lock().await {
+                    record.emit(StreamMessage::SessionError(SessionError::RefreshFailed));
+                }
+                return fail_all(MessageError::Unauthorized);
+            }
+
+            // The only lookup of the session for the whole batch.
+            let worker = match &*session_record.lock().await {
+                None => return fail_all(MessageError::MissingSession),
+                Some(record) => record.ws_worker.clone(),
+            };
+
+            let mut pending = Vec::with_capacity(messages.len());
+            for (message_id, (client_id, content)) in (first_id..).zip(messages) {
+                let message = OutgoingMessage { client_id, conversation_id: conversation_id.clone(), content, attachment: None };
+                let (guard, ack_rx) = PendingAck::insert(message_id, message.clone(), message_buffer.clone());
+                if let Err(error) = message.send(&**worker, message_id).await {
+                    warn!("Failed to send message, leaving it for a replay: {:?}", error);
+                }
+                pending.push((guard, ack_rx));
+            }
+
+            trace!(count, "Waiting for ACKs");
+            let deadline = tokio::time::Instant::now() + ack_timeout;
+            let mut results = Vec::with_capacity(pending.len());
+            for (message_id, (_guard, ack_rx)) in (first_id..).zip(pending) {
+                results.push(Self::wait_for_ack(message_id, ack_rx, deadline).await);
+            }
+            NetworkEvent::ChatBatch(MessageBatchEvent { results })
+        }.instrument(send_span));
+
+        Ok(self.create_task(task, Duration::from_millis(timeout), callback)?)
+    }
+
+    fn fetch_attachment(
+        &mut self,
+        blob_id: Uuid,
+        timeout: u64,
+        map_function: Box<dyn FnOnce(WithGeneration<AttachmentEvent>) + Send + Sync>,
+        err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        let worker = self.http_worker.clone();
+        let callback = Box::new(move |result: WithGeneration<NetworkResult>| {
+            let generation = result.generation;
+            match result.result {
+                Ok(event) => match event {
+                    NetworkEvent::Attachment(event) => map_function(WithGeneration {
+                        generation,
+                        result: event,
+                    }),
+                    _ => error!("Unexpected network event: {:?}", event),
+                },
+                Err(error) => err_function(WithGeneration {
+                    generation,
+                    result: error,
+                }),
+            }
+        });
+
+        let auth_record = self.auth_record.clone();
+        let chat_token = self.chat_token.clone();
+        let task = Box::pin(async move {
+            let result = match Self::fresh_access_token(worker.as_ref(), &auth_record, &chat_token).await {
+                Ok(None) => Err(AttachmentError::MissingToken),
+                Err(error) => {
+                    warn!("Failed to refresh access token before fetching attachment: {:?}", error);
+                    Err(AttachmentError::Unauthorized)
+                }
+                Ok(Some(access_token)) => match worker.fetch_attachment(access_token, blob_id).await {
+                    Ok(bytes) => Ok(bytes),
+                    Err(error) => {
+                        error!("Failed to fetch attachment: {:?}", error);
+                        let status = error.downcast_ref::<reqwest::Error>().and_then(|e| e.status());
+                        if status == Some(reqwest::StatusCode::UNAUTHORIZED) {
+                            Err(AttachmentError::Unauthorized)
+                        } else {
+                            Err(AttachmentError::FallbackError)
+                        }
+                    }
+                },
+            };
+
+            NetworkEvent::Attachment(AttachmentEvent { blob_id, result })
+        });
+
+        self.create_task(task, Duration::from_millis(timeout), callback)
+    }
+
+    fn send_typing(&mut self, conversation_id: ConversationId) -> anyhow::Result<()> {
+        // Called on keystrokes, so it only queues the notice; one lost on the way is not worth reporting.
+        anyhow::ensure!(self.session_generation.lock().unwrap().is_some(), "No chat session to send typing to");
+        let session_record = self.session_record.clone();
+        self.runtime_handle.spawn(async move {
+            if let Some(record) = &*session_record.lock().await {
+                if let Err(error) = record.ws_worker.send_typing(conversation_id).await {
+                    debug!("Failed to send typing notice: {:?}", error);
+                }
+            }
+        }.instrument(self.span.clone()));
+        Ok(())
+    }
+
+    fn send_read_receipt(&mut self, conversation_id: ConversationId, up_to_seq: u64) -> anyhow::Result<()> {
+        anyhow::ensure!(self.session_generation.lock().unwrap().is_some(), "No chat session to send read receipt to");
+        let session_record = self.session_record.clone();
+        self.runtime_handle.spawn(async move {
+            if let Some(record) = &*session_record.lock().await {
+                if let Err(error) = record.ws_worker.send_read(conversation_id, up_to_seq).await {
+                    warn!("Failed to send read receipt: {:?}", error);
+                }
+            }
+        }.instrument(self.span.clone()));
+        Ok(())
+    }
+
+    fn shutdown(&mut self, on_done: Box<dyn FnOnce() + Send + Sync>) -> anyhow::Result<()> {
+        let _enter = self.span.enter();
+        let runtime_thread_handle = self
+            .runtime_thread_handle
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("Network is already shut down"))?;
+
+        // The runtime stops with the cancellation, so the session is closed on it first;
+        // `on_done` is what waits for that, not the caller.
+        *self.session_generation.lock().unwrap() = None;
+        self.session_state.set(ConnectionState::Disconnected);
+        let session_record = self.session_record.clone();
+        let message_buffer = self.message_buffer.clone();
+        let cancellation_token = self.cancellation_token.clone();
+        self.runtime_handle.spawn(async move {
+            let record = session_record.lock().await.take();
+            if let Some(record) = record {
+                debug!("Tearing down chat session on shutdown");
+                Self::teardown_session(record, &message_buffer).close().await;
+            }
+            cancellation_token.cancel();
+        }.instrument(self.span.clone()));
+
+        let span = self.span.clone();
+        std::thread::spawn(move || {
+            let _enter = span.enter();
+            match runtime_thread_handle.join() {
+                Ok(()) => {
+                    debug!("Runtime thread stopped");
+                    on_done();
+                }
+                Err(_) => error!("Runtime thread panicked"),
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::UserId;
+    use crate::util::wait_until;
+    use std::net::TcpListener;
+    use std::sync::mpsc as std_mpsc;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// A config whose REST calls go to `listener`; unless a test serves it, they never get an answer.
+    fn config(listener: &TcpListener) -> NetworkConfig {
+        let api_base_url = format!("http://{}/api/", listener.local_addr().unwrap());
+        NetworkConfig::try_new(&api_base_url, "ws://127.0.0.1:1/", None).unwrap()
+    }
+
+    /// Answers every request to `listener` with an empty 200, passing on the bearer token it came with.
+    fn serve_tokens(listener: TcpListener) -> std_mpsc::Receiver<String> {
+        use std::io::{BufRead, BufReader, Write};
+        let (tx, rx) = std_mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut token = String::new();
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("authorization") {
+                            token = value.trim().trim_start_matches("Bearer ").to_string();
+                        }
+                    }
+                }
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+                let _ = tx.send(token);
+            }
+        });
+        rx
+    }
+
+    fn fetch_attachment(network: &mut NetworkImpl) {
+        let (tx, rx) = std_mpsc::channel();
+        let err_tx = tx.clone();
+        network
+            .fetch_attachment(
+                Uuid::new_v4(),
+                5000,
+                Box::new(move |event| tx.send(event.result.result.is_ok()).unwrap()),
+                Box::new(move |_| err_tx.send(false).unwrap()),
+            )
+            .unwrap();
+        assert!(rx.recv_timeout(WAIT).unwrap(), "Attachment fetch failed");
+    }
+
+    /// Sends `text` to `conversation_id`, returning what becomes of it.
+    fn send_text(network: &mut NetworkImpl, conversation_id: &ConversationId, client_id: Uuid, text: &str) -> std_mpsc::Receiver<Result<MessageSent, String>> {
+        let (tx, rx) = std_mpsc::channel();
+        let err_tx = tx.clone();
+        network
+            .send_chat_message(
+                conversation_id.clone(),
+                client_id,
+                ChatBody::Text(text.to_string()),
+                5000,
+                Box::new(move |event| tx.send(event.result.result.map_err(|error| format!("{:?}", error))).unwrap()),
+                Box::new(move |error| err_tx.send(Err(format!("{:?}", error.result))).unwrap()),
+            )
+            .unwrap();
+        rx
+    }
+
+    /// The `message_seq` of the frame `worker` sent for `client_id`, once it has.
+    fn sent_seq(worker: &FakeWsWorker, client_id: Uuid) -> u64 {
+        let find = || worker.sent().iter().find_map(|frame| match frame {
+            ClientToServer::Send(message) if message.client_id == client_id => Some(message.message_seq),
+            _ => None,
+        });
+        wait_until(|| find().is_some());
+        find().unwrap()
+    }
+
+    /// Opens a chat session through the `WsConnector` of `network`, returning its generation and what it streams.
+    fn connect(network: &mut NetworkImpl) -> (u64, std_mpsc::Receiver<StreamMessage>) {
+        let (stream_tx, stream_rx) = std_mpsc::channel();
+        let (tx, rx) = std_mpsc::channel();
+        let err_tx = tx.clone();
+        let generation = network
+            .connect_chat(
+                String::new(),
+                "chat-token".to_string(),
+                Box::new(move |message| {
+                    let _ = stream_tx.send(message);
+                }),
+                5000,
+                Box::new(move |event| tx.send(event.result.result.is_ok()).unwrap()),
+                Box::new(move |_| err_tx.send(false).unwrap()),
+            )
+            .unwrap();
+        assert!(rx.recv_timeout(WAIT).unwrap(), "Chat session did not connect");
+        (generation, stream_rx)
+    }
+
+    #[test]
+    fn never_completing_task_times_out_on_paused_clock() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut network = NetworkImpl::with_paused_clock(config(&listener), Arc::new(FakeWsConnector::default())).unwrap();
+        let (tx, rx) = std_mpsc::channel();
+        let err_tx = tx.clone();
+        // Ten minutes of waiting for a reply that never comes pass as soon as the runtime idles.
+        network
+            .fetch_captcha(
+                600_000,
+                Box::new(move |event| tx.send(Ok(event.generation)).unwrap()),
+                Box::new(move |error| err_tx.send(Err(error.result)).unwrap()),
+            )
+            .unwrap();
+
+        assert!(matches!(rx.recv_timeout(WAIT).unwrap(), Err(NetworkError::Timeout)));
+    }
+
+    #[test]
+    fn cancel_resolves_the_request_with_usr_cancelled() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut network = NetworkImpl::with_ws_connector(config(&listener), Arc::new(FakeWsConnector::default())).unwrap();
+        let (tx, rx) = std_mpsc::channel();
+        let err_tx = tx.clone();
+        let generation = network
+            .fetch_captcha(
+                60_000,
+                Box::new(move |_| tx.send(None).unwrap()),
+                Box::new(move |error| err_tx.send(Some(error)).unwrap()),
+            )
+            .unwrap();
+
+        network.cancel(generation).unwrap();
+
+        let error = rx.recv_timeout(WAIT).unwrap().unwrap();
+        assert_eq!(error.generation, generation);
+        assert!(matches!(error.result, NetworkError::UsrCancelled));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err(), "Cancelled request reported twice");
+    }
+
+    #[test]
+    fn task_dropped_before_reporting_is_aborted() {
+        let (result_tx, mut result_rx) = mpsc::channel(1);
+        drop(ResultReporter { generation: 3, result_tx: Some(result_tx), dropped_results: Arc::default() });
+
+        assert!(matches!(result_rx.try_recv(), Ok(WithGeneration { generation: 3, result: Err(NetworkError::Aborted) })));
+    }
+
+    #[tokio::test]
+    async fn result_loop_delivers_what_is_queued_and_stops_on_shutdown() {
+        let task_records = Arc::new(DashMap::new());
+        let (tx, rx) = std_mpsc::channel();
+        task_records.insert(1, TaskRecord {
+            abort_handle: tokio::spawn(std::future::pending::<()>()).abort_handle(),
+            callback: Box::new(move |with_generation: WithGeneration<NetworkResult>| {
+                tx.send(matches!(with_generation.result, Err(NetworkError::Timeout))).unwrap();
+            }),
+        });
+        let (result_tx, result_rx) = mpsc::channel(4);
+        result_tx.send(WithGeneration { generation: 1, result: Err(NetworkError::Timeout) }).await.unwrap();
+        let cancellation_token = CancellationToken::new();
+        cancellation_token.cancel();
+
+        let result_loop = NetworkImpl::send_result_back(task_records.clone(), cancellation_token, result_rx);
+        tokio::time::timeout(WAIT, result_loop).await.expect("Result loop kept running after shutdown");
+
+        assert!(rx.try_recv().unwrap());
+        assert!(task_records.is_empty());
+    }
+
+    #[test]
+    fn cancel_ends_the_session_of_its_generation() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config(&listener), Arc::new(connector.clone())).unwrap();
+        let (generation, _stream) = connect(&mut network);
+
+        network.cancel(generation).unwrap();
+
+        assert_eq!(network.session_state(), ConnectionState::Disconnected);
+        wait_until(|| connector.latest().unwrap().is_closed());
+        assert!(network.disconnect_chat().is_err());
+    }
+
+    #[test]
+    fn cancel_of_another_generation_leaves_the_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config(&listener), Arc::new(connector.clone())).unwrap();
+        let (_, _stream) = connect(&mut network);
+        let (tx, rx) = std_mpsc::channel();
+        let err_tx = tx.clone();
+        let generation = network
+            .fetch_captcha(
+                60_000,
+                Box::new(move |_| tx.send(None).unwrap()),
+                Box::new(move |error| err_tx.send(Some(error.result)).unwrap()),
+            )
+            .unwrap();
+
+        network.cancel(generation).unwrap();
+
+        assert!(matches!(rx.recv_timeout(WAIT).unwrap(), Some(NetworkError::UsrCancelled)));
+        assert_eq!(network.session_state(), ConnectionState::Connected);
+        assert!(!connector.latest().unwrap().is_closed());
+        // Neither a task nor a session goes by the old generation any more.
+        assert!(network.cancel(generation).is_err());
+    }
+
+    #[test]
+    fn cancel_of_a_replaced_session_keeps_the_new_one() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config(&listener), Arc::new(connector.clone())).unwrap();
+        let (first, _first_stream) = connect(&mut network);
+        let (_, _second_stream) = connect(&mut network);
+
+        assert!(network.cancel(first).is_err());
+        assert_eq!(network.session_state(), ConnectionState::Connected);
+        let workers = connector.workers();
+        assert!(workers[0].is_closed());
+        assert!(!workers[1].is_closed());
+    }
+
+    #[test]
+    fn requests_can_be_made_from_inside_a_tokio_task() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut network = NetworkImpl::with_ws_connector(config(&listener), Arc::new(FakeWsConnector::default())).unwrap();
+        let (_, _stream) = connect(&mut network);
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let (tx, rx) = std_mpsc::channel();
+
+        // Blocking on the network's runtime from in here would panic.
+        let task = runtime.spawn(async move {
+            let err_tx = tx.clone();
+            let generation = network.fetch_captcha(
+                50,
+                Box::new(move |_| tx.send(None).unwrap()),
+                Box::new(move |error| err_tx.send(Some(error.result)).unwrap()),
+            );
+            network.restore_auth(TokenInfo::for_test()).unwrap();
+            network.send_read_receipt(ConversationId(Uuid::new_v4()), 1).unwrap();
+            (generation, network)
+        });
+        let (generation, _network) = runtime.block_on(task).unwrap();
+
+        assert!(generation.is_ok());
+        assert!(matches!(rx.recv_timeout(WAIT).unwrap(), Some(NetworkError::Timeout)));
+    }
+
+    #[test]
+    fn typing_notice_is_sent_without_waiting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config(&listener), Arc::new(connector.clone())).unwrap();
+        let conversation_id = ConversationId(Uuid::new_v4());
+        assert!(network.send_typing(conversation_id.clone()).is_err());
+        let (_, _stream) = connect(&mut network);
+
+        network.send_typing(conversation_id.clone()).unwrap();
+
+        let worker = connector.latest().unwrap();
+        wait_until(|| !worker.sent().is_empty());
+        assert!(matches!(&worker.sent()[..], [ClientToServer::Typing(notice)] if notice.conversation_id == conversation_id));
+    }
+
+    #[test]
+    fn swapped_token_reaches_rest_calls_and_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = config(&listener);
+        let tokens = serve_tokens(listener);
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config, Arc::new(connector.clone())).unwrap();
+        let (_, _stream) = connect(&mut network);
+        fetch_attachment(&mut network);
+        assert_eq!(tokens.recv_timeout(WAIT).unwrap(), "chat-token");
+
+        network.set_auth_token("swapped-token".to_string(), 3600).unwrap();
+        fetch_attachment(&mut network);
+        connector.latest().unwrap().drop_connection(DisconnectReason::ConnectionError);
+
+        assert_eq!(tokens.recv_timeout(WAIT).unwrap(), "swapped-token");
+        wait_until(|| connector.workers().len() == 2);
+        assert_eq!(connector.latest().unwrap().access_token, "swapped-token");
+    }
+
+    #[test]
+    fn swapped_token_replaces_the_restored_one() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = config(&listener);
+        let tokens = serve_tokens(listener);
+        let mut network = NetworkImpl::with_ws_connector(config, Arc::new(FakeWsConnector::default())).unwrap();
+        network.restore_auth(TokenInfo { access_token: "restored-token".to_string(), ..TokenInfo::for_test() }).unwrap();
+        fetch_attachment(&mut network);
+        assert_eq!(tokens.recv_timeout(WAIT).unwrap(), "restored-token");
+
+        network.set_auth_token("swapped-token".to_string(), 3600).unwrap();
+        fetch_attachment(&mut network);
+
+        assert_eq!(tokens.recv_timeout(WAIT).unwrap(), "swapped-token");
+    }
+
+    #[test]
+    fn send_lost_with_the_connection_is_redelivered_after_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config(&listener), Arc::new(connector.clone())).unwrap();
+        let (_, _stream) = connect(&mut network);
+        let conversation_id = ConversationId(Uuid::new_v4());
+        let client_id = Uuid::new_v4();
+        let result = send_text(&mut network, &conversation_id, client_id, "hello");
+        let first = connector.latest().unwrap();
+        let message_seq = sent_seq(&first, client_id);
+
+        first.drop_connection(DisconnectReason::ConnectionError);
+        wait_until(|| connector.workers().len() == 2);
+        let second = connector.latest().unwrap();
+
+        assert_eq!(sent_seq(&second, client_id), message_seq);
+        second.inject(ServerToClient::ACK(ACK { message_seq, seq: Some(7), client_id: Some(client_id) })).unwrap();
+        assert!(matches!(result.recv_timeout(WAIT).unwrap(), Ok(MessageSent { seq: Some(7) })));
+    }
+
+    #[test]
+    fn ack_of_a_send_made_while_reconnecting_is_due_from_the_replay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = config(&listener);
+        config.timeouts.ack_ms = 200;
+        config.reconnect_initial_backoff = Duration::from_millis(600);
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config, Arc::new(connector.clone())).unwrap();
+        let (_, _stream) = connect(&mut network);
+        connector.set_refusing(true);
+        connector.latest().unwrap().drop_connection(DisconnectReason::ConnectionError);
+        wait_until(|| network.session_state() == ConnectionState::Reconnecting);
+
+        // Fails on the dropped connection, and the reconnect only comes after the ACK wait would be over.
+        let client_id = Uuid::new_v4();
+        let result = send_text(&mut network, &ConversationId(Uuid::new_v4()), client_id, "hello");
+        connector.set_refusing(false);
+        wait_until(|| connector.workers().len() == 2);
+        let second = connector.latest().unwrap();
+        let message_seq = sent_seq(&second, client_id);
+
+        second.inject(ServerToClient::ACK(ACK { message_seq, seq: None, client_id: Some(client_id) })).unwrap();
+        assert!(matches!(result.recv_timeout(WAIT).unwrap(), Ok(MessageSent { seq: None })));
+    }
+
+    #[test]
+    fn ack_resolves_the_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config(&listener), Arc::new(connector.clone())).unwrap();
+        let (_, _stream) = connect(&mut network);
+        let conversation_id = ConversationId(Uuid::new_v4());
+        let client_id = Uuid::new_v4();
+
+        let result = send_text(&mut network, &conversation_id, client_id, "hello");
+        let worker = connector.latest().unwrap();
+        let message_seq = sent_seq(&worker, client_id);
+        assert!(matches!(
+            &worker.sent()[..],
+            [ClientToServer::Send(SendMessage { content: ChatContent { conversation_id: sent_to, content: ChatBody::Text(text), .. }, .. })]
+                if *sent_to == conversation_id && text == "hello"
+        ));
+        assert_eq!(network.pending_count(), 1);
+        worker.inject(ServerToClient::ACK(ACK { message_seq, seq: Some(3), client_id: Some(client_id) })).unwrap();
+
+        assert!(matches!(result.recv_timeout(WAIT).unwrap(), Ok(MessageSent { seq: Some(3) })));
+        assert_eq!(network.pending_count(), 0);
+    }
+
+    #[test]
+    fn send_without_an_ack_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = config(&listener);
+        config.timeouts.ack_ms = 50;
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config, Arc::new(connector.clone())).unwrap();
+        let (_, _stream) = connect(&mut network);
+        let client_id = Uuid::new_v4();
+
+        let result = send_text(&mut network, &ConversationId(Uuid::new_v4()), client_id, "hello");
+        sent_seq(&connector.latest().unwrap(), client_id);
+
+        assert_eq!(result.recv_timeout(WAIT).unwrap().unwrap_err(), format!("{:?}", MessageError::AckTimeout));
+        assert_eq!(network.pending_count(), 0);
+    }
+
+    #[test]
+    fn nack_fails_the_send_with_its_reason() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config(&listener), Arc::new(connector.clone())).unwrap();
+        let (_, _stream) = connect(&mut network);
+        let conversation_id = ConversationId(Uuid::new_v4());
+        let client_id = Uuid::new_v4();
+
+        let result = send_text(&mut network, &conversation_id, client_id, "hello");
+        let worker = connector.latest().unwrap();
+        let message_seq = sent_seq(&worker, client_id);
+        worker.inject(ServerToClient::NACK(NACK {
+            message_seq,
+            reason: NackReason::NotAMember,
+            detail: Some("You left this conversation".to_string()),
+        })).unwrap();
+
+        assert_eq!(result.recv_timeout(WAIT).unwrap().unwrap_err(), format!("{:?}", MessageError::NotAMember));
+        assert_eq!(network.pending_count(), 0);
+    }
+
+    #[test]
+    fn distribute_reaches_the_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config(&listener), Arc::new(connector.clone())).unwrap();
+        let (_, stream) = connect(&mut network);
+        let conversation_id = ConversationId(Uuid::new_v4());
+        let sender = UserId(Uuid::new_v4());
+
+        connector.latest().unwrap().inject(ServerToClient::Distribute(DistributeMessage {
+            sender: sender.clone(),
+            sender_name: Some("alice".to_string()),
+            sent_at: Utc::now(),
+            seq: Some(1),
+            client_id: Some(Uuid::new_v4()),
+            content: ChatContent { conversation_id: conversation_id.clone(), content: ChatBody::Text("hi".to_string()), attachment: None },
+        })).unwrap();
+
+        let message = std::iter::from_fn(|| stream.recv_timeout(WAIT).ok())
+            .find_map(|message| match message {
+                StreamMessage::Distribute(message) => Some(message),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(message.sender, sender);
+        assert_eq!(message.conversation_id, conversation_id);
+        assert_eq!(message.seq, Some(1));
+        assert!(matches!(message.content, ChatBody::Text(ref text) if text == "hi"));
+    }
+
+    #[test]
+    fn ack_for_an_unknown_send_leaves_the_stream_running() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config(&listener), Arc::new(connector.clone())).unwrap();
+        let (_, stream) = connect(&mut network);
+        let conversation_id = ConversationId(Uuid::new_v4());
+        let worker = connector.latest().unwrap();
+
+        worker.inject(ServerToClient::ACK(ACK { message_seq: 999, seq: Some(1), client_id: None })).unwrap();
+        worker.inject(ServerToClient::Distribute(DistributeMessage {
+            sender: UserId(Uuid::new_v4()),
+            sender_name: None,
+            sent_at: Utc::now(),
+            seq: Some(2),
+            client_id: None,
+            content: ChatContent { conversation_id: conversation_id.clone(), content: ChatBody::Text("still here".to_string()), attachment: None },
+        })).unwrap();
+
+        let message = std::iter::from_fn(|| stream.recv_timeout(WAIT).ok())
+            .find_map(|message| match message {
+                StreamMessage::Distribute(message) => Some(message),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(message.conversation_id, conversation_id);
+        assert_eq!(message.seq, Some(2));
+    }
+
+    #[test]
+    fn distribute_repeated_under_one_client_id_reaches_the_stream_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config(&listener), Arc::new(connector.clone())).unwrap();
+        let (_, stream) = connect(&mut network);
+        let conversation_id = ConversationId(Uuid::new_v4());
+        let worker = connector.latest().unwrap();
+        let distribute = |client_id, text: &str| ServerToClient::Distribute(DistributeMessage {
+            sender: UserId(Uuid::new_v4()),
+            sender_name: None,
+            sent_at: Utc::now(),
+            seq: None,
+            client_id: Some(client_id),
+            content: ChatContent { conversation_id: conversation_id.clone(), content: ChatBody::Text(text.to_string()), attachment: None },
+        });
+        let next_text = || std::iter::from_fn(|| stream.recv_timeout(Duration::from_millis(200)).ok())
+            .find_map(|message| match message {
+                StreamMessage::Distribute(ChatMessage { content: ChatBody::Text(text), .. }) => Some(text),
+                _ => None,
+            });
+        let client_id = Uuid::new_v4();
+
+        worker.inject(distribute(client_id, "once")).unwrap();
+        worker.inject(distribute(client_id, "once")).unwrap();
+        assert_eq!(next_text().as_deref(), Some("once"));
+        assert_eq!(next_text(), None);
+
+        worker.inject(distribute(Uuid::new_v4(), "another")).unwrap();
+        assert_eq!(next_text().as_deref(), Some("another"));
+    }
+
+    #[test]
+    fn read_receipt_reaches_the_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config(&listener), Arc::new(connector.clone())).unwrap();
+        let (_, stream) = connect(&mut network);
+        let conversation_id = ConversationId(Uuid::new_v4());
+        let reader = UserId(Uuid::new_v4());
+
+        connector.latest().unwrap().inject(ServerToClient::Read(ReadMessage {
+            conversation_id: conversation_id.clone(),
+            reader: reader.clone(),
+            up_to_seq: 4,
+        })).unwrap();
+
+        let notification = std::iter::from_fn(|| stream.recv_timeout(WAIT).ok())
+            .find_map(|message| match message {
+                StreamMessage::Read(notification) => Some(notification),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(notification.conversation_id, conversation_id);
+        assert_eq!(notification.reader, reader);
+        assert_eq!(notification.up_to_seq, 4);
+    }
+
+    #[test]
+    fn flooded_stream_drops_the_oldest_messages_and_says_so() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = config(&listener);
+        config.channel_capacity = 4;
+        let connector = FakeWsConnector::default();
+        let mut network = NetworkImpl::with_ws_connector(config, Arc::new(connector.clone())).unwrap();
+        // Stalls the callback, and with it the runtime, while the test holds the lock.
+        let gate = Arc::new(std::sync::Mutex::new(()));
+        let callback_gate = gate.clone();
+        let (stream_tx, stream) = std_mpsc::channel();
+        let (tx, rx) = std_mpsc::channel();
+        let err_tx = tx.clone();
+        network
+            .connect_chat(
+                String::new(),
+                "chat-token".to_string(),
+                Box::new(move |message| {
+                    let _ = stream_tx.send(message);
+                    drop(callback_gate.lock().unwrap());
+                }),
+                5000,
+                Box::new(move |event| tx.send(event.result.result.is_ok()).unwrap()),
+                Box::new(move |_| err_tx.send(false).unwrap()),
+            )
+            .unwrap();
+        assert!(rx.recv_timeout(WAIT).unwrap(), "Chat session did not connect");
+        let conversation_id = ConversationId(Uuid::new_v4());
+        let worker = connector.latest().unwrap();
+        let distribute = |seq| ServerToClient::Distribute(DistributeMessage {
+            sender: UserId(Uuid::new_v4()),
+            sender_name: None,
+            sent_at: Utc::now(),
+            seq: Some(seq),
+            client_id: Some(Uuid::new_v4()),
+            content: ChatContent { conversation_id: conversation_id.clone(), content: ChatBody::Text(seq.to_string()), attachment: None },
+        });
+
+        let held = gate.lock().unwrap();
+        worker.inject(distribute(0)).unwrap();
+        let received_seq = |message| match message {
+            StreamMessage::Distribute(message) => message.seq,
+            _ => None,
+        };
+        assert_eq!(std::iter::from_fn(|| stream.recv_timeout(WAIT).ok()).find_map(received_seq), Some(0));
+        for seq in 1..=100 {
+            worker.inject(distribute(seq)).unwrap();
+        }
+        drop(held);
+
+        let mut dropped = 0;
+        let mut received = Vec::new();
+        while let Ok(message) = stream.recv_timeout(Duration::from_millis(200)) {
+            match message {
+                StreamMessage::MessagesDropped { count } => dropped += count,
+                message => received.extend(received_seq(message)),
+            }
+        }
+        // Only the newest messages that fit the channel are left; the rest are counted, not buffered.
+        assert_eq!(received, (97..=100).collect::<Vec<_>>());
+        assert_eq!(dropped, 96);
     }
 }
@@ -0,0 +1,229 @@
+use crate::domain::{ChatBody, ConversationId};
+use crate::protocol::network::*;
+use chrono::{DateTime, TimeDelta, Utc};
+use uuid::Uuid;
+
+/// `NetworkInterface` for when no real one could be built, e.g. behind the fatal page.
+///
+/// Every request fails to start and there is never a session.
+#[derive(Debug, Default)]
+pub struct OfflineNetwork;
+
+impl OfflineNetwork {
+    fn unavailable<T>() -> anyhow::Result<T> {
+        Err(anyhow::anyhow!("The network is unavailable"))
+    }
+}
+
+impl NetworkInterface for OfflineNetwork {
+    fn fetch_captcha(
+        &mut self,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<CaptchaEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn signup(
+        &mut self,
+        _username: String,
+        _password: String,
+        _captcha_id: Uuid,
+        _captcha_answer: String,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<SignupEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn login(
+        &mut self,
+        _username: String,
+        _password: String,
+        _captcha_id: Uuid,
+        _captcha_answer: String,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<LoginEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn restore_auth(&mut self, _token_info: TokenInfo) -> anyhow::Result<()> {
+        Self::unavailable()
+    }
+
+    fn set_auth_token(&mut self, _access_token: String, _expires_in: u64) -> anyhow::Result<()> {
+        Self::unavailable()
+    }
+
+    fn refresh(
+        &mut self,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<RefreshEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn logout(
+        &mut self,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<LogoutEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn fetch_history(
+        &mut self,
+        _conversation_id: ConversationId,
+        _before: Option<DateTime<Utc>>,
+        _limit: u32,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<HistoryEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn list_conversations(
+        &mut self,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<ConversationsEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn fetch_presence(
+        &mut self,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<PresenceEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn open_direct(
+        &mut self,
+        _username: String,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<DirectEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn server_info(
+        &mut self,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<ServerInfoEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn cancel(&mut self, _generation: u64) -> anyhow::Result<()> {
+        Self::unavailable()
+    }
+
+    fn connect_chat(
+        &mut self,
+        _address: String,
+        _jwt: String,
+        _msg_function: Box<dyn Fn(StreamMessage) + Send + Sync>,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<SessionEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn disconnect_chat(&mut self) -> anyhow::Result<()> {
+        Self::unavailable()
+    }
+
+    /// Nothing is ever streamed, so the callback is dropped right away.
+    fn subscribe_stream(&mut self, _callback: Box<dyn Fn(StreamMessage) + Send + Sync>) -> u64 {
+        0
+    }
+
+    fn unsubscribe_stream(&mut self, _subscription: u64) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn session_state(&self) -> ConnectionState {
+        ConnectionState::Disconnected
+    }
+
+    fn pending_count(&self) -> usize {
+        0
+    }
+
+    fn clock_offset(&self) -> TimeDelta {
+        TimeDelta::zero()
+    }
+
+    fn metrics(&self) -> ConnectionMetrics {
+        ConnectionMetrics::default()
+    }
+
+    fn send_chat_message(
+        &mut self,
+        _conversation_id: ConversationId,
+        _client_id: Uuid,
+        _message: ChatBody,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<MessageEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn send_chat_attachment(
+        &mut self,
+        _request: AttachmentRequest,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<MessageEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn send_chat_messages(
+        &mut self,
+        _conversation_id: ConversationId,
+        _messages: Vec<(Uuid, ChatBody)>,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<MessageBatchEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn fetch_attachment(
+        &mut self,
+        _blob_id: Uuid,
+        _timeout: u64,
+        _map_function: Box<dyn FnOnce(WithGeneration<AttachmentEvent>) + Send + Sync>,
+        _err_function: Box<dyn FnOnce(WithGeneration<NetworkError>) + Send + Sync>,
+    ) -> anyhow::Result<u64> {
+        Self::unavailable()
+    }
+
+    fn send_typing(&mut self, _conversation_id: ConversationId) -> anyhow::Result<()> {
+        Self::unavailable()
+    }
+
+    fn send_read_receipt(&mut self, _conversation_id: ConversationId, _up_to_seq: u64) -> anyhow::Result<()> {
+        Self::unavailable()
+    }
+
+    fn shutdown(&mut self, on_done: Box<dyn FnOnce() + Send + Sync>) -> anyhow::Result<()> {
+        on_done();
+        Ok(())
+    }
+}
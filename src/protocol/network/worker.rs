@@ -1,29 +1,47 @@
+use anyhow::Context;
 use futures_util::{StreamExt};
-use crate::protocol::network::{CaptchaData, TokenInfo, WithGeneration};
+use crate::protocol::network::{CaptchaData, ChatConnError, ChatMessage, ConnectionMetrics, ConversationInfo, ConversationMember, DirectError, DisconnectReason, LoginError, PresenceNotification, RefreshError, ServerDetail, ServerInfo, SignupError, TokenInfo, WithGeneration};
 use crate::domain;
 use chrono::{DateTime, Utc};
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io::BufReader;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use futures_util::SinkExt;
 use futures_util::stream::{SplitSink, SplitStream};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tokio::sync::watch;
+use tokio::sync::{broadcast, watch};
 use tokio::task::JoinHandle;
 use tokio_tungstenite::{connect_async_tls_with_config, MaybeTlsStream, WebSocketStream};
 use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http, Error, Message};
-use tracing::{trace, warn};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tracing::{debug, trace, warn};
+use url::Url;
 use uuid::Uuid;
 use crate::domain::ConversationId;
-use crate::protocol::network::ws_message::{ClientToServer, ServerToClient, ChatContent, SendMessage};
+use crate::protocol::network::ws_message::{ClientToServer, ServerToClient, ChatContent, DistributeMessage, PresenceMessage, ReadReceipt, SendMessage, TypingNotice};
 
-const API_BASE_URL: &str = "https://127.0.0.1:8443/api/v1";
 const CAPTCHA_SUFFIX: &str = "captcha";
 const SIGNUP_SUFFIX: &str = "signup";
 const LOGIN_SUFFIX: &str = "login";
+const REFRESH_SUFFIX: &str = "refresh";
+const LOGOUT_SUFFIX: &str = "logout";
+const CONVERSATIONS_SUFFIX: &str = "conversations";
+const DIRECT_SUFFIX: &str = "conversations/direct";
+const PRESENCE_SUFFIX: &str = "presence";
+const ATTACHMENTS_SUFFIX: &str = "attachments";
+const TIME_SUFFIX: &str = "time";
+const INFO_SUFFIX: &str = "info";
+const CAPTCHA_RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const CLOSE_TIMEOUT: Duration = Duration::from_millis(500);
+/// How often an open chat connection is pinged to measure its round trip.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
 
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -58,11 +76,168 @@ struct LoginRequest {
 struct LoginResponse {
     pub user_id: domain::UserId,
     pub auth_tokens: domain::AuthTokens,
+    #[serde(default)]
+    pub chat_address: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TimeResponse {
+    pub now: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InfoResponse {
+    pub version: String,
+    pub protocol_version: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryQuery {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<DateTime<Utc>>,
+    pub limit: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryResponse {
+    pub messages: Vec<DistributeMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadResponse {
+    pub blob_id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConversationsResponse {
+    pub conversations: Vec<ConversationResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConversationResponse {
+    pub conversation_id: ConversationId,
+    pub kind: domain::ConversationKind,
+    pub display_name: String,
+    #[serde(default)]
+    pub members: Vec<MemberResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MemberResponse {
+    pub user_id: domain::UserId,
+    pub username: String,
+    #[serde(default)]
+    pub online: bool,
+}
+
+fn conversation_info(conversation: ConversationResponse) -> ConversationInfo {
+    ConversationInfo {
+        conversation_id: conversation.conversation_id,
+        kind: conversation.kind,
+        display_name: conversation.display_name,
+        members: conversation.members
+            .into_iter()
+            .map(|member| ConversationMember { user_id: member.user_id, username: member.username, online: member.online })
+            .collect(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PresenceResponse {
+    pub users: Vec<PresenceMessage>,
+}
+
+#[derive(Debug, Serialize)]
+struct DirectRequest {
+    pub username: String,
+}
+
+/// Error body returned by the server alongside a non-success status.
+#[derive(Debug, Default)]
+struct ErrorResponse {
+    pub code: String,
+    /// Meant for the user, from `message` or, as some servers call it, `error`.
+    pub message: String,
+}
+
+impl ErrorResponse {
+    /// Takes what it can from `body`: a body that is not JSON, or a field that is not a string, counts as missing.
+    fn parse(body: &[u8]) -> Self {
+        let value = serde_json::from_slice::<serde_json::Value>(body).unwrap_or_default();
+        let field = |name: &str| value.get(name).and_then(serde_json::Value::as_str).map(str::trim).unwrap_or_default();
+        let message = [field("message"), field("error")].into_iter().find(|text| !text.is_empty()).unwrap_or_default();
+        Self { code: field("code").to_string(), message: message.to_string() }
+    }
+
+    /// Attaches `message` to `error` as a `ServerDetail`, unless there is none.
+    fn with_detail(&self, error: anyhow::Error) -> anyhow::Error {
+        match self.message.as_str() {
+            "" => error,
+            message => error.context(ServerDetail(message.to_string())),
+        }
+    }
+}
+
+async fn error_response(response: reqwest::Response) -> (StatusCode, ErrorResponse) {
+    let status = response.status();
+    let body = response.bytes().await.unwrap_or_default();
+    (status, ErrorResponse::parse(&body))
+}
+
+fn signup_error(status: StatusCode, body: ErrorResponse) -> anyhow::Error {
+    let error = match (status, body.code.as_str()) {
+        (_, "wrong_captcha") => SignupError::WrongCaptcha.into(),
+        (_, "weak_password") => SignupError::WeakPassword.into(),
+        (_, "duplicate_name") | (StatusCode::CONFLICT, _) => SignupError::DuplicateName.into(),
+        _ => anyhow::anyhow!("Signup rejected with {}", status),
+    };
+    body.with_detail(error)
+}
+
+fn login_error(status: StatusCode, body: ErrorResponse) -> anyhow::Error {
+    let error = match (status, body.code.as_str()) {
+        (_, "wrong_captcha") => LoginError::WrongCaptcha.into(),
+        (StatusCode::UNAUTHORIZED, _) => LoginError::Unauthorized.into(),
+        _ => anyhow::anyhow!("Login rejected with {}", status),
+    };
+    body.with_detail(error)
+}
+
+fn direct_error(status: StatusCode, body: ErrorResponse) -> anyhow::Error {
+    match (status, body.code.as_str()) {
+        (_, "user_not_found") | (StatusCode::NOT_FOUND, _) => DirectError::UserNotFound.into(),
+        (StatusCode::UNAUTHORIZED, _) => DirectError::Unauthorized.into(),
+        _ => anyhow::anyhow!("Direct conversation rejected with {}: {}", status, body.message),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RefreshResponse {
+    pub user_id: domain::UserId,
+    pub auth_tokens: domain::AuthTokens,
+}
+
+fn token_info(user_id: domain::UserId, auth_tokens: domain::AuthTokens, chat_address: Option<String>) -> TokenInfo {
+    TokenInfo {
+        user_id,
+        access_token: auth_tokens.access_token,
+        access_expires_in: auth_tokens.access_expires_in,
+        refresh_token: auth_tokens.refresh_token,
+        refresh_expires_in: auth_tokens.refresh_expires_in,
+        chat_address,
+    }
 }
 
 #[async_trait::async_trait]
 pub trait HttpWorker: Send + Sync {
-    async fn fetch_captcha(&self) -> anyhow::Result<CaptchaData>;
+    /// Tries up to `attempts` times, but never starts an attempt that would begin after `deadline`.
+    async fn fetch_captcha(&self, attempts: u32, deadline: tokio::time::Instant) -> anyhow::Result<CaptchaData>;
     async fn signup(
         &self,
         username: String,
@@ -77,49 +252,133 @@ pub trait HttpWorker: Send + Sync {
         captcha_id: Uuid,
         captcha_answer: String,
     ) -> anyhow::Result<TokenInfo>;
+    async fn refresh_token(&self, refresh_token: String) -> anyhow::Result<TokenInfo>;
+    async fn logout(&self, access_token: String) -> anyhow::Result<()>;
+    /// Returns up to `limit` messages sent before `before`, oldest first.
+    async fn fetch_history(
+        &self,
+        access_token: String,
+        conversation_id: ConversationId,
+        before: Option<DateTime<Utc>>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<ChatMessage>>;
+    /// Returns the conversations the user is a member of.
+    async fn list_conversations(&self, access_token: String) -> anyhow::Result<Vec<ConversationInfo>>;
+    /// Returns the online status of the users sharing a conversation with the user.
+    async fn fetch_presence(&self, access_token: String) -> anyhow::Result<Vec<PresenceNotification>>;
+    /// Returns the direct conversation with the user named `username`, started on the spot if there is none.
+    async fn open_direct(&self, access_token: String, username: String) -> anyhow::Result<ConversationInfo>;
+    async fn upload_attachment(
+        &self,
+        access_token: String,
+        bytes: Vec<u8>,
+        mime: String,
+        filename: String,
+    ) -> anyhow::Result<domain::Attachment>;
+    async fn fetch_attachment(&self, access_token: String, blob_id: Uuid) -> anyhow::Result<Vec<u8>>;
+    /// The server's clock, for estimating how far the local one is off.
+    async fn server_time(&self) -> anyhow::Result<DateTime<Utc>>;
+    /// Needs no login, so it doubles as a check that `api_base_url` points at a chat server.
+    async fn server_info(&self) -> anyhow::Result<ServerInfo>;
 
     fn clone_box(&self) -> Box<dyn HttpWorker>;
 }
 
-fn endpoint_url(suffix: &str) -> String {
+fn endpoint_url(base_url: &Url, suffix: &str) -> String {
     format!(
         "{}/{}",
-        API_BASE_URL.trim_end_matches('/'),
+        base_url.as_str().trim_end_matches('/'),
         suffix.trim_start_matches('/')
     )
 }
 
+/// Builds the TLS configuration shared by the HTTP and WebSocket workers.
+///
+/// Roots are read from `cert_path` when given, otherwise from the platform's native store.
+pub fn load_tls_config(cert_path: Option<&Path>) -> anyhow::Result<Arc<rustls::ClientConfig>> {
+    let mut root_store = rustls::RootCertStore::empty();
+    match cert_path {
+        Some(path) => {
+            let cert_file = fs::File::open(path)
+                .with_context(|| format!("Failed to open certificate: {}", path.display()))?;
+            let certs = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| format!("Failed to parse certificate: {}", path.display()))?;
+            for cert in certs {
+                root_store.add(cert)?
+            }
+        }
+        None => {
+            let native = rustls_native_certs::load_native_certs();
+            for error in native.errors {
+                warn!("Failed to load native certificate: {}", error);
+            }
+            let (added, ignored) = root_store.add_parsable_certificates(native.certs);
+            trace!("Loaded native certificates: {} added, {} ignored", added, ignored);
+        }
+    }
+    if root_store.is_empty() {
+        return Err(anyhow::anyhow!("No trusted root certificates available"));
+    }
+
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
 #[derive(Clone)]
 pub struct RealHttpWorker {
     client: Client,
+    api_base_url: Url,
 }
 
 impl RealHttpWorker {
-    pub fn new() -> Self {
-        let cert = fs::read("certs/dev_cert.pem").expect("Failed to read certificate");
-        let cert = reqwest::Certificate::from_pem(&cert).expect("Failed to parse cert");
-
+    pub fn try_new(api_base_url: Url, tls_config: Arc<rustls::ClientConfig>) -> anyhow::Result<Self> {
         let client = Client::builder()
-            .add_root_certificate(cert)
+            .use_preconfigured_tls((*tls_config).clone())
             .no_proxy()
             .build()
-            .expect("Failed to build http client");
-        Self { client }
+            .context("Failed to build http client")?;
+        Ok(Self { client, api_base_url })
     }
-}
 
-#[async_trait::async_trait]
-impl HttpWorker for RealHttpWorker {
-    async fn fetch_captcha(&self) -> anyhow::Result<CaptchaData> {
-        let response = self.client.get(endpoint_url(CAPTCHA_SUFFIX)).send().await?;
+    async fn request_captcha(&self) -> anyhow::Result<CaptchaData> {
+        let response = self.client.get(endpoint_url(&self.api_base_url, CAPTCHA_SUFFIX)).send().await?;
         let response: CaptchaResponse = response.json().await?;
         let captcha_data = CaptchaData {
             id: response.id,
             image_base64: response.image_base64,
+            expire_at: response.expire_at,
         };
 
         Ok(captcha_data)
     }
+}
+
+#[async_trait::async_trait]
+impl HttpWorker for RealHttpWorker {
+    async fn fetch_captcha(&self, attempts: u32, deadline: tokio::time::Instant) -> anyhow::Result<CaptchaData> {
+        let mut backoff = CAPTCHA_RETRY_INITIAL_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let error = match self.request_captcha().await {
+                Ok(captcha_data) => return Ok(captcha_data),
+                Err(error) => error,
+            };
+            // Jitter keeps clients that failed together from retrying in lockstep.
+            let delay = backoff.mul_f64(0.5 + fastrand::f64() * 0.5);
+            if attempt >= attempts || tokio::time::Instant::now() + delay >= deadline {
+                return Err(error);
+            }
+            warn!("Failed to fetch captcha (attempt {}/{}), retrying in {:?}: {:#}", attempt, attempts, delay, error);
+            tokio::time::sleep(delay).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
 
     async fn signup(
         &self,
@@ -137,11 +396,16 @@ impl HttpWorker for RealHttpWorker {
 
         let response = self
             .client
-            .post(endpoint_url(SIGNUP_SUFFIX))
+            .post(endpoint_url(&self.api_base_url, SIGNUP_SUFFIX))
             .json(&request)
             .send()
             .await?;
 
+        if !response.status().is_success() {
+            let (status, body) = error_response(response).await;
+            return Err(signup_error(status, body));
+        }
+
         let _response: SignupResponse = response.json().await?;
 
         Ok(())
@@ -163,19 +427,211 @@ impl HttpWorker for RealHttpWorker {
 
         let response = self
             .client
-            .post(endpoint_url(LOGIN_SUFFIX))
+            .post(endpoint_url(&self.api_base_url, LOGIN_SUFFIX))
             .json(&request)
             .send()
             .await?;
 
+        if !response.status().is_success() {
+            let (status, body) = error_response(response).await;
+            return Err(login_error(status, body));
+        }
+
         let response: LoginResponse = response.json().await?;
 
-        let token_info = TokenInfo {
-            user_id: response.user_id,
-            access_token: response.auth_tokens.access_token,
-        };
+        Ok(token_info(response.user_id, response.auth_tokens, response.chat_address))
+    }
+
+    async fn refresh_token(&self, refresh_token: String) -> anyhow::Result<TokenInfo> {
+        let request = RefreshRequest { refresh_token };
+
+        let response = self
+            .client
+            .post(endpoint_url(&self.api_base_url, REFRESH_SUFFIX))
+            .json(&request)
+            .send()
+            .await?;
 
-        Ok(token_info)
+        // The server turns down a refresh token it has revoked, which no retry can fix.
+        if matches!(response.status(), StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+            return Err(RefreshError::Expired.into());
+        }
+        let response: RefreshResponse = response.error_for_status()?.json().await?;
+
+        Ok(token_info(response.user_id, response.auth_tokens, None))
+    }
+
+    async fn logout(&self, access_token: String) -> anyhow::Result<()> {
+        self.client
+            .post(endpoint_url(&self.api_base_url, LOGOUT_SUFFIX))
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn fetch_history(
+        &self,
+        access_token: String,
+        conversation_id: ConversationId,
+        before: Option<DateTime<Utc>>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        let suffix = format!("{}/{}/messages", CONVERSATIONS_SUFFIX, conversation_id.0);
+        let response: HistoryResponse = self
+            .client
+            .get(endpoint_url(&self.api_base_url, &suffix))
+            .bearer_auth(access_token)
+            .query(&HistoryQuery { before, limit })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let mut messages: Vec<ChatMessage> = response.messages
+            .into_iter()
+            .map(|message| ChatMessage {
+                sender: message.sender,
+                sender_name: message.sender_name,
+                conversation_id: message.content.conversation_id,
+                content: message.content.content,
+                sent_at: message.sent_at,
+                seq: message.seq,
+                attachment: message.content.attachment,
+                client_id: message.client_id,
+            })
+            .collect();
+        messages.sort_by_key(|message| message.sent_at);
+
+        Ok(messages)
+    }
+
+    async fn list_conversations(&self, access_token: String) -> anyhow::Result<Vec<ConversationInfo>> {
+        let response: ConversationsResponse = self
+            .client
+            .get(endpoint_url(&self.api_base_url, CONVERSATIONS_SUFFIX))
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let conversations = response.conversations
+            .into_iter()
+            .map(conversation_info)
+            .collect();
+
+        Ok(conversations)
+    }
+
+    async fn fetch_presence(&self, access_token: String) -> anyhow::Result<Vec<PresenceNotification>> {
+        let response: PresenceResponse = self
+            .client
+            .get(endpoint_url(&self.api_base_url, PRESENCE_SUFFIX))
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let presence = response.users
+            .into_iter()
+            .map(|user| PresenceNotification { user_id: user.user_id, online: user.online })
+            .collect();
+
+        Ok(presence)
+    }
+
+    async fn open_direct(&self, access_token: String, username: String) -> anyhow::Result<ConversationInfo> {
+        let response = self
+            .client
+            .post(endpoint_url(&self.api_base_url, DIRECT_SUFFIX))
+            .bearer_auth(access_token)
+            .json(&DirectRequest { username })
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let (status, body) = error_response(response).await;
+            return Err(direct_error(status, body));
+        }
+
+        let conversation: ConversationResponse = response.json().await?;
+
+        Ok(conversation_info(conversation))
+    }
+
+    async fn upload_attachment(
+        &self,
+        access_token: String,
+        bytes: Vec<u8>,
+        mime: String,
+        filename: String,
+    ) -> anyhow::Result<domain::Attachment> {
+        let part = reqwest::multipart::Part::bytes(bytes)
+            .file_name(filename.clone())
+            .mime_str(&mime)?;
+        let form = reqwest::multipart::Form::new().part("file", part);
+        let response = self
+            .client
+            .post(endpoint_url(&self.api_base_url, ATTACHMENTS_SUFFIX))
+            .bearer_auth(access_token)
+            .multipart(form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let (status, body) = error_response(response).await;
+            return Err(body.with_detail(anyhow::anyhow!("Upload rejected with {}", status)));
+        }
+        let response: UploadResponse = response.json().await?;
+
+        Ok(domain::Attachment { blob_id: response.blob_id, mime, filename })
+    }
+
+    async fn fetch_attachment(&self, access_token: String, blob_id: Uuid) -> anyhow::Result<Vec<u8>> {
+        let suffix = format!("{}/{}", ATTACHMENTS_SUFFIX, blob_id);
+        let bytes = self
+            .client
+            .get(endpoint_url(&self.api_base_url, &suffix))
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        Ok(bytes.to_vec())
+    }
+
+    async fn server_time(&self) -> anyhow::Result<DateTime<Utc>> {
+        let response: TimeResponse = self
+            .client
+            .get(endpoint_url(&self.api_base_url, TIME_SUFFIX))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(response.now)
+    }
+
+    async fn server_info(&self) -> anyhow::Result<ServerInfo> {
+        let response: InfoResponse = self
+            .client
+            .get(endpoint_url(&self.api_base_url, INFO_SUFFIX))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(ServerInfo { version: response.version, protocol_version: response.protocol_version })
     }
 
     fn clone_box(&self) -> Box<dyn HttpWorker> {
@@ -189,57 +645,197 @@ impl Clone for Box<dyn HttpWorker> {
     }
 }
 
-const WS_CHAT_URL: &str = "wss://127.0.0.1:8443/api/v1/chat";
-
 #[async_trait::async_trait]
 pub trait WsWorker: Send + Sync {
-    async fn send_message(&self, message_seq: u64, conversation_id: ConversationId, content: String) -> anyhow::Result<()>;
+    async fn send_message(
+        &self,
+        message_seq: u64,
+        client_id: Uuid,
+        conversation_id: ConversationId,
+        content: domain::ChatBody,
+        attachment: Option<domain::Attachment>,
+    ) -> anyhow::Result<()>;
+    async fn send_typing(&self, conversation_id: ConversationId) -> anyhow::Result<()>;
+    async fn send_read(&self, conversation_id: ConversationId, up_to_seq: u64) -> anyhow::Result<()>;
+    /// Resolves once the underlying connection has stopped sending or receiving.
+    async fn closed(&self) -> DisconnectReason;
+    /// Sends a close frame and waits, briefly, for the connection to stop.
+    async fn close(&self);
+}
+
+/// Opens a chat session's WebSocket, first on connect and again on every reconnect.
+#[async_trait::async_trait]
+pub trait WsConnector: Send + Sync {
+    async fn connect(
+        &self,
+        ws_url: Url,
+        generation: u64,
+        access_token: String,
+        from_receiver: broadcast::Sender<WithGeneration<ServerToClient>>,
+        counters: Arc<ConnectionCounters>,
+    ) -> anyhow::Result<Box<dyn WsWorker>>;
+}
+
+/// What `NetworkInterface::metrics` reports, shared by a chat session and every connection it opens.
+/// Only atomics, so workers and the UI thread never wait on each other.
+pub struct ConnectionCounters {
+    /// Reference point for the instants below, which are kept as microseconds since it.
+    started: Instant,
+    /// 0 until the first pong.
+    rtt_micros: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+    reconnects: AtomicU64,
+    /// When the latest connection came up, plus one so that 0 can mean there was none.
+    connected_at: AtomicU64,
+}
+
+impl Default for ConnectionCounters {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            rtt_micros: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            connected_at: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ConnectionCounters {
+    fn micros(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64
+    }
+
+    pub fn count_sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_connected(&self) {
+        self.connected_at.store(self.micros() + 1, Ordering::Relaxed);
+    }
+
+    /// The payload of a ping, which the server echoes in its pong.
+    fn ping_payload(&self) -> Vec<u8> {
+        self.micros().to_be_bytes().to_vec()
+    }
+
+    /// Takes the round trip from a pong to a `ping_payload`; anything else is ignored.
+    fn record_pong(&self, payload: &[u8]) {
+        let Ok(sent_at) = <[u8; 8]>::try_from(payload).map(u64::from_be_bytes) else {
+            trace!("Skip pong with a foreign payload");
+            return;
+        };
+        let rtt = self.micros().saturating_sub(sent_at).max(1);
+        self.rtt_micros.store(rtt, Ordering::Relaxed);
+    }
+
+    /// Back to nothing sent, received or measured, for a new chat session.
+    pub fn reset(&self) {
+        for counter in [&self.rtt_micros, &self.sent, &self.received, &self.reconnects, &self.connected_at] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// `connected_for` is measured from the latest connection even if it has dropped since;
+    /// the session state tells whether it still stands.
+    pub fn snapshot(&self) -> ConnectionMetrics {
+        let rtt_micros = self.rtt_micros.load(Ordering::Relaxed);
+        let connected_at = self.connected_at.load(Ordering::Relaxed);
+        ConnectionMetrics {
+            last_rtt: (rtt_micros > 0).then(|| Duration::from_micros(rtt_micros)),
+            messages_sent: self.sent.load(Ordering::Relaxed),
+            messages_received: self.received.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            connected_for: (connected_at > 0).then(|| Duration::from_micros(self.micros().saturating_sub(connected_at - 1))),
+            in_flight: 0,
+        }
+    }
+}
+
+pub struct RealWsConnector {
+    pub tls_config: Arc<rustls::ClientConfig>,
+}
+
+#[async_trait::async_trait]
+impl WsConnector for RealWsConnector {
+    async fn connect(
+        &self,
+        ws_url: Url,
+        generation: u64,
+        access_token: String,
+        from_receiver: broadcast::Sender<WithGeneration<ServerToClient>>,
+        counters: Arc<ConnectionCounters>,
+    ) -> anyhow::Result<Box<dyn WsWorker>> {
+        let worker = RealWsWorker::try_new(ws_url, self.tls_config.clone(), generation, access_token, from_receiver, counters).await?;
+        Ok(Box::new(worker))
+    }
 }
 
 pub struct RealWsWorker {
     pub generation: u64,
     pub to_sender: UnboundedSender<ClientToServer>,
     pub watcher_handle: JoinHandle<()>,
+    shutdown_rx: watch::Receiver<Option<DisconnectReason>>,
+    /// Asks the sender task to close the socket; only it can write the close frame.
+    close_tx: watch::Sender<bool>,
 }
 
 impl RealWsWorker {
-    pub async fn try_new(generation: u64, access_token: String, from_receiver: UnboundedSender<WithGeneration<ServerToClient>>) -> anyhow::Result<Self> {
+    pub async fn try_new(ws_url: Url, tls_config: Arc<rustls::ClientConfig>, generation: u64, access_token: String, from_receiver: broadcast::Sender<WithGeneration<ServerToClient>>, counters: Arc<ConnectionCounters>) -> anyhow::Result<Self> {
         // region Create connection
-        let cert_file = &mut BufReader::new(fs::File::open("certs/dev_cert.pem")?);
-        let certs = rustls_pemfile::certs(cert_file).collect::<Result<Vec<_>, _>>()?;
-
-        let mut root_store = rustls::RootCertStore::empty();
-        for cert in certs {
-            root_store.add(cert)?
-        }
-
-        let _ = rustls::crypto::ring::default_provider().install_default();
+        let connector = tokio_tungstenite::Connector::Rustls(tls_config);
 
-        let config = rustls::ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-        let connector = tokio_tungstenite::Connector::Rustls(Arc::new(config));
-
-        let url = url::Url::parse(WS_CHAT_URL)?;
-        let mut request = url.into_client_request()?;
+        let mut request = ws_url.into_client_request()?;
         request.headers_mut().insert(
             http::header::AUTHORIZATION,
             http::HeaderValue::from_str(format!("Bearer {}", access_token).clone().as_str())?,
         );
 
-        let (ws_stream, _) = connect_async_tls_with_config(request, None, false, Some(connector)).await?;
+        let (ws_stream, _) = connect_async_tls_with_config(request, None, false, Some(connector))
+            .await
+            .map_err(|error| {
+                let kind = connect_error_kind(&error);
+                let body = match &error {
+                    Error::Http(response) => ErrorResponse::parse(response.body().as_deref().unwrap_or_default()),
+                    _ => ErrorResponse::default(),
+                };
+                body.with_detail(anyhow::Error::new(error).context(kind))
+            })?;
         let (mut to_server, mut from_server) = ws_stream.split();
         // endregion
 
         // region Create sender and receiver
         let (to_sender, from_app) = unbounded_channel();
-        let (shutdown_tx, shutdown_rx) = watch::channel(false);
-        let sender_handle = tokio::spawn(sender(from_app, to_server, shutdown_rx.clone()));
-        let receiver_handle = tokio::spawn(receiver(generation, from_server, from_receiver, shutdown_rx));
+        let (shutdown_tx, shutdown_rx) = watch::channel(None);
+        let (close_tx, close_rx) = watch::channel(false);
+        let sender_handle = tokio::spawn(sender(from_app, to_server, counters.clone(), close_rx, shutdown_rx.clone()));
+        let receiver_handle = tokio::spawn(receiver(generation, from_server, from_receiver, counters, shutdown_rx.clone()));
         let watcher_handle = tokio::spawn(watcher(sender_handle, receiver_handle, shutdown_tx));
         // endregion
 
-        Ok(Self { generation, to_sender, watcher_handle })
+        Ok(Self { generation, to_sender, watcher_handle, shutdown_rx, close_tx })
+    }
+}
+
+/// Sorts a failed upgrade by what the user can do about it; the error itself stays in the chain for the log.
+fn connect_error_kind(error: &Error) -> ChatConnError {
+    match error {
+        Error::Http(response) if response.status() == http::StatusCode::UNAUTHORIZED => ChatConnError::Unauthorized,
+        Error::Tls(_) => ChatConnError::Tls,
+        // The rustls connector reports handshake failures as I/O errors wrapping the rustls one.
+        Error::Io(error) if error.get_ref().is_some_and(|inner| inner.is::<rustls::Error>()) => ChatConnError::Tls,
+        Error::Io(error) if error.kind() == std::io::ErrorKind::ConnectionRefused => ChatConnError::Refused,
+        _ => ChatConnError::FallbackError,
     }
 }
 
@@ -247,14 +843,38 @@ impl RealWsWorker {
 async fn sender(
     mut from_app: UnboundedReceiver<ClientToServer>,
     mut to_server: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
-    mut shutdown: watch::Receiver<bool>,
-) {
+    counters: Arc<ConnectionCounters>,
+    mut close: watch::Receiver<bool>,
+    mut shutdown: watch::Receiver<Option<DisconnectReason>>,
+) -> DisconnectReason {
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
     loop {
         tokio::select! {
-            Some(message) = from_app.recv() => {
-                let _ = to_server.send(Message::Text(serde_json::to_string(&message).unwrap().into())).await;
+            message = from_app.recv() => match message {
+                Some(message) => {
+                    let message = Message::Text(serde_json::to_string(&message).unwrap().into());
+                    if to_server.send(message).await.is_err() {
+                        return DisconnectReason::ConnectionError;
+                    }
+                    counters.count_sent();
+                }
+                None => {
+                    // Every handle to the worker is gone, so close the socket politely.
+                    let _ = to_server.send(Message::Close(None)).await;
+                    return DisconnectReason::ClosedByClient;
+                }
+            },
+            _ = close.changed() => {
+                let _ = to_server.send(Message::Close(None)).await;
+                return DisconnectReason::ClosedByClient;
             }
-            _ = shutdown.changed() => break,
+            _ = ping.tick() => {
+                if to_server.send(Message::Ping(counters.ping_payload().into())).await.is_err() {
+                    return DisconnectReason::ConnectionError;
+                }
+            }
+            _ = shutdown.changed() => return DisconnectReason::ClosedByClient,
         }
     }
 }
@@ -262,19 +882,26 @@ async fn sender(
 async fn receiver(
     generation: u64,
     mut from_server: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-    mut from_receiver: UnboundedSender<WithGeneration<ServerToClient>>,
-    mut shutdown: watch::Receiver<bool>,
-) {
+    from_receiver: broadcast::Sender<WithGeneration<ServerToClient>>,
+    counters: Arc<ConnectionCounters>,
+    mut shutdown: watch::Receiver<Option<DisconnectReason>>,
+) -> DisconnectReason {
     loop {
         tokio::select! {
-            Some(message) = from_server.next() => {
+            message = from_server.next() => {
                 let message = match message {
-                    Ok(Message::Text(body)) => body,
-                    Ok(Message::Close(_)) => break,
-                    Ok(_) => continue,
-                    Err(_) => break,
+                    Some(Ok(Message::Text(body))) => body,
+                    Some(Ok(Message::Pong(payload))) => {
+                        counters.record_pong(&payload);
+                        continue;
+                    }
+                    Some(Ok(Message::Close(frame))) => return close_reason(frame.as_ref()),
+                    None => return DisconnectReason::ClosedByServer,
+                    Some(Ok(_)) => continue,
+                    Some(Err(_)) => return DisconnectReason::ConnectionError,
                 };
 
+                counters.count_received();
                 match serde_json::from_str(&message) {
                     Ok(message) => {
                         let message = WithGeneration {
@@ -284,36 +911,236 @@ async fn receiver(
                         trace!("Received message: {:?}", message);
                         let _ = from_receiver.send(message);
                     }
-                    Err(_) => break,
+                    Err(error) => {
+                        warn!("Skip malformed message ({}): {}", error, message.as_str());
+                        continue;
+                    }
                 }
             }
-            _ = shutdown.changed() => break,
+            _ = shutdown.changed() => return DisconnectReason::ClosedByClient,
         }
     }
 }
 
-async fn watcher(sender_handle: JoinHandle<()>, receiver_handle: JoinHandle<()>, shutdown: watch::Sender<bool>) {
-    let _ = tokio::select! {
+fn close_reason(frame: Option<&CloseFrame>) -> DisconnectReason {
+    debug!("Server closed the connection: {:?}", frame);
+    match frame.map(|frame| frame.code) {
+        Some(CloseCode::Policy) => DisconnectReason::AuthRevoked,
+        Some(CloseCode::Error) => DisconnectReason::ServerError,
+        _ => DisconnectReason::ClosedByServer,
+    }
+}
+
+async fn watcher(
+    sender_handle: JoinHandle<DisconnectReason>,
+    receiver_handle: JoinHandle<DisconnectReason>,
+    shutdown: watch::Sender<Option<DisconnectReason>>,
+) {
+    // A panicked task counts as a broken connection.
+    let reason = tokio::select! {
         result = sender_handle => {
-            warn!("Sender task ended");
-            let _ = shutdown.send(true);
+            warn!("Sender task ended: {:?}", result);
+            result.unwrap_or(DisconnectReason::ConnectionError)
         },
         result = receiver_handle => {
-            warn!("Receiver task ended");
-            let _ = shutdown.send(true);
+            warn!("Receiver task ended: {:?}", result);
+            result.unwrap_or(DisconnectReason::ConnectionError)
         }
     };
+    let _ = shutdown.send(Some(reason));
 }
 // endregion
 
 #[async_trait::async_trait]
 impl WsWorker for RealWsWorker {
-    async fn send_message(&self, message_seq: u64, conversation_id: ConversationId, content: String) -> anyhow::Result<()> {
+    async fn send_message(
+        &self,
+        message_seq: u64,
+        client_id: Uuid,
+        conversation_id: ConversationId,
+        content: domain::ChatBody,
+        attachment: Option<domain::Attachment>,
+    ) -> anyhow::Result<()> {
         let message = ClientToServer::Send(SendMessage {
             message_seq,
-            content: ChatContent { conversation_id, content },
+            client_id,
+            content: ChatContent { conversation_id, content, attachment },
         });
         self.to_sender.send(message)?;
         Ok(())
     }
+
+    async fn send_typing(&self, conversation_id: ConversationId) -> anyhow::Result<()> {
+        self.to_sender.send(ClientToServer::Typing(TypingNotice { conversation_id }))?;
+        Ok(())
+    }
+
+    async fn send_read(&self, conversation_id: ConversationId, up_to_seq: u64) -> anyhow::Result<()> {
+        self.to_sender.send(ClientToServer::Read(ReadReceipt { conversation_id, up_to_seq }))?;
+        Ok(())
+    }
+
+    async fn closed(&self) -> DisconnectReason {
+        let mut shutdown = self.shutdown_rx.clone();
+        let reason = match shutdown.wait_for(|reason| reason.is_some()).await {
+            Ok(reason) => *reason,
+            // The watcher is gone without a verdict, so the tasks are too.
+            Err(_) => None,
+        };
+        reason.unwrap_or(DisconnectReason::ConnectionError)
+    }
+
+    async fn close(&self) {
+        let _ = self.close_tx.send(true);
+        if tokio::time::timeout(CLOSE_TIMEOUT, self.closed()).await.is_err() {
+            warn!("WebSocket did not close within {:?}", CLOSE_TIMEOUT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::network::ws_message::ACK;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// A worker whose one request is answered with `status` and `body`.
+    async fn answered_with(status: &'static str, body: &'static str) -> RealHttpWorker {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_base_url = Url::parse(&format!("http://{}/api/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut content_length = 0;
+            let mut line = String::new();
+            while stream.read_line(&mut line).await.unwrap() > 0 && line != "\r\n" {
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                line.clear();
+            }
+            let mut request = vec![0; content_length];
+            stream.read_exact(&mut request).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body,
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        RealHttpWorker::try_new(api_base_url, load_tls_config(None).unwrap()).unwrap()
+    }
+
+    async fn login_with(status: &'static str, body: &'static str) -> anyhow::Error {
+        let worker = answered_with(status, body).await;
+        worker.login("alice".to_string(), "secret".to_string(), Uuid::new_v4(), "1234".to_string()).await.err().unwrap()
+    }
+
+    async fn signup_with(status: &'static str, body: &'static str) -> anyhow::Error {
+        let worker = answered_with(status, body).await;
+        worker.signup("alice".to_string(), "secret".to_string(), Uuid::new_v4(), "1234".to_string()).await.err().unwrap()
+    }
+
+    /// Runs `receiver` over a real connection whose server end sends `frames`, returning why it
+    /// stopped and what it passed on.
+    async fn receive(frames: Vec<Message>) -> (DisconnectReason, Vec<ServerToClient>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            for frame in frames {
+                socket.send(frame).await.unwrap();
+            }
+        });
+        let (socket, _) = tokio_tungstenite::connect_async(address).await.unwrap();
+        let (_to_server, from_server) = socket.split();
+        let (from_receiver, mut message_rx) = broadcast::channel(16);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(None);
+
+        let reason = receiver(0, from_server, from_receiver, Arc::new(ConnectionCounters::default()), shutdown_rx).await;
+        server.await.unwrap();
+        let received = std::iter::from_fn(|| message_rx.try_recv().ok()).map(|message| message.result).collect();
+        (reason, received)
+    }
+
+    fn close(code: CloseCode) -> Message {
+        Message::Close(Some(CloseFrame { code, reason: "closing".into() }))
+    }
+
+    fn ack(message_seq: u64) -> Message {
+        let ack = ServerToClient::ACK(ACK { message_seq, seq: None, client_id: None });
+        Message::text(serde_json::to_string(&ack).unwrap())
+    }
+
+    #[tokio::test]
+    async fn close_frame_code_decides_the_disconnect_reason() {
+        assert_eq!(receive(vec![close(CloseCode::Policy)]).await.0, DisconnectReason::AuthRevoked);
+        assert!(!DisconnectReason::AuthRevoked.is_transient());
+        assert_eq!(receive(vec![close(CloseCode::Error)]).await.0, DisconnectReason::ServerError);
+        assert_eq!(receive(vec![close(CloseCode::Normal)]).await.0, DisconnectReason::ClosedByServer);
+    }
+
+    #[tokio::test]
+    async fn malformed_and_unknown_frames_are_skipped() {
+        let frames = vec![
+            ack(1),
+            Message::text("not json"),
+            Message::text(r#"{"type":"future"}"#),
+            // Not taken for `Unknown` with a payload, but skipped all the same.
+            Message::text(r#"{"type":"future","payload":{"x":1}}"#),
+            ack(2),
+            close(CloseCode::Normal),
+        ];
+
+        let (reason, received) = receive(frames).await;
+
+        assert_eq!(reason, DisconnectReason::ClosedByServer);
+        assert!(matches!(&received[..], [
+            ServerToClient::ACK(ACK { message_seq: 1, .. }),
+            ServerToClient::Unknown,
+            ServerToClient::ACK(ACK { message_seq: 2, .. }),
+        ]));
+    }
+
+    #[tokio::test]
+    async fn login_rejections_map_to_login_errors() {
+        let error = login_with("401 Unauthorized", "{}").await;
+        assert!(matches!(error.downcast_ref(), Some(LoginError::Unauthorized)));
+
+        let error = login_with("400 Bad Request", r#"{"code":"wrong_captcha","message":"Try again"}"#).await;
+        assert!(matches!(error.downcast_ref(), Some(LoginError::WrongCaptcha)));
+        assert!(matches!(error.downcast_ref(), Some(ServerDetail(message)) if message == "Try again"));
+
+        let error = login_with("500 Internal Server Error", "").await;
+        assert!(error.downcast_ref::<LoginError>().is_none());
+    }
+
+    #[tokio::test]
+    async fn signup_rejections_map_to_signup_errors() {
+        let error = signup_with("409 Conflict", "{}").await;
+        assert!(matches!(error.downcast_ref(), Some(SignupError::DuplicateName)));
+
+        let error = signup_with("400 Bad Request", r#"{"code":"weak_password"}"#).await;
+        assert!(matches!(error.downcast_ref(), Some(SignupError::WeakPassword)));
+
+        let error = signup_with("400 Bad Request", r#"{"code":"wrong_captcha"}"#).await;
+        assert!(matches!(error.downcast_ref(), Some(SignupError::WrongCaptcha)));
+    }
+
+    #[tokio::test]
+    async fn revoked_refresh_token_is_expired() {
+        for status in ["401 Unauthorized", "403 Forbidden"] {
+            let error = answered_with(status, "{}").await.refresh_token("refresh-token".to_string()).await.err().unwrap();
+            assert!(matches!(error.downcast_ref(), Some(RefreshError::Expired)));
+        }
+
+        let error = answered_with("500 Internal Server Error", "{}").await.refresh_token("refresh-token".to_string()).await.err().unwrap();
+        let status = error.downcast_ref::<reqwest::Error>().and_then(|e| e.status());
+        assert_eq!(status, Some(StatusCode::INTERNAL_SERVER_ERROR));
+    }
 }
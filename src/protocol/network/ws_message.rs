@@ -1,41 +1,141 @@
+use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
-use crate::domain::{ConversationId, UserId};
+use uuid::Uuid;
+use crate::domain::{Attachment, ChatBody, ConversationId, UserId};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "payload", rename_all = "lowercase")]
 pub enum ClientToServer {
     HistoryFetched,
     Send(SendMessage),
+    Typing(TypingNotice),
+    Read(ReadReceipt),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SendMessage {
     pub message_seq: u64,
+    /// Stays the same when a message is resent, so the server can drop the duplicate.
+    pub client_id: Uuid,
     #[serde(flatten)]
     pub content: ChatContent,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypingNotice {
+    pub conversation_id: ConversationId,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadReceipt {
+    pub conversation_id: ConversationId,
+    pub up_to_seq: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "payload", rename_all = "lowercase")]
 pub enum ServerToClient {
     Distribute(DistributeMessage),
     ACK(ACK),
+    NACK(NACK),
+    Typing(TypingMessage),
+    Read(ReadMessage),
+    Presence(PresenceMessage),
+    Membership(MembershipMessage),
+    /// Any message type this client does not know yet.
+    #[serde(other)]
+    Unknown,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DistributeMessage {
     pub sender: UserId,
+    #[serde(default)]
+    pub sender_name: Option<String>,
+    pub sent_at: DateTime<Utc>,
+    /// Server-assigned position of the message within its conversation.
+    #[serde(default)]
+    pub seq: Option<u64>,
+    /// The sender's `SendMessage::client_id`, if the server echoes it.
+    #[serde(default)]
+    pub client_id: Option<Uuid>,
     #[serde(flatten)]
     pub content: ChatContent,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypingMessage {
+    pub sender: UserId,
+    pub conversation_id: ConversationId,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadMessage {
+    pub conversation_id: ConversationId,
+    pub reader: UserId,
+    pub up_to_seq: u64,
+}
+
+/// A user sharing a conversation with us came online or went offline.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceMessage {
+    pub user_id: UserId,
+    pub online: bool,
+}
+
+/// Someone joined or left a conversation we are in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MembershipMessage {
+    pub conversation_id: ConversationId,
+    pub user_id: UserId,
+    pub username: String,
+    pub change: MembershipChange,
+    /// Whether a joining member is online; meaningless for one leaving.
+    #[serde(default)]
+    pub online: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MembershipChange {
+    Joined,
+    Left,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatContent {
     pub conversation_id: ConversationId,
-    pub content: String,
+    pub content: ChatBody,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<Attachment>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ACK {
     pub message_seq: u64,
+    /// Server-assigned position of the acknowledged message, see `DistributeMessage::seq`.
+    #[serde(default)]
+    pub seq: Option<u64>,
+    #[serde(default)]
+    pub client_id: Option<Uuid>,
+}
+
+/// Sent instead of an `ACK` when the server refuses to distribute a message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NACK {
+    pub message_seq: u64,
+    pub reason: NackReason,
+    /// Why, in words meant for the user; older servers leave it out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NackReason {
+    UnknownConversation,
+    NotAMember,
+    RateLimited,
+    #[serde(other)]
+    Other,
 }
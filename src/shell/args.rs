@@ -1,5 +1,7 @@
 use std::fmt::Display;
+use std::path::PathBuf;
 use clap::{Parser, ValueEnum};
+use crate::protocol::network::{DEFAULT_API_BASE_URL, DEFAULT_WS_URL};
 
 #[derive(Clone, Debug, ValueEnum)]
 pub enum LogLevel {
@@ -25,5 +27,11 @@ impl Display for LogLevel {
 #[derive(Debug, Parser)]
 pub struct Args {
     #[arg(long, value_enum, default_value = "trace")]
-    pub log_level: LogLevel
+    pub log_level: LogLevel,
+    #[arg(long, default_value = DEFAULT_API_BASE_URL)]
+    pub api_base_url: String,
+    #[arg(long, default_value = DEFAULT_WS_URL)]
+    pub ws_url: String,
+    #[arg(long)]
+    pub cert_path: Option<PathBuf>,
 }
@@ -68,93 +68,549 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::Arc;
-use crate::page::{Network, FakeNetwork, Update, View, Route, LoginPage, SignupPage, NetworkEvent, LoginMessage, LobbyMessage};
+use crate::page::{chat_unstarted_message, rejection_message, saved_session_expired_message, saved_session_unreachable_message, user_message, Update, View, Route, LoginPage, SignupPage, LoginMessage, LobbyMessage};
 use crate::*;
 use anyhow::{anyhow, Result};
 use eframe::egui;
 use std::time::{Duration, Instant};
 use tracing::{debug, error, info, trace, warn};
-use crate::protocol::network::{ChatConnError, ChatMetaData, NetworkImpl, NetworkInterface, SessionEvent, StreamMessage, WithGeneration};
+use crate::shell::{SessionStore, StoredSettings, StreamBuffer, Theme, ToastLevel, Toasts};
+use crate::domain::{ChatBody, UserId};
+use crate::util::PollingPace;
+use crate::protocol::network::{ChatConnError, ChatMessage, ChatMetaData, NetworkConfig, NetworkError, NetworkImpl, NetworkTimeouts, NetworkInterface, OfflineNetwork, PROTOCOL_VERSION, RefreshEvent, ServerInfo, ServerInfoEvent, SessionEvent, StreamMessage, TokenInfo, WithGeneration};
 
-const IDLE_POLLING_INTERVAL: Duration = Duration::from_millis(100);
+/// While exiting, so the countdown and the network stopping show promptly.
 const FAST_POLLING_INTERVAL: Duration = Duration::from_millis(16);
 const EXITING_DEADLINE: Duration = Duration::from_secs(5);
+/// How long exiting can still be cancelled before the network is stopped, if sends are still in flight.
+const CANCEL_EXIT_WINDOW: Duration = Duration::from_secs(3);
+pub const APP_NAME: &str = "ClientSide";
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Lifecycle {
     PendingQuit,
     QuitingShell,
     Running,
 }
 
+/// Tells apart page instances, so a message for a page that is gone is not taken by its successor.
+pub type PageEpoch = u64;
+
 pub enum Page {
     Fatal(page::FatalPage),
-    Lobby(page::LobbyPage),
-    Login(page::LoginPage),
+    /// Boxed, as the lobby is far larger than any other page.
+    Lobby(PageEpoch, Box<page::LobbyPage>),
+    Login(PageEpoch, page::LoginPage),
     Shutdown(page::ShutdownPage),
-    Signup(page::SignupPage),
+    Signup(PageEpoch, page::SignupPage),
+    Splash(page::SplashPage),
+}
+
+impl Page {
+    /// Only pages that receive messages have an epoch.
+    fn epoch(&self) -> Option<PageEpoch> {
+        match self {
+            Page::Lobby(epoch, _) | Page::Login(epoch, _) | Page::Signup(epoch, _) => Some(*epoch),
+            Page::Fatal(_) | Page::Shutdown(_) | Page::Splash(_) => None,
+        }
+    }
 }
 
 pub struct App {
     lifecycle: Lifecycle,
-    network: Rc<RefCell<dyn Network>>,
     real_network: Rc<RefCell<dyn NetworkInterface>>,
+    /// What `Restart` rebuilds the network from; `None` when it never got that far.
+    config: Option<NetworkConfig>,
     chat_generation: Option<u64>,
-    stream_buffer: Vec<StreamMessage>,
+    /// Feeds chat messages to the toasts while the lobby is up, see `subscribe_notifications`.
+    stream_subscription: Option<u64>,
+    token_info: Option<TokenInfo>,
+    timeouts: NetworkTimeouts,
+    session_store: Option<SessionStore>,
+    /// `None` follows the OS until a theme is picked.
+    theme: Option<Theme>,
+    /// Only ever set from the settings file; there is no control for it yet.
+    scrollback_limit: Option<usize>,
+    /// See `StoredSettings::close_to_background`.
+    close_to_background: bool,
+    /// Where the last lobby was left and whose it was, for the next lobby of the same user.
+    lobby_position: Option<(UserId, page::LobbyPosition)>,
+    /// Whether the window had keyboard focus last frame; a minimized one does not.
+    window_focused: bool,
+    /// Set when a chat message arrives while the window is unfocused, until the window is asked to flash.
+    attention_requested: bool,
+    toasts: Toasts,
+    next_epoch: PageEpoch,
+    /// Handed out before its page exists, e.g. to the chat stream while the lobby waits for the connection.
+    reserved_epoch: Option<PageEpoch>,
+    /// Chat stream messages for `reserved_epoch`, handed to the lobby once it is built.
+    held_stream: StreamBuffer,
     current_page: Page,
+    /// The page exiting started from, kept until the network is stopped so `CancelExit` can return to it.
+    page_before_shutdown: Option<Page>,
+    /// Unsent messages counted when the window was closed, while asking whether to quit anyway.
+    unsent_prompt: Option<usize>,
+    /// Pages navigated away from, most recent last; `NavigateBack` pops from here.
+    history: Vec<Page>,
     message_tx: crossbeam_channel::Sender<AppMessage>,
     message_rx: crossbeam_channel::Receiver<AppMessage>,
-    polling_interval: Duration,
+    /// Sets the polling interval while running, from how recently the user or the network did anything.
+    pace: PollingPace,
+    /// Last title sent to the window, so it is only sent again when it changes.
+    window_title: String,
 }
 
 impl App {
-    pub fn new() -> App {
+    pub fn try_new(config: NetworkConfig) -> Result<App> {
+        let timeouts = config.timeouts;
+        let real_network = Rc::new(RefCell::new(NetworkImpl::try_new(config.clone())?));
+        let mut app = App::starting_on(real_network, timeouts, |message_tx, _| {
+            Page::Splash(page::SplashPage::new(message_tx.clone()))
+        });
+        app.config = Some(config);
+        app.open_session_store();
+        app.check_server();
+        Ok(app)
+    }
+
+    /// Starts on the login page over any `NetworkInterface`, e.g. `FakeNetworkInterface`;
+    /// no session is saved or resumed and the server is not checked.
+    pub fn with_network(real_network: Rc<RefCell<dyn NetworkInterface>>, timeouts: NetworkTimeouts) -> App {
+        let login_network = real_network.clone();
+        App::starting_on(real_network, timeouts, |message_tx, epoch| {
+            Page::Login(epoch, page::LoginPage::new(
+                message_tx.clone(),
+                Box::new(move |m| AppMessage::Login(epoch, m)),
+                Arc::new(Box::new(move |m| AppMessage::Login(epoch, m))),
+                login_network,
+                timeouts,
+            ))
+        })
+    }
+
+    /// `first_page` is handed the app's sender and the epoch reserved for it.
+    fn starting_on(
+        real_network: Rc<RefCell<dyn NetworkInterface>>,
+        timeouts: NetworkTimeouts,
+        first_page: impl FnOnce(&crossbeam_channel::Sender<AppMessage>, PageEpoch) -> Page,
+    ) -> App {
         let (message_tx, message_rx) = crossbeam_channel::unbounded();
-        let network: Rc<RefCell<dyn Network>> = Rc::new(RefCell::new(FakeNetwork::new(message_tx.clone())));
-        let real_network = Rc::new(RefCell::new(NetworkImpl::try_new().unwrap()));
+        let epoch = 0;
         App {
             lifecycle: Lifecycle::Running,
-            network: network.clone(),
-            real_network: real_network.clone(),
+            real_network,
+            config: None,
             chat_generation: None,
-            stream_buffer: Vec::new(),
-            current_page: Page::Login(page::LoginPage::new(
-                message_tx.clone(),
-                Box::new(|m| AppMessage::Login(m)),
-                Arc::new(Box::new(|m| AppMessage::Login(m))),
-                Rc::downgrade(&network),
-                real_network,
-            )),
+            stream_subscription: None,
+            token_info: None,
+            timeouts,
+            session_store: None,
+            theme: None,
+            scrollback_limit: None,
+            close_to_background: false,
+            lobby_position: None,
+            window_focused: true,
+            attention_requested: false,
+            toasts: Toasts::default(),
+            next_epoch: epoch + 1,
+            reserved_epoch: None,
+            held_stream: StreamBuffer::default(),
+            current_page: first_page(&message_tx, epoch),
+            page_before_shutdown: None,
+            unsent_prompt: None,
+            history: Vec::new(),
             message_tx,
             message_rx,
-            polling_interval: IDLE_POLLING_INTERVAL,
+            pace: PollingPace::new(),
+            window_title: APP_NAME.to_string(),
+        }
+    }
+
+    /// Renews a session saved by an earlier run and heads for the lobby; the login page
+    /// stays up if there is none or it cannot be renewed.
+    fn resume_session(&mut self) {
+        let Some(session_store) = self.session_store.clone() else {
+            return;
+        };
+        let stored = match session_store.load() {
+            Ok(Some(stored)) if !stored.is_expired() => stored,
+            Ok(Some(_)) => {
+                debug!("Saved session has expired");
+                self.forget_session();
+                return;
+            }
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Discarding saved session: {:#}", e);
+                self.forget_session();
+                return;
+            }
+        };
+
+        let chat_address = stored.chat_address.clone();
+        if let Err(e) = self.real_network.borrow_mut().restore_auth(stored.token_info()) {
+            warn!("Failed to restore saved session: {:#}", e);
+            return;
+        }
+
+        let message_tx = self.message_tx.clone();
+        let failed_store = session_store.clone();
+        let map = move |event: WithGeneration<RefreshEvent>| match event.result.result {
+            Ok(mut token_info) => {
+                info!("Resumed saved session");
+                token_info.chat_address = chat_address;
+                let _ = message_tx.send(AppMessage::ReqNavigate(Route::LobbyPage(token_info)));
+            }
+            Err(error) => {
+                warn!("Failed to renew saved session: {:?}", error);
+                let _ = failed_store.clear();
+                let text = saved_session_expired_message();
+                let _ = message_tx.send(AppMessage::Notify { level: ToastLevel::Warning, text });
+            }
+        };
+        let message_tx = self.message_tx.clone();
+        let map_err = move |error: WithGeneration<NetworkError>| {
+            warn!("Renewing saved session did not finish: {:?}", error.result);
+            let text = saved_session_unreachable_message();
+            let _ = message_tx.send(AppMessage::Notify { level: ToastLevel::Warning, text });
+        };
+
+        if let Err(e) = self.real_network.borrow_mut().refresh(self.timeouts.login_ms, Box::new(map), Box::new(map_err)) {
+            warn!("Failed to renew saved session: {:#}", e);
+        }
+    }
+
+    fn open_session_store(&mut self) {
+        self.session_store = SessionStore::try_new()
+            .inspect_err(|e| warn!("Sessions will not be kept: {:#}", e))
+            .ok();
+        self.load_settings();
+    }
+
+    /// Asks the server which protocol it speaks, on the splash page; `ServerChecked` moves on from there.
+    fn check_server(&mut self) {
+        if let Page::Splash(splash) = &mut self.current_page {
+            splash.set_checking();
+        } else {
+            self.current_page = Page::Splash(page::SplashPage::new(self.message_tx.clone()));
+        }
+
+        let message_tx = self.message_tx.clone();
+        let map = move |event: WithGeneration<ServerInfoEvent>| {
+            let result = event.result.result.map_err(|error| rejection_message(&error, None));
+            let _ = message_tx.send(AppMessage::ServerChecked(result));
+        };
+        let message_tx = self.message_tx.clone();
+        let map_err = move |error: WithGeneration<NetworkError>| {
+            let _ = message_tx.send(AppMessage::ServerChecked(Err(user_message(&error.result))));
+        };
+
+        let result = self.real_network.borrow_mut().server_info(self.timeouts.server_info_ms, Box::new(map), Box::new(map_err));
+        if let Err(e) = result {
+            warn!("Failed to check server: {:#}", e);
+            let _ = self.message_tx.send(AppMessage::ServerChecked(Err(format!("{:#}", e))));
+        }
+    }
+
+    /// A compatible server leads on to login, and to the lobby if a saved session resumes;
+    /// an incompatible one cannot be talked to, so only quitting is left.
+    fn server_checked(&mut self, result: Result<ServerInfo, String>) {
+        let Page::Splash(splash) = &mut self.current_page else {
+            debug!("Ignore server check result away from the splash page");
+            return;
+        };
+        match result {
+            Ok(info) if info.is_compatible() => {
+                info!("Server {} speaks protocol {}", info.version, info.protocol_version);
+                let _ = self.message_tx.send(AppMessage::ReqNavigate(Route::LoginPage(None)));
+                self.resume_session();
+            }
+            Ok(info) => {
+                error!("Server {} speaks protocol {}, expected {}", info.version, info.protocol_version, PROTOCOL_VERSION);
+                let text = format!(
+                    "The server (version {}) speaks protocol {}, but this client needs protocol {}. Please update the application.",
+                    info.version, info.protocol_version, PROTOCOL_VERSION,
+                );
+                self.current_page = Page::Fatal(page::FatalPage::new(self.message_tx.clone(), text, false));
+            }
+            Err(reason) => {
+                warn!("Server check failed: {}", reason);
+                splash.set_failure(reason);
+            }
+        }
+    }
+
+    /// Rebuilds the network from the saved config and starts over from the server check;
+    /// a failure leaves the app on a fatal page.
+    fn restart(&mut self) {
+        let Some(config) = self.config.clone() else {
+            warn!("Ignore restarting without a network config");
+            return;
+        };
+        let timeouts = config.timeouts;
+        let network = match NetworkImpl::try_new(config) {
+            Ok(network) => network,
+            Err(e) => {
+                error!("Failed to restart network: {:#}", e);
+                let _ = self.message_tx.send(AppMessage::ReqNavigate(Route::FatalPage(format!("{:#}", e))));
+                return;
+            }
+        };
+        info!("Network restarted");
+
+        self.real_network = Rc::new(RefCell::new(network));
+        self.timeouts = timeouts;
+        self.chat_generation = None;
+        self.stream_subscription = None;
+        self.token_info = None;
+        self.drop_held_messages();
+        self.history.clear();
+        if self.session_store.is_none() {
+            self.open_session_store();
+        }
+        self.check_server();
+    }
+
+    fn load_settings(&mut self) {
+        let Some(session_store) = &self.session_store else {
+            return;
+        };
+        match session_store.load_settings() {
+            Ok(settings) => {
+                self.theme = settings.theme;
+                self.scrollback_limit = settings.scrollback_limit;
+                self.close_to_background = settings.close_to_background;
+            }
+            Err(e) => warn!("Ignoring saved settings: {:#}", e),
+        }
+    }
+
+    fn set_theme(&mut self, theme: Theme) {
+        self.theme = Some(theme);
+        self.save_settings();
+    }
+
+    fn set_close_to_background(&mut self, close_to_background: bool) {
+        self.close_to_background = close_to_background;
+        self.save_settings();
+    }
+
+    fn save_settings(&self) {
+        let Some(session_store) = &self.session_store else {
+            return;
+        };
+        let settings = StoredSettings {
+            theme: self.theme,
+            scrollback_limit: self.scrollback_limit,
+            close_to_background: self.close_to_background,
+        };
+        if let Err(e) = session_store.save_settings(&settings) {
+            warn!("Failed to save settings: {:#}", e);
+        }
+    }
+
+    fn forget_session(&self) {
+        if let Some(session_store) = &self.session_store {
+            if let Err(e) = session_store.clear() {
+                warn!("Failed to remove saved session: {:#}", e);
+            }
         }
     }
     pub fn shutdown(&mut self) -> Result<()> {
-        let deadline = Instant::now() + EXITING_DEADLINE;
+        if !matches!(self.lifecycle, Lifecycle::Running) {
+            debug!("Ignore exiting twice");
+            return Ok(());
+        }
+        let cancel_until = Instant::now() + CANCEL_EXIT_WINDOW;
+        let deadline = cancel_until + EXITING_DEADLINE;
+        self.unsent_prompt = None;
         self.lifecycle = Lifecycle::PendingQuit;
-        self.current_page = Page::Shutdown(page::ShutdownPage::new(deadline));
-
-        self.polling_interval = FAST_POLLING_INTERVAL;
+        let shutdown_page = page::ShutdownPage::new(self.message_tx.clone(), cancel_until, deadline);
+        let previous = std::mem::replace(&mut self.current_page, Page::Shutdown(shutdown_page));
+        self.page_before_shutdown = Some(previous);
 
         Ok(())
     }
+
+    /// Returns to the page exiting started from, unless the network is already stopping.
+    fn cancel_exit(&mut self) {
+        if self.unsent_prompt.take().is_some() {
+            debug!("Staying open for the unsent messages");
+            return;
+        }
+        if !matches!(self.lifecycle, Lifecycle::PendingQuit) {
+            debug!("Ignore cancelling exit while not exiting");
+            return;
+        }
+        let Some(page) = self.page_before_shutdown.take() else {
+            warn!("Too late to cancel exiting");
+            return;
+        };
+        debug!("Exiting cancelled");
+        self.lifecycle = Lifecycle::Running;
+        self.current_page = page;
+    }
+
+    fn stop_network(&mut self) {
+        if self.page_before_shutdown.take().is_none() {
+            return;
+        }
+        self.history.clear();
+
+        // Quit as soon as the network has stopped; the deadline still covers a stuck runtime.
+        let message_tx = self.message_tx.clone();
+        let result = self.real_network.borrow_mut().shutdown(Box::new(move || {
+            debug!("Network shut down cleanly");
+            let _ = message_tx.send(AppMessage::Quit);
+        }));
+        if let Err(e) = result {
+            warn!("Failed to shut down network: {:#}", e);
+        }
+    }
     pub fn polling_interval(&self) -> Duration {
-        self.polling_interval
+        match self.lifecycle {
+            Lifecycle::Running => self.pace.interval(),
+            Lifecycle::PendingQuit | Lifecycle::QuitingShell => FAST_POLLING_INTERVAL,
+        }
+    }
+
+    pub fn lifecycle(&self) -> Lifecycle {
+        self.lifecycle
+    }
+
+    pub fn current_page(&self) -> &Page {
+        &self.current_page
+    }
+
+    /// Names the user and counts unread messages while the lobby is up, e.g. "ClientSide — alice (3)".
+    pub fn title(&self) -> String {
+        let Page::Lobby(_, lobby) = &self.current_page else {
+            return APP_NAME.to_string();
+        };
+        let title = match lobby.username() {
+            Some(username) => format!("{} — {}", APP_NAME, username),
+            None => APP_NAME.to_string(),
+        };
+        match lobby.unread_total() {
+            0 => title,
+            unread => format!("{} ({})", title, unread),
+        }
+    }
+
+    /// Shows the most recent visited page matching `is_target`, or pushes the current page
+    /// and shows `new_page` when there is none.
+    fn navigate(&mut self, is_target: fn(&Page) -> bool, new_page: impl FnOnce(&Self) -> Page) {
+        if let Some(index) = self.history.iter().rposition(is_target) {
+            let page = self.history.remove(index);
+            self.history.truncate(index);
+            self.current_page = page;
+            return;
+        }
+
+        let page = new_page(self);
+        let previous = std::mem::replace(&mut self.current_page, page);
+        if matches!(previous, Page::Login(..) | Page::Signup(..)) {
+            self.history.push(previous);
+        }
+    }
+
+    fn new_epoch(&mut self) -> PageEpoch {
+        let epoch = self.next_epoch;
+        self.next_epoch += 1;
+        epoch
+    }
+
+    /// Toasts chat messages from others that arrive outside the conversation on screen.
+    fn subscribe_notifications(&mut self, user_id: UserId) {
+        self.unsubscribe_notifications();
+        let message_tx = self.message_tx.clone();
+        let subscription = self.real_network.borrow_mut().subscribe_stream(Box::new(move |message| {
+            if let StreamMessage::Distribute(message) = message {
+                if message.sender != user_id && matches!(message.content, ChatBody::Text(_)) {
+                    let _ = message_tx.send(AppMessage::IncomingChat(message));
+                }
+            }
+        }));
+        self.stream_subscription = Some(subscription);
+    }
+
+    fn unsubscribe_notifications(&mut self) {
+        if let Some(subscription) = self.stream_subscription.take() {
+            if let Err(e) = self.real_network.borrow_mut().unsubscribe_stream(subscription) {
+                warn!("Failed to unsubscribe from chat stream: {:#}", e);
+            }
+        }
+    }
+
+    fn drop_held_messages(&mut self) {
+        self.reserved_epoch = None;
+        self.held_stream.clear();
+    }
+
+    /// The live page `epoch` belongs to, including one kept in the history or behind the shutdown page.
+    fn page_mut(&mut self, epoch: PageEpoch) -> Option<&mut Page> {
+        std::iter::once(&mut self.current_page)
+            .chain(self.history.iter_mut().rev())
+            .chain(self.page_before_shutdown.as_mut())
+            .find(|page| page.epoch() == Some(epoch))
+    }
+
+    /// Keeps a stream message for the lobby still being built; anything else without a page is stale.
+    fn hold_or_drop(&mut self, epoch: PageEpoch, message: AppMessage) {
+        match message {
+            AppMessage::Lobby(_, LobbyMessage::Stream(message)) if self.reserved_epoch == Some(epoch) => {
+                self.held_stream.push(message);
+            }
+            _ => trace!("Drop stale message for page {}", epoch),
+        }
+    }
+
+    fn navigate_back(&mut self) {
+        if matches!(self.current_page, Page::Fatal(_) | Page::Shutdown(_)) {
+            warn!("Ignore navigating back from a terminal page");
+            return;
+        }
+        match self.history.pop() {
+            Some(page) => self.current_page = page,
+            None => debug!("No page to navigate back to"),
+        }
     }
 }
 
+/// Page messages carry the `PageEpoch` of the page they are for, which decides their fate:
+/// - the live page with that epoch gets them, even from the history;
+/// - for the epoch reserved for the lobby while chat connects, its stream messages are held
+///   per conversation and handed over once the lobby exists;
+/// - any other epoch belonged to a page that is gone, so they are stale and dropped.
 pub enum AppMessage {
     Quit,
     Exiting,
+    /// Backs out of exiting while the shutdown page still allows it, or of closing with unsent messages.
+    CancelExit,
+    /// Sent once exiting can no longer be cancelled.
+    StopNetwork,
     PlaceHolder,
 
-    Lobby(page::LobbyMessage),
-    Login(page::LoginMessage),
-    Signup(page::SignupMessage),
+    Lobby(PageEpoch, page::LobbyMessage),
+    Login(PageEpoch, page::LoginMessage),
+    Signup(PageEpoch, page::SignupMessage),
 
     ReqNavigate(Route),
-
-    Stream(StreamMessage),
+    NavigateBack,
+    /// Leaves a recoverable fatal page with a fresh network.
+    Restart,
+    /// Retries the startup check from the splash page.
+    CheckServer,
+    /// The outcome of `CheckServer`; `Err` holds why the server could not be asked.
+    ServerChecked(Result<ServerInfo, String>),
+    SetTheme(Theme),
+    SetCloseToBackground(bool),
+    /// The lobby's conversation or scroll position changed.
+    SetLobbyPosition(page::LobbyPosition),
+    /// A chat message from someone else, toasted unless its conversation is on screen.
+    IncomingChat(ChatMessage),
+    /// Shows a toast over whichever page is up.
+    Notify { level: ToastLevel, text: String },
 }
 
 impl App {
@@ -166,6 +622,11 @@ impl App {
             Page::Shutdown(inner) => {
                 if now >= inner.get_deadline() {
                     messages.push(AppMessage::Quit);
+                } else if self.page_before_shutdown.is_some() {
+                    // The cancel window is only waited out while sends still wait for their ACK.
+                    if now >= inner.get_cancel_until() || self.real_network.borrow().pending_count() == 0 {
+                        messages.push(AppMessage::StopNetwork);
+                    }
                 }
             }
             _ => {}
@@ -176,145 +637,303 @@ impl App {
 
     pub fn receive_messages(&mut self, messages: &mut Vec<AppMessage>) {
         for message in messages.drain(..) {
-            self.message_tx.send(message).unwrap();
+            let _ = self.message_tx.send(message);
         }
     }
 
     pub fn update(&mut self) {
         let rx = self.message_rx.clone();
         for message in rx.try_iter() {
+            // Replies and stream messages alike, since either tends to come in bursts.
+            self.pace.record_activity();
             self.update_one(message).unwrap();
         }
     }
 
+    /// Everything a frame does besides drawing, so the app can be driven without a window.
+    pub fn step(&mut self) {
+        let mut internal_messages = self.poll_internal_events();
+        self.receive_messages(&mut internal_messages);
+        self.update();
+    }
+
+    /// Whether closing the window only hides it, keeping the chat session going.
+    fn closes_to_background(&self) -> bool {
+        // Only the lobby has a session worth keeping; `Exiting`, e.g. its Quit button, still quits.
+        matches!(self.lifecycle, Lifecycle::Running) && self.close_to_background && matches!(self.current_page, Page::Lobby(..))
+    }
+
+    /// Messages that quitting now would lose: queued in the lobby or waiting for their ACK.
+    fn unsent_count(&self) -> usize {
+        let queued = match &self.current_page {
+            Page::Lobby(_, inner) => inner.queued_count(),
+            _ => 0,
+        };
+        queued + self.real_network.borrow().pending_count()
+    }
+
+    /// Reacts to the window's close button; `true` means the window has to stay open for now.
+    pub fn close_requested(&mut self) -> bool {
+        match self.lifecycle {
+            Lifecycle::Running if self.closes_to_background() => {
+                debug!("Keeping the chat running in the background");
+                true
+            }
+            // Closing again while asked counts as quitting anyway.
+            Lifecycle::Running if self.unsent_prompt.is_none() && self.unsent_count() > 0 => {
+                let unsent = self.unsent_count();
+                debug!(unsent, "Asking before closing with unsent messages");
+                self.unsent_prompt = Some(unsent);
+                true
+            }
+            Lifecycle::Running => {
+                debug!("Closing app");
+                self.receive_messages(&mut vec![AppMessage::Exiting]);
+                true
+            }
+            Lifecycle::PendingQuit => {
+                warn!("Force closed");
+                false
+            }
+            Lifecycle::QuitingShell => {
+                debug!("Graceful shutdown");
+                false
+            }
+        }
+    }
+
     fn update_one(&mut self, message: AppMessage) -> Result<()> {
         match message {
             AppMessage::PlaceHolder => {}
             AppMessage::Exiting => {
                 self.shutdown().unwrap();
             }
+            AppMessage::CancelExit => {
+                self.cancel_exit();
+            }
+            AppMessage::StopNetwork => {
+                self.stop_network();
+            }
             AppMessage::Quit => {
                 self.lifecycle = Lifecycle::QuitingShell;
             }
-            AppMessage::Lobby(message) => match &mut self.current_page {
-                Page::Lobby(inner) => {
-                    inner.update_one(message);
-                }
-                _ => {}
+            AppMessage::Lobby(epoch, message) => match self.page_mut(epoch) {
+                Some(Page::Lobby(_, inner)) => inner.update_one(message),
+                _ => self.hold_or_drop(epoch, AppMessage::Lobby(epoch, message)),
             }
-            AppMessage::Login(message) => match &mut self.current_page {
-                Page::Login(inner) => {
-                    inner.update_one(message);
-                }
-                _ => {}
-            },
-            AppMessage::Signup(message) => match &mut self.current_page {
-                Page::Signup(inner) => {
-
-                }
-                _ => {}
+            AppMessage::Login(epoch, message) => match self.page_mut(epoch) {
+                Some(Page::Login(_, inner)) => inner.update_one(message),
+                _ => self.hold_or_drop(epoch, AppMessage::Login(epoch, message)),
+            }
+            AppMessage::Signup(epoch, message) => match self.page_mut(epoch) {
+                Some(Page::Signup(_, inner)) => inner.update_one(message),
+                _ => self.hold_or_drop(epoch, AppMessage::Signup(epoch, message)),
             }
             AppMessage::ReqNavigate(route) => {
                 debug!("Navigating to {:?}", route);
                 match route {
-                    Route::LoginPage => {
-                        let login_page = LoginPage::new(
-                            self.message_tx.clone(),
-                            Box::new(|m| AppMessage::Login(m)),
-                            Arc::new(Box::new(|m| AppMessage::Login(m))),
-                            Rc::downgrade(&self.network),
-                            self.real_network.clone(),
-                        );
-                        self.current_page = Page::Login(login_page);
+                    Route::LoginPage(username) => {
+                        if let Some(generation) = self.chat_generation.take() {
+                            // Also stops a connect still in flight; the lobby may have reconnected under another generation.
+                            let mut network = self.real_network.borrow_mut();
+                            if network.cancel(generation).is_err() {
+                                let _ = network.disconnect_chat();
+                            }
+                        }
+                        self.unsubscribe_notifications();
+                        self.drop_held_messages();
+                        self.token_info = None;
+                        if matches!(self.current_page, Page::Lobby(..)) {
+                            self.history.clear();
+                            self.forget_session();
+                        }
+
+                        let epoch = self.new_epoch();
+                        self.navigate(|page| matches!(page, Page::Login(..)), |app| {
+                            Page::Login(epoch, LoginPage::new(
+                                app.message_tx.clone(),
+                                Box::new(move |m| AppMessage::Login(epoch, m)),
+                                Arc::new(Box::new(move |m| AppMessage::Login(epoch, m))),
+                                app.real_network.clone(),
+                                app.timeouts,
+                            ))
+                        });
+                        // Also over a login page restored from the history, since the newer input is on the page left behind.
+                        if let (Page::Login(_, page), Some(username)) = (&mut self.current_page, username) {
+                            page.prefill_username(username);
+                        }
                     }
-                    Route::SignupPage => {
-                        let signup_page = SignupPage::new(
-                            self.message_tx.clone(),
-                            Box::new(|m| AppMessage::Signup(m)),
-                            Rc::downgrade(&self.network),
-                        );
-                        self.current_page = Page::Signup(signup_page);
+                    Route::SignupPage(username) => {
+                        let epoch = self.new_epoch();
+                        self.navigate(|page| matches!(page, Page::Signup(..)), |app| {
+                            Page::Signup(epoch, SignupPage::new(
+                                app.message_tx.clone(),
+                                Box::new(move |m| AppMessage::Signup(epoch, m)),
+                                Arc::new(Box::new(move |m| AppMessage::Signup(epoch, m))),
+                                app.real_network.clone(),
+                                app.timeouts,
+                            ))
+                        });
+                        if let (Page::Signup(_, page), Some(username)) = (&mut self.current_page, username) {
+                            page.prefill_username(username);
+                        }
                     }
-                    Route::LobbyPage(address, jwt) => {
+                    Route::LobbyPage(token_info) => {
+                        if let Some(session_store) = &self.session_store {
+                            if let Err(e) = session_store.save(&token_info) {
+                                warn!("Failed to save session: {:#}", e);
+                            }
+                        }
+                        self.token_info = Some(token_info.clone());
+                        let address = token_info.chat_address.unwrap_or_default();
+                        // The stream may start before the lobby is built, so its messages are held for it.
+                        let epoch = self.new_epoch();
+                        self.held_stream.clear();
+                        self.reserved_epoch = Some(epoch);
+                        let jwt = token_info.access_token;
+
                         let message_tx = self.message_tx.clone();
                         let map = move |event: WithGeneration<SessionEvent>| {
                             let message = match event.result.result {
                                 Ok(_) => AppMessage::ReqNavigate(Route::ChatConnSuccess),
-                                Err(_) => AppMessage::ReqNavigate(Route::ChatConnFailure),
+                                Err(error) => AppMessage::ReqNavigate(Route::ChatConnFailure(error, event.result.detail)),
                             };
                             let _ = message_tx.send(message);
                         };
 
                         let message_tx = self.message_tx.clone();
-                        let map_err = move |_error| {
-                            let _ = message_tx.send(AppMessage::ReqNavigate(Route::ChatConnFailure));
+                        let map_err = move |error: WithGeneration<NetworkError>| {
+                            // Cancelled on the way back to login, so there is nothing to report.
+                            if !matches!(error.result, NetworkError::UsrCancelled) {
+                                let _ = message_tx.send(AppMessage::ReqNavigate(Route::ChatConnFailure(ChatConnError::FallbackError, None)));
+                            }
                         };
 
                         let message_tx = self.message_tx.clone();
-                        self.chat_generation = self.real_network.borrow_mut().connect_chat(
+                        let result = self.real_network.borrow_mut().connect_chat(
                             address,
                             jwt,
                             Box::new(move |message| {
-                                let _ = message_tx.send(AppMessage::Stream(message));
+                                let _ = message_tx.send(AppMessage::Lobby(epoch, LobbyMessage::Stream(message)));
                             }),
-                            1000,
+                            self.timeouts.connect_ms,
                             Box::new(map),
                             Box::new(map_err),
-                        ).ok();
-
-                        // self.chat_generation = self.network.borrow_mut().connect_chat(
-                        //     address,
-                        //     jwt,
-                        //     1000,
-                        //     Box::new(|e| {
-                        //         match e {
-                        //             NetworkEvent::ChatConnSucceeded(generation) => {
-                        //                 AppMessage::ReqNavigate(Route::ChatConnSuccess)
-                        //             }
-                        //             NetworkEvent::ChatConnFailed(generation) => {
-                        //                 AppMessage::ReqNavigate(Route::ChatConnFailure)
-                        //             }
-                        //             _ => {AppMessage::PlaceHolder}
-                        //         }
-                        //     }),
-                        // ).ok();
+                        );
+                        self.chat_generation = match result {
+                            Ok(generation) => Some(generation),
+                            Err(e) => {
+                                error!("Failed to start chat connection: {:#}", e);
+                                let text = chat_unstarted_message();
+                                let _ = self.message_tx.send(AppMessage::Notify { level: ToastLevel::Error, text });
+                                let _ = self.message_tx.send(AppMessage::ReqNavigate(Route::ChatConnFailure(ChatConnError::FallbackError, None)));
+                                None
+                            }
+                        };
                     }
                     Route::ChatConnSuccess => {
-                        let lobby_page = page::LobbyPage::new(
+                        let (Some(token_info), Some(epoch)) = (self.token_info.clone(), self.reserved_epoch.take()) else {
+                            error!("Chat connected without a logged-in user");
+                            return Ok(());
+                        };
+                        self.subscribe_notifications(token_info.user_id.clone());
+                        // Another user's conversations would not be theirs to open.
+                        let position = match &self.lobby_position {
+                            Some((user_id, position)) if *user_id == token_info.user_id => position.clone(),
+                            _ => page::LobbyPosition::default(),
+                        };
+                        let mut lobby_page = page::LobbyPage::new(
                             self.message_tx.clone(),
-                            Box::new(|m| AppMessage::Lobby(m)),
-                            Arc::new(Box::new(|m| AppMessage::Lobby(m))),
-                            Rc::downgrade(&self.network),
+                            Box::new(move |m| AppMessage::Lobby(epoch, m)),
+                            Arc::new(Box::new(move |m| AppMessage::Lobby(epoch, m))),
                             self.real_network.clone(),
-                            0u64,
+                            self.timeouts,
+                            page::LobbyConfig {
+                                token_info,
+                                scrollback_limit: self.scrollback_limit.unwrap_or(page::DEFAULT_SCROLLBACK_LIMIT),
+                                close_to_background: self.close_to_background,
+                                position,
+                            },
                         );
-                        self.current_page = Page::Lobby(lobby_page);
+                        // Messages that raced ahead of the lobby go in before any live ones.
+                        lobby_page.take_held_stream(std::mem::take(&mut self.held_stream));
+                        // The session may have dropped since this was queued.
+                        lobby_page.check_connection();
+                        // Logging in is not undone by going back.
+                        self.history.clear();
+                        self.current_page = Page::Lobby(epoch, Box::new(lobby_page));
                     }
-                    Route::ChatConnFailure => {
-                        let _ = self.message_tx.send(AppMessage::Login(LoginMessage::ChatFailed));
+                    Route::ChatConnFailure(error, detail) => {
+                        self.drop_held_messages();
+                        // Retrying with a token the server turned down cannot work, so it is not kept or resumed.
+                        if error == ChatConnError::Unauthorized {
+                            self.token_info = None;
+                            self.forget_session();
+                        }
+                        let login_epoch = std::iter::once(&self.current_page)
+                            .chain(self.history.iter())
+                            .find_map(|page| match page {
+                                Page::Login(epoch, _) => Some(*epoch),
+                                _ => None,
+                            });
+                        if let Some(epoch) = login_epoch {
+                            let _ = self.message_tx.send(AppMessage::Login(epoch, LoginMessage::ChatFailed(error, detail)));
+                        }
+                    }
+                    Route::FatalPage(reason) => {
+                        if matches!(self.current_page, Page::Shutdown(_)) {
+                            warn!("Ignore fatal error while exiting: {}", reason);
+                            return Ok(());
+                        }
+                        error!("Fatal error: {}", reason);
+                        self.drop_held_messages();
+                        // Nothing behind a fatal page is worth going back to.
+                        self.history.clear();
+                        let recoverable = self.config.is_some();
+                        self.current_page = Page::Fatal(page::FatalPage::new(self.message_tx.clone(), reason, recoverable));
                     }
                     _ => {
                         warn!("Not implemented yet! {:?}", route);
                     }
                 }
             }
-            AppMessage::Stream(message) => {
-                match &mut self.current_page {
-                    Page::Lobby(inner) => {
-                        for m in self.stream_buffer.drain(..) {
-                            inner.update_one(LobbyMessage::Stream(m));
-                        }
-                        inner.update_one(LobbyMessage::Stream(message));
-                    }
-                    _ => {
-                        if self.stream_buffer.len() < 1024 {
-                            self.stream_buffer.push(message);
-                        } else {
-                            error!("Drop stream message because buffer is full");
-                        }
-                    }
+            AppMessage::NavigateBack => {
+                self.navigate_back();
+            }
+            AppMessage::Restart => {
+                self.restart();
+            }
+            AppMessage::CheckServer => {
+                self.check_server();
+            }
+            AppMessage::ServerChecked(result) => {
+                self.server_checked(result);
+            }
+            AppMessage::SetTheme(theme) => {
+                self.set_theme(theme);
+            }
+            AppMessage::SetCloseToBackground(close_to_background) => {
+                self.set_close_to_background(close_to_background);
+            }
+            AppMessage::SetLobbyPosition(position) => {
+                if let Some(token_info) = &self.token_info {
+                    self.lobby_position = Some((token_info.user_id.clone(), position));
                 }
             }
+            AppMessage::IncomingChat(message) => {
+                let on_screen = self.window_focused
+                    && matches!(&self.current_page, Page::Lobby(_, lobby) if lobby.is_showing(&message.conversation_id));
+                if !self.window_focused {
+                    self.attention_requested = true;
+                }
+                if !on_screen {
+                    let sender = message.sender_name.unwrap_or_else(|| "someone".to_string());
+                    self.toasts.push(ToastLevel::Info, format!("New message from {}", sender));
+                }
+            }
+            AppMessage::Notify { level, text } => {
+                self.toasts.push(level, text);
+            }
         }
         Ok(())
     }
@@ -325,28 +944,66 @@ impl App {
     pub fn view(&mut self, ctx: &egui::Context) {
         match &mut self.current_page {
             Page::Fatal(inner) => inner.view(ctx),
-            Page::Lobby(inner) => inner.view(ctx),
-            Page::Login(inner) => inner.view(ctx),
+            Page::Lobby(_, inner) => inner.view(ctx),
+            Page::Login(_, inner) => inner.view(ctx),
             Page::Shutdown(inner) => inner.view(ctx),
-            Page::Signup(inner) => inner.view(ctx),
+            Page::Signup(_, inner) => inner.view(ctx),
+            Page::Splash(inner) => inner.view(ctx),
+        }
+        if let Some(unsent) = self.unsent_prompt {
+            egui::Window::new("Unsent messages")
+                .collapsible(false)
+                .resizable(false)
+                .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+                .show(ctx, |ui| {
+                    ui.label(format!("You have {} unsent message(s), quit anyway?", unsent));
+                    ui.horizontal(|ui| {
+                        if ui.button("Wait").clicked() {
+                            let _ = self.message_tx.send(AppMessage::CancelExit);
+                        }
+                        if ui.button("Quit anyway").clicked() {
+                            let _ = self.message_tx.send(AppMessage::Exiting);
+                        }
+                    });
+                });
         }
+        self.toasts.show(ctx);
     }
 }
 
 // Test block
 impl App {
-    pub fn new_fatal() -> App {
+    /// Offers to return to login when `config` is given, since the network can be rebuilt from it.
+    pub fn new_fatal(error_message: String, config: Option<NetworkConfig>) -> App {
         let (message_tx, message_rx) = crossbeam_channel::unbounded();
+        let recoverable = config.is_some();
         App {
             lifecycle: Lifecycle::Running,
-            network: Rc::new(RefCell::new(FakeNetwork::new(message_tx.clone()))),
-            real_network: Rc::new(RefCell::new(NetworkImpl::try_new().unwrap())),
+            real_network: Rc::new(RefCell::new(OfflineNetwork)),
+            config,
             chat_generation: None,
-            stream_buffer: Vec::new(),
-            current_page: Page::Fatal(page::FatalPage::new("fatal error".into())),
+            stream_subscription: None,
+            token_info: None,
+            timeouts: NetworkTimeouts::default(),
+            session_store: None,
+            theme: None,
+            scrollback_limit: None,
+            close_to_background: false,
+            lobby_position: None,
+            window_focused: true,
+            attention_requested: false,
+            toasts: Toasts::default(),
+            next_epoch: 0,
+            reserved_epoch: None,
+            held_stream: StreamBuffer::default(),
+            current_page: Page::Fatal(page::FatalPage::new(message_tx.clone(), error_message, recoverable)),
+            page_before_shutdown: None,
+            unsent_prompt: None,
+            history: Vec::new(),
             message_tx,
             message_rx,
-            polling_interval: IDLE_POLLING_INTERVAL,
+            pace: PollingPace::new(),
+            window_title: APP_NAME.to_string(),
         }
     }
 }
@@ -354,30 +1011,35 @@ impl App {
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         let start_time = Instant::now();
-        let mut external_messages = Vec::<AppMessage>::new();
+
+        let theme = self.theme.unwrap_or_else(|| Theme::system(ctx));
+        ctx.set_visuals(theme.visuals());
 
         // Get input
-        if ctx.input(|i| i.viewport().close_requested()) {
-            match self.lifecycle {
-                Lifecycle::Running => {
-                    debug!("Closing app");
-                    external_messages.push(AppMessage::Exiting);
-                    ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
-                }
-                Lifecycle::PendingQuit => warn!("Force closed"),
-                Lifecycle::QuitingShell => debug!("Graceful shutdown"),
+        self.window_focused = ctx.input(|i| i.focused);
+        if ctx.input(|i| !i.events.is_empty() || i.pointer.is_moving()) {
+            self.pace.record_activity();
+        }
+        let to_background = self.closes_to_background();
+        if ctx.input(|i| i.viewport().close_requested()) && self.close_requested() {
+            ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
+            if to_background {
+                ctx.send_viewport_cmd(egui::ViewportCommand::Minimized(true));
             }
         }
 
-        // Pass information to app::receive_events (this populates the message bus)
-        self.receive_messages(&mut external_messages);
+        // Gather application information and update application state with app::update(message)
+        self.step();
 
-        // Gather application information
-        let mut internal_messages = self.poll_internal_events();
-        self.receive_messages(&mut internal_messages);
+        if std::mem::take(&mut self.attention_requested) {
+            ctx.send_viewport_cmd(egui::ViewportCommand::RequestUserAttention(egui::UserAttentionType::Informational));
+        }
 
-        // Loop: update application state with app::update(message)
-        self.update();
+        let title = self.title();
+        if title != self.window_title {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Title(title.clone()));
+            self.window_title = title;
+        }
 
         // Render UI with app::view
         if matches!(self.lifecycle, Lifecycle::QuitingShell) {
@@ -394,3 +1056,153 @@ impl eframe::App for App {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::ConversationId;
+    use crate::protocol::network::FakeNetworkInterface;
+
+    fn app() -> (App, Rc<RefCell<FakeNetworkInterface>>) {
+        let fake = Rc::new(RefCell::new(FakeNetworkInterface::new()));
+        (App::with_network(fake.clone(), NetworkTimeouts::default()), fake)
+    }
+
+    fn chat(conversation_id: &ConversationId, text: &str) -> LobbyMessage {
+        LobbyMessage::Stream(StreamMessage::Distribute(ChatMessage {
+            sender: UserId(uuid::Uuid::new_v4()),
+            sender_name: None,
+            conversation_id: conversation_id.clone(),
+            content: ChatBody::Text(text.to_string()),
+            sent_at: chrono::Utc::now(),
+            seq: None,
+            attachment: None,
+            client_id: None,
+        }))
+    }
+
+    fn lobby(app: &App) -> (PageEpoch, &page::LobbyPage) {
+        match app.current_page() {
+            Page::Lobby(epoch, lobby) => (*epoch, lobby),
+            _ => panic!("The lobby is not up"),
+        }
+    }
+
+    #[test]
+    fn exit_without_sends_in_flight_quits_right_away() {
+        let (mut app, _fake) = app();
+
+        app.receive_messages(&mut vec![AppMessage::Exiting]);
+        app.step();
+        app.step();
+        app.step();
+
+        assert_eq!(app.lifecycle(), Lifecycle::QuitingShell);
+    }
+
+    #[test]
+    fn exit_waits_for_sends_in_flight_to_drain() {
+        let (mut app, fake) = app();
+        fake.borrow_mut().pending_count = 1;
+
+        app.receive_messages(&mut vec![AppMessage::Exiting]);
+        app.step();
+        app.step();
+        assert_eq!(app.lifecycle(), Lifecycle::PendingQuit);
+
+        fake.borrow_mut().pending_count = 0;
+        app.step();
+        app.step();
+
+        assert_eq!(app.lifecycle(), Lifecycle::QuitingShell);
+    }
+
+    #[test]
+    fn logs_in_to_the_lobby_and_shuts_down() {
+        let (mut app, _fake) = app();
+        assert!(matches!(app.current_page(), Page::Login(..)));
+
+        // As the login page asks once the server accepts the login.
+        app.receive_messages(&mut vec![AppMessage::ReqNavigate(Route::LobbyPage(TokenInfo::for_test()))]);
+        app.step();
+        assert!(matches!(app.current_page(), Page::Lobby(..)));
+        assert_eq!(app.lifecycle(), Lifecycle::Running);
+
+        app.receive_messages(&mut vec![AppMessage::Exiting]);
+        app.step();
+        assert!(matches!(app.current_page(), Page::Shutdown(_)));
+        assert_eq!(app.lifecycle(), Lifecycle::PendingQuit);
+
+        app.step();
+        app.step();
+        assert_eq!(app.lifecycle(), Lifecycle::QuitingShell);
+    }
+
+    #[test]
+    fn stream_that_beats_the_lobby_is_delivered_once_in_order() {
+        let (mut app, fake) = app();
+        let conversation_id = ConversationId(uuid::Uuid::new_v4());
+        let sender = UserId(uuid::Uuid::new_v4());
+        let emit = |text: &str| fake.borrow().emit_chat(sender.clone(), conversation_id.clone(), text, chrono::Utc::now()).unwrap();
+
+        // The fake connects on the spot; its success is held back so the stream gets ahead of the lobby.
+        app.update_one(AppMessage::ReqNavigate(Route::LobbyPage(TokenInfo::for_test()))).unwrap();
+        let mut connected: Vec<_> = app.message_rx.try_iter().collect();
+        emit("one");
+        emit("two");
+        app.update();
+        assert!(matches!(app.current_page(), Page::Login(..)));
+
+        app.receive_messages(&mut connected);
+        app.update();
+        emit("three");
+        app.update();
+
+        let Page::Lobby(_, lobby) = app.current_page() else {
+            panic!("The lobby is not up");
+        };
+        assert_eq!(lobby.texts(&conversation_id), ["one", "two", "three"]);
+    }
+
+    #[test]
+    fn message_for_the_lobby_being_built_is_held_for_it() {
+        let (mut app, _fake) = app();
+        let conversation_id = ConversationId(uuid::Uuid::new_v4());
+
+        app.update_one(AppMessage::ReqNavigate(Route::LobbyPage(TokenInfo::for_test()))).unwrap();
+        let mut connected: Vec<_> = app.message_rx.try_iter().collect();
+        let reserved = app.reserved_epoch.unwrap();
+        app.receive_messages(&mut vec![AppMessage::Lobby(reserved, chat(&conversation_id, "early"))]);
+        app.update();
+        app.receive_messages(&mut connected);
+        app.update();
+
+        let (epoch, lobby) = lobby(&app);
+        assert_eq!(epoch, reserved);
+        assert_eq!(lobby.texts(&conversation_id), ["early"]);
+    }
+
+    #[test]
+    fn message_for_a_page_that_is_gone_is_dropped() {
+        let (mut app, _fake) = app();
+        let conversation_id = ConversationId(uuid::Uuid::new_v4());
+        app.receive_messages(&mut vec![AppMessage::ReqNavigate(Route::LobbyPage(TokenInfo::for_test()))]);
+        app.step();
+        let (old_epoch, _) = lobby(&app);
+
+        // Logging out and back in builds a new lobby under a new epoch.
+        app.receive_messages(&mut vec![AppMessage::ReqNavigate(Route::LoginPage(None))]);
+        app.step();
+        app.receive_messages(&mut vec![AppMessage::ReqNavigate(Route::LobbyPage(TokenInfo::for_test()))]);
+        app.step();
+        app.receive_messages(&mut vec![
+            AppMessage::Lobby(old_epoch, chat(&conversation_id, "stale")),
+            AppMessage::Lobby(lobby(&app).0, chat(&conversation_id, "fresh")),
+        ]);
+        app.step();
+
+        let (epoch, lobby) = lobby(&app);
+        assert_ne!(epoch, old_epoch);
+        assert_eq!(lobby.texts(&conversation_id), ["fresh"]);
+    }
+}
@@ -2,4 +2,16 @@ mod args;
 pub use args::*;
 
 mod eframe_shell;
-pub use eframe_shell::*;
\ No newline at end of file
+pub use eframe_shell::*;
+
+mod session_store;
+pub use session_store::*;
+
+mod theme;
+pub use theme::*;
+
+mod toast;
+pub use toast::*;
+
+mod stream_buffer;
+pub use stream_buffer::*;
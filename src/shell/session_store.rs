@@ -0,0 +1,173 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::domain::UserId;
+use crate::protocol::network::TokenInfo;
+use crate::shell::Theme;
+
+const APP_DIR_NAME: &str = "client_side";
+const SESSION_FILE_NAME: &str = "session.json";
+const SETTINGS_FILE_NAME: &str = "settings.json";
+
+/// What is kept on disk between runs; enough to renew the access token without logging in.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredSession {
+    pub user_id: UserId,
+    pub refresh_token: String,
+    pub refresh_expires_at: DateTime<Utc>,
+    pub chat_address: Option<String>,
+}
+
+impl StoredSession {
+    pub fn is_expired(&self) -> bool {
+        self.refresh_expires_at <= Utc::now()
+    }
+
+    /// Token info with an already expired access token, so the network refreshes it first.
+    pub fn token_info(&self) -> TokenInfo {
+        let remaining = (self.refresh_expires_at - Utc::now()).num_seconds().max(0) as u64;
+        TokenInfo {
+            user_id: self.user_id.clone(),
+            access_token: String::new(),
+            access_expires_in: 0,
+            refresh_token: self.refresh_token.clone(),
+            refresh_expires_in: remaining,
+            chat_address: self.chat_address.clone(),
+        }
+    }
+}
+
+/// Preferences that outlive a session; logging out leaves them alone.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StoredSettings {
+    /// `None` follows the OS.
+    #[serde(default)]
+    pub theme: Option<Theme>,
+    /// Messages kept in memory per conversation; `None` keeps `DEFAULT_SCROLLBACK_LIMIT`.
+    #[serde(default)]
+    pub scrollback_limit: Option<usize>,
+    /// Closing the window from the lobby minimizes it and keeps the chat connected.
+    #[serde(default)]
+    pub close_to_background: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionStore {
+    path: PathBuf,
+    settings_path: PathBuf,
+}
+
+impl SessionStore {
+    pub fn try_new() -> anyhow::Result<Self> {
+        let config_dir = dirs::config_dir().context("No config directory on this platform")?;
+        let app_dir = config_dir.join(APP_DIR_NAME);
+        Ok(Self {
+            path: app_dir.join(SESSION_FILE_NAME),
+            settings_path: app_dir.join(SETTINGS_FILE_NAME),
+        })
+    }
+
+    /// Returns `Ok(None)` when nothing was saved; an unreadable file is an error.
+    pub fn load(&self) -> anyhow::Result<Option<StoredSession>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", self.path.display())),
+        };
+        let session = serde_json::from_str(&content)
+            .with_context(|| format!("Corrupt session file: {}", self.path.display()))?;
+        Ok(Some(session))
+    }
+
+    pub fn save(&self, token_info: &TokenInfo) -> anyhow::Result<()> {
+        let session = StoredSession {
+            user_id: token_info.user_id.clone(),
+            refresh_token: token_info.refresh_token.clone(),
+            refresh_expires_at: Utc::now() + chrono::Duration::seconds(token_info.refresh_expires_in as i64),
+            chat_address: token_info.chat_address.clone(),
+        };
+
+        create_parent_dir(&self.path)?;
+        // The mode only applies to a new file, so a fresh one replaces the old, whatever its mode.
+        let temp_path = self.path.with_extension("json.tmp");
+        let _ = fs::remove_file(&temp_path);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(&temp_path)
+            .with_context(|| format!("Failed to open {}", temp_path.display()))?;
+        file.write_all(serde_json::to_string(&session)?.as_bytes())?;
+        drop(file);
+        fs::rename(&temp_path, &self.path)
+            .with_context(|| format!("Failed to replace {}", self.path.display()))
+    }
+
+    pub fn clear(&self) -> anyhow::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to remove {}", self.path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the defaults when nothing was saved; an unreadable file is an error.
+    pub fn load_settings(&self) -> anyhow::Result<StoredSettings> {
+        let content = match fs::read_to_string(&self.settings_path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(StoredSettings::default()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", self.settings_path.display())),
+        };
+        serde_json::from_str(&content)
+            .with_context(|| format!("Corrupt settings file: {}", self.settings_path.display()))
+    }
+
+    pub fn save_settings(&self, settings: &StoredSettings) -> anyhow::Result<()> {
+        create_parent_dir(&self.settings_path)?;
+        fs::write(&self.settings_path, serde_json::to_string(settings)?)
+            .with_context(|| format!("Failed to write {}", self.settings_path.display()))
+    }
+}
+
+fn create_parent_dir(path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str) -> SessionStore {
+        let dir = std::env::temp_dir().join(format!("client_side-{}-{}", name, uuid::Uuid::new_v4()));
+        SessionStore { path: dir.join(SESSION_FILE_NAME), settings_path: dir.join(SETTINGS_FILE_NAME) }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saving_over_a_readable_session_file_restricts_it() {
+        use std::os::unix::fs::PermissionsExt;
+        let store = store("permissions");
+        create_parent_dir(&store.path).unwrap();
+        fs::write(&store.path, "{}").unwrap();
+        fs::set_permissions(&store.path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        store.save(&TokenInfo::for_test()).unwrap();
+
+        let mode = fs::metadata(&store.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(store.load().unwrap().unwrap().refresh_token, "refresh-token");
+        let _ = fs::remove_dir_all(store.path.parent().unwrap());
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::{HashMap, VecDeque};
+use tracing::warn;
+use crate::domain::ConversationId;
+use crate::protocol::network::StreamMessage;
+
+/// Held per conversation; past this the conversation's oldest message makes room.
+const MAX_HELD_PER_CONVERSATION: usize = 256;
+/// Held for messages about the connection as a whole, e.g. state changes.
+const MAX_HELD_SESSION: usize = 64;
+
+/// Chat stream messages that arrived before the lobby was built, kept per conversation
+/// so a flood in one conversation cannot push out another's.
+#[derive(Default)]
+pub struct StreamBuffer {
+    conversations: HashMap<ConversationId, VecDeque<StreamMessage>>,
+    /// Conversations in the order their first message arrived, so they are handed over in that order.
+    arrival: Vec<ConversationId>,
+    session: VecDeque<StreamMessage>,
+    dropped: u64,
+}
+
+impl StreamBuffer {
+    pub fn push(&mut self, message: StreamMessage) {
+        let (queue, cap) = match message.conversation_id() {
+            Some(conversation_id) => {
+                if !self.conversations.contains_key(conversation_id) {
+                    self.arrival.push(conversation_id.clone());
+                }
+                (self.conversations.entry(conversation_id.clone()).or_default(), MAX_HELD_PER_CONVERSATION)
+            }
+            None => (&mut self.session, MAX_HELD_SESSION),
+        };
+        if queue.len() == cap {
+            warn!("Drop oldest held stream message because its buffer is full");
+            queue.pop_front();
+            self.dropped += 1;
+        }
+        queue.push_back(message);
+    }
+
+    pub fn clear(&mut self) {
+        *self = StreamBuffer::default();
+    }
+
+    /// Connection messages first, then each conversation's in order, and finally a
+    /// `StreamMessage::MessagesDropped` if anything had to make room.
+    pub fn into_messages(mut self) -> impl Iterator<Item = StreamMessage> {
+        let conversations = std::mem::take(&mut self.arrival)
+            .into_iter()
+            .flat_map(move |conversation_id| self.conversations.remove(&conversation_id).unwrap_or_default());
+        let dropped = (self.dropped > 0).then_some(StreamMessage::MessagesDropped { count: self.dropped });
+        self.session.into_iter().chain(conversations).chain(dropped)
+    }
+}
@@ -0,0 +1,37 @@
+use eframe::egui;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Theme {
+    Dark,
+    Light,
+}
+
+impl Theme {
+    /// What the OS asks for; dark when it does not say.
+    pub fn system(ctx: &egui::Context) -> Self {
+        match ctx.system_theme() {
+            Some(egui::Theme::Light) => Theme::Light,
+            _ => Theme::Dark,
+        }
+    }
+
+    pub fn of(visuals: &egui::Visuals) -> Self {
+        if visuals.dark_mode { Theme::Dark } else { Theme::Light }
+    }
+
+    pub fn toggled(self) -> Self {
+        match self {
+            Theme::Dark => Theme::Light,
+            Theme::Light => Theme::Dark,
+        }
+    }
+
+    pub fn visuals(self) -> egui::Visuals {
+        match self {
+            Theme::Dark => egui::Visuals::dark(),
+            Theme::Light => egui::Visuals::light(),
+        }
+    }
+}
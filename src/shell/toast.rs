@@ -0,0 +1,61 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use eframe::egui;
+
+const TOAST_DURATION: Duration = Duration::from_secs(4);
+const MAX_TOASTS: usize = 5;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ToastLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+struct Toast {
+    level: ToastLevel,
+    text: String,
+    expires_at: Instant,
+}
+
+/// Short-lived notifications stacked in a corner, drawn over whichever page is up.
+#[derive(Default)]
+pub struct Toasts {
+    toasts: VecDeque<Toast>,
+}
+
+impl Toasts {
+    /// The oldest toast makes room once `MAX_TOASTS` are showing.
+    pub fn push(&mut self, level: ToastLevel, text: String) {
+        if self.toasts.len() == MAX_TOASTS {
+            self.toasts.pop_front();
+        }
+        self.toasts.push_back(Toast { level, text, expires_at: Instant::now() + TOAST_DURATION });
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context) {
+        let now = Instant::now();
+        self.toasts.retain(|toast| toast.expires_at > now);
+        let Some(next_expiry) = self.toasts.iter().map(|toast| toast.expires_at).min() else {
+            return;
+        };
+        ctx.request_repaint_after(next_expiry - now);
+
+        egui::Area::new(egui::Id::new("toasts"))
+            .anchor(egui::Align2::RIGHT_TOP, [-8.0, 8.0])
+            .order(egui::Order::Foreground)
+            .interactable(false)
+            .show(ctx, |ui| {
+                for toast in &self.toasts {
+                    let color = match toast.level {
+                        ToastLevel::Info => ui.visuals().text_color(),
+                        ToastLevel::Warning => ui.visuals().warn_fg_color,
+                        ToastLevel::Error => ui.visuals().error_fg_color,
+                    };
+                    egui::Frame::popup(ui.style()).show(ui, |ui| {
+                        ui.colored_label(color, &toast.text);
+                    });
+                }
+            });
+    }
+}
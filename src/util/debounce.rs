@@ -0,0 +1,129 @@
+use std::time::{Duration, Instant};
+
+/// Where a `Debouncer` reads the time, so it can be driven without waiting.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A `Clock` that only moves when told to.
+#[cfg(test)]
+#[derive(Clone, Debug)]
+pub(crate) struct ManualClock(std::rc::Rc<std::cell::Cell<Instant>>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self(std::rc::Rc::new(std::cell::Cell::new(Instant::now())))
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.0.set(self.0.get() + duration);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.0.get()
+    }
+}
+
+/// Lets an action through at most once per `interval`, e.g. a typing notice per burst of keystrokes.
+#[derive(Debug)]
+pub struct Debouncer<C: Clock = SystemClock> {
+    interval: Duration,
+    last: Option<Instant>,
+    clock: C,
+}
+
+impl Debouncer {
+    pub fn new(interval: Duration) -> Self {
+        Self::with_clock(interval, SystemClock)
+    }
+}
+
+impl<C: Clock> Debouncer<C> {
+    pub fn with_clock(interval: Duration, clock: C) -> Self {
+        Self { interval, last: None, clock }
+    }
+
+    /// Whether `interval` has passed since the last recorded action, or none was recorded yet.
+    pub fn ready(&self) -> bool {
+        self.last.is_none_or(|last| self.clock.now().saturating_duration_since(last) >= self.interval)
+    }
+
+    pub fn record(&mut self) {
+        self.last = Some(self.clock.now());
+    }
+
+    /// Records the action if it may go through now; `false` means the caller should skip it.
+    pub fn try_record(&mut self) -> bool {
+        let ready = self.ready();
+        if ready {
+            self.record();
+        }
+        ready
+    }
+
+    /// Forgets the last action, so the next one goes through at once.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(3);
+
+    #[test]
+    fn lets_one_action_through_per_interval() {
+        let clock = ManualClock::new();
+        let mut debouncer = Debouncer::with_clock(INTERVAL, clock.clone());
+
+        assert!(debouncer.try_record());
+        assert!(!debouncer.try_record());
+        clock.advance(INTERVAL - Duration::from_millis(1));
+        assert!(!debouncer.ready());
+        clock.advance(Duration::from_millis(1));
+        assert!(debouncer.try_record());
+        assert!(!debouncer.ready());
+    }
+
+    #[test]
+    fn skipped_actions_do_not_push_the_interval_back() {
+        let clock = ManualClock::new();
+        let mut debouncer = Debouncer::with_clock(INTERVAL, clock.clone());
+        debouncer.record();
+
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(1));
+            debouncer.try_record();
+        }
+
+        // The third skip came exactly one interval after the recorded action, so it went through.
+        assert!(!debouncer.ready());
+        clock.advance(INTERVAL);
+        assert!(debouncer.ready());
+    }
+
+    #[test]
+    fn reset_lets_the_next_action_through() {
+        let mut debouncer = Debouncer::with_clock(INTERVAL, ManualClock::new());
+        debouncer.record();
+
+        debouncer.reset();
+
+        assert!(debouncer.try_record());
+    }
+}
@@ -0,0 +1,8 @@
+mod debounce;
+pub use debounce::*;
+mod polling;
+pub use polling::*;
+#[cfg(test)]
+mod wait;
+#[cfg(test)]
+pub(crate) use wait::*;
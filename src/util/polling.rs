@@ -0,0 +1,75 @@
+use std::time::{Duration, Instant};
+use crate::util::{Clock, SystemClock};
+
+/// While something happened within `ACTIVE_WINDOW`, e.g. typing or a burst of messages.
+const ACTIVE_POLLING_INTERVAL: Duration = Duration::from_millis(16);
+const IDLE_POLLING_INTERVAL: Duration = Duration::from_millis(100);
+/// Once nothing happened for `DORMANT_AFTER`.
+const DORMANT_POLLING_INTERVAL: Duration = Duration::from_millis(250);
+const ACTIVE_WINDOW: Duration = Duration::from_secs(1);
+const DORMANT_AFTER: Duration = Duration::from_secs(10);
+
+/// How often the app repaints on its own: quickly while something is going on, easing off the
+/// longer nothing happens.
+#[derive(Debug)]
+pub struct PollingPace<C: Clock = SystemClock> {
+    last_activity: Instant,
+    clock: C,
+}
+
+impl PollingPace {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl Default for PollingPace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Clock> PollingPace<C> {
+    /// Starts out active, as launching is activity too.
+    pub fn with_clock(clock: C) -> Self {
+        let last_activity = clock.now();
+        Self { last_activity, clock }
+    }
+
+    pub fn record_activity(&mut self) {
+        self.last_activity = self.clock.now();
+    }
+
+    pub fn interval(&self) -> Duration {
+        let quiet_for = self.clock.now().saturating_duration_since(self.last_activity);
+        if quiet_for < ACTIVE_WINDOW {
+            ACTIVE_POLLING_INTERVAL
+        } else if quiet_for < DORMANT_AFTER {
+            IDLE_POLLING_INTERVAL
+        } else {
+            DORMANT_POLLING_INTERVAL
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::debounce::ManualClock;
+
+    #[test]
+    fn eases_off_while_quiet_and_speeds_up_on_activity() {
+        let clock = ManualClock::new();
+        let mut pace = PollingPace::with_clock(clock.clone());
+        assert_eq!(pace.interval(), ACTIVE_POLLING_INTERVAL);
+
+        clock.advance(ACTIVE_WINDOW);
+        assert_eq!(pace.interval(), IDLE_POLLING_INTERVAL);
+
+        clock.advance(DORMANT_AFTER - ACTIVE_WINDOW);
+        assert_eq!(pace.interval(), DORMANT_POLLING_INTERVAL);
+
+        pace.record_activity();
+        assert_eq!(pace.interval(), ACTIVE_POLLING_INTERVAL);
+    }
+}
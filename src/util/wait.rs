@@ -0,0 +1,13 @@
+use std::time::{Duration, Instant};
+
+/// How long `wait_until` polls before failing the test.
+const WAIT: Duration = Duration::from_secs(5);
+
+/// Polls `condition` until it holds, for effects carried out on another thread.
+pub(crate) fn wait_until(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + WAIT;
+    while !condition() {
+        assert!(Instant::now() < deadline, "Condition not met in time");
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}